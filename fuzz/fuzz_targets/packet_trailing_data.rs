@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ntp_proto::{NoCipher, NtpPacket};
+
+fuzz_target!(|data: Vec<u8>| {
+    let strict = NtpPacket::deserialize(&data, &NoCipher);
+    let lenient = NtpPacket::deserialize_with_trailing(&data, &NoCipher);
+
+    match (strict, lenient) {
+        // packets that parse have no trailing data
+        (Ok((a, _)), Ok((b, _, trailing))) => {
+            assert_eq!(a, b);
+            assert_eq!(trailing.consumed, data.len());
+            assert!(trailing.unexpected.is_empty());
+        }
+        (Ok(_), Err(e)) => panic!("valid packet rejected with trailing data allowed: {}", e),
+        // junk after a packet is only allowed by the lenient parse
+        (Err(_), Ok((_, _, trailing))) => {
+            assert!(!trailing.unexpected.is_empty());
+            assert_eq!(trailing.consumed + trailing.unexpected.len(), data.len());
+        }
+        (Err(_), Err(_)) => {}
+    }
+});
@@ -7,11 +7,13 @@
 //! for more information.
 #![forbid(unsafe_code)]
 
-use std::{path::PathBuf, process::ExitCode};
+use std::{path::PathBuf, process::ExitCode, time::Duration};
 
 use clap::{Parser, Subcommand};
 use ntp_daemon::{Config, ConfigUpdate, ObservableState};
 use ntp_metrics_exporter::Metrics;
+use ntp_os_clock::DefaultNtpClock;
+use ntp_proto::SntpClient;
 
 #[derive(Parser)]
 #[command(version = "0.2.0", about = "Query and configure the ntpd-rs daemon")]
@@ -47,6 +49,16 @@ enum Command {
     Config(ConfigUpdate),
     #[command(about = "Validate configuration")]
     Validate,
+    #[command(about = "Compare the local clock to reference servers, without adjusting it")]
+    Query {
+        /// Servers to compare to, as host:port
+        #[arg(required = true)]
+        servers: Vec<String>,
+
+        /// Time to wait for the response of each server, in milliseconds
+        #[arg(long, default_value_t = 5000)]
+        timeout_ms: u64,
+    },
 }
 
 enum PrintState {
@@ -75,8 +87,13 @@ async fn validate(cli: Cli) -> std::io::Result<ExitCode> {
 pub async fn main() -> std::io::Result<ExitCode> {
     let cli = Cli::parse();
 
-    if matches!(cli.command, Command::Validate) {
-        return validate(cli).await;
+    match cli.command {
+        Command::Validate => return validate(cli).await,
+        Command::Query {
+            servers,
+            timeout_ms,
+        } => return query(servers, Duration::from_millis(timeout_ms)).await,
+        _ => {}
     }
 
     let config = Config::from_args(cli.config, vec![], vec![]).await;
@@ -102,7 +119,35 @@ pub async fn main() -> std::io::Result<ExitCode> {
         Command::System => print_state(PrintState::System, observation).await,
         Command::Prometheus => print_state(PrintState::Prometheus, observation).await,
         Command::Config(config_update) => update_config(configuration, config_update).await,
-        Command::Validate => unreachable!(),
+        Command::Validate | Command::Query { .. } => unreachable!(),
+    }
+}
+
+async fn query(servers: Vec<String>, timeout: Duration) -> std::io::Result<ExitCode> {
+    let client = SntpClient::new(DefaultNtpClock::realtime()).with_timeout(timeout);
+    let results = tokio::task::spawn_blocking(move || client.compare_to_references(servers))
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let mut any_success = false;
+    for (server, result) in results {
+        match result {
+            Ok(measurement) => {
+                any_success = true;
+                println!(
+                    "{server}: offset {:+.6}s, delay {:.6}s",
+                    measurement.offset.to_seconds(),
+                    measurement.delay.to_seconds()
+                );
+            }
+            Err(e) => eprintln!("{server}: {e}"),
+        }
+    }
+
+    if any_success {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
     }
 }
 
@@ -284,6 +329,8 @@ mod tests {
         let update = ConfigUpdate {
             log_filter: Some("foo".to_string()),
             panic_threshold: Some(0.123),
+            disable_peer: None,
+            enable_peer: None,
         };
 
         let fut = super::update_config(path, update.clone());
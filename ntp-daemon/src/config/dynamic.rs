@@ -34,6 +34,17 @@ pub struct ConfigUpdate {
     /// during startup, use startup_panic_threshold
     #[arg(long)]
     pub panic_threshold: Option<f64>,
+
+    /// Exclude peers from synchronization until they are enabled again. Peers
+    /// can be selected by their address (with or without port) or by their
+    /// reference id in dotted-quad notation
+    #[arg(long)]
+    pub disable_peer: Option<String>,
+
+    /// Enable peers that were previously disabled, using the same selector
+    /// that was used to disable them
+    #[arg(long)]
+    pub enable_peer: Option<String>,
 }
 
 // Deal with reloading not being possible during testing.
@@ -50,10 +61,17 @@ impl LogReloader for ReloadHandle {
 pub async fn spawn<H: LogReloader + Send + 'static>(
     config: ConfigureConfig,
     system_config_sender: tokio::sync::watch::Sender<CombinedSystemConfig>,
+    disabled_peers_sender: tokio::sync::watch::Sender<Vec<String>>,
     log_reload_handle: H,
 ) -> JoinHandle<std::io::Result<()>> {
     tokio::spawn(async move {
-        let result = dynamic_configuration(config, system_config_sender, log_reload_handle).await;
+        let result = dynamic_configuration(
+            config,
+            system_config_sender,
+            disabled_peers_sender,
+            log_reload_handle,
+        )
+        .await;
         if let Err(ref e) = result {
             warn!("Abnormal termination of dynamic configurator: {}", e);
             warn!("The dynamic configurator will not be available");
@@ -65,6 +83,7 @@ pub async fn spawn<H: LogReloader + Send + 'static>(
 async fn dynamic_configuration<H: LogReloader>(
     config: ConfigureConfig,
     system_config_sender: tokio::sync::watch::Sender<CombinedSystemConfig>,
+    disabled_peers_sender: tokio::sync::watch::Sender<Vec<String>>,
     log_reload_handle: H,
 ) -> std::io::Result<()> {
     let path = match config.path {
@@ -107,6 +126,25 @@ async fn dynamic_configuration<H: LogReloader>(
                 };
             });
         }
+
+        if let Some(selector) = operation.disable_peer {
+            disabled_peers_sender.send_if_modified(|disabled| {
+                if disabled.contains(&selector) {
+                    false
+                } else {
+                    disabled.push(selector);
+                    true
+                }
+            });
+        }
+
+        if let Some(selector) = operation.enable_peer {
+            disabled_peers_sender.send_if_modified(|disabled| {
+                let len = disabled.len();
+                disabled.retain(|s| *s != selector);
+                disabled.len() != len
+            });
+        }
     }
 }
 
@@ -127,6 +165,7 @@ mod tests {
     async fn test_dynamic_configuration_change() {
         let (system_config_sender, system_config_receiver) =
             tokio::sync::watch::channel(CombinedSystemConfig::default());
+        let (disabled_peers_sender, disabled_peers_receiver) = tokio::sync::watch::channel(vec![]);
 
         let path = std::env::temp_dir().join("ntp-test-stream-4");
         let config = ConfigureConfig {
@@ -134,7 +173,13 @@ mod tests {
             mode: 0o700,
         };
 
-        let handle = spawn(config, system_config_sender, TestLogReloader {}).await;
+        let handle = spawn(
+            config,
+            system_config_sender,
+            disabled_peers_sender,
+            TestLogReloader {},
+        )
+        .await;
 
         // Ensure client has started.
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -146,6 +191,8 @@ mod tests {
             &ConfigUpdate {
                 log_filter: Some("info".into()),
                 panic_threshold: Some(600.),
+                disable_peer: Some("example.com".into()),
+                enable_peer: None,
             },
         )
         .await
@@ -162,6 +209,7 @@ mod tests {
                 .forward,
             Some(NtpDuration::from_seconds(600.))
         );
+        assert_eq!(*disabled_peers_receiver.borrow(), vec!["example.com"]);
 
         handle.abort();
     }
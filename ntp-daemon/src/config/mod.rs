@@ -10,7 +10,9 @@ pub use peer::*;
 pub use server::*;
 
 use clap::Parser;
-use ntp_proto::{DefaultTimeSyncController, SystemConfig, TimeSyncController};
+use ntp_proto::{
+    DefaultTimeSyncController, KeysFileError, SymmetricKeys, SystemConfig, TimeSyncController,
+};
 use serde::{de, Deserialize, Deserializer};
 use std::{
     io::ErrorKind,
@@ -163,6 +165,8 @@ pub struct Config {
     pub peers: Vec<PeerConfig>,
     #[serde(alias = "server", default)]
     pub servers: Vec<ServerConfig>,
+    #[serde(alias = "broadcast-client", default)]
+    pub broadcast_clients: Vec<BroadcastClientConfig>,
     #[serde(alias = "nts-ke-server", default)]
     pub nts_ke: Option<NtsKeConfig>,
     #[serde(default)]
@@ -182,6 +186,10 @@ pub struct Config {
     pub keyset: KeysetConfig,
     #[serde(default)]
     pub clock: ClockConfig,
+    /// File with the symmetric keys that peers and clients of our servers can
+    /// use to authenticate exchanges, in the format of `ntp.keys`
+    #[serde(default)]
+    pub keys_file: Option<PathBuf>,
 }
 
 const fn default_observe_permissions() -> u32 {
@@ -248,6 +256,10 @@ pub enum ConfigError {
     Io(#[from] io::Error),
     #[error("config toml parsing error: {0}")]
     Toml(#[from] toml::de::Error),
+    #[error("keys file parsing error: {0}")]
+    KeysFile(#[from] KeysFileError),
+    #[error("peer uses key {0}, which is not in the keys file")]
+    MissingKey(u32),
 }
 
 impl Config {
@@ -312,6 +324,40 @@ impl Config {
         Ok(config)
     }
 
+    /// Read the symmetric keys from the keys file, if there is one, and check
+    /// that the keys of all peers are in it
+    pub async fn symmetric_keys(&self) -> Result<SymmetricKeys, ConfigError> {
+        let keys = match &self.keys_file {
+            Some(path) => {
+                let meta = std::fs::metadata(path)?;
+                if meta.permissions().mode() & libc::S_IROTH != 0 {
+                    warn!("Unrestricted keys file permissions: Others can read.");
+                }
+
+                read_to_string(path).await?.parse()?
+            }
+            None => SymmetricKeys::default(),
+        };
+
+        for peer in &self.peers {
+            if let PeerConfig::Standard(StandardPeerConfig { key: Some(id), .. }) = peer {
+                if keys.get(*id).is_none() {
+                    return Err(ConfigError::MissingKey(*id));
+                }
+            }
+        }
+
+        for broadcast_client in &self.broadcast_clients {
+            if let Some(id) = broadcast_client.key {
+                if keys.get(id).is_none() {
+                    return Err(ConfigError::MissingKey(id));
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
     /// Count potential number of peers in configuration
     fn count_peers(&self) -> usize {
         let mut count = 0;
@@ -322,7 +368,7 @@ impl Config {
                 PeerConfig::Pool(config) => count += config.max_peers,
             }
         }
-        count
+        count + self.broadcast_clients.len()
     }
 
     /// Check that the config is reasonable. This function may panic if the
@@ -334,7 +380,7 @@ impl Config {
         // using those fields should always work. This is also
         // probably a good policy in general (config should always work
         // but we may panic here to protect the user from themselves)
-        if self.peers.is_empty() {
+        if self.peers.is_empty() && self.broadcast_clients.is_empty() {
             warn!("No peers configured. Daemon will not change system time.");
             ok = false;
         }
@@ -363,6 +409,11 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
 
@@ -373,6 +424,11 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
 
@@ -383,6 +439,11 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
 
@@ -393,6 +454,11 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
         assert_eq!(
@@ -412,6 +478,11 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
         assert!(config.system.system.panic_threshold.forward.is_none());
@@ -447,10 +518,64 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
     }
 
+    #[tokio::test]
+    async fn test_symmetric_keys() {
+        let config: Config = toml::from_str("[[peer]]\naddr = \"example.com\"").unwrap();
+        assert!(config.symmetric_keys().await.unwrap().is_empty());
+
+        let path = std::env::temp_dir().join("ntpd-rs-test-symmetric-keys");
+        std::fs::write(&path, "1 MD5 secret\n").unwrap();
+
+        let mut config: Config = toml::from_str(&format!(
+            "keys-file = {:?}\n[[peer]]\naddr = \"example.com\"\nkey = 1",
+            path
+        ))
+        .unwrap();
+        let keys = config.symmetric_keys().await.unwrap();
+        assert!(keys.get(1).is_some());
+
+        config.peers = vec![PeerConfig::Standard(StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("example.com", 123),
+            prefer: false,
+            leap_smear: false,
+            address_family: AddressFamily::Any,
+            key: Some(2),
+            symmetric: false,
+        })];
+        assert!(matches!(
+            config.symmetric_keys().await,
+            Err(ConfigError::MissingKey(2))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_broadcast_client_config() {
+        let config: Config = toml::from_str(
+            "peers = []\n[[broadcast-client]]\naddr = \"224.0.1.1:123\"\ninterface = \"lo\"\nkey = 3",
+        )
+        .unwrap();
+        assert_eq!(
+            config.broadcast_clients,
+            vec![BroadcastClientConfig {
+                addr: "224.0.1.1:123".parse().unwrap(),
+                interface: Some(InterfaceName::from_str("lo").unwrap()),
+                key: Some(3),
+            }]
+        );
+        assert_eq!(config.count_peers(), 1);
+    }
+
     #[cfg(feature = "sentry")]
     #[test]
     fn test_sentry_config() {
@@ -530,6 +655,11 @@ mod tests {
             parsed_empty.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("foo.nl", 123),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             })]
         );
         assert!(parsed_empty.config.is_none());
@@ -543,9 +673,19 @@ mod tests {
             vec![
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("foo.rs", 123),
+                    prefer: false,
+                    leap_smear: false,
+                    address_family: AddressFamily::Any,
+                    key: None,
+                    symmetric: false,
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("spam.nl", 123),
+                    prefer: false,
+                    leap_smear: false,
+                    address_family: AddressFamily::Any,
+                    key: None,
+                    symmetric: false,
                 }),
             ]
         );
@@ -1,5 +1,7 @@
 use std::{fmt, net::SocketAddr, path::PathBuf, sync::Arc};
 
+use ntp_proto::AeadAlgorithm;
+use ntp_udp::InterfaceName;
 use rustls::Certificate;
 use serde::{
     de::{self, MapAccess, Visitor},
@@ -17,18 +19,95 @@ pub enum PeerHostMode {
     NtsServer,
     #[serde(alias = "pool")]
     Pool,
+    #[serde(alias = "symmetric")]
+    Symmetric,
+}
+
+/// Which addresses of a host to use, when it resolves to both IPv4 and IPv6
+/// addresses
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressFamily {
+    /// Use the addresses in the order given by the resolver
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl AddressFamily {
+    /// Whether `addr` may be used at all
+    pub fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Ipv4Only => addr.is_ipv4(),
+            AddressFamily::Ipv6Only => addr.is_ipv6(),
+            _ => true,
+        }
+    }
+
+    /// Lower ranks are preferred
+    pub fn rank(self, addr: &SocketAddr) -> u8 {
+        match self {
+            AddressFamily::PreferIpv4 => addr.is_ipv6() as u8,
+            AddressFamily::PreferIpv6 => addr.is_ipv4() as u8,
+            _ => 0,
+        }
+    }
+
+    /// The most preferred of the allowed `addresses`, the first one on a tie
+    pub fn select(self, addresses: impl Iterator<Item = SocketAddr>) -> Option<SocketAddr> {
+        addresses
+            .filter(|addr| self.allows(addr))
+            .min_by_key(|addr| self.rank(addr))
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct StandardPeerConfig {
     pub addr: NormalizedAddress,
+    #[serde(default)]
+    pub prefer: bool,
+    /// The peer smears leap seconds instead of announcing them, so it is kept
+    /// out of the leap second vote
+    #[serde(default)]
+    pub leap_smear: bool,
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    /// Id of the symmetric key from the keys file with which the exchanges
+    /// with this peer are authenticated
+    #[serde(default)]
+    pub key: Option<u32>,
+    /// Peer in a symmetric active association instead of being a client
+    #[serde(default)]
+    pub symmetric: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct NtsPeerConfig {
     pub ke_addr: NormalizedAddress,
     pub certificates: Arc<[Certificate]>,
+    pub prefer: bool,
+    pub leap_smear: bool,
+    pub address_family: AddressFamily,
+    /// Save the cookies here, to use them again after a restart
+    pub cookie_store: Option<CookieStoreConfig>,
+    /// Poll this NTP server, instead of the one the key exchange negotiated
+    pub ntp_server: Option<String>,
+    /// Poll on this port, instead of the one the key exchange negotiated
+    pub ntp_port: Option<u16>,
+    /// The AEAD algorithms offered in the key exchange, in order of preference
+    pub aead_algorithms: Vec<AeadAlgorithm>,
+}
+
+/// Where an NTS peer saves its cookies and keys, encrypted
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CookieStoreConfig {
+    pub path: PathBuf,
+    /// File with the secret from which the key to encrypt the cookies is derived
+    pub key_path: PathBuf,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -36,6 +115,24 @@ pub struct NtsPeerConfig {
 pub struct PoolPeerConfig {
     pub addr: NormalizedAddress,
     pub max_peers: usize,
+    #[serde(default)]
+    pub address_family: AddressFamily,
+}
+
+/// A server that is not polled, but whose broadcast or multicast packets are
+/// received on a local address
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BroadcastClientConfig {
+    /// Address to listen on, either a multicast group or a local address that
+    /// receives the broadcasts
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub interface: Option<InterfaceName>,
+    /// Id of the symmetric key from the keys file with which the broadcasts
+    /// and the calibration exchange are authenticated
+    #[serde(default)]
+    pub key: Option<u32>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -95,6 +192,17 @@ impl NormalizedAddress {
         })
     }
 
+    /// An address that was not configured by name, such as the source of a broadcast
+    pub(crate) fn from_socket_addr(addr: SocketAddr) -> Self {
+        Self {
+            server_name: addr.ip().to_string(),
+            port: addr.port(),
+
+            #[cfg(test)]
+            hardcoded_dns_resolve: vec![],
+        }
+    }
+
     fn from_string_help(address: String, default_port: u16) -> std::io::Result<(String, u16)> {
         if address.split(':').count() > 2 {
             // IPv6, try to parse it as such
@@ -185,6 +293,11 @@ impl TryFrom<&str> for StandardPeerConfig {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         Ok(Self {
             addr: NormalizedAddress::from_string_ntp(value.to_string())?,
+            prefer: false,
+            leap_smear: false,
+            address_family: AddressFamily::Any,
+            key: None,
+            symmetric: false,
         })
     }
 }
@@ -223,6 +336,15 @@ impl<'de> Deserialize<'de> for PeerConfig {
                 let mut addr = None;
                 let mut mode = None;
                 let mut max_peers = None;
+                let mut prefer = None;
+                let mut leap_smear = None;
+                let mut address_family = None;
+                let mut keyid = None;
+                let mut cookie_store = None;
+                let mut cookie_store_key = None;
+                let mut ntp_server = None;
+                let mut ntp_port = None;
+                let mut aead_algorithms = None;
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         "addr" => {
@@ -269,10 +391,102 @@ impl<'de> Deserialize<'de> for PeerConfig {
                             }
                             max_peers = Some(map.next_value()?);
                         }
+                        "prefer" => {
+                            if prefer.is_some() {
+                                return Err(de::Error::duplicate_field("prefer"));
+                            }
+                            prefer = Some(map.next_value()?);
+                        }
+                        "leap-smear" => {
+                            if leap_smear.is_some() {
+                                return Err(de::Error::duplicate_field("leap-smear"));
+                            }
+                            leap_smear = Some(map.next_value()?);
+                        }
+                        "address-family" => {
+                            if address_family.is_some() {
+                                return Err(de::Error::duplicate_field("address-family"));
+                            }
+                            address_family = Some(map.next_value()?);
+                        }
+                        "key" => {
+                            if keyid.is_some() {
+                                return Err(de::Error::duplicate_field("key"));
+                            }
+                            keyid = Some(map.next_value()?);
+                        }
+                        "cookie-store" => {
+                            if cookie_store.is_some() {
+                                return Err(de::Error::duplicate_field("cookie-store"));
+                            }
+                            let raw: String = map.next_value()?;
+                            cookie_store = Some(PathBuf::from(raw));
+                        }
+                        "cookie-store-key" => {
+                            if cookie_store_key.is_some() {
+                                return Err(de::Error::duplicate_field("cookie-store-key"));
+                            }
+                            let raw: String = map.next_value()?;
+                            cookie_store_key = Some(PathBuf::from(raw));
+                        }
+                        "ntp-server" => {
+                            if ntp_server.is_some() {
+                                return Err(de::Error::duplicate_field("ntp-server"));
+                            }
+                            ntp_server = Some(map.next_value()?);
+                        }
+                        "ntp-port" => {
+                            if ntp_port.is_some() {
+                                return Err(de::Error::duplicate_field("ntp-port"));
+                            }
+                            ntp_port = Some(map.next_value()?);
+                        }
+                        "aead-algorithms" => {
+                            if aead_algorithms.is_some() {
+                                return Err(de::Error::duplicate_field("aead-algorithms"));
+                            }
+                            let raw: Vec<String> = map.next_value()?;
+                            let parsed = raw
+                                .iter()
+                                .map(|name| {
+                                    AeadAlgorithm::IN_ORDER_OF_PREFERENCE
+                                        .iter()
+                                        .find(|algorithm| algorithm.name() == name)
+                                        .copied()
+                                        .ok_or_else(|| {
+                                            de::Error::custom(format!(
+                                                "unsupported AEAD algorithm {name}"
+                                            ))
+                                        })
+                                })
+                                .collect::<Result<Vec<_>, _>>()?;
+                            if parsed.is_empty() {
+                                return Err(de::Error::invalid_length(
+                                    0,
+                                    &"at least one algorithm",
+                                ));
+                            }
+                            aead_algorithms = Some(parsed);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key.as_str(),
-                                &["addr", "ke-addr", "certificate", "mode", "max-peers"],
+                                &[
+                                    "addr",
+                                    "ke-addr",
+                                    "certificate",
+                                    "mode",
+                                    "max-peers",
+                                    "prefer",
+                                    "leap-smear",
+                                    "address-family",
+                                    "key",
+                                    "cookie-store",
+                                    "cookie-store-key",
+                                    "ntp-server",
+                                    "ntp-port",
+                                    "aead-algorithms",
+                                ],
                             ));
                         }
                     }
@@ -284,27 +498,78 @@ impl<'de> Deserialize<'de> for PeerConfig {
                     |field, valid_fields| Err(de::Error::unknown_field(field, valid_fields));
 
                 match mode {
-                    PeerHostMode::Server => {
+                    PeerHostMode::Server | PeerHostMode::Symmetric => {
                         let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
 
-                        let valid_fields = &["addr", "mode"];
+                        let valid_fields = &[
+                            "addr",
+                            "mode",
+                            "prefer",
+                            "leap-smear",
+                            "address-family",
+                            "key",
+                        ];
                         if max_peers.is_some() {
                             unknown_field("max-peers", valid_fields)
                         } else if ke_addr.is_some() {
                             unknown_field("ke-addr", valid_fields)
                         } else if opt_certificate_path.is_some() {
                             unknown_field("certificate", valid_fields)
+                        } else if cookie_store.is_some() {
+                            unknown_field("cookie-store", valid_fields)
+                        } else if cookie_store_key.is_some() {
+                            unknown_field("cookie-store-key", valid_fields)
+                        } else if ntp_server.is_some() {
+                            unknown_field("ntp-server", valid_fields)
+                        } else if ntp_port.is_some() {
+                            unknown_field("ntp-port", valid_fields)
+                        } else if aead_algorithms.is_some() {
+                            unknown_field("aead-algorithms", valid_fields)
                         } else {
-                            Ok(PeerConfig::Standard(StandardPeerConfig { addr }))
+                            Ok(PeerConfig::Standard(StandardPeerConfig {
+                                addr,
+                                prefer: prefer.unwrap_or_default(),
+                                leap_smear: leap_smear.unwrap_or_default(),
+                                address_family: address_family.unwrap_or_default(),
+                                key: keyid,
+                                symmetric: mode == PeerHostMode::Symmetric,
+                            }))
                         }
                     }
                     PeerHostMode::NtsServer => {
                         let ke_addr = ke_addr.ok_or_else(|| de::Error::missing_field("ke_addr"))?;
 
-                        let valid_fields = &["mode", "ke-addr", "certificate"];
+                        let valid_fields = &[
+                            "mode",
+                            "ke-addr",
+                            "certificate",
+                            "prefer",
+                            "leap-smear",
+                            "address-family",
+                            "cookie-store",
+                            "cookie-store-key",
+                            "ntp-server",
+                            "ntp-port",
+                            "aead-algorithms",
+                        ];
                         if max_peers.is_some() {
                             unknown_field("max-peers", valid_fields)
+                        } else if keyid.is_some() {
+                            unknown_field("key", valid_fields)
                         } else {
+                            let cookie_store = match (cookie_store, cookie_store_key) {
+                                (Some(path), Some(key_path)) => {
+                                    Some(CookieStoreConfig { path, key_path })
+                                }
+                                (Some(_), None) => {
+                                    return Err(de::Error::missing_field("cookie-store-key"))
+                                }
+                                (None, Some(_)) => {
+                                    return Err(de::Error::missing_field("cookie-store"))
+                                }
+                                (None, None) => None,
+                            };
+
                             let certificates: Arc<[Certificate]> = if let Some(certificate_path) =
                                 opt_certificate_path
                             {
@@ -324,21 +589,50 @@ impl<'de> Deserialize<'de> for PeerConfig {
                             Ok(PeerConfig::Nts(NtsPeerConfig {
                                 ke_addr,
                                 certificates,
+                                prefer: prefer.unwrap_or_default(),
+                                leap_smear: leap_smear.unwrap_or_default(),
+                                address_family: address_family.unwrap_or_default(),
+                                cookie_store,
+                                ntp_server,
+                                ntp_port,
+                                aead_algorithms: aead_algorithms.unwrap_or_else(|| {
+                                    AeadAlgorithm::IN_ORDER_OF_PREFERENCE.to_vec()
+                                }),
                             }))
                         }
                     }
                     PeerHostMode::Pool => {
                         let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
 
-                        let valid_fields = &["addr", "mode", "max-peers"];
+                        let valid_fields = &["addr", "mode", "max-peers", "address-family"];
                         if ke_addr.is_some() {
                             unknown_field("ke-addr", valid_fields)
                         } else if opt_certificate_path.is_some() {
                             unknown_field("certificate", valid_fields)
+                        } else if prefer.is_some() {
+                            unknown_field("prefer", valid_fields)
+                        } else if leap_smear.is_some() {
+                            unknown_field("leap-smear", valid_fields)
+                        } else if keyid.is_some() {
+                            unknown_field("key", valid_fields)
+                        } else if cookie_store.is_some() {
+                            unknown_field("cookie-store", valid_fields)
+                        } else if cookie_store_key.is_some() {
+                            unknown_field("cookie-store-key", valid_fields)
+                        } else if ntp_server.is_some() {
+                            unknown_field("ntp-server", valid_fields)
+                        } else if ntp_port.is_some() {
+                            unknown_field("ntp-port", valid_fields)
+                        } else if aead_algorithms.is_some() {
+                            unknown_field("aead-algorithms", valid_fields)
                         } else {
                             let max_peers = max_peers.unwrap_or(1);
 
-                            Ok(PeerConfig::Pool(PoolPeerConfig { addr, max_peers }))
+                            Ok(PeerConfig::Pool(PoolPeerConfig {
+                                addr,
+                                max_peers,
+                                address_family: address_family.unwrap_or_default(),
+                            }))
                         }
                     }
                 }
@@ -435,7 +729,331 @@ mod tests {
         assert!(matches!(test.peer, PeerConfig::Nts(_)));
         if let PeerConfig::Nts(config) = test.peer {
             assert_eq!(config.ke_addr.to_string(), "example.com:4460");
+            assert!(!config.prefer);
+            assert_eq!(config.cookie_store, None);
+            assert_eq!(config.ntp_server, None);
+            assert_eq!(config.ntp_port, None);
+            assert_eq!(
+                config.aead_algorithms,
+                AeadAlgorithm::IN_ORDER_OF_PREFERENCE
+            );
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            ke-addr = "example.com"
+            mode = "NtsServer"
+            aead-algorithms = ["AEAD_AES_SIV_CMAC_256"]
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Nts(config) = test.peer {
+            assert_eq!(config.aead_algorithms, [AeadAlgorithm::AeadAesSivCmac256]);
+        } else {
+            panic!("expected an NTS peer");
+        }
+
+        for algorithms in ["[]", r#"["AEAD_AES_SIV_CMAC_384"]"#] {
+            let test: Result<TestConfig, _> = toml::from_str(&format!(
+                r#"
+                [peer]
+                ke-addr = "example.com"
+                mode = "NtsServer"
+                aead-algorithms = {algorithms}
+                "#
+            ));
+            assert!(test.is_err());
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            ke-addr = "example.com"
+            mode = "NtsServer"
+            ntp-server = "192.0.2.1"
+            ntp-port = 10123
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Nts(config) = test.peer {
+            assert_eq!(config.ntp_server.as_deref(), Some("192.0.2.1"));
+            assert_eq!(config.ntp_port, Some(10123));
+        } else {
+            panic!("expected an NTS peer");
+        }
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            ntp-port = 10123
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            ke-addr = "example.com"
+            mode = "NtsServer"
+            cookie-store = "/var/lib/ntpd-rs/example.cookies"
+            cookie-store-key = "/etc/ntpd-rs/cookie.key"
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Nts(config) = test.peer {
+            assert_eq!(
+                config.cookie_store,
+                Some(CookieStoreConfig {
+                    path: "/var/lib/ntpd-rs/example.cookies".into(),
+                    key_path: "/etc/ntpd-rs/cookie.key".into(),
+                })
+            );
+        } else {
+            panic!("expected an NTS peer");
+        }
+
+        // the cookies are only stored encrypted
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            ke-addr = "example.com"
+            mode = "NtsServer"
+            cookie-store = "/var/lib/ntpd-rs/example.cookies"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            cookie-store = "/var/lib/ntpd-rs/example.cookies"
+            cookie-store-key = "/etc/ntpd-rs/cookie.key"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            prefer = true
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(test.peer, PeerConfig::Standard(_)));
+        if let PeerConfig::Standard(config) = test.peer {
+            assert!(config.prefer);
+        }
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            prefer = true
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            leap-smear = true
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert!(config.leap_smear);
+        } else {
+            panic!("expected a standard peer");
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            ke-addr = "example.com"
+            mode = "NtsServer"
+            leap-smear = true
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Nts(config) = test.peer {
+            assert!(config.leap_smear);
+        } else {
+            panic!("expected an NTS peer");
+        }
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            leap-smear = true
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_address_family() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            address-family = "ipv6-only"
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert_eq!(config.address_family, AddressFamily::Ipv6Only);
+        } else {
+            panic!("expected a standard peer");
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            address-family = "prefer-ipv4"
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Pool(config) = test.peer {
+            assert_eq!(config.address_family, AddressFamily::PreferIpv4);
+        } else {
+            panic!("expected a pool");
+        }
+
+        let test: TestConfig = toml::from_str("[peer]\naddr = \"example.com\"").unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert_eq!(config.address_family, AddressFamily::Any);
         }
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            address-family = "ipv5"
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_key() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            key = 12
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert_eq!(config.key, Some(12));
+        } else {
+            panic!("expected a standard peer");
+        }
+
+        // pools and nts peers can't use symmetric keys
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            key = 12
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            ke-addr = "example.com"
+            mode = "NtsServer"
+            key = 12
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_symmetric() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "symmetric"
+            key = 12
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert!(config.symmetric);
+            assert_eq!(config.key, Some(12));
+        } else {
+            panic!("expected a standard peer");
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert!(!config.symmetric);
+        } else {
+            panic!("expected a standard peer");
+        }
+    }
+
+    #[test]
+    fn test_address_family_select() {
+        // the resolution result of a dual-stack host
+        let v4_a: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let v6_a: SocketAddr = "[2001:db8::1]:123".parse().unwrap();
+        let v4_b: SocketAddr = "192.0.2.2:123".parse().unwrap();
+        let v6_b: SocketAddr = "[2001:db8::2]:123".parse().unwrap();
+        let dual_stack = [v4_a, v6_a, v4_b, v6_b];
+        let v6_first = [v6_a, v4_a];
+
+        let select = |family: AddressFamily, addresses: &[SocketAddr]| {
+            family.select(addresses.iter().copied())
+        };
+
+        assert_eq!(select(AddressFamily::Any, &dual_stack), Some(v4_a));
+        assert_eq!(select(AddressFamily::Any, &v6_first), Some(v6_a));
+        assert_eq!(select(AddressFamily::PreferIpv4, &v6_first), Some(v4_a));
+        assert_eq!(select(AddressFamily::PreferIpv6, &dual_stack), Some(v6_a));
+        assert_eq!(select(AddressFamily::Ipv4Only, &v6_first), Some(v4_a));
+        assert_eq!(select(AddressFamily::Ipv6Only, &dual_stack), Some(v6_a));
+
+        // a preference falls back to the other family, a requirement doesn't
+        assert_eq!(select(AddressFamily::PreferIpv6, &[v4_b]), Some(v4_b));
+        assert_eq!(select(AddressFamily::Ipv6Only, &[v4_a, v4_b]), None);
+        assert_eq!(select(AddressFamily::Ipv4Only, &[v6_a, v6_b]), None);
     }
 
     #[test]
@@ -462,7 +1080,31 @@ mod tests {
         assert!(matches!(test.peer, PeerConfig::Nts(_)));
         if let PeerConfig::Nts(config) = test.peer {
             assert_eq!(config.ke_addr.to_string(), "example.com:4460");
+            assert!(!config.prefer);
         }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            prefer = true
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(test.peer, PeerConfig::Standard(_)));
+        if let PeerConfig::Standard(config) = test.peer {
+            assert!(config.prefer);
+        }
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            prefer = true
+            "#,
+        );
+        assert!(test.is_err());
     }
 
     #[test]
@@ -24,6 +24,13 @@ pub struct KeysetConfig {
     pub rotation_interval: usize,
     #[serde(default)]
     pub storage_path: Option<String>,
+    /// Encrypt the stored keys with a key derived from the secret in this file
+    #[serde(default)]
+    pub storage_secret_path: Option<PathBuf>,
+    /// Derive the keys from the secret in this file instead of generating
+    /// them, rotating at every multiple of the rotation interval
+    #[serde(default)]
+    pub master_secret_path: Option<PathBuf>,
 }
 
 impl Default for KeysetConfig {
@@ -32,6 +39,8 @@ impl Default for KeysetConfig {
             old_keys: default_old_keys(),
             rotation_interval: default_rotation_interval(),
             storage_path: None,
+            storage_secret_path: None,
+            master_secret_path: None,
         }
     }
 }
@@ -52,6 +61,18 @@ pub enum FilterAction {
     Deny,
 }
 
+/// What the server does with requests while it is not synchronized itself
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize)]
+pub enum UnsynchronizedAction {
+    /// Respond as usual, advertising stratum 16
+    #[default]
+    Serve,
+    /// Send a deny response
+    Deny,
+    /// Don't respond at all
+    Ignore,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ServerConfig {
     pub addr: SocketAddr,
@@ -61,6 +82,14 @@ pub struct ServerConfig {
     pub allowlist_action: FilterAction,
     pub rate_limiting_cache_size: usize,
     pub rate_limiting_cutoff: Duration,
+    pub echo_fields: Vec<u16>,
+    pub response_jitter: Duration,
+    pub unsynchronized_action: UnsynchronizedAction,
+    /// Answer requests that fail MAC authentication with a crypto-NAK
+    pub crypto_nak: bool,
+    /// Pad NTS responses to the size of the request, hiding how many cookies
+    /// they carry
+    pub nts_response_padding: bool,
 }
 
 impl ServerConfig {
@@ -81,6 +110,11 @@ impl TryFrom<&str> for ServerConfig {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cache_size: Default::default(),
             rate_limiting_cutoff: Default::default(),
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         })
     }
 }
@@ -113,6 +147,11 @@ impl<'de> Deserialize<'de> for ServerConfig {
                 let mut allowlist_action = None;
                 let mut denylist = None;
                 let mut denylist_action = None;
+                let mut echo_fields = None;
+                let mut response_jitter = None;
+                let mut unsynchronized_action = None;
+                let mut crypto_nak = None;
+                let mut nts_response_padding = None;
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         "addr" => {
@@ -161,6 +200,41 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                             rate_limiting_cutoff = Some(Duration::from_millis(map.next_value()?));
                         }
+                        "echo-fields" => {
+                            if echo_fields.is_some() {
+                                return Err(de::Error::duplicate_field("echo-fields"));
+                            }
+
+                            echo_fields = Some(map.next_value()?);
+                        }
+                        "response-jitter-ms" => {
+                            if response_jitter.is_some() {
+                                return Err(de::Error::duplicate_field("response-jitter-ms"));
+                            }
+
+                            response_jitter = Some(Duration::from_millis(map.next_value()?));
+                        }
+                        "unsynchronized-action" => {
+                            if unsynchronized_action.is_some() {
+                                return Err(de::Error::duplicate_field("unsynchronized-action"));
+                            }
+
+                            unsynchronized_action = Some(map.next_value()?);
+                        }
+                        "crypto-nak" => {
+                            if crypto_nak.is_some() {
+                                return Err(de::Error::duplicate_field("crypto-nak"));
+                            }
+
+                            crypto_nak = Some(map.next_value()?);
+                        }
+                        "nts-response-padding" => {
+                            if nts_response_padding.is_some() {
+                                return Err(de::Error::duplicate_field("nts-response-padding"));
+                            }
+
+                            nts_response_padding = Some(map.next_value()?);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key.as_str(),
@@ -172,6 +246,11 @@ impl<'de> Deserialize<'de> for ServerConfig {
                                     "denylist-action",
                                     "rate-limiting-cache-size",
                                     "rate-limiting-cutoff-ms",
+                                    "echo-fields",
+                                    "response-jitter-ms",
+                                    "unsynchronized-action",
+                                    "crypto-nak",
+                                    "nts-response-padding",
                                 ],
                             ));
                         }
@@ -198,6 +277,11 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                 let rate_limiting_cache_size = rate_limiting_cache_size.unwrap_or_default();
                 let rate_limiting_cutoff = rate_limiting_cutoff.unwrap_or_default();
+                let echo_fields = echo_fields.unwrap_or_default();
+                let response_jitter = response_jitter.unwrap_or_default();
+                let unsynchronized_action = unsynchronized_action.unwrap_or_default();
+                let crypto_nak = crypto_nak.unwrap_or_default();
+                let nts_response_padding = nts_response_padding.unwrap_or_default();
 
                 Ok(ServerConfig {
                     addr,
@@ -207,6 +291,11 @@ impl<'de> Deserialize<'de> for ServerConfig {
                     denylist_action,
                     rate_limiting_cache_size,
                     rate_limiting_cutoff,
+                    echo_fields,
+                    response_jitter,
+                    unsynchronized_action,
+                    crypto_nak,
+                    nts_response_padding,
                 })
             }
         }
@@ -223,6 +312,12 @@ pub struct NtsKeConfig {
     #[serde(default = "default_nts_ke_timeout")]
     pub timeout_ms: u64,
     pub addr: SocketAddr,
+    /// NTP server that clients should use, when not on the same host
+    #[serde(default)]
+    pub ntp_server: Option<String>,
+    /// Port of the NTP server that clients should use, when not the default
+    #[serde(default)]
+    pub ntp_port: Option<u16>,
 }
 
 fn default_nts_ke_timeout() -> u64 {
@@ -264,6 +359,67 @@ mod tests {
             test.server.rate_limiting_cutoff,
             Duration::from_millis(1000)
         );
+        assert!(test.server.echo_fields.is_empty());
+        assert_eq!(test.server.response_jitter, Duration::ZERO);
+        assert_eq!(
+            test.server.unsynchronized_action,
+            UnsynchronizedAction::Serve
+        );
+        assert!(!test.server.crypto_nak);
+        assert!(!test.server.nts_response_padding);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            echo-fields = [30583, 65280]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.server.echo_fields, vec![30583, 65280]);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            response-jitter-ms = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.server.response_jitter, Duration::from_millis(5));
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            unsynchronized-action = "Deny"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.server.unsynchronized_action,
+            UnsynchronizedAction::Deny
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            crypto-nak = true
+            "#,
+        )
+        .unwrap();
+        assert!(test.server.crypto_nak);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            nts-response-padding = true
+            "#,
+        )
+        .unwrap();
+        assert!(test.server.nts_response_padding);
     }
 
     #[test]
@@ -9,7 +9,8 @@ use std::{
 };
 
 use ntp_proto::{
-    KeyExchangeClient, KeyExchangeError, KeyExchangeResult, KeyExchangeServer, KeySet,
+    AeadAlgorithm, KeyExchangeClient, KeyExchangeError, KeyExchangeResult, KeyExchangeServer,
+    KeySet,
 };
 use rustls::{Certificate, PrivateKey};
 use tokio::{
@@ -24,6 +25,7 @@ pub(crate) async fn key_exchange_client(
     server_name: String,
     port: u16,
     extra_certificates: &[Certificate],
+    algorithms: &[AeadAlgorithm],
 ) -> Result<KeyExchangeResult, KeyExchangeError> {
     let socket = tokio::net::TcpStream::connect((server_name.as_str(), port)).await?;
 
@@ -42,7 +44,7 @@ pub(crate) async fn key_exchange_client(
         .with_root_certificates(roots)
         .with_no_client_auth();
 
-    BoundKeyExchangeClient::new(socket, server_name, config)?.await
+    BoundKeyExchangeClient::new(socket, server_name, config, algorithms)?.await
 }
 
 pub fn spawn(
@@ -101,6 +103,8 @@ async fn run_nts_ke(
         cert_chain,
         key_der,
         nts_ke_config.timeout_ms,
+        nts_ke_config.ntp_server,
+        nts_ke_config.ntp_port,
     )
     .await
 }
@@ -111,11 +115,13 @@ async fn key_exchange_server(
     certificate_chain: Vec<Certificate>,
     key_der: PrivateKey,
     timeout_ms: u64,
+    ntp_server: Option<String>,
+    ntp_port: Option<u16>,
 ) -> std::io::Result<()> {
     use std::io;
 
-    dbg!("listening");
     let listener = TcpListener::bind(&address).await?;
+    tracing::info!(address = ?listener.local_addr()?, "NTS KE server listening");
 
     let mut config = rustls::ServerConfig::builder()
         .with_safe_defaults()
@@ -132,9 +138,10 @@ async fn key_exchange_server(
         let (stream, peer_addr) = listener.accept().await?;
         let config = config.clone();
         let keyset = keyset.borrow().clone();
+        let ntp_server = ntp_server.clone();
 
         let fut = async move {
-            BoundKeyExchangeServer::run(stream, config, keyset)
+            BoundKeyExchangeServer::run(stream, config, keyset, ntp_server, ntp_port)
                 .await
                 .map_err(|ke_error| std::io::Error::new(std::io::ErrorKind::Other, ke_error))
         };
@@ -165,11 +172,12 @@ where
         io: IO,
         server_name: String,
         config: rustls::ClientConfig,
+        algorithms: &[AeadAlgorithm],
     ) -> Result<Self, KeyExchangeError> {
         Ok(Self {
             inner: Some(BoundKeyExchangeClientData {
                 io,
-                client: KeyExchangeClient::new(server_name, config)?,
+                client: KeyExchangeClient::with_algorithms(server_name, config, algorithms)?,
                 need_flush: false,
             }),
         })
@@ -294,10 +302,12 @@ where
         io: IO,
         config: Arc<rustls::ServerConfig>,
         keyset: Arc<KeySet>,
+        ntp_server: Option<String>,
+        ntp_port: Option<u16>,
     ) -> Result<Self, KeyExchangeError> {
         let data = BoundKeyExchangeServerData {
             io,
-            server: KeyExchangeServer::new(config, keyset)?,
+            server: KeyExchangeServer::new(config, keyset)?.with_ntp_endpoint(ntp_server, ntp_port),
             need_flush: false,
         };
 
@@ -308,8 +318,10 @@ where
         io: IO,
         config: Arc<rustls::ServerConfig>,
         keyset: Arc<KeySet>,
+        ntp_server: Option<String>,
+        ntp_port: Option<u16>,
     ) -> Result<(), KeyExchangeError> {
-        let this = Self::new(io, config, keyset)?;
+        let this = Self::new(io, config, keyset, ntp_server, ntp_port)?;
 
         this.await
     }
@@ -522,6 +534,8 @@ mod tests {
             key_der_path: PathBuf::from("../test-keys/end.key"),
             timeout_ms: 1000,
             addr: "0.0.0.0:5431".parse().unwrap(),
+            ntp_server: None,
+            ntp_port: None,
         };
 
         let _join_handle = spawn(nts_ke_config, keyset);
@@ -534,17 +548,42 @@ mod tests {
             "localhost".to_string(),
             5431,
             &certificates_from_bufread(BufReader::new(Cursor::new(ca))).unwrap(),
+            AeadAlgorithm::IN_ORDER_OF_PREFERENCE,
         )
         .await
         .unwrap();
 
         assert_eq!(result.remote, "localhost");
         assert_eq!(result.port, 123);
+        assert_eq!(
+            result.nts.aead_id(),
+            AeadAlgorithm::AeadAesSivCmac512 as u16
+        );
+
+        // the server picks from the algorithms we offer
+        let result = key_exchange_client(
+            "localhost".to_string(),
+            5431,
+            &certificates_from_bufread(BufReader::new(Cursor::new(ca))).unwrap(),
+            &[AeadAlgorithm::AeadAesSivCmac256],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result.nts.aead_id(),
+            AeadAlgorithm::AeadAesSivCmac256 as u16
+        );
     }
 
     #[tokio::test]
     async fn client_connection_refused() {
-        let result = key_exchange_client("localhost".to_string(), 5434, &[]).await;
+        let result = key_exchange_client(
+            "localhost".to_string(),
+            5434,
+            &[],
+            AeadAlgorithm::IN_ORDER_OF_PREFERENCE,
+        )
+        .await;
 
         let error = result.unwrap_err();
 
@@ -72,16 +72,32 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     // tracing setup to ensure logging is fully configured.
     config.check();
 
+    let symmetric_keys = match config.symmetric_keys().await {
+        Ok(keys) => Arc::new(keys),
+        Err(e) => {
+            ::tracing::error!("There was an error loading the keys file: {e}");
+            std::process::exit(exitcode::CONFIG);
+        }
+    };
+
     // we always generate the keyset (even if NTS is not used)
-    let keyset = crate::nts_key_provider::spawn(config.keyset).await;
+    let keyset = match crate::nts_key_provider::spawn(config.keyset).await {
+        Ok(keyset) => keyset,
+        Err(e) => {
+            ::tracing::error!("There was an error loading the NTS master secret: {e}");
+            std::process::exit(exitcode::CONFIG);
+        }
+    };
 
     ::tracing::debug!("Configuration loaded, spawning daemon jobs");
     let (main_loop_handle, channels) = crate::spawn(
         config.system,
         config.clock,
         &config.peers,
+        &config.broadcast_clients,
         &config.servers,
         keyset.clone(),
+        symmetric_keys,
     )
     .await?;
 
@@ -100,6 +116,7 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     crate::config::dynamic::spawn(
         config.configure,
         channels.config_sender,
+        channels.disabled_peers_sender,
         tracing_state.reload_handle,
     )
     .await;
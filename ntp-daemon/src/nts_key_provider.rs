@@ -1,30 +1,96 @@
-use std::{fs::File, sync::Arc};
+use std::{fs::File, path::Path, sync::Arc};
 
-use ntp_proto::{KeySet, KeySetProvider};
+use ntp_proto::{write_private_file, KeySet, KeySetProvider};
 use tokio::sync::watch;
 use tracing::{error, warn};
+use zeroize::Zeroizing;
 
 use crate::config::KeysetConfig;
 
-pub async fn spawn(config: KeysetConfig) -> watch::Receiver<Arc<KeySet>> {
+/// Read a secret from a file, failing when it is empty
+async fn read_secret(path: &Path, name: &str) -> std::io::Result<Vec<u8>> {
+    let secret = tokio::fs::read(path).await?;
+    if secret.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{name} is empty"),
+        ));
+    }
+    Ok(secret)
+}
+
+/// Store the keys at `path`, readable by the owner only, replacing the old
+/// keys only once the new ones are on disk
+fn store_keys(
+    provider: &KeySetProvider,
+    path: &str,
+    storage_secret: Option<&[u8]>,
+) -> std::io::Result<()> {
+    let mut contents = Zeroizing::new(vec![]);
+    match storage_secret {
+        Some(secret) => provider.store_encrypted(&mut *contents, secret)?,
+        None => provider.store(&mut *contents)?,
+    }
+
+    write_private_file(Path::new(path), &contents)
+}
+
+/// Spawn the task providing the keyset. Fails when a master secret or
+/// storage secret is configured but cannot be read or is empty, as falling
+/// back to generated or unencrypted keys would silently break cookies shared
+/// with other servers, or leak the keys.
+pub async fn spawn(config: KeysetConfig) -> std::io::Result<watch::Receiver<Arc<KeySet>>> {
+    if let Some(path) = &config.master_secret_path {
+        let secret = read_secret(path, "NTS master secret").await?;
+        return Ok(spawn_derived(&secret, config).await);
+    }
+
+    let storage_secret = match &config.storage_secret_path {
+        Some(path) => Some(read_secret(path, "NTS keyset storage secret").await?),
+        None => None,
+    };
+
+    // keys that could not be loaded are left alone, they may still be needed
+    let mut storage_path = config.storage_path.clone();
     let (mut provider, mut next_interval) = match &config.storage_path {
         Some(path) => {
-            let path = path.to_owned();
-            let (provider, time) = tokio::task::spawn_blocking(
+            let load_path = path.to_owned();
+            let load_secret = storage_secret.clone();
+            let loaded = tokio::task::spawn_blocking(
                 move || -> std::io::Result<(KeySetProvider, std::time::SystemTime)> {
-                    let mut input = File::open(path)?;
-                    KeySetProvider::load(&mut input, config.old_keys)
+                    let mut input = File::open(load_path)?;
+                    match &load_secret {
+                        Some(secret) => {
+                            KeySetProvider::load_encrypted(&mut input, secret, config.old_keys)
+                        }
+                        None => KeySetProvider::load(&mut input, config.old_keys),
+                    }
                 },
             )
             .await
-            .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)))
-            .unwrap_or_else(|e| {
-                error!(error = ?e, "Could not load nts server keys, starting with new set");
-                (
-                    KeySetProvider::new(config.old_keys),
-                    std::time::SystemTime::now(),
-                )
-            });
+            .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+            let (provider, time) = match loaded {
+                Ok(loaded) => loaded,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let provider = KeySetProvider::new(config.old_keys);
+                    // store the new keys right away, so their cookies survive a restart
+                    if let Err(e) = store_keys(&provider, path, storage_secret.as_deref()) {
+                        warn!(error = ?e, "Could not store nts server keys");
+                    }
+                    (provider, std::time::SystemTime::now())
+                }
+                Err(e) => {
+                    error!(
+                        error = ?e,
+                        "Could not load nts server keys, starting with new set that is not stored"
+                    );
+                    storage_path = None;
+                    (
+                        KeySetProvider::new(config.old_keys),
+                        std::time::SystemTime::now(),
+                    )
+                }
+            };
             (
                 provider,
                 std::time::Duration::from_secs(config.rotation_interval as _).saturating_sub(
@@ -44,11 +110,8 @@ pub async fn spawn(config: KeysetConfig) -> watch::Receiver<Arc<KeySet>> {
         std::thread::sleep(next_interval);
         next_interval = std::time::Duration::from_secs(config.rotation_interval as _);
         provider.rotate();
-        if let Some(path) = &config.storage_path {
-            if let Err(e) = (|| -> std::io::Result<()> {
-                let mut output = File::create(path)?;
-                provider.store(&mut output)
-            })() {
+        if let Some(path) = &storage_path {
+            if let Err(e) = store_keys(&provider, path, storage_secret.as_deref()) {
                 warn!(error = ?e, "Could not store nts server keys");
             }
         }
@@ -56,5 +119,145 @@ pub async fn spawn(config: KeysetConfig) -> watch::Receiver<Arc<KeySet>> {
             break;
         }
     });
+    Ok(rx)
+}
+
+/// The current epoch, and the time until the next one starts
+fn current_epoch(rotation_interval: u64) -> (u32, std::time::Duration) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or(std::time::Duration::from_secs(0));
+    let rotation_interval = rotation_interval.max(1);
+    let epoch = now.as_secs() / rotation_interval;
+    let next = std::time::Duration::from_secs((epoch + 1) * rotation_interval);
+
+    (epoch as u32, next.saturating_sub(now))
+}
+
+async fn spawn_derived(secret: &[u8], config: KeysetConfig) -> watch::Receiver<Arc<KeySet>> {
+    let rotation_interval = config.rotation_interval as u64;
+    let (epoch, mut next_interval) = current_epoch(rotation_interval);
+    let mut provider = KeySetProvider::from_master_secret(secret, epoch, config.old_keys);
+
+    let (tx, rx) = watch::channel(provider.get());
+    tokio::task::spawn_blocking(move || loop {
+        std::thread::sleep(next_interval);
+        let (epoch, until_next) = current_epoch(rotation_interval);
+        next_interval = until_next;
+        provider.rotate_to_epoch(epoch);
+        if tx.send(provider.get()).is_err() {
+            break;
+        }
+    });
     rx
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_current_epoch() {
+        let (epoch, until_next) = current_epoch(86400);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        assert!(until_next <= std::time::Duration::from_secs(86400));
+        // allow for the second to have passed in between
+        assert!(now / 86400 - epoch as u64 <= 1);
+    }
+
+    #[tokio::test]
+    async fn test_master_secret_fails_closed() {
+        let path = std::env::temp_dir().join("ntpd-rs-test-missing-master-secret");
+        let _ = std::fs::remove_file(&path);
+        let config = KeysetConfig {
+            master_secret_path: Some(path.clone()),
+            ..KeysetConfig::default()
+        };
+        assert!(spawn(config.clone()).await.is_err());
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(spawn(config).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keys_stored_encrypted() {
+        let dir = std::env::temp_dir().join("ntpd-rs-test-stored-keys");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let storage_path = dir.join("keys");
+        let secret_path = dir.join("secret");
+        std::fs::write(&secret_path, b"storage secret").unwrap();
+
+        let config = KeysetConfig {
+            storage_path: Some(storage_path.to_str().unwrap().into()),
+            storage_secret_path: Some(secret_path),
+            ..KeysetConfig::default()
+        };
+
+        // the rotation tasks sleep for a day, which the runtime must not wait for
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let keyset = runtime.block_on(spawn(config.clone()));
+        let stored = std::fs::read(&storage_path);
+        let restarted = runtime.block_on(spawn(config));
+        let restored = std::fs::read(&storage_path);
+        runtime.shutdown_background();
+
+        // new keys are stored right away, for the owner's eyes only
+        let stored = stored.unwrap();
+        let mode = std::fs::metadata(&storage_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (provider, _) =
+            KeySetProvider::load_encrypted(&mut stored.as_slice(), b"storage secret", 7).unwrap();
+        assert_eq!(
+            format!("{:?}", provider.get()),
+            format!("{:?}", *keyset.unwrap().borrow())
+        );
+
+        // after a restart, the stored keys are used
+        assert!(restarted.is_ok());
+        assert_eq!(restored.unwrap(), stored);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_keys_not_overwritten() {
+        let dir = std::env::temp_dir().join("ntpd-rs-test-unreadable-keys");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let storage_path = dir.join("keys");
+        let secret_path = dir.join("secret");
+        std::fs::write(&secret_path, b"other storage secret").unwrap();
+        std::fs::write(&storage_path, b"keys under another secret").unwrap();
+
+        let config = KeysetConfig {
+            storage_path: Some(storage_path.to_str().unwrap().into()),
+            storage_secret_path: Some(secret_path),
+            ..KeysetConfig::default()
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let keyset = runtime.block_on(spawn(config));
+        runtime.shutdown_background();
+
+        // the daemon runs with new keys, but keeps the stored ones as they are
+        assert!(keyset.is_ok());
+        assert_eq!(
+            std::fs::read(&storage_path).unwrap(),
+            b"keys under another secret"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use crate::server::ServerStats;
 use crate::{sockets::create_unix_socket, system::ServerData};
-use ntp_proto::{ObservablePeerTimedata, PollInterval, Reach, ReferenceId, SystemSnapshot};
+use ntp_proto::{
+    KissCode, ObservablePeerTimedata, PollInterval, Reach, ReferenceId, SystemSnapshot,
+};
 use prometheus_client::encoding::EncodeLabelValue;
 use std::fmt::Write;
 use std::net::SocketAddr;
@@ -60,6 +62,10 @@ pub enum ObservablePeerState {
         poll_interval: PollInterval,
         peer_id: ReferenceId,
         address: String,
+        #[serde(default)]
+        last_kiss: Option<KissCode>,
+        #[serde(default)]
+        kiss_count: u32,
     },
 }
 
@@ -104,7 +110,7 @@ async fn observer(
 
         let observe = ObservableState {
             peers: peers_reader.borrow().to_owned(),
-            system: *system_reader.borrow(),
+            system: system_reader.borrow().clone(),
             servers: server_reader.borrow().iter().map(|s| s.into()).collect(),
         };
 
@@ -189,6 +195,8 @@ mod tests {
                 poll_interval: PollIntervalLimits::default().min,
                 peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
                 address: "127.0.0.3:123".into(),
+                last_kiss: None,
+                kiss_count: 0,
             },
         ]);
 
@@ -206,6 +214,9 @@ mod tests {
                 leap_indicator: NtpLeapIndicator::Leap59,
                 accumulated_steps: NtpDuration::ZERO,
             },
+            clock_error_bound: NtpDuration::ZERO,
+            reference_timestamp: NtpTimestamp::default(),
+            ..Default::default()
         });
 
         let handle = tokio::spawn(async move {
@@ -252,6 +263,8 @@ mod tests {
                 poll_interval: PollIntervalLimits::default().min,
                 peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
                 address: "127.0.0.3:123".into(),
+                last_kiss: None,
+                kiss_count: 0,
             },
         ]);
 
@@ -269,6 +282,9 @@ mod tests {
                 leap_indicator: NtpLeapIndicator::Leap59,
                 accumulated_steps: NtpDuration::ZERO,
             },
+            clock_error_bound: NtpDuration::ZERO,
+            reference_timestamp: NtpTimestamp::default(),
+            ..Default::default()
         });
 
         let handle = tokio::spawn(async move {
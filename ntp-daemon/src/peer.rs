@@ -6,16 +6,23 @@ use std::{
 };
 
 use ntp_proto::{
-    IgnoreReason, Measurement, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, Peer, PeerNtsData,
-    PeerSnapshot, PollError, ReferenceId, SystemSnapshot, Update,
+    clock_stepped, IgnoreReason, Measurement, NtpAssociationMode, NtpClock, NtpInstant, NtpPacket,
+    NtpTimestamp, Peer, PeerNtsData, PeerSnapshot, PollError, ReferenceId, SymmetricKey,
+    SystemSnapshot, Update,
 };
 use ntp_udp::{EnableTimestamps, InterfaceName, UdpSocket};
 use rand::{thread_rng, Rng};
 use tracing::{debug, error, instrument, warn, Instrument, Span};
 
-use tokio::time::{Instant, Sleep};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{Instant, Sleep},
+};
 
-use crate::{config::CombinedSystemConfig, spawn::PeerId};
+use crate::{
+    config::{BroadcastClientConfig, CombinedSystemConfig},
+    spawn::{nts::NtsStateStore, PeerId},
+};
 
 /// Trait needed to allow injecting of futures other than tokio::time::Sleep for testing
 pub trait Wait: Future<Output = ()> {
@@ -45,11 +52,22 @@ pub enum MsgForSystem {
     UpdatedSnapshot(PeerId, PeerSnapshot),
 }
 
+/// Request to poll outside of the normal schedule, for example on operator request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForcePoll {
+    /// Every peer should poll
+    All,
+    /// Only the given peer should poll
+    Peer(PeerId),
+}
+
 #[derive(Debug, Clone)]
 pub struct PeerChannels {
     pub msg_for_system_sender: tokio::sync::mpsc::Sender<MsgForSystem>,
     pub system_snapshot_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
     pub system_config_receiver: tokio::sync::watch::Receiver<CombinedSystemConfig>,
+    // Peers subscribe to this when they are spawned
+    pub force_poll_sender: tokio::sync::broadcast::Sender<ForcePoll>,
 }
 
 pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
@@ -57,9 +75,14 @@ pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
     index: PeerId,
     clock: C,
     socket: UdpSocket,
+    /// Socket on which the broadcasts of the peer arrive, if we follow them
+    broadcast_socket: Option<UdpSocket>,
     channels: PeerChannels,
+    force_poll_receiver: tokio::sync::broadcast::Receiver<ForcePoll>,
 
     peer: Peer,
+    /// Where the NTS cookies of the peer are saved, if anywhere
+    nts_store: Option<Box<NtsStateStore>>,
 
     // we don't store the real origin timestamp in the packet, because that would leak our
     // system time to the network (and could make attacks easier). So instead there is some
@@ -67,6 +90,8 @@ pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
     // actual origin timestamp ourselves.
     /// Timestamp of the last packet that we sent
     last_send_timestamp: Option<NtpTimestamp>,
+    /// Monotonic time at which the last packet was sent, to detect clock steps
+    last_send_instant: Option<NtpInstant>,
 
     /// Instant last poll message was sent (used for timing the wait)
     last_poll_sent: Instant,
@@ -90,8 +115,16 @@ where
     C: 'static + NtpClock + Send,
     T: Wait,
 {
+    /// Let the store know the cookies changed, which they do with every poll
+    /// and every response
+    async fn nts_state_changed(&mut self) {
+        if let (Some(store), Some(nts)) = (&mut self.nts_store, self.peer.nts()) {
+            store.cookies_changed(nts).await;
+        }
+    }
+
     /// Set the next deadline for the poll interval based on current state
-    fn update_poll_wait(&self, poll_wait: &mut Pin<&mut T>, system_snapshot: SystemSnapshot) {
+    fn update_poll_wait(&self, poll_wait: &mut Pin<&mut T>, system_snapshot: &SystemSnapshot) {
         let poll_interval = self
             .peer
             .current_poll_interval(system_snapshot)
@@ -105,8 +138,27 @@ where
             .reset(self.last_poll_sent + poll_interval);
     }
 
+    /// Move the next poll forward, but never closer to the previous poll than
+    /// the minimum poll interval allows, so forced polls respect rate limits.
+    fn handle_force_poll(&self, poll_wait: &mut Pin<&mut T>) {
+        let min_poll_interval = self.peer.min_poll_interval().as_system_duration();
+
+        debug!("forced poll requested");
+        poll_wait
+            .as_mut()
+            .reset(self.last_poll_sent + min_poll_interval);
+    }
+
     async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) -> PollResult {
-        let system_snapshot = *self.channels.system_snapshot_receiver.borrow();
+        let system_snapshot = self.channels.system_snapshot_receiver.borrow().clone();
+
+        // once the delay is known, the broadcasts of the peer take the place of polls
+        if self.broadcast_socket.is_some() && self.peer.broadcast_calibrated() {
+            self.last_poll_sent = Instant::now();
+            self.update_poll_wait(poll_wait, &system_snapshot);
+            return PollResult::Ok;
+        }
+
         let config_snapshot_system = self
             .channels
             .system_config_receiver
@@ -115,7 +167,7 @@ where
         let mut buf = [0; 1024];
         let packet = match self.peer.generate_poll_message(
             &mut buf,
-            system_snapshot,
+            &system_snapshot,
             &config_snapshot_system,
         ) {
             Ok(packet) => packet,
@@ -128,11 +180,21 @@ where
                 warn!("Peer is no longer reachable over network, restarting");
                 return PollResult::Unreachable;
             }
+            Err(PollError::RequestInFlight) => {
+                // Try again once the previous request has had its chance
+                debug!("previous request still in flight, delaying poll");
+                let min_poll_interval = self.peer.min_poll_interval().as_system_duration();
+                poll_wait.as_mut().reset(Instant::now() + min_poll_interval);
+                return PollResult::Ok;
+            }
         };
 
+        // The poll used up one of our cookies
+        self.nts_state_changed().await;
+
         // Sent a poll, so update waiting to match deadline of next
         self.last_poll_sent = Instant::now();
-        self.update_poll_wait(poll_wait, system_snapshot);
+        self.update_poll_wait(poll_wait, &system_snapshot);
 
         // NOTE: fitness check is not performed here, but by System
         let snapshot = PeerSnapshot::from_peer(&self.peer);
@@ -149,6 +211,7 @@ where
             }
             Ok(ts) => {
                 self.last_send_timestamp = Some(ts);
+                self.last_send_instant = Some(NtpInstant::now());
             }
         }
 
@@ -178,13 +241,19 @@ where
         poll_wait: &mut Pin<&mut T>,
         packet: &'a [u8],
         send_timestamp: NtpTimestamp,
+        send_instant: NtpInstant,
         recv_timestamp: NtpTimestamp,
     ) -> PacketResult {
         let ntp_instant = NtpInstant::now();
 
-        let system_snapshot = *self.channels.system_snapshot_receiver.borrow();
+        if clock_stepped(send_timestamp, send_instant, recv_timestamp, ntp_instant) {
+            warn!("Local clock was stepped during the exchange, discarding measurement");
+            return PacketResult::Ok;
+        }
+
+        let system_snapshot = self.channels.system_snapshot_receiver.borrow().clone();
         let result = self.peer.handle_incoming(
-            system_snapshot,
+            &system_snapshot,
             packet,
             ntp_instant,
             send_timestamp,
@@ -192,11 +261,12 @@ where
         );
 
         // Handle incoming may have changed poll interval based on message, respect that change
-        self.update_poll_wait(poll_wait, system_snapshot);
+        self.update_poll_wait(poll_wait, &system_snapshot);
 
         match result {
             Ok(update) => {
                 debug!("packet accepted");
+                self.nts_state_changed().await;
 
                 // NOTE: fitness check is not performed here, but by System
 
@@ -215,6 +285,13 @@ where
 
                 return PacketResult::Demobilize;
             }
+            Err(IgnoreReason::CryptoNak) => {
+                warn!("Demobilizing peer connection, the remote does not accept our key.");
+                let msg = MsgForSystem::MustDemobilize(self.index);
+                self.channels.msg_for_system_sender.send(msg).await.ok();
+
+                return PacketResult::Demobilize;
+            }
             Err(ignore_reason) => {
                 debug!(?ignore_reason, "packet ignored");
             }
@@ -223,9 +300,33 @@ where
         PacketResult::Ok
     }
 
+    async fn handle_broadcast(&mut self, packet: &[u8], recv_timestamp: NtpTimestamp) {
+        let result = self
+            .peer
+            .handle_broadcast(packet, NtpInstant::now(), recv_timestamp);
+
+        match result {
+            Ok(update) => {
+                debug!("broadcast accepted");
+
+                let msg = match update {
+                    Update::BareUpdate(update) => MsgForSystem::UpdatedSnapshot(self.index, update),
+                    Update::NewMeasurement(update, measurement, packet) => {
+                        MsgForSystem::NewMeasurement(self.index, update, measurement, packet)
+                    }
+                };
+                self.channels.msg_for_system_sender.send(msg).await.ok();
+            }
+            Err(ignore_reason) => {
+                debug!(?ignore_reason, "broadcast ignored");
+            }
+        }
+    }
+
     async fn run(&mut self, mut poll_wait: Pin<&mut T>) {
         loop {
             let mut buf = [0_u8; 1024];
+            let mut broadcast_buf = [0_u8; 1024];
 
             tokio::select! {
                 () = &mut poll_wait => {
@@ -246,15 +347,15 @@ where
                     tracing::debug!("accept packet");
                     match accept_packet(result, &buf) {
                         AcceptResult::Accept(packet, recv_timestamp) => {
-                            let send_timestamp = match self.last_send_timestamp {
-                                Some(ts) => ts,
-                                None => {
+                            let (send_timestamp, send_instant) = match (self.last_send_timestamp, self.last_send_instant) {
+                                (Some(ts), Some(instant)) => (ts, instant),
+                                _ => {
                                     warn!("we received a message without having sent one; discarding");
                                     continue;
                                 }
                             };
 
-                            match self.handle_packet(&mut poll_wait, packet, send_timestamp, recv_timestamp).await {
+                            match self.handle_packet(&mut poll_wait, packet, send_timestamp, send_instant, recv_timestamp).await {
                                 PacketResult::Ok => {},
                                 PacketResult::Demobilize => break,
                             }
@@ -266,11 +367,45 @@ where
                         AcceptResult::Ignore => {},
                     }
                 },
+                result = recv_broadcast(&self.broadcast_socket, &mut broadcast_buf) => {
+                    // anyone on the network can broadcast, only follow our peer
+                    let peer_ip = self.socket.as_ref().peer_addr().ok().map(|addr| addr.ip());
+                    if let Ok((_, source, _)) = &result {
+                        if Some(source.ip()) != peer_ip {
+                            continue;
+                        }
+                    }
+
+                    match accept_packet(result, &broadcast_buf) {
+                        AcceptResult::Accept(packet, recv_timestamp) => {
+                            self.handle_broadcast(packet, recv_timestamp).await;
+                        },
+                        AcceptResult::NetworkGone => {
+                            self.channels.msg_for_system_sender.send(MsgForSystem::NetworkIssue(self.index)).await.ok();
+                            break;
+                        },
+                        AcceptResult::Ignore => {},
+                    }
+                },
                 _ = self.channels.system_config_receiver.changed(), if self.channels.system_config_receiver.has_changed().is_ok() => {
                     self.peer.update_config(self.channels.system_config_receiver.borrow_and_update().system);
                 },
+                request = self.force_poll_receiver.recv() => {
+                    match request {
+                        Ok(ForcePoll::Peer(index)) if index != self.index => {},
+                        // when lagging we may have missed a request for us, so poll anyway
+                        Ok(_) | Err(RecvError::Lagged(_)) => self.handle_force_poll(&mut poll_wait),
+                        // we hold a sender ourselves, so the channel cannot close
+                        Err(RecvError::Closed) => {},
+                    }
+                },
             }
         }
+
+        // the peer stops, so it won't save later changes anymore
+        if let (Some(store), Some(nts)) = (&mut self.nts_store, self.peer.nts()) {
+            store.flush(nts).await;
+        }
     }
 }
 
@@ -279,7 +414,7 @@ where
     C: 'static + NtpClock + Send,
 {
     #[allow(clippy::too_many_arguments)]
-    #[instrument(skip(clock, channels))]
+    #[instrument(skip(clock, channels, symmetric_key))]
     pub fn spawn(
         index: PeerId,
         addr: SocketAddr,
@@ -289,6 +424,10 @@ where
         network_wait_period: std::time::Duration,
         mut channels: PeerChannels,
         nts: Option<Box<PeerNtsData>>,
+        symmetric_key: Option<(u32, SymmetricKey)>,
+        broadcast: Option<BroadcastClientConfig>,
+        mode: NtpAssociationMode,
+        nts_store: Option<Box<NtsStateStore>>,
     ) -> tokio::task::JoinHandle<()> {
         tokio::spawn(
             (async move {
@@ -312,6 +451,23 @@ where
                         return;
                     }
                 };
+                let broadcast_socket = match broadcast {
+                    Some(config) => match crate::spawn::broadcast::listen(&config).await {
+                        Ok(socket) => Some(socket),
+                        Err(error) => {
+                            warn!(?error, "Could not open broadcast socket");
+                            tokio::time::sleep(network_wait_period).await;
+                            channels
+                                .msg_for_system_sender
+                                .send(MsgForSystem::NetworkIssue(index))
+                                .await
+                                .ok();
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+
                 // Unwrap should be safe because we know the socket was bound to a local addres just before
                 let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
 
@@ -320,29 +476,45 @@ where
 
                 let local_clock_time = NtpInstant::now();
                 let config_snapshot = *channels.system_config_receiver.borrow_and_update();
-                let peer = if let Some(nts) = nts {
-                    Peer::new_nts(
+                let peer = match (nts, symmetric_key) {
+                    (Some(nts), _) => Peer::new_nts(
                         our_id,
                         peer_id,
                         local_clock_time,
                         config_snapshot.system,
                         nts,
-                    )
-                } else {
-                    Peer::new(our_id, peer_id, local_clock_time, config_snapshot.system)
-                };
+                    ),
+                    (None, Some((keyid, key))) => Peer::new_symmetric(
+                        our_id,
+                        peer_id,
+                        local_clock_time,
+                        config_snapshot.system,
+                        keyid,
+                        key,
+                    ),
+                    (None, None) => {
+                        Peer::new(our_id, peer_id, local_clock_time, config_snapshot.system)
+                    }
+                }
+                .with_mode(mode);
 
                 let poll_wait = tokio::time::sleep(std::time::Duration::default());
                 tokio::pin!(poll_wait);
 
+                let force_poll_receiver = channels.force_poll_sender.subscribe();
+
                 let mut process = PeerTask {
                     _wait: PhantomData,
                     index,
                     clock,
                     channels,
+                    force_poll_receiver,
                     socket,
+                    broadcast_socket,
                     peer,
+                    nts_store,
                     last_send_timestamp: None,
+                    last_send_instant: None,
                     last_poll_sent: Instant::now(),
                 };
 
@@ -360,6 +532,17 @@ enum AcceptResult<'a> {
     NetworkGone,
 }
 
+/// Receive on the broadcast socket, never completes when there is none
+async fn recv_broadcast(
+    socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<NtpTimestamp>)> {
+    match socket {
+        Some(socket) => socket.recv(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
 fn unspecified_for(addr: SocketAddr) -> SocketAddr {
     match addr {
         SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
@@ -482,8 +665,6 @@ mod tests {
         }
     }
 
-    const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
-
     #[derive(Debug, Clone, Default)]
     struct TestClock {}
 
@@ -494,8 +675,8 @@ mod tests {
             let cur =
                 std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
 
-            Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
-                EPOCH_OFFSET.wrapping_add(cur.as_secs() as u32),
+            Ok(NtpTimestamp::from_unix_time(
+                cur.as_secs() as i64,
                 cur.subsec_nanos(),
             ))
         }
@@ -565,6 +746,7 @@ mod tests {
         let (_, mut system_config_receiver) =
             tokio::sync::watch::channel(CombinedSystemConfig::default());
         let (msg_for_system_sender, msg_for_system_receiver) = mpsc::channel(1);
+        let (force_poll_sender, force_poll_receiver) = tokio::sync::broadcast::channel(1);
 
         let local_clock_time = NtpInstant::now();
         let peer = Peer::new(
@@ -582,10 +764,15 @@ mod tests {
                 msg_for_system_sender,
                 system_snapshot_receiver,
                 system_config_receiver,
+                force_poll_sender,
             },
+            force_poll_receiver,
             socket,
+            broadcast_socket: None,
             peer,
+            nts_store: None,
             last_send_timestamp: None,
+            last_send_instant: None,
             last_poll_sent: Instant::now(),
         };
 
@@ -616,6 +803,58 @@ mod tests {
         handle.abort();
     }
 
+    #[tokio::test]
+    async fn test_force_poll() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, socket, mut msg_recv) = test_startup::<Sleep>(8012).await;
+        let force_poll_sender = process.channels.force_poll_sender.clone();
+
+        // the normal schedule would only poll in an hour, the last poll was long enough ago
+        let min_poll_interval = process.peer.min_poll_interval().as_system_duration();
+        process.last_poll_sent = Instant::now() - min_poll_interval;
+        let poll_wait = tokio::time::sleep(Duration::from_secs(3600));
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        // a request for another peer is ignored
+        force_poll_sender
+            .send(ForcePoll::Peer(PeerId::new()))
+            .unwrap();
+
+        let mut buf = [0; 48];
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {/*expected */},
+            _ = socket.recv(&mut buf) => { unreachable!("should not receive anything") }
+        }
+
+        force_poll_sender.send(ForcePoll::All).unwrap();
+
+        let msg = tokio::time::timeout(Duration::from_secs(1), msg_recv.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _)));
+
+        let (size, _, _) = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(size, 48);
+
+        // we just polled, so another forced poll has to wait for the minimum poll interval
+        force_poll_sender.send(ForcePoll::All).unwrap();
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {/*expected */},
+            _ = socket.recv(&mut buf) => { unreachable!("should not receive anything") }
+        }
+
+        handle.abort();
+    }
+
     fn serialize_packet_unencryped(send_packet: &NtpPacket) -> [u8; 48] {
         let mut buf = [0; 48];
         let mut cursor = Cursor::new(buf.as_mut_slice());
@@ -709,4 +948,19 @@ mod tests {
 
         handle.abort();
     }
+
+    #[test]
+    fn test_accept_packet_received_length() {
+        let addr: SocketAddr = "127.0.0.1:123".parse().unwrap();
+        let timestamp = NtpTimestamp::default();
+        let buf = [0u8; 1024];
+
+        // only the bytes actually received are handed on
+        let result = accept_packet(Ok((48, addr, Some(timestamp))), &buf);
+        assert!(matches!(result, AcceptResult::Accept(packet, _) if packet.len() == 48));
+
+        // a short datagram is dropped, even though the buffer is large enough
+        let result = accept_packet(Ok((20, addr, Some(timestamp))), &buf);
+        assert!(matches!(result, AcceptResult::Ignore));
+    }
 }
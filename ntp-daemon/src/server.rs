@@ -3,24 +3,40 @@ use std::{
     hash::BuildHasher,
     io::Cursor,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use ntp_proto::{
-    DecodedServerCookie, KeySet, NoCipher, NtpAssociationMode, NtpClock, NtpPacket, NtpTimestamp,
-    SystemSnapshot,
+    validate_request_with_scratch, DecodedServerCookie, ExtensionField, KeySet, MacError, NoCipher,
+    NtpAssociationMode, NtpClock, NtpPacket, NtpTimestamp, PreviousExchange, RequestError,
+    SymmetricKeys, SystemSnapshot,
 };
 use ntp_udp::{InterfaceName, UdpSocket};
 use prometheus_client::metrics::counter::Counter;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use tokio::task::JoinHandle;
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
 use tracing::{debug, error, info, instrument, trace, warn};
 
-use crate::config::{FilterAction, ServerConfig};
+use crate::{
+    config::{FilterAction, ServerConfig, UnsynchronizedAction},
+    spawn::passive::PassiveRequest,
+};
 
 // Maximum size of udp packet we handle
 const MAX_PACKET_SIZE: usize = 1024;
+// Maximum number of responses waiting for their response jitter at the same time,
+// further responses are dropped
+const MAX_DELAYED_RESPONSES: usize = 1024;
+// Number of clients for which we remember the last exchange, for interleaved mode
+const EXCHANGE_CACHE_SIZE: usize = 4096;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ServerStats {
@@ -28,8 +44,10 @@ pub struct ServerStats {
     pub accepted_packets: WrappedCounter,
     pub denied_packets: WrappedCounter,
     pub ignored_packets: WrappedCounter,
+    pub reserved_mode_packets: WrappedCounter,
     pub rate_limited_packets: WrappedCounter,
     pub response_send_errors: WrappedCounter,
+    pub response_latency: LatencyHistogram,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -66,13 +84,94 @@ impl std::ops::Deref for WrappedCounter {
     }
 }
 
+/// Number of buckets in a [`LatencyHistogram`]
+const LATENCY_BUCKETS: usize = 24;
+
+/// Histogram of the time the server takes from receiving a request to sending
+/// the response. Bucket `i` counts latencies below 2^i microseconds (and at least
+/// 2^(i-1) microseconds), the last bucket also counts all larger latencies.
+#[derive(Default, Debug, Clone)]
+pub struct LatencyHistogram(Arc<[AtomicU64; LATENCY_BUCKETS]>);
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.0[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Vec<u64> {
+        self.0.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Upper bound of the bucket containing the given percentile (0-1) of the
+    /// recorded latencies, or `None` when nothing was recorded yet
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let target = ((percentile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        let bucket = counts
+            .iter()
+            .position(|count| {
+                seen += count;
+                seen >= target
+            })
+            .unwrap_or(LATENCY_BUCKETS - 1);
+
+        Some(Duration::from_micros(1 << bucket))
+    }
+}
+
+impl Serialize for LatencyHistogram {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.counts().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LatencyHistogram {
+    fn deserialize<D>(deserializer: D) -> Result<LatencyHistogram, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let counts: Vec<u64> = Deserialize::deserialize(deserializer)?;
+        if counts.len() != LATENCY_BUCKETS {
+            return Err(serde::de::Error::invalid_length(
+                counts.len(),
+                &"one count per latency bucket",
+            ));
+        }
+
+        let histogram = LatencyHistogram::default();
+        for (bucket, count) in histogram.0.iter().zip(counts) {
+            bucket.store(count, Ordering::Relaxed);
+        }
+        Ok(histogram)
+    }
+}
+
 pub struct ServerTask<C: 'static + NtpClock + Send> {
     config: ServerConfig,
     network_wait_period: std::time::Duration,
     system_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
     keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+    symmetric_keys: Arc<SymmetricKeys>,
     system: SystemSnapshot,
     client_cache: TimestampedCache<IpAddr>,
+    exchange_cache: ExchangeCache,
+    delayed_responses: Arc<Semaphore>,
+    /// Exchanges completed by delayed responses, to be added to the exchange cache
+    delayed_exchange_sender: mpsc::Sender<(SocketAddr, PreviousExchange)>,
+    delayed_exchange_receiver: mpsc::Receiver<(SocketAddr, PreviousExchange)>,
+    /// Requests for passive associations with symmetric active peers
+    passive_sender: mpsc::Sender<PassiveRequest>,
     clock: C,
     interface: Option<InterfaceName>,
     stats: ServerStats,
@@ -86,6 +185,8 @@ enum AcceptResult<'a> {
         decoded_cookie: Option<DecodedServerCookie>,
         peer_addr: SocketAddr,
         recv_timestamp: NtpTimestamp,
+        /// Id of the symmetric key with which the request was authenticated
+        mac_keyid: Option<u32>,
     },
     Ignore,
     Deny {
@@ -100,23 +201,205 @@ enum AcceptResult<'a> {
         decoded_cookie: Option<DecodedServerCookie>,
         peer_addr: SocketAddr,
     },
+    /// The request failed MAC authentication, answer with a crypto-NAK
+    CryptoNak {
+        packet: NtpPacket<'a>,
+        max_response_size: usize,
+        peer_addr: SocketAddr,
+    },
+    /// The NTS cookie of the request is not valid (anymore), answer with an NTS NAK
+    NtsNak {
+        packet: NtpPacket<'a>,
+        max_response_size: usize,
+        peer_addr: SocketAddr,
+    },
     NetworkGone,
 }
 
+impl<'a> AcceptResult<'a> {
+    /// Turn an accepted request into a deny kiss. A request that would get a
+    /// crypto-NAK gets the (equally unauthenticated) deny kiss instead. An NTS NAK is
+    /// not authenticated and we can't send an authenticated deny either, so that
+    /// request is ignored.
+    fn deny(self) -> Self {
+        match self {
+            AcceptResult::Accept {
+                packet,
+                max_response_size,
+                decoded_cookie,
+                peer_addr,
+                ..
+            } => AcceptResult::Deny {
+                packet,
+                max_response_size,
+                decoded_cookie,
+                peer_addr,
+            },
+            AcceptResult::CryptoNak {
+                packet,
+                max_response_size,
+                peer_addr,
+            } => AcceptResult::Deny {
+                packet,
+                max_response_size,
+                decoded_cookie: None,
+                peer_addr,
+            },
+            AcceptResult::NtsNak { .. } => AcceptResult::Ignore,
+            v => v,
+        }
+    }
+
+    /// Turn an accepted request into a rate limiting kiss. Like with [`Self::deny`],
+    /// a request that would get a crypto-NAK gets the kiss instead and requests that
+    /// would get an NTS NAK are ignored.
+    fn rate_limit(self) -> Self {
+        match self {
+            AcceptResult::Accept {
+                packet,
+                max_response_size,
+                decoded_cookie,
+                peer_addr,
+                ..
+            } => AcceptResult::RateLimit {
+                packet,
+                max_response_size,
+                decoded_cookie,
+                peer_addr,
+            },
+            AcceptResult::CryptoNak {
+                packet,
+                max_response_size,
+                peer_addr,
+            } => AcceptResult::RateLimit {
+                packet,
+                max_response_size,
+                decoded_cookie: None,
+                peer_addr,
+            },
+            AcceptResult::NtsNak { .. } => AcceptResult::Ignore,
+            v => v,
+        }
+    }
+}
+
+/// Everything needed to build the response to an accepted request
+#[derive(Debug)]
+struct PendingResponse<'a> {
+    packet: NtpPacket<'a>,
+    max_response_size: usize,
+    decoded_cookie: Option<DecodedServerCookie>,
+    recv_timestamp: NtpTimestamp,
+    mac_keyid: Option<u32>,
+    echo_fields: Vec<ExtensionField<'static>>,
+    previous: Option<PreviousExchange>,
+    /// Pad NTS responses to the size of the request
+    pad_nts: bool,
+}
+
+impl PendingResponse<'_> {
+    fn into_owned(self) -> PendingResponse<'static> {
+        PendingResponse {
+            packet: self.packet.into_owned(),
+            max_response_size: self.max_response_size,
+            decoded_cookie: self.decoded_cookie,
+            recv_timestamp: self.recv_timestamp,
+            mac_keyid: self.mac_keyid,
+            echo_fields: self.echo_fields,
+            previous: self.previous,
+            pad_nts: self.pad_nts,
+        }
+    }
+
+    /// Serialize the response into `buf`, returning its size. The transmit
+    /// timestamp is read from `clock` while doing so.
+    fn serialize<C: NtpClock>(
+        self,
+        system: &SystemSnapshot,
+        clock: &C,
+        keyset: &KeySet,
+        symmetric_keys: &SymmetricKeys,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let mut cursor = Cursor::new(buf);
+        let serialize_result = match self.decoded_cookie {
+            Some(decoded_cookie) => {
+                let mut response = NtpPacket::nts_interleaved_timestamp_response(
+                    system,
+                    self.packet,
+                    self.recv_timestamp,
+                    clock,
+                    &decoded_cookie,
+                    keyset,
+                    self.previous,
+                );
+                response.add_echo_fields(self.echo_fields);
+                // drop cookies rather than sending more than the client did
+                if !response.fit_nts_response(
+                    self.max_response_size,
+                    decoded_cookie.s2c.as_ref(),
+                    self.pad_nts,
+                ) {
+                    debug!("NTS response does not fit in the size of the request");
+                    return None;
+                }
+                response.serialize(&mut cursor, decoded_cookie.s2c.as_ref())
+            }
+            None => {
+                let mut response = NtpPacket::interleaved_timestamp_response(
+                    system,
+                    self.packet,
+                    self.recv_timestamp,
+                    clock,
+                    self.previous,
+                );
+                response.add_echo_fields(self.echo_fields);
+                // authenticated requests get a response authenticated with the same key
+                match self
+                    .mac_keyid
+                    .and_then(|id| Some((id, symmetric_keys.get(id)?)))
+                {
+                    Some((keyid, key)) => response
+                        .add_mac(keyid, key)
+                        .and_then(|_| response.serialize(&mut cursor, &NoCipher)),
+                    None => response.serialize(&mut cursor, &NoCipher),
+                }
+            }
+        };
+
+        if let Err(serialize_err) = serialize_result {
+            error!(error=?serialize_err, "Could not serialize response");
+            return None;
+        }
+
+        if cursor.position() as usize > self.max_response_size {
+            error!("Generated response that was larger than the request");
+            return None;
+        }
+
+        Some(cursor.position() as usize)
+    }
+}
+
 impl<C: 'static + NtpClock + Send> ServerTask<C> {
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         config: ServerConfig,
         stats: ServerStats,
         mut system_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
         keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+        symmetric_keys: Arc<SymmetricKeys>,
         clock: C,
         interface: Option<InterfaceName>,
         network_wait_period: Duration,
+        passive_sender: mpsc::Sender<PassiveRequest>,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             let rate_limiting_cutoff = config.rate_limiting_cutoff;
             let rate_limiting_cache_size = config.rate_limiting_cache_size;
-            let system = *system_receiver.borrow_and_update();
+            let system = system_receiver.borrow_and_update().clone();
+            let (delayed_exchange_sender, delayed_exchange_receiver) =
+                mpsc::channel(MAX_DELAYED_RESPONSES);
 
             let mut process = ServerTask {
                 config,
@@ -124,9 +407,15 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 system,
                 system_receiver,
                 keyset,
+                symmetric_keys,
                 clock,
                 interface,
                 client_cache: TimestampedCache::new(rate_limiting_cache_size),
+                exchange_cache: ExchangeCache::new(EXCHANGE_CACHE_SIZE),
+                delayed_responses: Arc::new(Semaphore::new(MAX_DELAYED_RESPONSES)),
+                delayed_exchange_sender,
+                delayed_exchange_receiver,
+                passive_sender,
                 stats,
             };
 
@@ -157,7 +446,7 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
             } else {
                 cur_socket = Some(loop {
                     match UdpSocket::server(self.config.addr, self.interface).await {
-                        Ok(socket) => break socket,
+                        Ok(socket) => break Arc::new(socket),
                         Err(error) => {
                             warn!(?error, "Could not open server socket");
                             tokio::time::sleep(self.network_wait_period).await;
@@ -165,34 +454,44 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                     }
                 });
                 // system may now be wildly out of date, ensure it is always updated.
-                self.system = *self.system_receiver.borrow_and_update();
+                self.system = self.system_receiver.borrow_and_update().clone();
 
                 cur_socket.as_ref().unwrap()
             };
 
             let mut buf = [0_u8; MAX_PACKET_SIZE];
+            // NTS fields of requests are decrypted into this, instead of allocating
+            let mut scratch = [0_u8; MAX_PACKET_SIZE];
             tokio::select! {
+                // record finished exchanges first, so an interleaved request that
+                // follows a delayed response can refer to it
+                biased;
+                Some((peer_addr, exchange)) = self.delayed_exchange_receiver.recv() => {
+                    self.exchange_cache.insert(peer_addr, exchange);
+                }
+                _ = self.system_receiver.changed(), if self.system_receiver.has_changed().is_ok() => {
+                    self.system = self.system_receiver.borrow_and_update().clone();
+                }
                 recv_res = socket.recv(&mut buf) => {
-                    if !self.serve_packet(socket, &buf, recv_res, rate_limiting_cutoff).await {
+                    if !self.serve_packet(socket, &buf, &mut scratch, recv_res, rate_limiting_cutoff).await {
                         cur_socket = None;
                     }
                 },
-                _ = self.system_receiver.changed(), if self.system_receiver.has_changed().is_ok() => {
-                    self.system = *self.system_receiver.borrow_and_update();
-                }
             }
         }
     }
 
     async fn serve_packet(
         &mut self,
-        socket: &UdpSocket,
+        socket: &Arc<UdpSocket>,
         buf: &[u8],
+        scratch: &mut [u8],
         recv_res: std::io::Result<(usize, SocketAddr, Option<NtpTimestamp>)>,
         rate_limiting_cutoff: Duration,
     ) -> bool {
+        let start = Instant::now();
         self.stats.received_packets.inc();
-        let accept_result = self.accept_packet(rate_limiting_cutoff, recv_res, buf);
+        let accept_result = self.accept_packet(rate_limiting_cutoff, recv_res, buf, scratch);
 
         match accept_result {
             AcceptResult::Accept {
@@ -201,51 +500,125 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 decoded_cookie,
                 peer_addr,
                 recv_timestamp,
+                mac_keyid,
             } => {
                 self.stats.accepted_packets.inc();
 
-                let keyset = self.keyset.borrow().clone();
-                let mut buf = [0; MAX_PACKET_SIZE];
-                let mut cursor = Cursor::new(buf.as_mut_slice());
-                let serialize_result = match decoded_cookie {
-                    Some(decoded_cookie) => {
-                        let response = NtpPacket::nts_timestamp_response(
-                            &self.system,
-                            packet,
-                            recv_timestamp,
-                            &self.clock,
-                            &decoded_cookie,
-                            &keyset,
-                        );
-                        response.serialize(&mut cursor, decoded_cookie.s2c.as_ref())
-                    }
-                    None => {
-                        let response = NtpPacket::timestamp_response(
-                            &self.system,
-                            packet,
-                            recv_timestamp,
-                            &self.clock,
-                        );
-                        response.serialize(&mut cursor, &NoCipher)
-                    }
-                };
-
-                if let Err(serialize_err) = serialize_result {
-                    error!(error=?serialize_err, "Could not serialize response");
-                    return true;
+                if packet.mode() == NtpAssociationMode::SymmetricActive {
+                    self.mobilize_passive(peer_addr, mac_keyid);
                 }
 
-                if cursor.position() as usize > max_response_size {
-                    error!("Generated response that was larger than the request");
-                    return true;
-                }
+                let response = PendingResponse {
+                    // only explicitly configured fields are echoed, and never more than the
+                    // client sent, so this can't be used for amplification
+                    echo_fields: packet.echo_fields(&self.config.echo_fields),
+                    previous: self.exchange_cache.get(&peer_addr),
+                    packet,
+                    max_response_size,
+                    decoded_cookie,
+                    recv_timestamp,
+                    mac_keyid,
+                    pad_nts: self.config.nts_response_padding,
+                };
+                let keyset = self.keyset.borrow().clone();
 
-                if let Err(send_err) = socket
-                    .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
-                    .await
-                {
-                    self.stats.response_send_errors.inc();
-                    debug!(error=?send_err, "Could not send response packet");
+                let jitter = response_jitter(self.config.response_jitter, &mut thread_rng());
+                if jitter.is_zero() {
+                    let mut buf = [0; MAX_PACKET_SIZE];
+                    let size = match response.serialize(
+                        &self.system,
+                        &self.clock,
+                        &keyset,
+                        &self.symmetric_keys,
+                        &mut buf,
+                    ) {
+                        Some(size) => size,
+                        None => return true,
+                    };
+
+                    let send_result = socket.send_to(&buf[..size], peer_addr).await;
+                    self.stats.response_latency.record(start.elapsed());
+                    match send_result {
+                        Ok(_) => {
+                            // The time right after sending is a better transmit timestamp
+                            // than the one in the response, which we can provide when
+                            // the client's next request is interleaved
+                            if let Ok(transmit_timestamp) = self.clock.now() {
+                                self.exchange_cache.insert(
+                                    peer_addr,
+                                    PreviousExchange {
+                                        receive_timestamp: recv_timestamp,
+                                        transmit_timestamp,
+                                    },
+                                );
+                            }
+                        }
+                        Err(send_err) => {
+                            self.stats.response_send_errors.inc();
+                            debug!(error=?send_err, "Could not send response packet");
+                        }
+                    }
+                } else {
+                    // bound the number of waiting responses, so a flood of requests can't
+                    // turn into an unbounded number of tasks
+                    let permit = match self.delayed_responses.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            self.stats.response_send_errors.inc();
+                            debug!("Too many delayed responses, dropping response");
+                            return true;
+                        }
+                    };
+
+                    // delay the response in a separate task, so other clients are not
+                    // held up while we wait. The response is only built after the delay,
+                    // so its transmit timestamp is accurate.
+                    let response = response.into_owned();
+                    let socket = socket.clone();
+                    let system = self.system.clone();
+                    let clock = self.clock.clone();
+                    let symmetric_keys = self.symmetric_keys.clone();
+                    let response_send_errors = self.stats.response_send_errors.clone();
+                    let response_latency = self.stats.response_latency.clone();
+                    let delayed_exchange_sender = self.delayed_exchange_sender.clone();
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        tokio::time::sleep(jitter).await;
+
+                        let mut buf = [0; MAX_PACKET_SIZE];
+                        let size = match response.serialize(
+                            &system,
+                            &clock,
+                            &keyset,
+                            &symmetric_keys,
+                            &mut buf,
+                        ) {
+                            Some(size) => size,
+                            None => return,
+                        };
+
+                        let send_result = socket.send_to(&buf[..size], peer_addr).await;
+                        // the jitter is added deliberately, so it is not part of the latency
+                        response_latency.record(start.elapsed().saturating_sub(jitter));
+                        match send_result {
+                            Ok(_) => {
+                                let transmit_timestamp = clock.now().ok();
+                                if let Some(transmit_timestamp) = transmit_timestamp {
+                                    let exchange = PreviousExchange {
+                                        receive_timestamp: recv_timestamp,
+                                        transmit_timestamp,
+                                    };
+                                    // only fails when the server task is gone
+                                    let _ =
+                                        delayed_exchange_sender.send((peer_addr, exchange)).await;
+                                }
+                            }
+                            Err(send_err) => {
+                                response_send_errors.inc();
+                                debug!(error=?send_err, "Could not send response packet");
+                            }
+                        }
+                    });
                 }
             }
             AcceptResult::Deny {
@@ -332,6 +705,66 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                     debug!(error=?send_err, "Could not send response packet");
                 }
             }
+            AcceptResult::CryptoNak {
+                packet,
+                max_response_size,
+                peer_addr,
+            } => {
+                // the client is refused service until it uses a key we know
+                self.stats.denied_packets.inc();
+
+                let mut buf = [0; 52];
+                let mut cursor = Cursor::new(buf.as_mut_slice());
+                let response = NtpPacket::crypto_nak_response(packet);
+                if let Err(serialize_err) = response.serialize(&mut cursor, &NoCipher) {
+                    self.stats.response_send_errors.inc();
+                    error!(error=?serialize_err, "Could not serialize response");
+                    return true;
+                }
+
+                if cursor.position() as usize > max_response_size {
+                    error!("Generated response that was larger than the request");
+                    return true;
+                }
+
+                if let Err(send_err) = socket
+                    .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
+                    .await
+                {
+                    self.stats.response_send_errors.inc();
+                    debug!(error=?send_err, "Could not send crypto-NAK");
+                }
+            }
+            AcceptResult::NtsNak {
+                packet,
+                max_response_size,
+                peer_addr,
+            } => {
+                // the client needs new cookies from the key exchange server
+                self.stats.denied_packets.inc();
+
+                let mut buf = [0; MAX_PACKET_SIZE];
+                let mut cursor = Cursor::new(buf.as_mut_slice());
+                let response = NtpPacket::nts_nak_response(packet);
+                if let Err(serialize_err) = response.serialize(&mut cursor, &NoCipher) {
+                    self.stats.response_send_errors.inc();
+                    error!(error=?serialize_err, "Could not serialize response");
+                    return true;
+                }
+
+                if cursor.position() as usize > max_response_size {
+                    error!("Generated response that was larger than the request");
+                    return true;
+                }
+
+                if let Err(send_err) = socket
+                    .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
+                    .await
+                {
+                    self.stats.response_send_errors.inc();
+                    debug!(error=?send_err, "Could not send NTS NAK");
+                }
+            }
             AcceptResult::Ignore => {
                 self.stats.ignored_packets.inc();
             }
@@ -339,11 +772,29 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         true
     }
 
+    /// Ask for a passive association with a symmetric active peer. We may end up
+    /// synchronizing to it, so only authenticated peers can mobilize one.
+    fn mobilize_passive(&self, peer_addr: SocketAddr, mac_keyid: Option<u32>) {
+        match mac_keyid {
+            Some(key) => {
+                // the active peer is expected to serve on the same port as we do
+                let addr = SocketAddr::new(peer_addr.ip(), self.config.addr.port());
+                // when too many requests are waiting, the active peer will ask again
+                let _ = self.passive_sender.try_send(PassiveRequest { addr, key });
+            }
+            None => debug!(
+                "Not mobilizing a passive association for unauthenticated peer {}",
+                peer_addr
+            ),
+        }
+    }
+
     fn accept_packet<'a>(
         &mut self,
         rate_limiting_cutoff: Duration,
         result: Result<(usize, SocketAddr, Option<NtpTimestamp>), std::io::Error>,
         buf: &'a [u8],
+        scratch: &'a mut [u8],
     ) -> AcceptResult<'a> {
         match result {
             Ok((size, peer_addr, Some(recv_timestamp))) if size >= 48 => {
@@ -353,24 +804,11 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 // Messages of fewer than 48 bytes are skipped entirely
                 match self.filter(&peer_addr.ip()) {
                     Some(FilterAction::Deny) => {
-                        match self.accept_data(&buf[..size], peer_addr, recv_timestamp) {
-                            // We should send deny messages only to reasonable requests
-                            // otherwise two servers could end up in a loop of sending
-                            // deny's to each other.
-                            AcceptResult::Accept {
-                                packet,
-                                max_response_size,
-                                decoded_cookie,
-                                peer_addr,
-                                ..
-                            } => AcceptResult::Deny {
-                                packet,
-                                max_response_size,
-                                decoded_cookie,
-                                peer_addr,
-                            },
-                            v => v,
-                        }
+                        // We should send deny messages only to reasonable requests
+                        // otherwise two servers could end up in a loop of sending
+                        // deny's to each other.
+                        self.accept_data(&buf[..size], scratch, peer_addr, recv_timestamp)
+                            .deny()
                     }
                     Some(FilterAction::Ignore) => AcceptResult::Ignore,
                     None => {
@@ -381,20 +819,14 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                                 .client_cache
                                 .is_allowed(peer_addr.ip(), timestamp, cutoff);
 
-                        match self.accept_data(&buf[..size], peer_addr, recv_timestamp) {
-                            AcceptResult::Accept {
-                                packet,
-                                max_response_size,
-                                decoded_cookie,
-                                peer_addr,
-                                ..
-                            } if too_soon => AcceptResult::RateLimit {
-                                packet,
-                                max_response_size,
-                                decoded_cookie,
-                                peer_addr,
-                            },
-                            accept_result => accept_result,
+                        let accept_result =
+                            self.accept_data(&buf[..size], scratch, peer_addr, recv_timestamp);
+                        if too_soon {
+                            accept_result.rate_limit()
+                        } else if !self.is_synchronized() {
+                            self.unsynchronized_result(accept_result)
+                        } else {
+                            accept_result
                         }
                     }
                 }
@@ -428,42 +860,118 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         }
     }
 
+    fn is_synchronized(&self) -> bool {
+        // stratum 16 signals that we are not synchronized
+        self.system.stratum < 16
+    }
+
+    /// Apply the configured policy for serving clients while we are not synchronized
+    fn unsynchronized_result<'a>(&self, accept_result: AcceptResult<'a>) -> AcceptResult<'a> {
+        match (self.config.unsynchronized_action, accept_result) {
+            (UnsynchronizedAction::Deny, accept_result) => accept_result.deny(),
+            (
+                UnsynchronizedAction::Ignore,
+                AcceptResult::Accept { .. }
+                | AcceptResult::CryptoNak { .. }
+                | AcceptResult::NtsNak { .. },
+            ) => AcceptResult::Ignore,
+            (_, accept_result) => accept_result,
+        }
+    }
+
     fn accept_data<'a>(
         &self,
         buf: &'a [u8],
+        scratch: &'a mut [u8],
         peer_addr: SocketAddr,
         recv_timestamp: NtpTimestamp,
     ) -> AcceptResult<'a> {
         let keyset = self.keyset.borrow().clone();
-        match NtpPacket::deserialize(buf, keyset.as_ref()) {
-            Ok((packet, decoded_cookie)) => match packet.mode() {
-                NtpAssociationMode::Client => {
-                    trace!("NTP client request accepted from {}", peer_addr);
-                    AcceptResult::Accept {
-                        packet,
-                        max_response_size: buf.len(),
-                        decoded_cookie,
-                        peer_addr,
-                        recv_timestamp,
+        match validate_request_with_scratch(
+            buf,
+            peer_addr,
+            recv_timestamp,
+            keyset.as_ref(),
+            scratch,
+        ) {
+            Ok(request) => {
+                let mac_keyid = request.packet.mac_keyid();
+                if let Some(keyid) = mac_keyid {
+                    let verified = match self.symmetric_keys.get(keyid) {
+                        Some(key) => request.packet.verify_mac(buf, keyid, key),
+                        None => Err(MacError::UnknownKey(keyid)),
+                    };
+                    if let Err(error) = verified {
+                        debug!(%error, "NTP client request failed authentication from {}", peer_addr);
+                        return match self.config.crypto_nak {
+                            true => AcceptResult::CryptoNak {
+                                packet: request.packet,
+                                max_response_size: buf.len(),
+                                peer_addr,
+                            },
+                            false => AcceptResult::Ignore,
+                        };
                     }
                 }
-                _ => {
-                    trace!(
-                        "NTP packet with unkown mode {:?} ignored from {}",
-                        packet.mode(),
-                        peer_addr
-                    );
-                    AcceptResult::Ignore
+
+                trace!("NTP client request accepted from {}", peer_addr);
+                AcceptResult::Accept {
+                    packet: request.packet,
+                    max_response_size: buf.len(),
+                    decoded_cookie: request.decoded_cookie,
+                    peer_addr,
+                    recv_timestamp,
+                    mac_keyid,
                 }
-            },
+            }
+            Err(RequestError::InvalidNts(packet)) => {
+                debug!("NTS request with an invalid cookie from {}", peer_addr);
+                AcceptResult::NtsNak {
+                    packet,
+                    max_response_size: buf.len(),
+                    peer_addr,
+                }
+            }
+            Err(RequestError::InvalidMode(NtpAssociationMode::Reserved)) => {
+                // mode 0 never occurs in legitimate traffic
+                debug!("NTP packet with reserved mode dropped from {}", peer_addr);
+                self.stats.reserved_mode_packets.inc();
+                AcceptResult::Ignore
+            }
+            Err(RequestError::InvalidMode(mode)) => {
+                trace!(
+                    "NTP packet with unkown mode {:?} ignored from {}",
+                    mode,
+                    peer_addr
+                );
+                AcceptResult::Ignore
+            }
             Err(e) => {
-                info!("received invalid packet: {}", e);
+                // tell packets that are only followed by junk apart from garbage
+                match NtpPacket::deserialize_with_trailing(buf, keyset.as_ref()) {
+                    Ok((_, _, trailing)) if !trailing.unexpected.is_empty() => info!(
+                        "received packet followed by {} unexpected bytes from {}",
+                        trailing.unexpected.len(),
+                        peer_addr
+                    ),
+                    _ => info!("received invalid packet: {}", e),
+                }
                 AcceptResult::Ignore
             }
         }
     }
 }
 
+/// Random delay to add before sending a response, uniformly distributed between zero
+/// and `max_jitter`. A `max_jitter` of zero disables the delay.
+fn response_jitter(max_jitter: Duration, rng: &mut impl Rng) -> Duration {
+    if max_jitter.is_zero() {
+        Duration::ZERO
+    } else {
+        rng.gen_range(Duration::ZERO..=max_jitter)
+    }
+}
+
 /// A size-bounded cache where each entry is timestamped.
 ///
 /// The planned use is in rate limiting: we keep track of when a peer last checked in. If it checks
@@ -530,9 +1038,49 @@ impl<T: std::hash::Hash + Eq> TimestampedCache<T> {
     }
 }
 
+/// The last exchange with each client, to answer its next request in interleaved
+/// mode. Like [`TimestampedCache`], this is a vector indexed by a hash of the client
+/// address. A client whose entry was overwritten by a collision simply gets a
+/// response in basic mode.
+#[derive(Debug)]
+struct ExchangeCache {
+    randomstate: RandomState,
+    elements: Vec<Option<(SocketAddr, PreviousExchange)>>,
+}
+
+impl ExchangeCache {
+    fn new(length: usize) -> Self {
+        Self {
+            elements: vec![None; length],
+            randomstate: RandomState::new(),
+        }
+    }
+
+    fn index(&self, addr: &SocketAddr) -> usize {
+        self.randomstate.hash_one(addr) as usize % self.elements.len()
+    }
+
+    fn get(&self, addr: &SocketAddr) -> Option<PreviousExchange> {
+        if self.elements.is_empty() {
+            return None;
+        }
+
+        self.elements[self.index(addr)]
+            .filter(|(v, _)| v == addr)
+            .map(|(_, exchange)| exchange)
+    }
+
+    fn insert(&mut self, addr: SocketAddr, exchange: PreviousExchange) {
+        if !self.elements.is_empty() {
+            let index = self.index(&addr);
+            self.elements[index] = Some((addr, exchange));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{net::Ipv4Addr, time::Duration};
 
     use ntp_proto::{
         KeySetProvider, NtpDuration, NtpLeapIndicator, PollInterval, PollIntervalLimits,
@@ -543,8 +1091,6 @@ mod tests {
 
     use super::*;
 
-    const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
-
     #[derive(Debug, Clone, Default)]
     struct TestClock {}
 
@@ -555,8 +1101,8 @@ mod tests {
             let cur =
                 std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
 
-            Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
-                EPOCH_OFFSET.wrapping_add(cur.as_secs() as u32),
+            Ok(NtpTimestamp::from_unix_time(
+                cur.as_secs() as i64,
                 cur.subsec_nanos(),
             ))
         }
@@ -618,6 +1164,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -628,9 +1179,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -666,6 +1219,11 @@ mod tests {
             allowlist_action: FilterAction::Deny,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -676,9 +1234,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -715,6 +1275,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -725,9 +1290,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -758,6 +1325,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -768,9 +1340,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -806,6 +1380,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -816,9 +1395,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -855,6 +1436,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -865,9 +1451,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -898,6 +1486,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_millis(100),
             rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -908,9 +1501,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -978,6 +1573,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::default(),
             rate_limiting_cache_size: Default::default(),
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -988,9 +1588,11 @@ mod tests {
             Default::default(),
             system_snapshots,
             keyset,
+            Default::default(),
             clock,
             InterfaceName::DEFAULT,
             Duration::from_secs(1),
+            mpsc::channel(1).0,
         );
 
         let mut socket = UdpSocket::client(
@@ -1016,6 +1618,1157 @@ mod tests {
 
         server.abort();
     }
+
+    #[tokio::test]
+    async fn test_server_echo_fields() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9016".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![0x7777],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9017".parse().unwrap(),
+            "127.0.0.1:9016".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+
+        // an opted-in diagnostic field, followed by one that is not echoed
+        let echoed: Vec<u8> = [0x77, 0x77, 0, 36].into_iter().chain(0..32).collect();
+        let ignored: Vec<u8> = [0x77, 0x78, 0, 36].into_iter().chain(0..32).collect();
+        let mut serialized = serialize_packet_unencryped(&packet).to_vec();
+        serialized.extend_from_slice(&echoed);
+        serialized.extend_from_slice(&ignored);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert_ne!(packet.stratum(), 0);
+        assert!(packet.valid_server_response(id, false));
+        assert_eq!(&buf[48..size], echoed.as_slice());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_interleaved() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9034".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9035".parse().unwrap(),
+            "127.0.0.1:9034".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let first = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+        assert!(first.valid_server_response(id, false));
+
+        // refer to the first exchange, with a made up time we received its response
+        let received = NtpTimestamp::from_seconds_nanos_since_ntp_era(1234, 0);
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let (packet, id) = packet.into_interleaved_request(id, first.receive_timestamp(), received);
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let second = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+        assert!(second.valid_server_response(id, false));
+        assert_eq!(second.origin_timestamp(), received);
+        // the transmit timestamp of the first response, taken after sending it
+        assert!(second.transmit_timestamp() - first.transmit_timestamp() >= NtpDuration::ZERO);
+        assert!(second.receive_timestamp() - second.transmit_timestamp() >= NtpDuration::ZERO);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_symmetric_key() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9038".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+        let symmetric_keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = symmetric_keys.get(1).unwrap().clone();
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Arc::new(symmetric_keys),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9039".parse().unwrap(),
+            "127.0.0.1:9038".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // an authenticated request gets an authenticated response
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.add_mac(1, &key).unwrap();
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &NoCipher).unwrap();
+        let size = cursor.position() as usize;
+        socket.send(&buf[..size]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert!(response.valid_server_response(id, false));
+        assert_eq!(response.verify_mac(&buf[..size], 1, &key), Ok(()));
+
+        // requests failing authentication are ignored
+        for keyid in [1, 2] {
+            let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            packet.add_mac(keyid, &key).unwrap();
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, &NoCipher).unwrap();
+            let size = cursor.position() as usize;
+            buf[47] ^= 1;
+            socket.send(&buf[..size]).await.unwrap();
+
+            let mut buf = [0; 1024];
+            let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+            assert!(res.is_err());
+        }
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_crypto_nak() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9048".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: true,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+        let symmetric_keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = symmetric_keys.get(1).unwrap().clone();
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Arc::new(symmetric_keys),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9049".parse().unwrap(),
+            "127.0.0.1:9048".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // requests with an unknown key or a wrong MAC get a crypto-NAK
+        for keyid in [1, 2] {
+            let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            packet.add_mac(keyid, &key).unwrap();
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, &NoCipher).unwrap();
+            let size = cursor.position() as usize;
+            buf[size - 1] ^= 1;
+            socket.send(&buf[..size]).await.unwrap();
+
+            let mut buf = [0; 1024];
+            let (size, _, _) =
+                tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+            assert!(response.valid_server_response(id, false));
+            assert!(response.is_crypto_nak());
+        }
+
+        // requests without a MAC are served as usual
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &NoCipher).unwrap();
+        let size = cursor.position() as usize;
+        socket.send(&buf[..size]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert!(response.valid_server_response(id, false));
+        assert!(!response.is_crypto_nak());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_crypto_nak_rate_limited() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9056".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: true,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+        let symmetric_keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = symmetric_keys.get(1).unwrap().clone();
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Arc::new(symmetric_keys),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9057".parse().unwrap(),
+            "127.0.0.1:9056".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.add_mac(1, &key).unwrap();
+        let mut request = [0; 1024];
+        let mut cursor = Cursor::new(request.as_mut_slice());
+        packet.serialize(&mut cursor, &NoCipher).unwrap();
+        let size = cursor.position() as usize;
+        request[size - 1] ^= 1;
+
+        // the first request gets a crypto-NAK, the next one is rate limited
+        for expect_crypto_nak in [true, false] {
+            socket.send(&request[..size]).await.unwrap();
+
+            let mut buf = [0; 1024];
+            let (size, _, _) =
+                tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+            assert!(response.valid_server_response(id, false));
+            assert_eq!(response.is_crypto_nak(), expect_crypto_nak);
+            assert_eq!(response.is_kiss_rate(), !expect_crypto_nak);
+        }
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_nts_nak() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9050".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9051".parse().unwrap(),
+            "127.0.0.1:9050".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // a cookie the server can't decrypt, as if its key was rotated out
+        let cipher = ntp_proto::cipher_for_aead(15, &[0; 32]).unwrap();
+        let (packet, id) =
+            NtpPacket::nts_poll_message(&[7; 100], 1, PollIntervalLimits::default().min);
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, cipher.as_ref()).unwrap();
+        let size = cursor.position() as usize;
+        socket.send(&buf[..size]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert!(response.valid_server_response(id, true));
+        assert!(response.is_kiss_ntsn());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_nts_nak_denylisted() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9052".parse().unwrap(),
+            denylist: IpFilter::new(&["127.0.0.0/24".parse().unwrap()]),
+            denylist_action: FilterAction::Deny,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9053".parse().unwrap(),
+            "127.0.0.1:9052".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let cipher = ntp_proto::cipher_for_aead(15, &[0; 32]).unwrap();
+        let (packet, _) =
+            NtpPacket::nts_poll_message(&[7; 100], 1, PollIntervalLimits::default().min);
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, cipher.as_ref()).unwrap();
+        let size = cursor.position() as usize;
+        socket.send(&buf[..size]).await.unwrap();
+
+        // a denied client gets neither an NTS NAK nor an (unauthenticated) deny
+        let mut buf = [0; 1024];
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_nts_nak_rate_limited() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9054".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9055".parse().unwrap(),
+            "127.0.0.1:9054".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let cipher = ntp_proto::cipher_for_aead(15, &[0; 32]).unwrap();
+        let (packet, id) =
+            NtpPacket::nts_poll_message(&[7; 100], 1, PollIntervalLimits::default().min);
+        let mut request = [0; 1024];
+        let mut cursor = Cursor::new(request.as_mut_slice());
+        packet.serialize(&mut cursor, cipher.as_ref()).unwrap();
+        let size = cursor.position() as usize;
+
+        // the first request gets an NTS NAK
+        socket.send(&request[..size]).await.unwrap();
+        let mut buf = [0; 1024];
+        let (response_size, _, _) =
+            tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        let response = NtpPacket::deserialize(&buf[..response_size], &NoCipher)
+            .unwrap()
+            .0;
+        assert!(response.valid_server_response(id, true));
+        assert!(response.is_kiss_ntsn());
+
+        // but a request that comes in too soon after it is not answered
+        socket.send(&request[..size]).await.unwrap();
+        let mut buf = [0; 1024];
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_symmetric_active() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9046".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+        let symmetric_keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = symmetric_keys.get(1).unwrap().clone();
+        let (passive_sender, mut passive_receiver) = mpsc::channel(1);
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Arc::new(symmetric_keys),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            passive_sender,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9047".parse().unwrap(),
+            "127.0.0.1:9046".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let send_active = |authenticated: bool| {
+            let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let mut packet = packet.into_symmetric_request(NtpAssociationMode::SymmetricActive);
+            if authenticated {
+                packet.add_mac(1, &key).unwrap();
+            }
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, &NoCipher).unwrap();
+            let size = cursor.position() as usize;
+            (buf[..size].to_vec(), id)
+        };
+
+        // an unauthenticated active peer is answered, but can't mobilize an association
+        let (request, id) = send_active(false);
+        socket.send(&request).await.unwrap();
+        let mut buf = [0; 1024];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert!(response.valid_server_response(id, false));
+        assert_eq!(response.mode(), NtpAssociationMode::SymmetricPassive);
+        assert!(passive_receiver.try_recv().is_err());
+
+        // an authenticated one asks for a passive association with its server
+        let (request, _) = send_active(true);
+        socket.send(&request).await.unwrap();
+        let mut buf = [0; 1024];
+        tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            passive_receiver.try_recv().unwrap(),
+            PassiveRequest {
+                addr: "127.0.0.1:9046".parse().unwrap(),
+                key: 1,
+            }
+        );
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_cmac_key() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9040".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+        let symmetric_keys: SymmetricKeys = "1 SHA1 secret\n2 AES128CMAC sixteen-byte-key"
+            .parse()
+            .unwrap();
+        let key = symmetric_keys.get(2).unwrap().clone();
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Arc::new(symmetric_keys),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9041".parse().unwrap(),
+            "127.0.0.1:9040".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // a request authenticated with AES-128-CMAC gets a response authenticated
+        // with the same key
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.add_mac(2, &key).unwrap();
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &NoCipher).unwrap();
+        let size = cursor.position() as usize;
+        socket.send(&buf[..size]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert!(response.valid_server_response(id, false));
+        assert_eq!(response.verify_mac(&buf[..size], 2, &key), Ok(()));
+
+        // requests failing authentication are ignored, including those claiming
+        // the key id of the SHA1 key
+        for keyid in [1, 2] {
+            let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            packet.add_mac(keyid, &key).unwrap();
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, &NoCipher).unwrap();
+            let size = cursor.position() as usize;
+            buf[47] ^= 1;
+            socket.send(&buf[..size]).await.unwrap();
+
+            let mut buf = [0; 1024];
+            let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+            assert!(res.is_err());
+        }
+
+        server.abort();
+    }
+
+    #[test]
+    fn test_response_jitter() {
+        let mut rng = thread_rng();
+
+        // disabled by default
+        for _ in 0..100 {
+            assert_eq!(response_jitter(Duration::ZERO, &mut rng), Duration::ZERO);
+        }
+
+        let max_jitter = Duration::from_millis(5);
+        for _ in 0..1000 {
+            assert!(response_jitter(max_jitter, &mut rng) <= max_jitter);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_response_jitter() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9018".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::from_millis(5),
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9019".parse().unwrap(),
+            "127.0.0.1:9018".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        // the delayed response still arrives
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(50), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+        assert_ne!(packet.stratum(), 0);
+        assert!(packet.valid_server_response(id, false));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_response_jitter_timestamps() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9042".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::from_millis(100),
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+        let stats = ServerStats::default();
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock.clone(),
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9043".parse().unwrap(),
+            "127.0.0.1:9042".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // the transmit timestamp is taken after the delay, so the delay shows up as
+        // time spent at the server rather than as network delay
+        for _ in 0..3 {
+            let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let send_timestamp = clock.now().unwrap();
+            socket
+                .send(&serialize_packet_unencryped(&packet))
+                .await
+                .unwrap();
+
+            let mut buf = [0; 48];
+            tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let recv_timestamp = clock.now().unwrap();
+            let packet = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+            assert!(packet.valid_server_response(id, false));
+
+            let delay = (recv_timestamp - send_timestamp)
+                - (packet.transmit_timestamp() - packet.receive_timestamp());
+            assert!(delay < NtpDuration::from_seconds(0.01), "{delay:?}");
+        }
+
+        server.abort();
+
+        // the deliberate delay is not counted as latency
+        let latency = stats.response_latency.percentile(1.0).unwrap();
+        assert!(latency <= Duration::from_micros(1 << 15), "{latency:?}");
+    }
+
+    #[tokio::test]
+    async fn test_server_response_jitter_interleaved() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9036".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::from_millis(20),
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9037".parse().unwrap(),
+            "127.0.0.1:9036".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let first = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+        assert!(first.valid_server_response(id, false));
+
+        // the delayed response was recorded, so the next request can refer to it
+        let received = NtpTimestamp::from_seconds_nanos_since_ntp_era(1234, 0);
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let (packet, id) = packet.into_interleaved_request(id, first.receive_timestamp(), received);
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let second = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+        assert!(second.valid_server_response(id, false));
+        assert_eq!(second.origin_timestamp(), received);
+        // the transmit timestamp of the first response, taken after sending it
+        assert!(second.transmit_timestamp() - first.transmit_timestamp() >= NtpDuration::ZERO);
+        assert!(second.receive_timestamp() - second.transmit_timestamp() >= NtpDuration::ZERO);
+
+        server.abort();
+    }
+
+    async fn unsynchronized_response(
+        port_base: u16,
+        system: SystemSnapshot,
+        unsynchronized_action: UnsynchronizedAction,
+    ) -> Option<NtpPacket<'static>> {
+        let config = ServerConfig {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port_base)),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(system);
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port_base + 1)),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port_base)),
+        )
+        .await
+        .unwrap();
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 48];
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        server.abort();
+
+        res.ok().map(|res| {
+            res.unwrap();
+            let packet = NtpPacket::deserialize(&buf, &NoCipher).unwrap().0;
+            assert!(packet.valid_server_response(id, false));
+            packet.into_owned()
+        })
+    }
+
+    fn synchronized_system() -> SystemSnapshot {
+        SystemSnapshot {
+            stratum: 2,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_unsynchronized_serve() {
+        // while warming up, we advertise that we are not synchronized
+        let packet =
+            unsynchronized_response(9020, SystemSnapshot::default(), UnsynchronizedAction::Serve)
+                .await
+                .unwrap();
+        assert_eq!(packet.stratum(), 16);
+
+        let packet =
+            unsynchronized_response(9022, synchronized_system(), UnsynchronizedAction::Serve)
+                .await
+                .unwrap();
+        assert_eq!(packet.stratum(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_unsynchronized_deny() {
+        let packet =
+            unsynchronized_response(9024, SystemSnapshot::default(), UnsynchronizedAction::Deny)
+                .await
+                .unwrap();
+        assert_eq!(packet.stratum(), 0);
+        assert_eq!(packet.reference_id(), ReferenceId::KISS_DENY);
+
+        // once synchronized, clients are served as usual
+        let packet =
+            unsynchronized_response(9026, synchronized_system(), UnsynchronizedAction::Deny)
+                .await
+                .unwrap();
+        assert_eq!(packet.stratum(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_unsynchronized_ignore() {
+        let packet = unsynchronized_response(
+            9028,
+            SystemSnapshot::default(),
+            UnsynchronizedAction::Ignore,
+        )
+        .await;
+        assert!(packet.is_none());
+
+        let packet =
+            unsynchronized_response(9030, synchronized_system(), UnsynchronizedAction::Ignore)
+                .await
+                .unwrap();
+        assert_eq!(packet.stratum(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_drops_reserved_mode() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9032".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            echo_fields: vec![],
+            response_jitter: Duration::ZERO,
+            unsynchronized_action: UnsynchronizedAction::Serve,
+            crypto_nak: false,
+            nts_response_padding: false,
+        };
+        let stats = ServerStats::default();
+        let (_, system_snapshots) = tokio::sync::watch::channel(synchronized_system());
+        let clock = TestClock {};
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            system_snapshots,
+            keyset,
+            Default::default(),
+            clock,
+            InterfaceName::DEFAULT,
+            Duration::from_secs(1),
+            mpsc::channel(1).0,
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9033".parse().unwrap(),
+            "127.0.0.1:9032".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.set_mode(NtpAssociationMode::Reserved);
+
+        let serialized = serialize_packet_unencryped(&packet);
+        socket.send(&serialized).await.unwrap();
+
+        let mut buf = [0; 48];
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        server.abort();
+
+        assert_eq!(stats.received_packets.get(), 1);
+        assert_eq!(stats.reserved_mode_packets.get(), 1);
+        assert_eq!(stats.ignored_packets.get(), 1);
+        // no response, so no latency either
+        assert_eq!(stats.response_latency.percentile(1.0), None);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        // 90 fast responses, 9 slower ones and a single very slow one
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(20));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_micros(300));
+        }
+        histogram.record(Duration::from_secs(100));
+
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_micros(32)));
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(32)));
+        assert_eq!(histogram.percentile(0.9), Some(Duration::from_micros(32)));
+        assert_eq!(histogram.percentile(0.95), Some(Duration::from_micros(512)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_micros(512)));
+        // latencies beyond the last bucket end up in the last bucket
+        assert_eq!(
+            histogram.percentile(1.0),
+            Some(Duration::from_micros(1 << (LATENCY_BUCKETS - 1)))
+        );
+
+        // sub-microsecond latencies
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_nanos(500));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_micros(1)));
+    }
+
+    #[test]
+    fn test_latency_histogram_serialization() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(20));
+        histogram.record(Duration::from_micros(300));
+
+        let serialized = serde_json::to_string(&histogram).unwrap();
+        let deserialized: LatencyHistogram = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.counts(), histogram.counts());
+
+        assert!(serde_json::from_str::<LatencyHistogram>("[1, 2, 3]").is_err());
+    }
 }
 
 #[cfg(test)]
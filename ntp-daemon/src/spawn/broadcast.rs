@@ -0,0 +1,198 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ntp_proto::{NoCipher, NtpAssociationMode, NtpPacket};
+use ntp_udp::UdpSocket;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::config::{BroadcastClientConfig, NormalizedAddress};
+
+use super::{
+    BasicSpawner, PeerId, PeerRemovalReason, PeerRemovedEvent, SpawnAction, SpawnEvent, SpawnerId,
+};
+
+pub struct BroadcastSpawner {
+    id: SpawnerId,
+    config: BroadcastClientConfig,
+    network_wait_period: std::time::Duration,
+}
+
+#[derive(Error, Debug)]
+pub enum BroadcastSpawnError {
+    #[error("Channel send error: {0}")]
+    SendError(#[from] mpsc::error::SendError<SpawnEvent>),
+}
+
+/// Open a socket that receives the broadcasts for `config`, joining the
+/// multicast group when the address is one
+pub(crate) async fn listen(config: &BroadcastClientConfig) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::server(config.addr, config.interface).await?;
+
+    match config.addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => socket
+            .as_ref()
+            .join_multicast_v4(&group, &std::net::Ipv4Addr::UNSPECIFIED)?,
+        IpAddr::V6(group) if group.is_multicast() => {
+            socket.as_ref().join_multicast_v6(&group, 0)?
+        }
+        _ => {}
+    }
+
+    Ok(socket)
+}
+
+impl BroadcastSpawner {
+    pub fn new(
+        config: BroadcastClientConfig,
+        network_wait_period: std::time::Duration,
+    ) -> BroadcastSpawner {
+        BroadcastSpawner {
+            id: Default::default(),
+            config,
+            network_wait_period,
+        }
+    }
+
+    /// Wait for the first broadcast, the server that sent it is the one we
+    /// calibrate against and follow
+    async fn find_server(&self) -> SocketAddr {
+        loop {
+            let socket = match listen(&self.config).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!(error = ?e, "could not listen for broadcasts, retrying");
+                    tokio::time::sleep(self.network_wait_period).await;
+                    continue;
+                }
+            };
+
+            let mut buf = [0; 1024];
+            loop {
+                match socket.recv(&mut buf).await {
+                    Ok((size, addr, _)) => {
+                        let is_broadcast = NtpPacket::deserialize(&buf[..size], &NoCipher)
+                            .map(|(packet, _)| packet.mode() == NtpAssociationMode::Broadcast)
+                            .unwrap_or(false);
+                        if is_broadcast {
+                            return addr;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(error = ?e, "error while waiting for broadcasts, retrying");
+                        tokio::time::sleep(self.network_wait_period).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn spawn(
+        &mut self,
+        action_tx: &mpsc::Sender<SpawnEvent>,
+    ) -> Result<(), BroadcastSpawnError> {
+        let addr = self.find_server().await;
+        let mut action = SpawnAction::create(
+            PeerId::new(),
+            addr,
+            NormalizedAddress::from_socket_addr(addr),
+            None,
+            false,
+            self.config.key,
+        );
+        let SpawnAction::Create(params) = &mut action;
+        params.broadcast = Some(self.config.clone());
+
+        action_tx.send(SpawnEvent::new(self.id, action)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl BasicSpawner for BroadcastSpawner {
+    type Error = BroadcastSpawnError;
+
+    async fn handle_init(
+        &mut self,
+        action_tx: &mpsc::Sender<SpawnEvent>,
+    ) -> Result<(), BroadcastSpawnError> {
+        self.spawn(action_tx).await
+    }
+
+    async fn handle_peer_removed(
+        &mut self,
+        removed_peer: PeerRemovedEvent,
+        action_tx: &mpsc::Sender<SpawnEvent>,
+    ) -> Result<(), BroadcastSpawnError> {
+        if removed_peer.reason != PeerRemovalReason::Demobilized {
+            self.spawn(action_tx).await
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_id(&self) -> SpawnerId {
+        self.id
+    }
+
+    fn get_addr_description(&self) -> String {
+        self.config.addr.to_string()
+    }
+
+    fn get_description(&self) -> &str {
+        "broadcast"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ntp_proto::{NtpAssociationMode, NtpPacket};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        config::BroadcastClientConfig,
+        spawn::{broadcast::BroadcastSpawner, tests::get_create_params, Spawner},
+        system::{MESSAGE_BUFFER_SIZE, NETWORK_WAIT_PERIOD},
+    };
+
+    #[tokio::test]
+    async fn creates_peer_for_broadcast_server() {
+        let config = BroadcastClientConfig {
+            addr: "127.0.0.1:9044".parse().unwrap(),
+            interface: None,
+            key: Some(1),
+        };
+        let spawner = BroadcastSpawner::new(config.clone(), NETWORK_WAIT_PERIOD);
+        let spawner_id = spawner.get_id();
+        let (action_tx, mut action_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+        let (_notify_tx, notify_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+
+        tokio::spawn(async move { spawner.run(action_tx, notify_rx).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:9045").await.unwrap();
+        let mut packet = NtpPacket::test();
+        packet.set_mode(NtpAssociationMode::Broadcast);
+        let mut buf = [0; 48];
+        packet
+            .serialize(
+                &mut std::io::Cursor::new(&mut buf[..]),
+                &ntp_proto::NoCipher,
+            )
+            .unwrap();
+        server.send_to(&buf, "127.0.0.1:9044").await.unwrap();
+
+        let res = tokio::time::timeout(Duration::from_secs(1), action_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.id, spawner_id);
+        let params = get_create_params(res);
+        assert_eq!(params.addr.to_string(), "127.0.0.1:9045");
+        assert_eq!(params.key, Some(1));
+        assert_eq!(params.broadcast, Some(config));
+    }
+}
@@ -1,13 +1,15 @@
 use std::{net::SocketAddr, sync::atomic::AtomicU64};
 
-use ntp_proto::PeerNtsData;
+use ntp_proto::{NtpAssociationMode, PeerNtsData};
 use tokio::sync::mpsc;
 
-use crate::config::NormalizedAddress;
+use crate::config::{BroadcastClientConfig, NormalizedAddress};
 
+pub mod broadcast;
 #[cfg(test)]
 pub mod dummy;
 pub mod nts;
+pub mod passive;
 pub mod pool;
 pub mod standard;
 
@@ -107,12 +109,20 @@ impl SpawnAction {
         addr: SocketAddr,
         normalized_addr: NormalizedAddress,
         nts: Option<Box<PeerNtsData>>,
+        prefer: bool,
+        key: Option<u32>,
     ) -> SpawnAction {
         SpawnAction::Create(PeerCreateParameters {
             id,
             addr,
             normalized_addr,
             nts,
+            prefer,
+            leap_smear: false,
+            key,
+            broadcast: None,
+            mode: NtpAssociationMode::Client,
+            nts_store: None,
         })
     }
 }
@@ -123,6 +133,17 @@ pub struct PeerCreateParameters {
     pub addr: SocketAddr,
     pub normalized_addr: NormalizedAddress,
     pub nts: Option<Box<PeerNtsData>>,
+    pub prefer: bool,
+    /// The peer smears leap seconds instead of announcing them
+    pub leap_smear: bool,
+    /// Id of the symmetric key to authenticate the exchanges with
+    pub key: Option<u32>,
+    /// Also listen for the broadcasts of the peer
+    pub broadcast: Option<BroadcastClientConfig>,
+    /// Mode of the requests to the peer, client or one of the symmetric modes
+    pub mode: NtpAssociationMode,
+    /// Where the peer saves its NTS cookies, to use them again after a restart
+    pub nts_store: Option<Box<nts::NtsStateStore>>,
 }
 
 #[cfg(test)]
@@ -142,6 +163,12 @@ impl PeerCreateParameters {
             ))
             .unwrap(),
             nts: None,
+            prefer: false,
+            leap_smear: false,
+            key: None,
+            broadcast: None,
+            mode: NtpAssociationMode::Client,
+            nts_store: None,
         }
     }
 
@@ -171,6 +198,12 @@ impl PeerCreateParameters {
             addr: socket_addr,
             normalized_addr: addr,
             nts: None,
+            prefer: false,
+            leap_smear: false,
+            key: None,
+            broadcast: None,
+            mode: NtpAssociationMode::Client,
+            nts_store: None,
         }
     }
 }
@@ -1,8 +1,17 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ntp_proto::{CookieStore, FileCookieStore, KeyExchangeResult, MemoryCookieStore, PeerNtsData};
 use thiserror::Error;
 use tokio::sync::mpsc;
-use tracing::warn;
+use tracing::{info, warn};
 
-use crate::{config::NtsPeerConfig, keyexchange::key_exchange_client};
+use crate::{
+    config::{CookieStoreConfig, NtsPeerConfig},
+    keyexchange::key_exchange_client,
+};
 
 use super::{BasicSpawner, PeerId, PeerRemovedEvent, SpawnAction, SpawnEvent, SpawnerId};
 
@@ -18,6 +27,80 @@ pub enum NtsSpawnError {
     SendError(#[from] mpsc::error::SendError<SpawnEvent>),
 }
 
+/// How often a peer saves its cookies at most. The cookies change with every
+/// poll, so after a crash the peer may resume with cookies of up to this long
+/// ago, some of which it already used.
+const NTS_STATE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The cookie store of a peer, which the peer keeps up to date with the
+/// cookies it has left
+#[derive(Debug)]
+pub struct NtsStateStore {
+    store: Arc<Mutex<FileCookieStore>>,
+    remote: String,
+    port: u16,
+    /// Whether the cookies changed since they were last saved
+    changed: bool,
+    last_save: Instant,
+}
+
+impl NtsStateStore {
+    fn new(store: FileCookieStore, remote: String, port: u16) -> Self {
+        NtsStateStore {
+            store: Arc::new(Mutex::new(store)),
+            remote,
+            port,
+            changed: false,
+            last_save: Instant::now(),
+        }
+    }
+
+    /// Note that the cookies changed, and save them when the last save was
+    /// long enough ago
+    pub async fn cookies_changed(&mut self, nts: &PeerNtsData) {
+        self.changed = true;
+        if self.last_save.elapsed() >= NTS_STATE_SAVE_INTERVAL {
+            self.save(nts).await;
+        }
+    }
+
+    /// Save the cookies if they changed since the last save
+    pub async fn flush(&mut self, nts: &PeerNtsData) {
+        if self.changed {
+            self.save(nts).await;
+        }
+    }
+
+    async fn save(&mut self, nts: &PeerNtsData) {
+        self.changed = false;
+        self.last_save = Instant::now();
+
+        // serializing is cheap, but the encryption and file IO should not block the runtime
+        let mut state = MemoryCookieStore::default();
+        let result = match nts.save_with_server(&self.remote, self.port, &mut state) {
+            Ok(()) => {
+                let store = self.store.clone();
+                tokio::task::spawn_blocking(move || {
+                    let state = state.load()?.unwrap_or_default();
+                    store.lock().unwrap().save(&state)
+                })
+                .await
+                .unwrap_or_else(|error| Err(error.into()))
+            }
+            Err(error) => Err(error),
+        };
+
+        if let Err(error) = result {
+            warn!(?error, "could not save the NTS cookies");
+        }
+    }
+}
+
+async fn open_cookie_store(config: &CookieStoreConfig) -> std::io::Result<FileCookieStore> {
+    let secret = tokio::fs::read(&config.key_path).await?;
+    Ok(FileCookieStore::with_secret(&config.path, &secret))
+}
+
 impl NtsSpawner {
     pub fn new(config: NtsPeerConfig, network_wait_period: std::time::Duration) -> NtsSpawner {
         NtsSpawner {
@@ -27,12 +110,13 @@ impl NtsSpawner {
         }
     }
 
-    async fn spawn(&mut self, action_tx: &mpsc::Sender<SpawnEvent>) -> Result<(), NtsSpawnError> {
-        let ke = loop {
+    async fn key_exchange(&self) -> KeyExchangeResult {
+        loop {
             match key_exchange_client(
                 self.config.ke_addr.server_name.clone(),
                 self.config.ke_addr.port,
                 &self.config.certificates,
+                &self.config.aead_algorithms,
             )
             .await
             {
@@ -42,12 +126,72 @@ impl NtsSpawner {
                     tokio::time::sleep(self.network_wait_period).await;
                 }
             };
+        }
+    }
+
+    /// Spawn the peer with the cookies in the cookie store when `restore` is
+    /// set, or else with those of a new key exchange
+    async fn spawn(
+        &mut self,
+        action_tx: &mpsc::Sender<SpawnEvent>,
+        restore: bool,
+    ) -> Result<(), NtsSpawnError> {
+        let store = match &self.config.cookie_store {
+            Some(config) => match open_cookie_store(config).await {
+                Ok(store) => Some(store),
+                Err(error) => {
+                    warn!(?error, "could not read the cookie store key");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let restored = match &store {
+            Some(store) if restore => match KeyExchangeResult::load(store) {
+                Ok(restored) => restored,
+                Err(error) => {
+                    warn!(?error, "could not restore the NTS cookies");
+                    None
+                }
+            },
+            _ => None,
         };
 
+        let from_store = restored.is_some();
+        let ke = match restored {
+            Some(ke) => {
+                info!(
+                    remote = ke.remote,
+                    port = ke.port,
+                    "resuming with stored NTS cookies"
+                );
+                ke
+            }
+            None => self.key_exchange().await,
+        };
+
+        let mut nts_store =
+            store.map(|store| Box::new(NtsStateStore::new(store, ke.remote.clone(), ke.port)));
+        if let (Some(nts_store), false) = (&mut nts_store, from_store) {
+            nts_store.save(&ke.nts).await;
+        }
+
+        // the configured endpoint takes precedence, for when the negotiated
+        // one can't be reached from here
+        let ntp_server = self.config.ntp_server.as_deref().unwrap_or(&ke.remote);
+        let ntp_port = self.config.ntp_port.unwrap_or(ke.port);
+        if (ntp_server, ntp_port) != (ke.remote.as_str(), ke.port) {
+            info!(
+                negotiated = format!("{}:{}", ke.remote, ke.port),
+                ntp_server, ntp_port, "overriding the negotiated NTP server"
+            );
+        }
+
         let addr = loop {
-            let address = (ke.remote.as_str(), ke.port);
+            let address = (ntp_server, ntp_port);
             match tokio::net::lookup_host(address).await {
-                Ok(mut addresses) => match addresses.next() {
+                Ok(addresses) => match self.config.address_family.select(addresses) {
                     None => {
                         warn!("Could not resolve peer address, retrying");
                         tokio::time::sleep(self.network_wait_period).await
@@ -63,17 +207,19 @@ impl NtsSpawner {
             }
         };
 
-        action_tx
-            .send(SpawnEvent::new(
-                self.id,
-                SpawnAction::create(
-                    PeerId::new(),
-                    addr,
-                    self.config.ke_addr.clone(),
-                    Some(ke.nts),
-                ),
-            ))
-            .await?;
+        let mut action = SpawnAction::create(
+            PeerId::new(),
+            addr,
+            self.config.ke_addr.clone(),
+            Some(ke.nts),
+            self.config.prefer,
+            None,
+        );
+        let SpawnAction::Create(params) = &mut action;
+        params.leap_smear = self.config.leap_smear;
+        params.nts_store = nts_store;
+
+        action_tx.send(SpawnEvent::new(self.id, action)).await?;
         Ok(())
     }
 }
@@ -86,7 +232,7 @@ impl BasicSpawner for NtsSpawner {
         &mut self,
         action_tx: &mpsc::Sender<SpawnEvent>,
     ) -> Result<(), NtsSpawnError> {
-        self.spawn(action_tx).await
+        self.spawn(action_tx, true).await
     }
 
     async fn handle_peer_removed(
@@ -94,7 +240,8 @@ impl BasicSpawner for NtsSpawner {
         _removed_peer: PeerRemovedEvent,
         action_tx: &mpsc::Sender<SpawnEvent>,
     ) -> Result<(), NtsSpawnError> {
-        self.handle_init(action_tx).await
+        // the stored cookies are those the removed peer ran out of or had rejected
+        self.spawn(action_tx, false).await
     }
 
     fn get_id(&self) -> SpawnerId {
@@ -109,3 +256,43 @@ impl BasicSpawner for NtsSpawner {
         "nts"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nts_data() -> PeerNtsData {
+        // AEAD_AES_SIV_CMAC_256 with two keys of 32 bytes and a single cookie
+        let mut state = vec![0, 15, 0, 32];
+        state.extend_from_slice(&[1; 64]);
+        state.extend_from_slice(&[0, 16]);
+        state.extend_from_slice(&[2; 16]);
+
+        let mut store = MemoryCookieStore::default();
+        store.save(&state).unwrap();
+        PeerNtsData::load(&store).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_state_store_saves_on_flush() {
+        let path = std::env::temp_dir().join(format!("ntpd-rs-{}-peer", std::process::id()));
+        let store = FileCookieStore::new(&path, [0x55; 64]);
+        let mut nts_store = NtsStateStore::new(store, "example.com".into(), 4123);
+        let nts = nts_data();
+
+        // right after the last save, a change is not written yet
+        nts_store.cookies_changed(&nts).await;
+        assert!(!path.exists());
+
+        nts_store.flush(&nts).await;
+        let stored = FileCookieStore::new(&path, [0x55; 64]);
+        let restored = KeyExchangeResult::load(&stored).unwrap().unwrap();
+        assert_eq!(restored.remote, "example.com");
+        assert_eq!(restored.port, 4123);
+
+        // without changes, there is nothing to write
+        std::fs::remove_file(&path).unwrap();
+        nts_store.flush(&nts).await;
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use ntp_proto::NtpAssociationMode;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::config::NormalizedAddress;
+
+use super::{PeerId, SpawnAction, SpawnEvent, Spawner, SpawnerId, SystemEvent};
+
+/// Request of a server to mobilize a passive association, after it received an
+/// authenticated symmetric active packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassiveRequest {
+    /// Address of the server of the active peer
+    pub addr: SocketAddr,
+    /// Id of the key with which the active peer authenticated its packet
+    pub key: u32,
+}
+
+/// Spawns ephemeral symmetric passive associations. These are not restarted
+/// when they are removed, the active peer has to mobilize them again.
+pub struct PassiveSpawner {
+    id: SpawnerId,
+    requests: mpsc::Receiver<PassiveRequest>,
+    mobilized: HashMap<PeerId, IpAddr>,
+}
+
+#[derive(Error, Debug)]
+pub enum PassiveSpawnError {
+    #[error("Channel send error: {0}")]
+    SendError(#[from] mpsc::error::SendError<SpawnEvent>),
+}
+
+impl PassiveSpawner {
+    pub fn new(requests: mpsc::Receiver<PassiveRequest>) -> PassiveSpawner {
+        PassiveSpawner {
+            id: Default::default(),
+            requests,
+            mobilized: HashMap::new(),
+        }
+    }
+
+    async fn mobilize(
+        &mut self,
+        request: PassiveRequest,
+        action_tx: &mpsc::Sender<SpawnEvent>,
+    ) -> Result<(), PassiveSpawnError> {
+        let ip = request.addr.ip();
+        if self.mobilized.values().any(|mobilized| *mobilized == ip) {
+            return Ok(());
+        }
+
+        info!(addr = ?request.addr, "mobilizing passive association");
+        let id = PeerId::new();
+        let mut action = SpawnAction::create(
+            id,
+            request.addr,
+            NormalizedAddress::from_socket_addr(request.addr),
+            None,
+            false,
+            Some(request.key),
+        );
+        let SpawnAction::Create(params) = &mut action;
+        params.mode = NtpAssociationMode::SymmetricPassive;
+
+        action_tx.send(SpawnEvent::new(self.id, action)).await?;
+        self.mobilized.insert(id, ip);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Spawner for PassiveSpawner {
+    type Error = PassiveSpawnError;
+
+    async fn run(
+        mut self,
+        action_tx: mpsc::Sender<SpawnEvent>,
+        mut system_notify: mpsc::Receiver<SystemEvent>,
+    ) -> Result<(), PassiveSpawnError> {
+        loop {
+            tokio::select! {
+                Some(request) = self.requests.recv() => {
+                    self.mobilize(request, &action_tx).await?;
+                }
+                event = system_notify.recv() => match event {
+                    Some(SystemEvent::PeerRemoved(removed_peer)) => {
+                        self.mobilized.remove(&removed_peer.id);
+                    }
+                    Some(SystemEvent::PeerRegistered(_)) => {}
+                    Some(SystemEvent::Shutdown) | None => break,
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_id(&self) -> SpawnerId {
+        self.id
+    }
+
+    fn get_addr_description(&self) -> String {
+        "symmetric active peers".to_string()
+    }
+
+    fn get_description(&self) -> &str {
+        "passive"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ntp_proto::NtpAssociationMode;
+    use tokio::sync::mpsc::{self, error::TryRecvError};
+
+    use crate::{
+        spawn::{
+            passive::{PassiveRequest, PassiveSpawner},
+            tests::get_create_params,
+            PeerRemovalReason, Spawner, SystemEvent,
+        },
+        system::MESSAGE_BUFFER_SIZE,
+    };
+
+    #[tokio::test]
+    async fn mobilizes_once_per_peer() {
+        let (request_tx, request_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+        let spawner = PassiveSpawner::new(request_rx);
+        let (action_tx, mut action_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+        let (notify_tx, notify_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+        tokio::spawn(async move { spawner.run(action_tx, notify_rx).await });
+
+        let request = PassiveRequest {
+            addr: "127.0.0.1:123".parse().unwrap(),
+            key: 1,
+        };
+        request_tx.send(request).await.unwrap();
+        request_tx.send(request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let params = get_create_params(action_rx.try_recv().unwrap());
+        assert_eq!(params.addr, request.addr);
+        assert_eq!(params.key, Some(1));
+        assert_eq!(params.mode, NtpAssociationMode::SymmetricPassive);
+        assert_eq!(action_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        // once removed, the active peer can mobilize the association again
+        notify_tx
+            .send(SystemEvent::peer_removed(
+                params.id,
+                PeerRemovalReason::Unreachable,
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(action_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        request_tx.send(request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let params = get_create_params(action_rx.try_recv().unwrap());
+        assert_eq!(params.addr, request.addr);
+    }
+}
@@ -52,9 +52,15 @@ impl PoolSpawner {
                     Ok(addresses) => {
                         // add the addresses looked up to our list of known ips
                         self.known_ips.append(&mut addresses.collect());
-                        // remove known ips that we are already connected to
+                        // remove known ips that we are already connected to, or
+                        // that are of an address family we shouldn't use
+                        let family = self.config.address_family;
+                        self.known_ips.retain(|ip| {
+                            family.allows(ip) && !self.current_peers.iter().any(|p| p.addr == *ip)
+                        });
+                        // peers are taken from the back, so put the preferred ones there
                         self.known_ips
-                            .retain(|ip| !self.current_peers.iter().any(|p| p.addr == *ip))
+                            .sort_by_key(|ip| std::cmp::Reverse(family.rank(ip)));
                     }
                     Err(e) => {
                         warn!(error = ?e, "error while resolving peer address, retrying");
@@ -69,7 +75,8 @@ impl PoolSpawner {
                 if let Some(addr) = self.known_ips.pop() {
                     let id = PeerId::new();
                     self.current_peers.push(PoolPeer { id, addr });
-                    let action = SpawnAction::create(id, addr, self.config.addr.clone(), None);
+                    let action =
+                        SpawnAction::create(id, addr, self.config.addr.clone(), None, false, None);
                     tracing::debug!(?action, "intending to spawn new pool peer at");
 
                     action_tx
@@ -141,7 +148,7 @@ mod tests {
     use tokio::sync::mpsc::{self, error::TryRecvError};
 
     use crate::{
-        config::{NormalizedAddress, PoolPeerConfig},
+        config::{AddressFamily, NormalizedAddress, PoolPeerConfig},
         spawn::{
             pool::PoolSpawner, tests::get_create_params, PeerRemovalReason, Spawner, SystemEvent,
         },
@@ -162,6 +169,7 @@ mod tests {
                     ],
                 ),
                 max_peers: 2,
+                address_family: AddressFamily::Any,
             },
             NETWORK_WAIT_PERIOD,
         );
@@ -186,6 +194,49 @@ mod tests {
         assert_eq!(res, TryRecvError::Empty);
     }
 
+    #[tokio::test]
+    async fn uses_address_family() {
+        let dual_stack = vec![
+            "127.0.0.1:123".parse().unwrap(),
+            "[::1]:123".parse().unwrap(),
+            "127.0.0.2:123".parse().unwrap(),
+            "[::2]:123".parse().unwrap(),
+        ];
+
+        for (address_family, expected) in [
+            (AddressFamily::Any, ["[::2]:123", "127.0.0.2:123"]),
+            (
+                AddressFamily::PreferIpv4,
+                ["127.0.0.2:123", "127.0.0.1:123"],
+            ),
+            (AddressFamily::PreferIpv6, ["[::2]:123", "[::1]:123"]),
+            (AddressFamily::Ipv4Only, ["127.0.0.2:123", "127.0.0.1:123"]),
+            (AddressFamily::Ipv6Only, ["[::2]:123", "[::1]:123"]),
+        ] {
+            let pool = PoolSpawner::new(
+                PoolPeerConfig {
+                    addr: NormalizedAddress::with_hardcoded_dns(
+                        "example.com",
+                        123,
+                        dual_stack.clone(),
+                    ),
+                    max_peers: 2,
+                    address_family,
+                },
+                NETWORK_WAIT_PERIOD,
+            );
+            let (action_tx, mut action_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+            let (_notify_tx, notify_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+            tokio::spawn(async move { pool.run(action_tx, notify_rx).await });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            for expected in expected {
+                let params = get_create_params(action_rx.try_recv().unwrap());
+                assert_eq!(params.addr.to_string(), expected);
+            }
+        }
+    }
+
     #[tokio::test]
     async fn refills_peers_upto_limit() {
         let pool = PoolSpawner::new(
@@ -200,6 +251,7 @@ mod tests {
                     ],
                 ),
                 max_peers: 2,
+                address_family: AddressFamily::Any,
             },
             NETWORK_WAIT_PERIOD,
         );
@@ -233,6 +285,7 @@ mod tests {
             PoolPeerConfig {
                 addr: NormalizedAddress::with_hardcoded_dns("does.not.resolve", 123, vec![]),
                 max_peers: 2,
+                address_family: AddressFamily::Any,
             },
             NETWORK_WAIT_PERIOD,
         );
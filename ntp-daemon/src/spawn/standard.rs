@@ -1,5 +1,6 @@
 use std::net::SocketAddr;
 
+use ntp_proto::NtpAssociationMode;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tracing::warn;
@@ -42,7 +43,7 @@ impl StandardSpawner {
         } else {
             let addr = loop {
                 match self.config.addr.lookup_host().await {
-                    Ok(mut addresses) => match addresses.next() {
+                    Ok(addresses) => match self.config.address_family.select(addresses) {
                         None => {
                             warn!("Could not resolve peer address, retrying");
                             tokio::time::sleep(self.network_wait_period).await
@@ -67,12 +68,21 @@ impl StandardSpawner {
         action_tx: &mpsc::Sender<SpawnEvent>,
     ) -> Result<(), StandardSpawnError> {
         let addr = self.do_resolve(false).await;
-        action_tx
-            .send(SpawnEvent::new(
-                self.id,
-                SpawnAction::create(PeerId::new(), addr, self.config.addr.clone(), None),
-            ))
-            .await?;
+        let mut action = SpawnAction::create(
+            PeerId::new(),
+            addr,
+            self.config.addr.clone(),
+            None,
+            self.config.prefer,
+            self.config.key,
+        );
+        let SpawnAction::Create(params) = &mut action;
+        params.leap_smear = self.config.leap_smear;
+        if self.config.symmetric {
+            params.mode = NtpAssociationMode::SymmetricActive;
+        }
+
+        action_tx.send(SpawnEvent::new(self.id, action)).await?;
         Ok(())
     }
 }
@@ -124,7 +134,7 @@ mod tests {
     use tokio::sync::mpsc::{self, error::TryRecvError};
 
     use crate::{
-        config::{NormalizedAddress, StandardPeerConfig},
+        config::{AddressFamily, NormalizedAddress, StandardPeerConfig},
         spawn::{
             standard::StandardSpawner, tests::get_create_params, PeerRemovalReason, Spawner,
             SystemEvent,
@@ -141,6 +151,11 @@ mod tests {
                     123,
                     vec!["127.0.0.1:123".parse().unwrap()],
                 ),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             },
             NETWORK_WAIT_PERIOD,
         );
@@ -161,6 +176,46 @@ mod tests {
         assert_eq!(res, TryRecvError::Empty);
     }
 
+    #[tokio::test]
+    async fn uses_address_family() {
+        let dual_stack = vec![
+            "127.0.0.1:123".parse().unwrap(),
+            "[::1]:123".parse().unwrap(),
+        ];
+
+        for (address_family, expected) in [
+            (AddressFamily::Any, "127.0.0.1:123"),
+            (AddressFamily::PreferIpv4, "127.0.0.1:123"),
+            (AddressFamily::PreferIpv6, "[::1]:123"),
+            (AddressFamily::Ipv4Only, "127.0.0.1:123"),
+            (AddressFamily::Ipv6Only, "[::1]:123"),
+        ] {
+            let spawner = StandardSpawner::new(
+                StandardPeerConfig {
+                    addr: NormalizedAddress::with_hardcoded_dns(
+                        "example.com",
+                        123,
+                        dual_stack.clone(),
+                    ),
+                    prefer: false,
+                    leap_smear: false,
+                    address_family,
+                    key: None,
+                    symmetric: false,
+                },
+                NETWORK_WAIT_PERIOD,
+            );
+            let (action_tx, mut action_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+            let (_notify_tx, notify_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+
+            tokio::spawn(async move { spawner.run(action_tx, notify_rx).await });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let res = action_rx.try_recv().unwrap();
+            let params = get_create_params(res);
+            assert_eq!(params.addr.to_string(), expected);
+        }
+    }
+
     #[tokio::test]
     async fn recreates_a_peer() {
         let spawner = StandardSpawner::new(
@@ -170,6 +225,11 @@ mod tests {
                     123,
                     vec!["127.0.0.1:123".parse().unwrap()],
                 ),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             },
             NETWORK_WAIT_PERIOD,
         );
@@ -200,6 +260,11 @@ mod tests {
         let spawner = StandardSpawner::new(
             StandardPeerConfig {
                 addr: NormalizedAddress::with_hardcoded_dns("europe.pool.ntp.org", 123, vec![]),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             },
             NETWORK_WAIT_PERIOD,
         );
@@ -282,6 +347,11 @@ mod tests {
         let spawner = StandardSpawner::new(
             StandardPeerConfig {
                 addr: NormalizedAddress::with_hardcoded_dns("does.not.resolve", 123, vec![]),
+                prefer: false,
+                leap_smear: false,
+                address_family: AddressFamily::Any,
+                key: None,
+                symmetric: false,
             },
             NETWORK_WAIT_PERIOD,
         );
@@ -1,19 +1,35 @@
 use crate::{
-    config::{ClockConfig, CombinedSystemConfig, NormalizedAddress, PeerConfig, ServerConfig},
-    peer::{MsgForSystem, PeerChannels},
+    config::{
+        BroadcastClientConfig, ClockConfig, CombinedSystemConfig, NormalizedAddress, PeerConfig,
+        ServerConfig,
+    },
+    peer::{ForcePoll, MsgForSystem, PeerChannels},
     peer::{PeerTask, Wait},
     server::{ServerStats, ServerTask},
     spawn::{
-        nts::NtsSpawner, pool::PoolSpawner, standard::StandardSpawner, PeerCreateParameters,
-        PeerId, PeerRemovalReason, SpawnAction, SpawnEvent, Spawner, SpawnerId, SystemEvent,
+        broadcast::BroadcastSpawner,
+        nts::NtsSpawner,
+        passive::{PassiveRequest, PassiveSpawner},
+        pool::PoolSpawner,
+        standard::StandardSpawner,
+        PeerCreateParameters, PeerId, PeerRemovalReason, SpawnAction, SpawnEvent, Spawner,
+        SpawnerId, SystemEvent,
     },
     ObservablePeerState,
 };
 
-use std::{collections::HashMap, future::Future, marker::PhantomData, pin::Pin, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
+    sync::Arc,
+};
 
 use ntp_proto::{
-    DefaultTimeSyncController, KeySet, NtpClock, NtpDuration, PeerSnapshot, SystemSnapshot,
+    DefaultTimeSyncController, KeySet, NtpClock, NtpDuration, NtpInstant, PeerSnapshot,
+    ReferenceId, ServerId, SymmetricKeys, SyncWatchdog, SystemConfig, SystemSnapshot,
     TimeSyncController,
 };
 use ntp_udp::{EnableTimestamps, InterfaceName};
@@ -24,6 +40,13 @@ pub const NETWORK_WAIT_PERIOD: std::time::Duration = std::time::Duration::from_s
 
 pub const MESSAGE_BUFFER_SIZE: usize = 32;
 
+// How often the sync watchdog checks the time since the last synchronization
+const WATCHDOG_CHECK_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+
+// How often the growth of the clock's error bound is sampled into the snapshot
+// we publish, so the servers don't need to read the clock for every response
+const ERROR_BOUND_SAMPLE_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+
 struct SingleshotSleep<T> {
     enabled: bool,
     sleep: Pin<Box<T>>,
@@ -74,6 +97,23 @@ pub struct DaemonChannels {
     pub server_data_receiver: tokio::sync::watch::Receiver<Vec<ServerData>>,
     pub system_snapshot_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
     pub keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+    pub force_poll_sender: tokio::sync::broadcast::Sender<ForcePoll>,
+    pub disabled_peers_sender: tokio::sync::watch::Sender<Vec<String>>,
+}
+
+impl DaemonChannels {
+    /// Request an out-of-schedule poll of the given peer, or of all peers when
+    /// `None`. Peers still respect their minimum poll interval, so the poll
+    /// may be delayed if the peer polled very recently.
+    pub fn force_poll(&self, peer: Option<PeerId>) {
+        let request = match peer {
+            Some(index) => ForcePoll::Peer(index),
+            None => ForcePoll::All,
+        };
+
+        // Don't care if there are no peers to receive the request
+        let _ = self.force_poll_sender.send(request);
+    }
 }
 
 /// Spawn the NTP daemon
@@ -81,8 +121,10 @@ pub async fn spawn(
     system_config: CombinedSystemConfig,
     clock_config: ClockConfig,
     peer_configs: &[PeerConfig],
+    broadcast_configs: &[BroadcastClientConfig],
     server_configs: &[ServerConfig],
     keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+    symmetric_keys: Arc<SymmetricKeys>,
 ) -> std::io::Result<(JoinHandle<std::io::Result<()>>, DaemonChannels)> {
     let (mut system, channels) = System::new(
         clock_config.clock,
@@ -90,6 +132,7 @@ pub async fn spawn(
         clock_config.enable_timestamps,
         system_config,
         keyset,
+        symmetric_keys,
     );
 
     for peer_config in peer_configs {
@@ -106,8 +149,24 @@ pub async fn spawn(
         }
     }
 
+    for broadcast_config in broadcast_configs {
+        system.add_spawner(BroadcastSpawner::new(
+            broadcast_config.clone(),
+            NETWORK_WAIT_PERIOD,
+        ));
+    }
+
+    // servers receive the packets of symmetric active peers, and ask for the
+    // passive associations with them
+    let (passive_sender, passive_receiver) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+    if !server_configs.is_empty() {
+        system.add_spawner(PassiveSpawner::new(passive_receiver));
+    }
+
     for server_config in server_configs.iter() {
-        system.add_server(server_config.to_owned()).await;
+        system
+            .add_server(server_config.to_owned(), passive_sender.clone())
+            .await;
     }
 
     let handle = tokio::spawn(async move {
@@ -131,10 +190,12 @@ struct System<C: NtpClock, T: Wait> {
     system: SystemSnapshot,
 
     config_receiver: tokio::sync::watch::Receiver<CombinedSystemConfig>,
+    disabled_peers_receiver: tokio::sync::watch::Receiver<Vec<String>>,
     system_snapshot_sender: tokio::sync::watch::Sender<SystemSnapshot>,
     peer_snapshots_sender: tokio::sync::watch::Sender<Vec<ObservablePeerState>>,
     server_data_sender: tokio::sync::watch::Sender<Vec<ServerData>>,
     keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+    symmetric_keys: Arc<SymmetricKeys>,
 
     msg_for_system_rx: mpsc::Receiver<MsgForSystem>,
     spawn_tx: mpsc::Sender<SpawnEvent>,
@@ -148,6 +209,7 @@ struct System<C: NtpClock, T: Wait> {
 
     clock: C,
     controller: DefaultTimeSyncController<C, PeerId>,
+    watchdog: Option<SyncWatchdog>,
 
     // which timestamps to use (this is a hint, OS or hardware may ignore)
     enable_timestamps: EnableTimestamps,
@@ -164,22 +226,25 @@ impl<C: NtpClock, T: Wait> System<C, T> {
         enable_timestamps: EnableTimestamps,
         config: CombinedSystemConfig,
         keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+        symmetric_keys: Arc<SymmetricKeys>,
     ) -> (Self, DaemonChannels) {
         // Setup system snapshot
         let system = SystemSnapshot {
             stratum: config.system.local_stratum,
-            ..Default::default()
+            ..SystemSnapshot::with_server_id(ServerId::new(&mut rand::thread_rng()))
         };
 
         // Create communication channels
         let (config_sender, config_receiver) = tokio::sync::watch::channel(config);
         let (system_snapshot_sender, system_snapshot_receiver) =
-            tokio::sync::watch::channel(system);
+            tokio::sync::watch::channel(system.clone());
         let (peer_snapshots_sender, peer_snapshots_receiver) = tokio::sync::watch::channel(vec![]);
         let (server_data_sender, server_data_receiver) = tokio::sync::watch::channel(vec![]);
         let (msg_for_system_sender, msg_for_system_receiver) =
             tokio::sync::mpsc::channel(MESSAGE_BUFFER_SIZE);
         let (spawn_tx, spawn_rx) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+        let (force_poll_sender, _) = tokio::sync::broadcast::channel(MESSAGE_BUFFER_SIZE);
+        let (disabled_peers_sender, disabled_peers_receiver) = tokio::sync::watch::channel(vec![]);
 
         // Build System and its channels
         (
@@ -189,10 +254,12 @@ impl<C: NtpClock, T: Wait> System<C, T> {
                 system,
 
                 config_receiver: config_receiver.clone(),
+                disabled_peers_receiver,
                 system_snapshot_sender,
                 peer_snapshots_sender,
                 server_data_sender,
                 keyset: keyset.clone(),
+                symmetric_keys,
 
                 msg_for_system_rx: msg_for_system_receiver,
                 spawn_rx,
@@ -205,7 +272,9 @@ impl<C: NtpClock, T: Wait> System<C, T> {
                     msg_for_system_sender,
                     system_snapshot_receiver: system_snapshot_receiver.clone(),
                     system_config_receiver: config_receiver.clone(),
+                    force_poll_sender: force_poll_sender.clone(),
                 },
+                watchdog: Self::create_watchdog(&config.system, None),
                 clock: clock.clone(),
                 controller: DefaultTimeSyncController::new(clock, config.system, config.algorithm),
                 enable_timestamps,
@@ -218,6 +287,8 @@ impl<C: NtpClock, T: Wait> System<C, T> {
                 server_data_receiver,
                 system_snapshot_receiver,
                 keyset,
+                force_poll_sender,
+                disabled_peers_sender,
             },
         )
     }
@@ -233,7 +304,26 @@ impl<C: NtpClock, T: Wait> System<C, T> {
         id
     }
 
+    fn create_watchdog(
+        config: &SystemConfig,
+        previous: Option<&SyncWatchdog>,
+    ) -> Option<SyncWatchdog> {
+        let timeout = config.sync_watchdog_timeout?;
+        let last_sync = match previous {
+            Some(watchdog) => watchdog.last_sync(),
+            None => NtpInstant::now(),
+        };
+        Some(SyncWatchdog::with_action(
+            timeout,
+            last_sync,
+            config.sync_watchdog_action,
+        ))
+    }
+
     async fn run(&mut self, mut wait: Pin<&mut SingleshotSleep<T>>) -> std::io::Result<()> {
+        let mut watchdog_check = tokio::time::interval(WATCHDOG_CHECK_PERIOD);
+        let mut error_bound_sample = tokio::time::interval(ERROR_BOUND_SAMPLE_PERIOD);
+
         loop {
             tokio::select! {
                 opt_msg_for_system = self.msg_for_system_rx.recv() => {
@@ -264,6 +354,15 @@ impl<C: NtpClock, T: Wait> System<C, T> {
                 _ = self.config_receiver.changed(), if self.config_receiver.has_changed().is_ok() => {
                     self.handle_config_update();
                 }
+                _ = self.disabled_peers_receiver.changed(), if self.disabled_peers_receiver.has_changed().is_ok() => {
+                    self.handle_disabled_peers_update();
+                }
+                _ = watchdog_check.tick(), if self.watchdog.is_some() => {
+                    self.handle_watchdog_check();
+                }
+                _ = error_bound_sample.tick() => {
+                    self.publish_snapshot();
+                }
             }
         }
 
@@ -275,9 +374,49 @@ impl<C: NtpClock, T: Wait> System<C, T> {
         let config = *self.config_receiver.borrow_and_update();
         self.controller
             .update_config(config.system, config.algorithm);
+        if config.system.sync_watchdog_timeout != self.config.system.sync_watchdog_timeout
+            || config.system.sync_watchdog_action != self.config.system.sync_watchdog_action
+        {
+            self.watchdog = Self::create_watchdog(&config.system, self.watchdog.as_ref());
+        }
         self.config = config;
     }
 
+    fn handle_watchdog_check(&mut self) {
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.check(NtpInstant::now());
+        }
+    }
+
+    fn handle_disabled_peers_update(&mut self) {
+        let disabled = self.disabled_peers_receiver.borrow_and_update().clone();
+        for (index, data) in self.peers.iter() {
+            let is_disabled = disabled.iter().any(|selector| data.matches(selector));
+            if is_disabled {
+                info!(peer_id=?index, addr=%data.peer_address, "peer disabled");
+            }
+            if let Some(snapshot) = data.snapshot {
+                self.controller.peer_update(
+                    *index,
+                    !is_disabled
+                        && snapshot
+                            .accept_synchronization(
+                                self.config.system.local_stratum,
+                                &self.system.server_id,
+                            )
+                            .is_ok(),
+                );
+            }
+        }
+    }
+
+    fn is_disabled(&self, data: &PeerState) -> bool {
+        self.disabled_peers_receiver
+            .borrow()
+            .iter()
+            .any(|selector| data.matches(selector))
+    }
+
     fn handle_timer(&mut self, wait: &mut Pin<&mut SingleshotSleep<T>>) {
         tracing::debug!("Timer expired");
         // note: local needed for borrow checker
@@ -364,11 +503,16 @@ impl<C: NtpClock, T: Wait> System<C, T> {
     }
 
     fn handle_peer_snapshot(&mut self, index: PeerId, snapshot: PeerSnapshot) {
+        let is_disabled = self.is_disabled(&self.peers[&index]);
         self.controller.peer_update(
             index,
-            snapshot
-                .accept_synchronization(self.config.system.local_stratum)
-                .is_ok(),
+            !is_disabled
+                && snapshot
+                    .accept_synchronization(
+                        self.config.system.local_stratum,
+                        &self.system.server_id,
+                    )
+                    .is_ok(),
         );
         self.peers.get_mut(&index).unwrap().snapshot = Some(snapshot);
     }
@@ -393,6 +537,9 @@ impl<C: NtpClock, T: Wait> System<C, T> {
         wait: &mut Pin<&mut SingleshotSleep<T>>,
     ) {
         if let Some(ref used_peers) = update.used_peers {
+            if let Some(watchdog) = self.watchdog.as_mut() {
+                watchdog.synchronized(NtpInstant::now());
+            }
             self.system.update_used_peers(used_peers.iter().map(|v| {
                 self.peers.get(v).and_then(|data| data.snapshot).expect(
                     "Critical error: Peer used for synchronization that is not known to system",
@@ -402,6 +549,12 @@ impl<C: NtpClock, T: Wait> System<C, T> {
         if let Some(time_snapshot) = update.time_snapshot {
             self.system
                 .update_timedata(time_snapshot, &self.config.system);
+            if let Err(error) = self.system.update_error_bound(&self.clock) {
+                tracing::warn!(?error, "Could not read the clock error bound");
+            }
+            if let Err(error) = self.system.update_reference_timestamp(&self.clock) {
+                tracing::warn!(?error, "Could not read the time of the clock update");
+            }
         }
         if let Some(timestamp) = update.next_update {
             let duration = timestamp - self.clock.now().expect("Could not get current time");
@@ -410,11 +563,21 @@ impl<C: NtpClock, T: Wait> System<C, T> {
             wait.as_mut().reset(tokio::time::Instant::now() + duration);
         }
         if update.used_peers.is_some() || update.time_snapshot.is_some() {
-            // Don't care if there is no receiver.
-            let _ = self.system_snapshot_sender.send(self.system);
+            self.publish_snapshot();
         }
     }
 
+    /// Publish our snapshot, with the growth of the clock's error bound since
+    /// the last timekeeping update added to the root dispersion
+    fn publish_snapshot(&self) {
+        let snapshot = self
+            .system
+            .with_error_bound_growth(&self.clock)
+            .unwrap_or_else(|_| self.system.clone());
+        // Don't care if there is no receiver.
+        let _ = self.system_snapshot_sender.send(snapshot);
+    }
+
     async fn handle_peer_demobilize(&mut self, index: PeerId) {
         self.controller.peer_remove(index);
         let state = self.peers.remove(&index).unwrap();
@@ -452,6 +615,13 @@ impl<C: NtpClock, T: Wait> System<C, T> {
             },
         );
         self.controller.peer_add(peer_id);
+        self.controller.peer_prefer(peer_id, params.prefer);
+        self.controller.peer_leap_smear(peer_id, params.leap_smear);
+
+        // the keys of configured peers were checked to exist when loading them
+        let symmetric_key = params
+            .key
+            .and_then(|id| Some((id, self.symmetric_keys.get(id)?.clone())));
 
         PeerTask::spawn(
             peer_id,
@@ -462,6 +632,10 @@ impl<C: NtpClock, T: Wait> System<C, T> {
             NETWORK_WAIT_PERIOD,
             self.peer_channels.clone(),
             params.nts.take(),
+            symmetric_key,
+            params.broadcast.take(),
+            params.mode,
+            params.nts_store.take(),
         );
 
         // Don't care if there is no receiver
@@ -487,7 +661,11 @@ impl<C: NtpClock, T: Wait> System<C, T> {
         }
     }
 
-    async fn add_server(&mut self, config: ServerConfig) {
+    async fn add_server(
+        &mut self,
+        config: ServerConfig,
+        passive_sender: mpsc::Sender<PassiveRequest>,
+    ) {
         let stats = ServerStats::default();
         self.servers.push(ServerData {
             stats: stats.clone(),
@@ -498,9 +676,11 @@ impl<C: NtpClock, T: Wait> System<C, T> {
             stats,
             self.peer_channels.system_snapshot_receiver.clone(),
             self.keyset.clone(),
+            self.symmetric_keys.clone(),
             self.clock.clone(),
             self.interface,
             NETWORK_WAIT_PERIOD,
+            passive_sender,
         );
         let _ = self.server_data_sender.send(self.servers.clone());
     }
@@ -516,6 +696,8 @@ impl<C: NtpClock, T: Wait> System<C, T> {
                             poll_interval: snapshot.poll_interval,
                             peer_id: snapshot.peer_id,
                             address: data.peer_address.to_string(),
+                            last_kiss: snapshot.last_kiss,
+                            kiss_count: snapshot.kiss_count,
                         }
                     } else {
                         ObservablePeerState::Nothing
@@ -534,6 +716,22 @@ struct PeerState {
     peer_id: PeerId,
 }
 
+impl PeerState {
+    /// Check whether this peer is selected by the given selector, which is
+    /// either an address (with or without port) or a reference id in
+    /// dotted-quad notation.
+    fn matches(&self, selector: &str) -> bool {
+        if self.peer_address.to_string() == selector || self.peer_address.server_name == selector {
+            return true;
+        }
+
+        match (selector.parse::<Ipv4Addr>(), self.snapshot) {
+            (Ok(ip), Some(snapshot)) => snapshot.peer_id == ReferenceId::from_ip(IpAddr::V4(ip)),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerData {
     pub stats: ServerStats,
@@ -543,8 +741,8 @@ pub struct ServerData {
 #[cfg(test)]
 mod tests {
     use ntp_proto::{
-        peer_snapshot, KeySetProvider, Measurement, NtpDuration, NtpInstant, NtpLeapIndicator,
-        NtpPacket, NtpTimestamp, PollInterval,
+        peer_snapshot, KeySetProvider, Measurement, NtpDuration, NtpLeapIndicator, NtpPacket,
+        NtpTimestamp, PollInterval,
     };
 
     use crate::spawn::dummy::DummySpawner;
@@ -597,6 +795,36 @@ mod tests {
         fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
             Ok(())
         }
+
+        fn error_bound(&self) -> Result<NtpDuration, Self::Error> {
+            Ok(NtpDuration::from_seconds(0.25))
+        }
+    }
+
+    #[test]
+    fn test_publish_error_bound_growth() {
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let (system, channels) = System::<_, tokio::time::Sleep>::new(
+            TestClock {},
+            InterfaceName::DEFAULT,
+            EnableTimestamps::default(),
+            CombinedSystemConfig::default(),
+            keyset,
+            Default::default(),
+        );
+
+        // the clock's bound grew since the (never happened) last update
+        system.publish_snapshot();
+        let published = channels.system_snapshot_receiver.borrow().clone();
+        assert_eq!(
+            published.time_snapshot.root_dispersion,
+            NtpDuration::from_seconds(0.25)
+        );
+        assert_eq!(
+            system.system.time_snapshot.root_dispersion,
+            NtpDuration::ZERO
+        );
     }
 
     #[tokio::test]
@@ -610,6 +838,7 @@ mod tests {
             EnableTimestamps::default(),
             CombinedSystemConfig::default(),
             keyset,
+            Default::default(),
         );
         let wait =
             SingleshotSleep::new_disabled(tokio::time::sleep(std::time::Duration::from_secs(0)));
@@ -653,6 +882,9 @@ mod tests {
                         offset: NtpDuration::from_seconds(0.),
                         localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(0, 0),
                         monotime: base,
+                        remote_precision: NtpDuration::ZERO,
+                        root_delay: NtpDuration::ZERO,
+                        root_dispersion: NtpDuration::ZERO,
                     },
                     NtpPacket::test(),
                 ),
@@ -682,6 +914,9 @@ mod tests {
                         offset: NtpDuration::from_seconds(0.),
                         localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(0, 0),
                         monotime: base,
+                        remote_precision: NtpDuration::ZERO,
+                        root_delay: NtpDuration::ZERO,
+                        root_dispersion: NtpDuration::ZERO,
                     },
                     NtpPacket::test(),
                 ),
@@ -736,4 +971,167 @@ mod tests {
             1
         );
     }
+
+    #[derive(Debug, Clone, Default)]
+    struct SteerTrackingClock {
+        has_steered: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl SteerTrackingClock {
+        fn has_steered(&self) -> bool {
+            self.has_steered.load(std::sync::atomic::Ordering::Relaxed)
+        }
+
+        fn steer(&self) {
+            self.has_steered
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    impl NtpClock for SteerTrackingClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
+            Ok(NtpTimestamp::default())
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<NtpTimestamp, Self::Error> {
+            self.steer();
+            Ok(NtpTimestamp::default())
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+            self.steer();
+            Ok(NtpTimestamp::default())
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    // The RFC algorithm compares measurements against the actual current instant,
+    // which does not combine with the simulated measurements used here.
+    #[cfg(not(feature = "rfc-algorithm"))]
+    #[tokio::test]
+    async fn test_disabled_peer() {
+        let (_, keyset) = tokio::sync::watch::channel(KeySetProvider::new(1).get());
+
+        let clock = SteerTrackingClock::default();
+        let mut config = CombinedSystemConfig::default();
+        config.system.min_intersection_survivors = 1;
+        let (mut system, channels) = System::new(
+            clock.clone(),
+            InterfaceName::DEFAULT,
+            EnableTimestamps::default(),
+            config,
+            keyset,
+            Default::default(),
+        );
+        let wait =
+            SingleshotSleep::new_disabled(tokio::time::sleep(std::time::Duration::from_secs(0)));
+        tokio::pin!(wait);
+
+        let id = system.add_spawner(DummySpawner::empty());
+        let index = system
+            .create_peer(
+                id,
+                PeerCreateParameters::from_new_ip_and_port("127.0.0.1", 123),
+            )
+            .await;
+
+        // ignore startup steer of frequency.
+        clock
+            .has_steered
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+
+        channels
+            .disabled_peers_sender
+            .send_modify(|disabled| disabled.push("127.0.0.1".into()));
+        system.handle_disabled_peers_update();
+
+        let base = NtpInstant::now();
+        let measure = |i: u32| {
+            MsgForSystem::NewMeasurement(
+                index,
+                peer_snapshot(),
+                Measurement {
+                    delay: NtpDuration::from_seconds(0.001 + 1e-9 * i as f64),
+                    offset: NtpDuration::from_seconds(1700.0 + 1e-9 * i as f64),
+                    localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(i, 0),
+                    monotime: base + std::time::Duration::from_secs(i as u64),
+                    remote_precision: NtpDuration::ZERO,
+                    root_delay: NtpDuration::ZERO,
+                    root_dispersion: NtpDuration::ZERO,
+                },
+                NtpPacket::test(),
+            )
+        };
+
+        for i in 1..=50 {
+            system
+                .handle_peer_update(measure(i), &mut wait)
+                .await
+                .unwrap();
+        }
+        assert!(!clock.has_steered());
+
+        channels
+            .disabled_peers_sender
+            .send_modify(|disabled| disabled.clear());
+        system.handle_disabled_peers_update();
+
+        for i in 51..=100 {
+            system
+                .handle_peer_update(measure(i), &mut wait)
+                .await
+                .unwrap();
+        }
+        assert!(clock.has_steered());
+    }
+
+    #[test]
+    fn test_peer_selector() {
+        let mut state = PeerState {
+            snapshot: None,
+            peer_address: NormalizedAddress::new_unchecked("example.com", 123),
+            spawner_id: SpawnerId::new(),
+            peer_id: PeerId::new(),
+        };
+
+        assert!(state.matches("example.com"));
+        assert!(state.matches("example.com:123"));
+        assert!(!state.matches("example.com:124"));
+        assert!(!state.matches("example.org"));
+        assert!(!state.matches("0.0.0.0"));
+
+        // the reference id of a peer is only known once it has sent a snapshot
+        state.snapshot = Some(peer_snapshot());
+        assert!(state.matches("0.0.0.0"));
+        assert!(!state.matches("127.0.0.1"));
+    }
 }
@@ -33,11 +33,13 @@ pub struct Metrics {
     peer_reachability_status: Family<PeerLabels, Gauge>,
     peer_offset: Family<PeerLabels, Gauge<f64, AtomicU64>>,
     peer_uncertainty: Family<PeerLabels, Gauge<f64, AtomicU64>>,
+    peer_kiss_count: Family<PeerLabels, Gauge>,
     peer_delay: Family<PeerLabels, Gauge<f64, AtomicU64>>,
     server_received_packets: Family<ServerLabels, Counter>,
     server_accepted_packets: Family<ServerLabels, Counter>,
     server_denied_packets: Family<ServerLabels, Counter>,
     server_ignored_packets: Family<ServerLabels, Counter>,
+    server_reserved_mode_packets: Family<ServerLabels, Counter>,
     server_rate_limited_packets: Family<ServerLabels, Counter>,
     server_response_send_errors: Family<ServerLabels, Counter>,
 }
@@ -74,6 +76,7 @@ impl Metrics {
                 reachability,
                 poll_interval,
                 address,
+                kiss_count,
                 ..
             } = peer
             {
@@ -103,6 +106,9 @@ impl Metrics {
                 self.peer_uncertainty
                     .get_or_create(&labels)
                     .set(timedata.uncertainty.to_seconds());
+                self.peer_kiss_count
+                    .get_or_create(&labels)
+                    .set(*kiss_count as i64);
             }
         }
 
@@ -127,6 +133,10 @@ impl Metrics {
                 .get_or_create(&labels)
                 .inner()
                 .store(server.stats.ignored_packets.get(), Ordering::Relaxed);
+            self.server_reserved_mode_packets
+                .get_or_create(&labels)
+                .inner()
+                .store(server.stats.reserved_mode_packets.get(), Ordering::Relaxed);
             self.server_rate_limited_packets
                 .get_or_create(&labels)
                 .inner()
@@ -227,6 +237,12 @@ impl Metrics {
             self.peer_uncertainty.clone(),
         );
 
+        peer.register(
+            "kiss_count",
+            "Number of Kiss-o'-Death packets received from the upstream server",
+            self.peer_kiss_count.clone(),
+        );
+
         let server = registry.sub_registry_with_prefix("server");
 
         server.register(
@@ -253,6 +269,12 @@ impl Metrics {
             self.server_ignored_packets.clone(),
         );
 
+        server.register(
+            "reserved_mode_packets",
+            "Number of packets dropped for using the reserved mode",
+            self.server_reserved_mode_packets.clone(),
+        );
+
         server.register(
             "rate_limited_packets",
             "Number of rate limited packets",
@@ -24,8 +24,3 @@ pub enum Error {
     #[error("Invalid clock path")]
     InvalidClockPath,
 }
-
-// Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
-// This leads to an offset equivalent to 70 years in seconds
-// there are 17 leap years between the two dates so the offset is
-pub(crate) const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
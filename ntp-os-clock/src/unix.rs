@@ -10,7 +10,7 @@ use std::{
     path::Path,
 };
 
-use crate::{Error, EPOCH_OFFSET};
+use crate::Error;
 use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
 
 // Libc has no good other way of obtaining this, so let's at least make our functions
@@ -320,11 +320,12 @@ fn micros_to_nanos(micros: u32) -> u32 {
     }
 }
 
+// time_t is 32 bits on some platforms
 #[cfg_attr(target_os = "linux", allow(unused))]
+#[allow(clippy::unnecessary_cast)]
 fn current_time_timespec(timespec: libc::timespec, precision: Precision) -> NtpTimestamp {
-    // Negative eras are completely valid, so any wrapping is perfectly reasonable here.
-    NtpTimestamp::from_seconds_nanos_since_ntp_era(
-        (timespec.tv_sec as u32).wrapping_add(EPOCH_OFFSET),
+    NtpTimestamp::from_unix_time(
+        timespec.tv_sec as i64,
         match precision {
             Precision::Nano => timespec.tv_nsec as u32,
             Precision::Micro => micros_to_nanos(timespec.tv_nsec as u32),
@@ -332,10 +333,10 @@ fn current_time_timespec(timespec: libc::timespec, precision: Precision) -> NtpT
     )
 }
 
+#[allow(clippy::unnecessary_cast)]
 fn current_time_timeval(timespec: libc::timeval, precision: Precision) -> NtpTimestamp {
-    // Negative eras are completely valid, so any wrapping is perfectly reasonable here.
-    NtpTimestamp::from_seconds_nanos_since_ntp_era(
-        (timespec.tv_sec as u32).wrapping_add(EPOCH_OFFSET),
+    NtpTimestamp::from_unix_time(
+        timespec.tv_sec as i64,
         match precision {
             Precision::Nano => timespec.tv_usec as u32,
             Precision::Micro => micros_to_nanos(timespec.tv_usec as u32),
@@ -434,6 +435,14 @@ impl NtpClock for UnixNtpClock {
         ignore_not_supported(self.adjtime(&mut timex))
     }
 
+    fn error_bound(&self) -> Result<NtpDuration, Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        self.adjtime(&mut timex)?;
+
+        // maxerror is in microseconds
+        Ok(NtpDuration::from_seconds(timex.maxerror as f64 * 1e-6))
+    }
+
     fn status_update(&self, leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
         let mut timex = EMPTY_TIMEX;
         self.adjtime(&mut timex)?;
@@ -14,11 +14,15 @@ pub(super) struct Combine<Index: Copy> {
     pub leap_indicator: Option<NtpLeapIndicator>,
 }
 
+// Peers that smear leap seconds never announce them, so they don't get a vote.
+// When all selected peers smear, we follow their smeared timescale.
 fn vote_leap<Index: Copy>(selection: &[PeerSnapshot<Index>]) -> Option<NtpLeapIndicator> {
     let mut votes_59 = 0;
     let mut votes_61 = 0;
     let mut votes_none = 0;
-    for snapshot in selection {
+    let mut voters = 0;
+    for snapshot in selection.iter().filter(|snapshot| !snapshot.leap_smearing) {
+        voters += 1;
         match snapshot.leap_indicator {
             NtpLeapIndicator::NoWarning => votes_none += 1,
             NtpLeapIndicator::Leap61 => votes_61 += 1,
@@ -28,39 +32,61 @@ fn vote_leap<Index: Copy>(selection: &[PeerSnapshot<Index>]) -> Option<NtpLeapIn
             }
         }
     }
-    if votes_none * 2 > selection.len() {
+    if voters == 0 || votes_none * 2 > voters {
         Some(NtpLeapIndicator::NoWarning)
-    } else if votes_59 * 2 > selection.len() {
+    } else if votes_59 * 2 > voters {
         Some(NtpLeapIndicator::Leap59)
-    } else if votes_61 * 2 > selection.len() {
+    } else if votes_61 * 2 > voters {
         Some(NtpLeapIndicator::Leap61)
     } else {
         None
     }
 }
 
+fn peer_uncertainty<Index: Copy>(
+    snapshot: &PeerSnapshot<Index>,
+    algo_config: &AlgorithmConfig,
+) -> Matrix {
+    // Like in the measurement noise, a quarter of the delay variance ends up in the offset
+    let congestion = algo_config.congestion_weight * snapshot.congestion / 4.;
+    let uncertainty = snapshot.uncertainty + Matrix::new(congestion, 0., 0., 0.);
+    if algo_config.ignore_server_dispersion {
+        uncertainty
+    } else {
+        uncertainty + Matrix::new(sqr(snapshot.peer_uncertainty.to_seconds()), 0., 0., 0.)
+    }
+}
+
 pub(super) fn combine<Index: Copy>(
     selection: &[PeerSnapshot<Index>],
     algo_config: &AlgorithmConfig,
 ) -> Option<Combine<Index>> {
+    let leap_indicator = vote_leap(selection);
+
+    // Around an announced leap second, smearing peers drift away from the
+    // peers that step by up to a second, so only the latter are combined.
+    let stepping: Vec<_>;
+    let selection = match leap_indicator {
+        Some(NtpLeapIndicator::Leap59 | NtpLeapIndicator::Leap61) => {
+            stepping = selection
+                .iter()
+                .filter(|snapshot| !snapshot.leap_smearing)
+                .cloned()
+                .collect();
+            &stepping
+        }
+        _ => selection,
+    };
+
     selection.first().map(|first| {
         let mut estimate = first.state;
-        let mut uncertainty = if algo_config.ignore_server_dispersion {
-            first.uncertainty
-        } else {
-            first.uncertainty + Matrix::new(sqr(first.peer_uncertainty.to_seconds()), 0., 0., 0.)
-        };
+        let mut uncertainty = peer_uncertainty(first, algo_config);
 
         let mut used_peers = vec![(first.index, uncertainty.determinant())];
 
         for snapshot in selection.iter().skip(1) {
             let peer_estimate = snapshot.state;
-            let peer_uncertainty = if algo_config.ignore_server_dispersion {
-                snapshot.uncertainty
-            } else {
-                snapshot.uncertainty
-                    + Matrix::new(sqr(snapshot.peer_uncertainty.to_seconds()), 0., 0., 0.)
-            };
+            let peer_uncertainty = peer_uncertainty(snapshot, algo_config);
 
             used_peers.push((snapshot.index, peer_uncertainty.determinant()));
 
@@ -81,7 +107,7 @@ pub(super) fn combine<Index: Copy>(
                 .map(|v| NtpDuration::from_seconds(v.delay) + v.peer_delay)
                 .min()
                 .unwrap_or(NtpDuration::from_seconds(first.delay) + first.peer_delay),
-            leap_indicator: vote_leap(selection),
+            leap_indicator,
         }
     })
 }
@@ -102,10 +128,14 @@ mod tests {
             state,
             uncertainty,
             delay: 0.0,
+            delay_jitter: 0.0,
+            congestion: 0.0,
             peer_uncertainty: NtpDuration::from_seconds(peer_uncertainty),
             peer_delay: NtpDuration::from_seconds(0.01),
             leap_indicator: crate::NtpLeapIndicator::NoWarning,
             last_update: NtpTimestamp::from_fixed_int(0),
+            prefer: false,
+            leap_smearing: false,
         }
     }
 
@@ -225,10 +255,14 @@ mod tests {
             state: Vector::new(0.0, 0.0),
             uncertainty: Matrix::new(1e-6, 0.0, 0.0, 1e-12),
             delay: 0.0,
+            delay_jitter: 0.0,
+            congestion: 0.0,
             peer_uncertainty: NtpDuration::from_seconds(0.0),
             peer_delay: NtpDuration::from_seconds(0.0),
             leap_indicator: leap,
             last_update: NtpTimestamp::from_fixed_int(0),
+            prefer: false,
+            leap_smearing: false,
         }
     }
 
@@ -283,4 +317,33 @@ mod tests {
         let result = combine(&selected, &algconfig).unwrap();
         assert_eq!(result.leap_indicator, None);
     }
+
+    #[test]
+    fn test_leap_vote_ignores_smearing_peers() {
+        let algconfig = AlgorithmConfig::default();
+        let smearing = PeerSnapshot {
+            index: 1,
+            state: Vector::new(0.5, 0.0),
+            leap_smearing: true,
+            ..snapshot_for_leap(NtpLeapIndicator::NoWarning)
+        };
+
+        // a smearing peer can't outvote the announcement of a leap second,
+        // and is left out of the combination around it
+        let selected = vec![
+            smearing.clone(),
+            smearing.clone(),
+            snapshot_for_leap(NtpLeapIndicator::Leap61),
+        ];
+        let result = combine(&selected, &algconfig).unwrap();
+        assert_eq!(result.leap_indicator, Some(NtpLeapIndicator::Leap61));
+        assert_eq!(result.peers, vec![0]);
+        assert_eq!(result.estimate.entry(0), 0.0);
+
+        // when all peers smear, their timescale is used
+        let selected = vec![smearing.clone(), smearing];
+        let result = combine(&selected, &algconfig).unwrap();
+        assert_eq!(result.leap_indicator, Some(NtpLeapIndicator::NoWarning));
+        assert_eq!(result.peers.len(), 2);
+    }
 }
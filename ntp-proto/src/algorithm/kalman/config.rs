@@ -65,6 +65,12 @@ pub struct AlgorithmConfig {
     /// ranges. (weight, 0-1)
     #[serde(default = "default_range_delay_weight")]
     pub range_delay_weight: f64,
+    /// Weight of a rise in the delay variance of a peer, relative
+    /// to its long-term average, in the uncertainty of that peer
+    /// when combining. Temporarily down-weights peers with a
+    /// congested network path. (weight, 0+)
+    #[serde(default = "default_congestion_weight")]
+    pub congestion_weight: f64,
 
     /// How far from 0 (in multiples of the uncertainty) should
     /// the offset be before we correct. (standard deviations, 0+)
@@ -86,6 +92,10 @@ pub struct AlgorithmConfig {
     /// trying to adjust gradually? (seconds, 0+)
     #[serde(default = "default_jump_threshold")]
     pub jump_threshold: f64,
+    /// Minimum time between two jumps of the clock. Offsets
+    /// beyond the jump threshold are slewed during this time. (seconds, 0+)
+    #[serde(default)]
+    pub step_cooldown: f64,
     /// What is the maximum frequency offset during a slew (s/s)
     #[serde(default = "default_slew_max_frequency_offset")]
     pub slew_max_frequency_offset: f64,
@@ -96,6 +106,11 @@ pub struct AlgorithmConfig {
     /// Absolute maximum frequency correction (s/s)
     #[serde(default = "default_max_frequency_steer")]
     pub max_frequency_steer: f64,
+    /// Maximum change of the frequency correction in a single
+    /// update. Larger corrections are spread out over multiple
+    /// updates. (s/s, 0+)
+    #[serde(default)]
+    pub max_frequency_change: Option<f64>,
 
     /// Ignore a servers advertised dispersion when synchronizing.
     /// Can improve synchronization quality with servers reporting
@@ -125,16 +140,19 @@ impl Default for AlgorithmConfig {
             max_peer_uncertainty: default_max_peer_uncertainty(),
             range_statistical_weight: default_range_statistical_weight(),
             range_delay_weight: default_range_delay_weight(),
+            congestion_weight: default_congestion_weight(),
 
             steer_offset_threshold: default_steer_offset_threshold(),
             steer_offset_leftover: default_steer_offset_leftover(),
             steer_frequency_threshold: default_steer_frequency_threshold(),
             steer_frequency_leftover: default_steer_frequency_leftover(),
             jump_threshold: default_jump_threshold(),
+            step_cooldown: 0.0,
             slew_max_frequency_offset: default_slew_max_frequency_offset(),
             slew_min_duration: default_slew_min_duration(),
 
             max_frequency_steer: default_max_frequency_steer(),
+            max_frequency_change: None,
 
             ignore_server_dispersion: false,
         }
@@ -197,6 +215,10 @@ fn default_range_delay_weight() -> f64 {
     0.25
 }
 
+fn default_congestion_weight() -> f64 {
+    1.0
+}
+
 fn default_steer_offset_threshold() -> f64 {
     2.0
 }
@@ -1,11 +1,13 @@
 use std::ops::{Add, Mul, Sub};
 
-#[derive(Clone, Copy, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Matrix {
     data: [f64; 4],
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Vector {
     data: [f64; 2],
 }
@@ -1,10 +1,16 @@
-use std::{collections::HashMap, fmt::Debug, hash::Hash};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::Hash,
+};
 
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, instrument};
 
 use crate::{
-    Measurement, NtpClock, NtpDuration, NtpLeapIndicator, NtpPacket, NtpTimestamp,
-    ObservablePeerTimedata, StateUpdate, SystemConfig, TimeSnapshot, TimeSyncController,
+    Measurement, NoMajorityPolicy, NtpClock, NtpDuration, NtpLeapIndicator, NtpPacket,
+    NtpTimescale, NtpTimestamp, ObservablePeerTimedata, StateUpdate, SystemConfig, TimeSnapshot,
+    TimeSyncController,
 };
 
 use self::{
@@ -12,6 +18,7 @@ use self::{
     config::AlgorithmConfig,
     matrix::{Matrix, Vector},
     peer::PeerState,
+    select::SelectionResult,
 };
 
 mod combiner;
@@ -30,12 +37,18 @@ struct PeerSnapshot<Index: Copy> {
     state: Vector,
     uncertainty: Matrix,
     delay: f64,
+    delay_jitter: f64,
+    // Rise of the delay variance above its long-term average (s^2)
+    congestion: f64,
 
     peer_uncertainty: NtpDuration,
     peer_delay: NtpDuration,
     leap_indicator: NtpLeapIndicator,
 
     last_update: NtpTimestamp,
+
+    prefer: bool,
+    leap_smearing: bool,
 }
 
 impl<Index: Copy> PeerSnapshot<Index> {
@@ -52,6 +65,7 @@ impl<Index: Copy> PeerSnapshot<Index> {
             offset: NtpDuration::from_seconds(self.offset()),
             uncertainty: NtpDuration::from_seconds(self.offset_uncertainty()),
             delay: NtpDuration::from_seconds(self.delay),
+            delay_jitter: NtpDuration::from_seconds(self.delay_jitter),
             remote_delay: self.peer_delay,
             remote_uncertainty: self.peer_uncertainty,
             last_update: self.last_update,
@@ -59,9 +73,24 @@ impl<Index: Copy> PeerSnapshot<Index> {
     }
 }
 
+/// State of the clock controller that is kept across a reload of the configuration:
+/// the filters of the peers, and the frequency correction of the clock.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KalmanState<PeerID> {
+    peers: Vec<(PeerID, PeerState)>,
+    freq_offset: f64,
+    desired_freq: f64,
+    timedata: TimeSnapshot,
+    in_startup: bool,
+    #[serde(default)]
+    cold_started: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct KalmanClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     peers: HashMap<PeerID, (PeerState, bool)>,
+    preferred: HashSet<PeerID>,
+    leap_smearing: HashSet<PeerID>,
     clock: C,
     config: SystemConfig,
     algo_config: AlgorithmConfig,
@@ -69,9 +98,47 @@ pub struct KalmanClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug>
     timedata: TimeSnapshot,
     desired_freq: f64,
     in_startup: bool,
+    cold_started: bool,
+    no_majority: bool,
+    last_step: Option<NtpTimestamp>,
 }
 
 impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> KalmanClockController<C, PeerID> {
+    pub fn export_state(&self) -> KalmanState<PeerID> {
+        KalmanState {
+            peers: self
+                .peers
+                .iter()
+                .map(|(id, (state, _))| (*id, state.clone()))
+                .collect(),
+            freq_offset: self.freq_offset,
+            desired_freq: self.desired_freq,
+            timedata: self.timedata,
+            in_startup: self.in_startup,
+            cold_started: self.cold_started,
+        }
+    }
+
+    /// Restore state exported from a controller before a reload. Only the filters
+    /// of peers currently known to the controller are restored. The frequency
+    /// correction is applied to the clock again, as creating the controller reset it.
+    pub fn import_state(&mut self, state: KalmanState<PeerID>) -> Result<(), C::Error> {
+        self.clock.set_frequency(state.freq_offset)?;
+        self.freq_offset = state.freq_offset;
+        self.desired_freq = state.desired_freq;
+        self.timedata = state.timedata;
+        self.in_startup = state.in_startup;
+        self.cold_started = state.cold_started;
+
+        for (id, peer_state) in state.peers {
+            if let Some((current, _)) = self.peers.get_mut(&id) {
+                *current = peer_state;
+            }
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn update_peer(
         &mut self,
@@ -107,20 +174,37 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> KalmanClockController<C, Pee
             state.progress_filtertime(time);
         }
 
-        let selection = select::select(
+        let selection = match select::select(
             &self.config,
             &self.algo_config,
             self.peers
                 .iter()
                 .filter_map(|(index, (state, usable))| {
                     if *usable {
-                        state.snapshot(*index)
+                        state.snapshot(*index).map(|snapshot| PeerSnapshot {
+                            prefer: self.preferred.contains(index),
+                            leap_smearing: self.leap_smearing.contains(index),
+                            ..snapshot
+                        })
                     } else {
                         None
                     }
                 })
                 .collect(),
-        );
+        ) {
+            SelectionResult::Selected(selection) => {
+                if self.no_majority {
+                    info!("A majority of the peers agrees on the time again");
+                    self.no_majority = false;
+                }
+                selection
+            }
+            SelectionResult::TooFewSurvivors => vec![],
+            SelectionResult::NoMajority => {
+                self.handle_no_majority();
+                vec![]
+            }
+        };
 
         if let Some(combined) = combine(&selection, &self.algo_config) {
             info!(
@@ -224,13 +308,59 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> KalmanClockController<C, Pee
         }
     }
 
+    fn handle_no_majority(&mut self) {
+        match self.config.no_majority {
+            NoMajorityPolicy::Hold => {
+                if !self.no_majority {
+                    error!("No majority of the peers agrees on the time, holding the clock until one does.");
+                }
+            }
+            NoMajorityPolicy::Exit => {
+                error!("No majority of the peers agrees on the time, please manually verify the state of the peers and restart if appropriate.");
+                #[cfg(not(test))]
+                std::process::exit(exitcode::SOFTWARE);
+                #[cfg(test)]
+                panic!("No majority");
+            }
+        }
+        self.no_majority = true;
+    }
+
+    /// Step the clock by the offset of a single measurement, without checking
+    /// the panic threshold. This happens at most once, on the first measurement
+    /// of a usable peer when cold start is enabled.
+    fn cold_start_step(&mut self, offset: NtpDuration) {
+        let step_time = self.clock.step_clock(offset).expect("Cannot adjust clock");
+        self.last_step = Some(step_time);
+        self.cold_started = true;
+        for (state, _) in self.peers.values_mut() {
+            state.process_offset_steering(offset.to_seconds())
+        }
+        info!(
+            "Cold start: jumped offset by {}ms",
+            offset.to_seconds() * 1e3
+        );
+    }
+
+    fn in_step_cooldown(&self) -> bool {
+        match self.last_step {
+            Some(last_step) => {
+                let now = self.clock.now().expect("Cannot read clock");
+                now - last_step < NtpDuration::from_seconds(self.algo_config.step_cooldown)
+            }
+            None => false,
+        }
+    }
+
     fn steer_offset(&mut self, change: f64, freq_delta: f64) -> Option<NtpTimestamp> {
         self.check_offset_steer(change);
-        if change.abs() > self.algo_config.jump_threshold {
+        if change.abs() > self.algo_config.jump_threshold && !self.in_step_cooldown() {
             // jump
-            self.clock
+            let step_time = self
+                .clock
                 .step_clock(NtpDuration::from_seconds(change))
                 .expect("Cannot adjust clock");
+            self.last_step = Some(step_time);
             for (state, _) in self.peers.values_mut() {
                 state.process_offset_steering(change)
             }
@@ -259,6 +389,12 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> KalmanClockController<C, Pee
     }
 
     fn steer_frequency(&mut self, change: f64) -> NtpTimestamp {
+        // Any part of the change we don't make now remains in the frequency
+        // estimates of the peers, and is corrected by later updates.
+        let change = match self.algo_config.max_frequency_change {
+            Some(max_change) => change.clamp(-max_change, max_change),
+            None => change,
+        };
         let new_freq_offset = ((1.0 + self.freq_offset) * (1.0 + change) - 1.0).clamp(
             -self.algo_config.max_frequency_steer,
             self.algo_config.max_frequency_steer,
@@ -309,6 +445,8 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
 
         KalmanClockController {
             peers: HashMap::new(),
+            preferred: HashSet::new(),
+            leap_smearing: HashSet::new(),
             clock,
             config,
             algo_config,
@@ -316,6 +454,9 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
             desired_freq: 0.0,
             timedata: TimeSnapshot::default(),
             in_startup: true,
+            cold_started: false,
+            no_majority: false,
+            last_step: None,
         }
     }
 
@@ -330,6 +471,8 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
 
     fn peer_remove(&mut self, id: PeerID) {
         self.peers.remove(&id);
+        self.preferred.remove(&id);
+        self.leap_smearing.remove(&id);
     }
 
     fn peer_update(&mut self, id: PeerID, usable: bool) {
@@ -338,12 +481,48 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
         }
     }
 
+    fn peer_prefer(&mut self, id: PeerID, prefer: bool) {
+        if prefer {
+            self.preferred.insert(id);
+        } else {
+            self.preferred.remove(&id);
+        }
+    }
+
+    fn peer_leap_smear(&mut self, id: PeerID, smearing: bool) {
+        if smearing {
+            self.leap_smearing.insert(id);
+        } else {
+            self.leap_smearing.remove(&id);
+        }
+    }
+
     fn peer_measurement(
         &mut self,
         id: PeerID,
         measurement: Measurement,
         packet: NtpPacket<'static>,
     ) -> StateUpdate<PeerID> {
+        // NTPv5 servers tell us when they smear leap seconds
+        if packet.timescale() == Some(NtpTimescale::LeapSmearedUtc) {
+            self.leap_smearing.insert(id);
+        }
+
+        if self.config.cold_start
+            && self.in_startup
+            && !self.cold_started
+            && self.peers.get(&id).is_some_and(|(_, usable)| *usable)
+        {
+            // The measurement is relative to the clock before the step, so it
+            // is not useful to the filter afterwards.
+            self.cold_start_step(measurement.offset);
+            return StateUpdate {
+                used_peers: None,
+                time_snapshot: Some(self.timedata),
+                next_update: None,
+            };
+        }
+
         let should_update_clock = self.update_peer(id, measurement, packet);
         self.update_desired_poll();
         if should_update_clock {
@@ -369,6 +548,30 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
             .and_then(|v| v.0.snapshot(id))
             .map(|v| v.observe())
     }
+
+    fn estimate_time_to_sync(&self) -> Option<NtpDuration> {
+        if !self.in_startup {
+            return Some(NtpDuration::ZERO);
+        }
+
+        // Peers still initializing are polled at the minimum poll interval,
+        // and we need enough of them to satisfy the selection.
+        let mut remaining: Vec<u32> = self
+            .peers
+            .values()
+            .filter(|(_, usable)| *usable)
+            .map(|(state, _)| state.remaining_initial_samples())
+            .collect();
+        remaining.sort_unstable();
+
+        let needed = self.config.min_intersection_survivors.max(1);
+        match remaining.get(needed - 1) {
+            // Enough peers are initialized but there is no consensus yet,
+            // we can't tell when there will be
+            Some(0) | None => None,
+            Some(samples) => Some(self.config.poll_limits.min.as_duration() * *samples),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -431,6 +634,195 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_export_import_state() {
+        let system_config = SystemConfig {
+            min_intersection_survivors: 1,
+            ..SystemConfig::default()
+        };
+        let algo_config = AlgorithmConfig::default();
+        let mut algo = KalmanClockController::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: NtpTimestamp::from_fixed_int(0),
+            },
+            system_config,
+            algo_config,
+        );
+        let mut cur_instant = NtpInstant::now();
+
+        algo.peer_add(0);
+        algo.peer_update(0, true);
+
+        let mut noise = 1e-9;
+        for _ in 0..16 {
+            cur_instant = cur_instant + std::time::Duration::from_secs(1);
+            algo.clock.current_time += NtpDuration::from_seconds(1.0);
+            noise += 1e-9;
+            algo.peer_measurement(
+                0,
+                Measurement {
+                    delay: NtpDuration::from_seconds(0.001 + noise),
+                    offset: NtpDuration::from_seconds(0.002 + noise),
+                    localtime: algo.clock.current_time,
+                    monotime: cur_instant,
+                    remote_precision: NtpDuration::ZERO,
+                    root_delay: NtpDuration::ZERO,
+                    root_dispersion: NtpDuration::ZERO,
+                },
+                NtpPacket::test(),
+            );
+        }
+        let before = algo.peer_snapshot(0).unwrap();
+
+        let exported = serde_json::to_string(&algo.export_state()).unwrap();
+
+        let mut restored = KalmanClockController::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: algo.clock.current_time,
+            },
+            system_config,
+            algo_config,
+        );
+        restored.peer_add(0);
+        restored.peer_update(0, true);
+        // ignore the reset of the frequency on creation
+        *restored.clock.has_steered.borrow_mut() = false;
+        restored
+            .import_state(serde_json::from_str(&exported).unwrap())
+            .unwrap();
+
+        let after = restored.peer_snapshot(0).unwrap();
+        assert!((after.offset - before.offset).abs() < NtpDuration::from_seconds(1e-9));
+        assert!((after.uncertainty - before.uncertainty).abs() < NtpDuration::from_seconds(1e-9));
+        assert_eq!(restored.in_startup, algo.in_startup);
+        assert_eq!(restored.freq_offset, algo.freq_offset);
+        assert!(*restored.clock.has_steered.borrow());
+    }
+
+    #[test]
+    fn test_step_cooldown() {
+        let algo_config = AlgorithmConfig {
+            step_cooldown: 100.0,
+            ..AlgorithmConfig::default()
+        };
+        let mut algo = KalmanClockController::<_, u32>::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: NtpTimestamp::from_fixed_int(0),
+            },
+            SystemConfig::default(),
+            algo_config,
+        );
+
+        // the first large offset is stepped
+        *algo.clock.has_steered.borrow_mut() = false;
+        assert_eq!(algo.steer_offset(1.0, 0.0), None);
+        assert!(*algo.clock.has_steered.borrow());
+
+        // but a second one shortly after is slewed instead
+        algo.clock.current_time += NtpDuration::from_seconds(10.0);
+        assert!(algo.steer_offset(-1.0, 0.0).is_some());
+        assert_ne!(algo.desired_freq, 0.0);
+
+        // once the cooldown is over we step again
+        algo.change_desired_frequency(0.0, 0.0);
+        algo.clock.current_time += NtpDuration::from_seconds(100.0);
+        assert_eq!(algo.steer_offset(1.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_frequency_change_limited() {
+        let system_config = SystemConfig::default();
+        let algo_config = AlgorithmConfig {
+            max_frequency_change: Some(10e-6),
+            ..AlgorithmConfig::default()
+        };
+        let mut algo = KalmanClockController::<_, u32>::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: NtpTimestamp::from_fixed_int(0),
+            },
+            system_config,
+            algo_config,
+        );
+
+        // a correction of 45ppm is needed, but each update may change at most 10ppm
+        let mut required: f64 = 45e-6;
+        let mut updates = 0;
+        while required.abs() > 1e-12 {
+            let before = algo.freq_offset;
+            algo.steer_frequency(required);
+            let applied = (1.0 + algo.freq_offset) / (1.0 + before) - 1.0;
+            assert!(applied.abs() <= 10e-6 + 1e-12);
+            required = (1.0 + required) / (1.0 + applied) - 1.0;
+            updates += 1;
+        }
+
+        assert_eq!(updates, 5);
+        assert!((algo.freq_offset - 45e-6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_estimate_time_to_sync() {
+        let algo_config = AlgorithmConfig::default();
+        let mut algo = KalmanClockController::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: NtpTimestamp::from_fixed_int(0),
+            },
+            SystemConfig::default(),
+            algo_config,
+        );
+        let mut cur_instant = NtpInstant::now();
+        let min_poll = algo.config.poll_limits.min.as_duration();
+
+        // not enough usable peers to ever synchronize
+        assert_eq!(algo.estimate_time_to_sync(), None);
+        for id in 0..3 {
+            algo.peer_add(id);
+            algo.peer_update(id, true);
+        }
+        algo.peer_add(3);
+
+        // a freshly started client needs a full set of samples from each peer
+        assert_eq!(algo.estimate_time_to_sync(), Some(min_poll * 8));
+
+        for _ in 0..3 {
+            cur_instant = cur_instant + std::time::Duration::from_secs(1);
+            algo.clock.current_time += NtpDuration::from_seconds(1.0);
+            for id in 0..2 {
+                algo.peer_measurement(
+                    id,
+                    Measurement {
+                        delay: NtpDuration::from_seconds(0.001),
+                        offset: NtpDuration::from_seconds(0.002),
+                        localtime: algo.clock.current_time,
+                        monotime: cur_instant,
+                        remote_precision: NtpDuration::ZERO,
+                        root_delay: NtpDuration::ZERO,
+                        root_dispersion: NtpDuration::ZERO,
+                    },
+                    NtpPacket::test(),
+                );
+            }
+        }
+
+        // the slowest of the peers needed determines the estimate
+        assert_eq!(algo.estimate_time_to_sync(), Some(min_poll * 8));
+        algo.peer_update(2, false);
+        assert_eq!(algo.estimate_time_to_sync(), None);
+        algo.peer_update(3, true);
+        assert_eq!(algo.estimate_time_to_sync(), Some(min_poll * 8));
+        algo.peer_remove(3);
+        algo.config.min_intersection_survivors = 2;
+        assert_eq!(algo.estimate_time_to_sync(), Some(min_poll * 5));
+
+        algo.in_startup = false;
+        assert_eq!(algo.estimate_time_to_sync(), Some(NtpDuration::ZERO));
+    }
+
     #[test]
     fn test_startup_flag_unsets() {
         let system_config = SystemConfig {
@@ -469,6 +861,9 @@ mod tests {
                     offset: NtpDuration::from_seconds(1700.0 + noise),
                     localtime: algo.clock.current_time,
                     monotime: cur_instant,
+                    remote_precision: NtpDuration::ZERO,
+                    root_delay: NtpDuration::ZERO,
+                    root_dispersion: NtpDuration::ZERO,
                 },
                 NtpPacket::test(),
             );
@@ -514,6 +909,9 @@ mod tests {
                     offset: NtpDuration::from_seconds(1700.0 + noise),
                     localtime: algo.clock.current_time,
                     monotime: cur_instant,
+                    remote_precision: NtpDuration::ZERO,
+                    root_delay: NtpDuration::ZERO,
+                    root_dispersion: NtpDuration::ZERO,
                 },
                 NtpPacket::test(),
             );
@@ -557,9 +955,184 @@ mod tests {
                     offset: NtpDuration::from_seconds(-3600.0 + noise),
                     localtime: algo.clock.current_time,
                     monotime: cur_instant,
+                    remote_precision: NtpDuration::ZERO,
+                    root_delay: NtpDuration::ZERO,
+                    root_dispersion: NtpDuration::ZERO,
                 },
                 NtpPacket::test(),
             );
         }
     }
+
+    fn disagreeing_peers(system_config: SystemConfig) -> KalmanClockController<TestClock, usize> {
+        let mut algo = KalmanClockController::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: NtpTimestamp::from_fixed_int(0),
+            },
+            system_config,
+            AlgorithmConfig::default(),
+        );
+
+        for id in 0..2 {
+            algo.peer_add(id);
+            algo.peer_update(id, true);
+        }
+
+        algo
+    }
+
+    fn measure_disagreeing_peers(algo: &mut KalmanClockController<TestClock, usize>) {
+        let mut cur_instant = NtpInstant::now();
+        let mut noise = 1e-9;
+
+        for _ in 0..20 {
+            for (id, offset) in [(0, -100.0), (1, 100.0)] {
+                cur_instant = cur_instant + std::time::Duration::from_secs(1);
+                algo.clock.current_time += NtpDuration::from_seconds(1.0);
+                noise *= -1.0;
+                algo.peer_measurement(
+                    id,
+                    Measurement {
+                        delay: NtpDuration::from_seconds(0.001 + noise),
+                        offset: NtpDuration::from_seconds(offset + noise),
+                        localtime: algo.clock.current_time,
+                        monotime: cur_instant,
+                        remote_precision: NtpDuration::ZERO,
+                        root_delay: NtpDuration::ZERO,
+                        root_dispersion: NtpDuration::ZERO,
+                    },
+                    NtpPacket::test(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_majority_holds_clock() {
+        let mut algo = disagreeing_peers(SystemConfig {
+            min_intersection_survivors: 1,
+            ..SystemConfig::default()
+        });
+
+        measure_disagreeing_peers(&mut algo);
+        assert!(algo.no_majority);
+
+        // once the peers disagree, the clock is left alone
+        *algo.clock.has_steered.borrow_mut() = false;
+        measure_disagreeing_peers(&mut algo);
+        assert!(algo.no_majority);
+        assert!(!*algo.clock.has_steered.borrow());
+    }
+
+    #[test]
+    #[should_panic(expected = "No majority")]
+    fn test_no_majority_exit_panics() {
+        let mut algo = disagreeing_peers(SystemConfig {
+            min_intersection_survivors: 1,
+            no_majority: NoMajorityPolicy::Exit,
+            ..SystemConfig::default()
+        });
+
+        measure_disagreeing_peers(&mut algo);
+    }
+
+    #[test]
+    fn test_cold_start_steps_from_epoch() {
+        let system_config = SystemConfig {
+            min_intersection_survivors: 1,
+            cold_start: true,
+            ..SystemConfig::default()
+        };
+        let algo_config = AlgorithmConfig::default();
+        let mut algo = KalmanClockController::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                // 1970-01-01T00:00:00Z
+                current_time: NtpTimestamp::from_fixed_int(2_208_988_800 << 32),
+            },
+            system_config,
+            algo_config,
+        );
+        let cur_instant = NtpInstant::now();
+
+        // ignore startup steer of frequency.
+        *algo.clock.has_steered.borrow_mut() = false;
+
+        algo.peer_add(0);
+        algo.peer_update(0, true);
+
+        // roughly 56 years ahead, way beyond any panic threshold
+        let measurement = Measurement {
+            delay: NtpDuration::from_seconds(0.001),
+            offset: NtpDuration::from_seconds(1_760_000_000.0),
+            localtime: algo.clock.current_time,
+            monotime: cur_instant,
+            remote_precision: NtpDuration::ZERO,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::ZERO,
+        };
+        algo.peer_measurement(0, measurement, NtpPacket::test());
+
+        assert!(*algo.clock.has_steered.borrow());
+        assert!(algo.cold_started);
+        assert!(algo.in_startup);
+
+        // the cold start only happens once
+        *algo.clock.has_steered.borrow_mut() = false;
+        algo.clock.current_time += NtpDuration::from_seconds(1_760_000_001.0);
+        algo.peer_measurement(
+            0,
+            Measurement {
+                offset: NtpDuration::from_seconds(0.001),
+                localtime: algo.clock.current_time,
+                monotime: cur_instant + std::time::Duration::from_secs(1),
+                ..measurement
+            },
+            NtpPacket::test(),
+        );
+        assert!(!*algo.clock.has_steered.borrow());
+        assert!(algo.export_state().cold_started);
+    }
+
+    #[test]
+    fn test_cold_start_ignores_startup_panic_threshold() {
+        let system_config = SystemConfig {
+            min_intersection_survivors: 1,
+            cold_start: true,
+            ..SystemConfig::default()
+        };
+        let algo_config = AlgorithmConfig::default();
+        let mut algo = KalmanClockController::new(
+            TestClock {
+                has_steered: RefCell::new(false),
+                current_time: NtpTimestamp::from_fixed_int(0),
+            },
+            system_config,
+            algo_config,
+        );
+
+        // ignore startup steer of frequency.
+        *algo.clock.has_steered.borrow_mut() = false;
+
+        algo.peer_add(0);
+        algo.peer_update(0, true);
+
+        // beyond the default backward startup panic threshold
+        algo.peer_measurement(
+            0,
+            Measurement {
+                delay: NtpDuration::from_seconds(0.001),
+                offset: NtpDuration::from_seconds(-3600.0),
+                localtime: algo.clock.current_time,
+                monotime: NtpInstant::now(),
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
+            },
+            NtpPacket::test(),
+        );
+
+        assert!(*algo.clock.has_steered.borrow());
+    }
 }
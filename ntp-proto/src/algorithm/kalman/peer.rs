@@ -59,6 +59,13 @@
 /// s/4 s/4
 /// s/4 s/2
 ///
+/// On top of this, the server's timestamps are only as good as the
+/// precision p it advertises. Treating this as an additional independent
+/// error on D with variance p^2 gives the measurement noise
+/// s/4+p^2 s/4+p^2
+/// s/4+p^2 s/2+2p^2
+/// so that sources with a coarse precision are trusted less.
+///
 /// This setup leaves two major issues:
 ///  - How often do we want measurements (what is the desired polling interval)
 ///  - What is v
@@ -72,6 +79,7 @@
 /// If they are often too small, v is quartered, and if they are often too
 /// large, v is quadrupled (note, this corresponds with doubling/halving
 /// the more intuitive standard deviation).
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info, trace};
 
 use crate::{
@@ -85,7 +93,7 @@ use super::{
     sqr, PeerSnapshot,
 };
 
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 struct AveragingBuffer {
     data: [f64; 8],
     next_idx: usize,
@@ -107,7 +115,13 @@ impl AveragingBuffer {
     }
 }
 
-#[derive(Debug, Clone)]
+// Number of measurements used to initialize the filter of a peer
+const INITIAL_SAMPLES: i32 = 8;
+
+// Number of measurements over which the long-term delay variance is averaged
+const DELAY_VARIANCE_BASELINE_SAMPLES: f64 = 64.;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct InitialPeerFilter {
     roundtriptime_stats: AveragingBuffer,
     init_offset: AveragingBuffer,
@@ -131,19 +145,23 @@ impl InitialPeerFilter {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct PeerFilter {
     state: Vector,
     uncertainty: Matrix,
     clock_wander: f64,
 
     roundtriptime_stats: AveragingBuffer,
+    // Long-term average of the delay variance, against which congestion is detected
+    #[serde(default)]
+    delay_variance_baseline: f64,
 
     precision_score: i32,
     poll_score: i32,
     desired_poll_interval: PollInterval,
 
     last_measurement: Measurement,
+    #[serde(with = "packet_header")]
     last_packet: NtpPacket<'static>,
     prev_was_outlier: bool,
 
@@ -153,6 +171,32 @@ struct PeerFilter {
     filter_time: NtpTimestamp,
 }
 
+// Only the header of the last packet is used by the filter, so that is all we keep.
+mod packet_header {
+    use std::io::Cursor;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::{packet::NoCipher, NtpPacket};
+
+    pub fn serialize<S: Serializer>(packet: &NtpPacket, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut buf = [0; 48];
+        packet
+            .header_only()
+            .serialize(&mut Cursor::new(buf.as_mut_slice()), &NoCipher)
+            .map_err(serde::ser::Error::custom)?;
+        buf.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<NtpPacket<'static>, D::Error> {
+        let data = Vec::<u8>::deserialize(deserializer)?;
+        let (packet, _) = NtpPacket::deserialize(&data, &NoCipher).map_err(D::Error::custom)?;
+        Ok(packet.into_owned())
+    }
+}
+
 impl PeerFilter {
     /// Move the filter forward to reflect the situation at a new, later timestamp
     fn progress_filtertime(&mut self, time: NtpTimestamp) {
@@ -195,11 +239,12 @@ impl PeerFilter {
             (measurement.offset - self.last_measurement.offset).to_seconds(),
         );
         let measurement_transform = Matrix::new(1., 0., 0., m_delta_t);
+        let precision_variance = sqr(measurement.remote_precision.to_seconds());
         let measurement_noise = Matrix::new(
-            delay_variance / 4.,
-            delay_variance / 4.,
-            delay_variance / 4.,
-            delay_variance / 2.,
+            delay_variance / 4. + precision_variance,
+            delay_variance / 4. + precision_variance,
+            delay_variance / 4. + precision_variance,
+            delay_variance / 2. + 2. * precision_variance,
         );
         let difference = measurement_vec - measurement_transform * self.state;
         let difference_covariance =
@@ -332,6 +377,9 @@ impl PeerFilter {
         self.progress_filtertime(measurement.localtime);
         self.roundtriptime_stats
             .update(measurement.delay.to_seconds());
+        self.delay_variance_baseline += (self.roundtriptime_stats.variance()
+            - self.delay_variance_baseline)
+            / DELAY_VARIANCE_BASELINE_SAMPLES;
 
         let (chi, weight, measurement_period) = self.absorb_measurement(measurement);
 
@@ -351,6 +399,12 @@ impl PeerFilter {
         true
     }
 
+    /// How much the recent delay variance exceeds its long-term average. A
+    /// sudden rise indicates congestion on the network path to the peer.
+    fn congestion(&self) -> f64 {
+        (self.roundtriptime_stats.variance() - self.delay_variance_baseline).max(0.)
+    }
+
     fn process_offset_steering(&mut self, steer: f64) {
         self.state = self.state - Vector::new(steer, 0.0);
         self.last_measurement.offset -= NtpDuration::from_seconds(steer);
@@ -367,14 +421,14 @@ impl PeerFilter {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(clippy::large_enum_variant)]
 enum PeerStateInner {
     Initial(InitialPeerFilter),
     Stable(PeerFilter),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(super) struct PeerState(PeerStateInner);
 
 impl PeerState {
@@ -397,7 +451,7 @@ impl PeerState {
         match &mut self.0 {
             PeerStateInner::Initial(filter) => {
                 filter.update(measurement);
-                if filter.samples == 8 {
+                if filter.samples == INITIAL_SAMPLES {
                     *self = PeerState(PeerStateInner::Stable(PeerFilter {
                         state: Vector::new(filter.init_offset.mean(), 0.),
                         uncertainty: Matrix::new(
@@ -408,6 +462,7 @@ impl PeerState {
                         ),
                         clock_wander: sqr(algo_config.initial_wander),
                         roundtriptime_stats: filter.roundtriptime_stats,
+                        delay_variance_baseline: filter.roundtriptime_stats.variance(),
                         precision_score: 0,
                         poll_score: 0,
                         desired_poll_interval: config.initial_poll,
@@ -437,14 +492,26 @@ impl PeerState {
                 state: filter.state,
                 uncertainty: filter.uncertainty,
                 delay: filter.roundtriptime_stats.mean(),
+                delay_jitter: filter.roundtriptime_stats.variance().sqrt(),
+                congestion: filter.congestion(),
                 peer_uncertainty: filter.last_packet.root_dispersion(),
                 peer_delay: filter.last_packet.root_delay(),
                 leap_indicator: filter.last_packet.leap(),
                 last_update: filter.last_iter,
+                prefer: false,
+                leap_smearing: false,
             }),
         }
     }
 
+    /// Number of measurements still needed before the peer can be used
+    pub fn remaining_initial_samples(&self) -> u32 {
+        match &self.0 {
+            PeerStateInner::Initial(filter) => (INITIAL_SAMPLES - filter.samples).max(0) as u32,
+            PeerStateInner::Stable(_) => 0,
+        }
+    }
+
     pub fn get_filtertime(&self) -> Option<NtpTimestamp> {
         match &self.0 {
             PeerStateInner::Initial(_) => None,
@@ -487,7 +554,7 @@ mod tests {
 
     use crate::{Measurement, NtpInstant, NtpPacket, PollIntervalLimits};
 
-    use super::*;
+    use super::{super::combiner::combine, *};
 
     #[test]
     fn test_offset_steering_and_measurements() {
@@ -501,6 +568,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -509,6 +577,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(20e-3),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -532,6 +603,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -540,6 +612,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(20e-3),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -558,6 +633,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(20e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -573,6 +651,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -581,6 +660,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(-20e-3),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -601,6 +683,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(-20e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -621,6 +706,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -629,6 +715,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(0.0),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -653,6 +742,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -661,6 +751,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(0.0),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -690,6 +783,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(0e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -702,6 +798,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(1e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -714,6 +813,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(2e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -726,6 +828,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(3e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -738,6 +843,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(4e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -750,6 +858,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(5e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -762,6 +873,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(6e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -774,6 +888,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(7e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -796,6 +913,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(4e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -808,6 +928,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(5e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -820,6 +943,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(6e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -832,6 +958,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(7e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -845,6 +974,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(4e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -857,6 +989,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(5e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -869,6 +1004,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(6e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -881,6 +1019,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(7e-3),
                 localtime: base + NtpDuration::from_seconds(1000.0),
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             NtpPacket::poll_message(PollIntervalLimits::default().min).0,
         );
@@ -907,6 +1048,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -915,6 +1057,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(0.0),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -1026,6 +1171,7 @@ mod tests {
                 data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
                 next_idx: 0,
             },
+            delay_variance_baseline: 0.0,
             precision_score: 0,
             poll_score: 0,
             desired_poll_interval: PollIntervalLimits::default().min,
@@ -1034,6 +1180,9 @@ mod tests {
                 offset: NtpDuration::from_seconds(0.0),
                 localtime: base,
                 monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
             },
             last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
             prev_was_outlier: false,
@@ -1083,4 +1232,140 @@ mod tests {
         assert_eq!(peer.precision_score, 0);
         assert!((peer.clock_wander - 1e-8).abs() < 1e-12);
     }
+
+    #[test]
+    fn test_remote_precision_weighting() {
+        let base = NtpTimestamp::from_fixed_int(0);
+        let basei = NtpInstant::now();
+        let filter = PeerFilter {
+            state: Vector::new(0.0, 0.),
+            uncertainty: Matrix::new(1e-6, 0., 0., 1e-8),
+            clock_wander: 1e-8,
+            roundtriptime_stats: AveragingBuffer {
+                data: [0.0, 0.0, 0.0, 0.0, 0.875e-6, 0.875e-6, 0.875e-6, 0.875e-6],
+                next_idx: 0,
+            },
+            delay_variance_baseline: 0.0,
+            precision_score: 0,
+            poll_score: 0,
+            desired_poll_interval: PollIntervalLimits::default().min,
+            last_measurement: Measurement {
+                delay: NtpDuration::from_seconds(0.0),
+                offset: NtpDuration::from_seconds(0.0),
+                localtime: base,
+                monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
+            },
+            last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
+            prev_was_outlier: false,
+            last_iter: base,
+            filter_time: base,
+        };
+        let measurement = |remote_precision| Measurement {
+            delay: NtpDuration::from_seconds(0.0),
+            offset: NtpDuration::from_seconds(1e-3),
+            localtime: base + NtpDuration::from_seconds(1.0),
+            monotime: basei,
+            remote_precision,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::ZERO,
+        };
+
+        let mut fine = filter.clone();
+        let (_, fine_weight, _) =
+            fine.absorb_measurement(measurement(NtpDuration::from_exponent(-20)));
+        let mut coarse = filter;
+        let (_, coarse_weight, _) =
+            coarse.absorb_measurement(measurement(NtpDuration::from_exponent(-6)));
+
+        // the measurement from the coarse source moves the state less
+        assert!(coarse_weight < fine_weight);
+        assert!(coarse.state.entry(0) < fine.state.entry(0));
+
+        // and leaves more uncertainty, down-weighting the source when combining
+        assert!(coarse.uncertainty.entry(0, 0) > fine.uncertainty.entry(0, 0));
+    }
+
+    #[test]
+    fn test_congestion_down_weights_peer() {
+        let config = SystemConfig::default();
+        let algo_config = AlgorithmConfig::default();
+
+        let base = NtpTimestamp::from_fixed_int(0);
+        let basei = NtpInstant::now();
+        let mut filter = PeerFilter {
+            state: Vector::new(1e-3, 0.),
+            uncertainty: Matrix::new(1e-8, 0., 0., 1e-12),
+            clock_wander: 1e-8,
+            roundtriptime_stats: AveragingBuffer {
+                data: [1e-3; 8],
+                next_idx: 0,
+            },
+            delay_variance_baseline: 0.0,
+            precision_score: 0,
+            poll_score: 0,
+            desired_poll_interval: PollIntervalLimits::default().min,
+            last_measurement: Measurement {
+                delay: NtpDuration::from_seconds(1e-3),
+                offset: NtpDuration::from_seconds(1e-3),
+                localtime: base,
+                monotime: basei,
+                remote_precision: NtpDuration::ZERO,
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: NtpDuration::ZERO,
+            },
+            last_packet: NtpPacket::poll_message(PollIntervalLimits::default().min).0,
+            prev_was_outlier: false,
+            last_iter: base,
+            filter_time: base,
+        };
+        let feed = |filter: &mut PeerFilter, delays: &[f64]| {
+            for delay in delays {
+                let measurement = Measurement {
+                    delay: NtpDuration::from_seconds(*delay),
+                    offset: NtpDuration::from_seconds(1e-3),
+                    localtime: filter.last_iter + NtpDuration::from_seconds(1.0),
+                    monotime: basei,
+                    remote_precision: NtpDuration::ZERO,
+                    root_delay: NtpDuration::ZERO,
+                    root_dispersion: NtpDuration::ZERO,
+                };
+                assert!(filter.update(&config, &algo_config, measurement, NtpPacket::test()));
+            }
+        };
+
+        feed(&mut filter, &[1e-3; 8]);
+        let calm = PeerState(PeerStateInner::Stable(filter.clone()))
+            .snapshot(0)
+            .unwrap();
+        assert!(calm.congestion < 1e-15);
+        assert!(calm.delay_jitter < 1e-9);
+
+        // the delay suddenly starts varying
+        feed(&mut filter, &[0.5e-3, 1.5e-3, 0.5e-3, 1.5e-3]);
+        let congested = PeerState(PeerStateInner::Stable(filter.clone()))
+            .snapshot(1)
+            .unwrap();
+        assert!(congested.congestion > 1e-8);
+        assert!(congested.delay_jitter > 1e-4);
+
+        // combined with a peer at offset 0, the congested peer pulls the estimate less
+        let other = PeerSnapshot {
+            index: 2,
+            state: Vector::new(0.0, 0.0),
+            ..calm.clone()
+        };
+        let with_calm = combine(&[calm, other.clone()], &algo_config).unwrap();
+        let with_congested = combine(&[congested, other], &algo_config).unwrap();
+        assert!(with_congested.estimate.entry(0) < with_calm.estimate.entry(0));
+
+        // once the delay is stable again, the peer is no longer down-weighted
+        feed(&mut filter, &[1e-3; 8]);
+        let recovered = PeerState(PeerStateInner::Stable(filter))
+            .snapshot(0)
+            .unwrap();
+        assert!(recovered.congestion < 1e-15);
+    }
 }
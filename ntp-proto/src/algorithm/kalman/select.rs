@@ -2,6 +2,17 @@ use crate::SystemConfig;
 
 use super::{config::AlgorithmConfig, PeerSnapshot};
 
+pub(super) enum SelectionResult<Index: Copy> {
+    /// The peers agreeing on the time, to be combined
+    Selected(Vec<PeerSnapshot<Index>>),
+    /// Fewer than the minimum number of intersection survivors agree. This is
+    /// the normal state during startup, or with few usable peers.
+    TooFewSurvivors,
+    /// No group of usable peers has a strict majority, so at least half of them
+    /// are falsetickers and no estimate of the time can be trusted.
+    NoMajority,
+}
+
 enum BoundType {
     Start,
     End,
@@ -15,12 +26,21 @@ enum BoundType {
 // is also statistically more sound. Any difference (larger set of accepted peers)
 // can be compensated for if desired by setting tighter bounds on the weights
 // determining the confidence interval.
+//
+// Peers marked as preferred count as two votes instead of one, both when looking
+// for the point of maximum overlap and when checking that this point is supported
+// by a strict majority. As a result, in a tie the group containing the preferred
+// peer wins, and a preferred peer can only be outvoted by a group of peers that
+// outnumbers its own group by at least two. The min_intersection_survivors
+// requirement still counts actual peers, so preference never substitutes for
+// an additional peer there.
 pub(super) fn select<Index: Copy>(
     config: &SystemConfig,
     algo_config: &AlgorithmConfig,
     candidates: Vec<PeerSnapshot<Index>>,
-) -> Vec<PeerSnapshot<Index>> {
-    let mut bounds: Vec<(f64, BoundType)> = Vec::with_capacity(2 * candidates.len());
+) -> SelectionResult<Index> {
+    let mut bounds: Vec<(f64, BoundType, usize)> = Vec::with_capacity(2 * candidates.len());
+    let mut total_weight: usize = 0;
 
     for snapshot in candidates.iter() {
         let radius = snapshot.offset_uncertainty() * algo_config.range_statistical_weight
@@ -29,42 +49,59 @@ pub(super) fn select<Index: Copy>(
             continue;
         }
 
-        bounds.push((snapshot.offset() - radius, BoundType::Start));
-        bounds.push((snapshot.offset() + radius, BoundType::End));
+        let weight = if snapshot.prefer { 2 } else { 1 };
+        total_weight += weight;
+
+        bounds.push((snapshot.offset() - radius, BoundType::Start, weight));
+        bounds.push((snapshot.offset() + radius, BoundType::End, weight));
     }
 
     bounds.sort_by(|a, b| a.0.total_cmp(&b.0));
 
     let mut max: usize = 0;
+    let mut max_weight: usize = 0;
     let mut maxt: f64 = 0.0;
     let mut cur: usize = 0;
+    let mut cur_weight: usize = 0;
 
-    for (time, boundtype) in bounds.iter() {
+    for (time, boundtype, weight) in bounds.iter() {
         match boundtype {
-            BoundType::Start => cur += 1,
-            BoundType::End => cur -= 1,
+            BoundType::Start => {
+                cur += 1;
+                cur_weight += weight;
+            }
+            BoundType::End => {
+                cur -= 1;
+                cur_weight -= weight;
+            }
         }
-        if cur > max {
+        if cur_weight > max_weight {
             max = cur;
+            max_weight = cur_weight;
             maxt = *time;
         }
     }
 
-    if max >= config.min_intersection_survivors && max * 4 > bounds.len() {
-        candidates
-            .iter()
-            .filter(|snapshot| {
-                let radius = snapshot.offset_uncertainty() * algo_config.range_statistical_weight
-                    + snapshot.delay * algo_config.range_delay_weight;
-                radius <= algo_config.max_peer_uncertainty
-                    && snapshot.offset() - radius <= maxt
-                    && snapshot.offset() + radius >= maxt
-                    && snapshot.leap_indicator.is_synchronized()
-            })
-            .cloned()
-            .collect()
+    if total_weight > 0 && max_weight * 2 <= total_weight {
+        SelectionResult::NoMajority
+    } else if max < config.min_intersection_survivors {
+        SelectionResult::TooFewSurvivors
     } else {
-        vec![]
+        SelectionResult::Selected(
+            candidates
+                .iter()
+                .filter(|snapshot| {
+                    let radius = snapshot.offset_uncertainty()
+                        * algo_config.range_statistical_weight
+                        + snapshot.delay * algo_config.range_delay_weight;
+                    radius <= algo_config.max_peer_uncertainty
+                        && snapshot.offset() - radius <= maxt
+                        && snapshot.offset() + radius >= maxt
+                        && snapshot.leap_indicator.is_synchronized()
+                })
+                .cloned()
+                .collect(),
+        )
     }
 }
 
@@ -80,16 +117,28 @@ mod tests {
 
     use super::*;
 
+    fn selected(result: SelectionResult<usize>) -> Vec<PeerSnapshot<usize>> {
+        match result {
+            SelectionResult::Selected(selection) => selection,
+            SelectionResult::TooFewSurvivors => panic!("too few survivors"),
+            SelectionResult::NoMajority => panic!("no majority"),
+        }
+    }
+
     fn snapshot_for_range(center: f64, uncertainty: f64, delay: f64) -> PeerSnapshot<usize> {
         PeerSnapshot {
             index: 0,
             state: Vector::new(center, 0.0),
             uncertainty: Matrix::new(sqr(uncertainty), 0.0, 0.0, 10e-12),
             delay,
+            delay_jitter: 0.0,
+            congestion: 0.0,
             peer_uncertainty: NtpDuration::from_seconds(0.01),
             peer_delay: NtpDuration::from_seconds(0.01),
             leap_indicator: crate::NtpLeapIndicator::NoWarning,
             last_update: NtpTimestamp::from_fixed_int(0),
+            prefer: false,
+            leap_smearing: false,
         }
     }
 
@@ -114,8 +163,10 @@ mod tests {
             range_delay_weight: 0.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates.clone());
-        assert_eq!(result.len(), 0);
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates.clone()),
+            SelectionResult::TooFewSurvivors
+        ));
 
         let algconfig = AlgorithmConfig {
             max_peer_uncertainty: 1.0,
@@ -123,8 +174,10 @@ mod tests {
             range_delay_weight: 1.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates.clone());
-        assert_eq!(result.len(), 0);
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates.clone()),
+            SelectionResult::TooFewSurvivors
+        ));
 
         let algconfig = AlgorithmConfig {
             max_peer_uncertainty: 1.0,
@@ -132,7 +185,7 @@ mod tests {
             range_delay_weight: 1.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates);
+        let result = selected(select(&sysconfig, &algconfig, candidates));
         assert_eq!(result.len(), 4);
     }
 
@@ -155,7 +208,7 @@ mod tests {
             range_delay_weight: 1.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates.clone());
+        let result = selected(select(&sysconfig, &algconfig, candidates.clone()));
         assert_eq!(result.len(), 3);
 
         let algconfig = AlgorithmConfig {
@@ -164,7 +217,7 @@ mod tests {
             range_delay_weight: 1.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates.clone());
+        let result = selected(select(&sysconfig, &algconfig, candidates.clone()));
         assert_eq!(result.len(), 2);
 
         let algconfig = AlgorithmConfig {
@@ -173,7 +226,7 @@ mod tests {
             range_delay_weight: 1.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates.clone());
+        let result = selected(select(&sysconfig, &algconfig, candidates.clone()));
         assert_eq!(result.len(), 1);
 
         let algconfig = AlgorithmConfig {
@@ -182,8 +235,10 @@ mod tests {
             range_delay_weight: 1.0,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates);
-        assert_eq!(result.len(), 0);
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates),
+            SelectionResult::TooFewSurvivors
+        ));
     }
 
     #[test]
@@ -207,15 +262,17 @@ mod tests {
             min_intersection_survivors: 3,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates.clone());
+        let result = selected(select(&sysconfig, &algconfig, candidates.clone()));
         assert_eq!(result.len(), 3);
 
         let sysconfig = SystemConfig {
             min_intersection_survivors: 4,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates);
-        assert_eq!(result.len(), 0);
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates),
+            SelectionResult::TooFewSurvivors
+        ));
     }
 
     #[test]
@@ -237,7 +294,102 @@ mod tests {
             min_intersection_survivors: 1,
             ..Default::default()
         };
-        let result = select(&sysconfig, &algconfig, candidates);
-        assert_eq!(result.len(), 0);
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates),
+            SelectionResult::NoMajority
+        ));
+    }
+
+    #[test]
+    fn test_prefer_breaks_tie() {
+        // Test that in the case of a tie the group with the preferred peer is chosen.
+        let mut candidates = vec![
+            snapshot_for_range(0.0, 0.1, 0.1),
+            snapshot_for_range(0.0, 0.1, 0.1),
+            snapshot_for_range(0.5, 0.1, 0.1),
+            snapshot_for_range(0.5, 0.1, 0.1),
+        ];
+        candidates[2].prefer = true;
+        let algconfig = AlgorithmConfig {
+            max_peer_uncertainty: 3.0,
+            range_statistical_weight: 1.0,
+            range_delay_weight: 1.0,
+            ..Default::default()
+        };
+        let sysconfig = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+        let result = selected(select(&sysconfig, &algconfig, candidates));
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|snapshot| snapshot.offset() == 0.5));
+    }
+
+    #[test]
+    fn test_prefer_outvoted() {
+        // Test that a preferred peer does not overrule a large enough majority,
+        // but does prevent a majority of a single peer.
+        let algconfig = AlgorithmConfig {
+            max_peer_uncertainty: 3.0,
+            range_statistical_weight: 1.0,
+            range_delay_weight: 1.0,
+            ..Default::default()
+        };
+        let sysconfig = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+
+        let mut candidates = vec![
+            snapshot_for_range(0.0, 0.1, 0.1),
+            snapshot_for_range(0.5, 0.1, 0.1),
+            snapshot_for_range(0.5, 0.1, 0.1),
+            snapshot_for_range(0.5, 0.1, 0.1),
+        ];
+        candidates[0].prefer = true;
+        let result = selected(select(&sysconfig, &algconfig, candidates.clone()));
+        assert_eq!(result.len(), 3);
+        assert!(result.iter().all(|snapshot| !snapshot.prefer));
+
+        candidates.pop();
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates),
+            SelectionResult::NoMajority
+        ));
+    }
+
+    #[test]
+    fn test_no_majority() {
+        // Test that mutually inconsistent peers are recognized as such, and that
+        // this is distinguished from too few peers agreeing.
+        let candidates = vec![
+            snapshot_for_range(0.0, 0.1, 0.1),
+            snapshot_for_range(1.0, 0.1, 0.1),
+            snapshot_for_range(2.0, 0.1, 0.1),
+        ];
+        let algconfig = AlgorithmConfig {
+            max_peer_uncertainty: 3.0,
+            range_statistical_weight: 1.0,
+            range_delay_weight: 1.0,
+            ..Default::default()
+        };
+        let sysconfig = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            select(&sysconfig, &algconfig, candidates.clone()),
+            SelectionResult::NoMajority
+        ));
+
+        // without usable peers there is nothing to disagree
+        let strict_algconfig = AlgorithmConfig {
+            max_peer_uncertainty: 0.003,
+            ..algconfig
+        };
+        assert!(matches!(
+            select(&sysconfig, &strict_algconfig, candidates),
+            SelectionResult::TooFewSurvivors
+        ));
     }
 }
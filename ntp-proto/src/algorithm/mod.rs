@@ -11,6 +11,9 @@ pub struct ObservablePeerTimedata {
     pub offset: NtpDuration,
     pub uncertainty: NtpDuration,
     pub delay: NtpDuration,
+    /// Standard deviation of the delay over recent measurements
+    #[serde(default)]
+    pub delay_jitter: NtpDuration,
 
     pub remote_delay: NtpDuration,
     pub remote_uncertainty: NtpDuration,
@@ -55,6 +58,14 @@ pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     /// Notify the controller that the status of a peer (whether
     /// or not it is usable for synchronization) has changed.
     fn peer_update(&mut self, id: PeerID, usable: bool);
+    /// Notify the controller whether a peer was configured as preferred.
+    /// Controllers may give preferred peers extra weight during selection,
+    /// by default the preference is ignored.
+    fn peer_prefer(&mut self, _id: PeerID, _prefer: bool) {}
+    /// Notify the controller whether a peer was configured as smearing leap
+    /// seconds. Controllers may keep such peers out of the leap second vote,
+    /// by default this is ignored.
+    fn peer_leap_smear(&mut self, _id: PeerID, _smearing: bool) {}
     /// Notify the controller of a new measurement from a peer.
     /// The list of peerIDs is used for loop detection, with the
     /// first peerID given considered the primary peer used.
@@ -68,12 +79,18 @@ pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     fn time_update(&mut self) -> StateUpdate<PeerID>;
     /// Get a snapshot of the timekeeping state of a peer.
     fn peer_snapshot(&self, id: PeerID) -> Option<ObservablePeerTimedata>;
+    /// Estimate how long it will take until the controller first
+    /// synchronizes the clock, assuming all usable peers keep answering.
+    /// Zero once synchronized, None if no estimate can be given.
+    fn estimate_time_to_sync(&self) -> Option<NtpDuration> {
+        None
+    }
 }
 
 mod kalman;
 mod standard;
 
-pub use kalman::KalmanClockController;
+pub use kalman::{KalmanClockController, KalmanState};
 pub use standard::StandardClockController;
 #[cfg(feature = "rfc-algorithm")]
 pub type DefaultTimeSyncController<C, PeerID> = standard::StandardClockController<C, PeerID>;
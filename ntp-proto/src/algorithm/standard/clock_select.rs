@@ -387,16 +387,17 @@ pub struct ClockCombine {
 /// be configured to avoid this algorithm by designating a
 /// preferred peer.
 ///
-/// Assumption: the survivors are the output of the clustering algorithm,
-/// in particular they are in the order produced by the clustering algorithm.
-fn clock_combine<'a, PeerID: Hash + Eq + Copy + Debug>(
-    survivors: &'a [SurvivorTuple<'a, PeerID>],
-    system_selection_jitter: NtpDuration,
+/// Returns the combined offset and jitter. The survivors must not be empty.
+fn combine<PeerID: Hash + Eq + Copy + Debug>(
+    survivors: &[SurvivorTuple<PeerID>],
     local_clock_time: NtpInstant,
     frequency_tolerance: FrequencyTolerance,
-) -> ClockCombine {
+) -> (NtpDuration, NtpDuration) {
+    let first_offset = survivors[0].peer.1.statistics.offset.to_seconds();
+
     let mut y = 0.0; // normalization factor
     let mut z = 0.0; // weighed offset sum
+    let mut w = 0.0; // weighed squared difference to the first survivor
 
     for tuple in survivors {
         let peer = tuple.peer;
@@ -404,13 +405,31 @@ fn clock_combine<'a, PeerID: Hash + Eq + Copy + Debug>(
             .1
             .root_distance(local_clock_time, frequency_tolerance)
             .to_seconds();
+        let offset = peer.1.statistics.offset.to_seconds();
         y += 1.0 / x;
-        z += peer.1.statistics.offset.to_seconds() / x;
+        z += offset / x;
+        w += (offset - first_offset).powi(2) / x;
     }
 
-    let system_offset = NtpDuration::from_seconds(z / y);
+    (
+        NtpDuration::from_seconds(z / y),
+        NtpDuration::from_seconds((w / y).sqrt()),
+    )
+}
+
+/// Determine the system offset and jitter from the survivors, see [`combine`].
+///
+/// Assumption: the survivors are the output of the clustering algorithm,
+/// in particular they are in the order produced by the clustering algorithm.
+fn clock_combine<'a, PeerID: Hash + Eq + Copy + Debug>(
+    survivors: &'a [SurvivorTuple<'a, PeerID>],
+    system_selection_jitter: NtpDuration,
+    local_clock_time: NtpInstant,
+    frequency_tolerance: FrequencyTolerance,
+) -> ClockCombine {
+    let (system_offset, _) = combine(survivors, local_clock_time, frequency_tolerance);
 
-    // deviation: the code skeleton does some weird statistics here.
+    // deviation: the code skeleton uses the jitter computed by `combine` here.
     // we just pick the jitter of the peer that will become the system peer
     // this may be an overestimate but that is not a problem
     let system_peer_jitter = survivors[0].peer.1.statistics.jitter;
@@ -508,6 +527,82 @@ mod test {
         fuzz_find_interval(&[(-1, 2)]);
     }
 
+    fn combine_peer(
+        offset: f64,
+        root_dispersion: f64,
+        instant: NtpInstant,
+    ) -> (usize, PeerTimeSnapshot) {
+        // root distance is 0.1 + root_dispersion
+        (
+            0,
+            peer_time_snapshot(
+                PeerStatistics {
+                    delay: NtpDuration::from_seconds(0.1),
+                    offset: NtpDuration::from_seconds(offset),
+                    dispersion: NtpDuration::from_seconds(0.0),
+                    jitter: 0.0,
+                },
+                instant,
+                NtpDuration::from_seconds(0.1),
+                NtpDuration::from_seconds(root_dispersion),
+            ),
+        )
+    }
+
+    fn combine_peers(peers: &[(usize, PeerTimeSnapshot)], instant: NtpInstant) -> (f64, f64) {
+        let survivors: Vec<_> = peers
+            .iter()
+            .map(|peer| SurvivorTuple {
+                peer,
+                metric: NtpDuration::ZERO,
+            })
+            .collect();
+        let (offset, jitter) = combine(&survivors, instant, FrequencyTolerance::ppm(15));
+        (offset.to_seconds(), jitter.to_seconds())
+    }
+
+    #[test]
+    fn combine_single() {
+        let instant = NtpInstant::now();
+        let peers = [combine_peer(0.003, 0.1, instant)];
+
+        let (offset, jitter) = combine_peers(&peers, instant);
+        assert!((offset - 0.003).abs() < 1e-9);
+        assert_eq!(jitter, 0.0);
+    }
+
+    #[test]
+    fn combine_equal_distances() {
+        // with equal root distances, the offset is the plain average, and the
+        // jitter the RMS difference to the first survivor
+        let instant = NtpInstant::now();
+        let peers = [
+            combine_peer(0.0, 0.1, instant),
+            combine_peer(0.005, 0.1, instant),
+            combine_peer(-0.005, 0.1, instant),
+        ];
+
+        let (offset, jitter) = combine_peers(&peers, instant);
+        assert!(offset.abs() < 1e-9);
+        assert!((jitter - (50e-6f64 / 3.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn combine_weighted_by_root_distance() {
+        // root distances of 0.2 and 0.4, so weights 5 and 2.5
+        let instant = NtpInstant::now();
+        let peers = [
+            combine_peer(0.001, 0.1, instant),
+            combine_peer(0.004, 0.3, instant),
+        ];
+
+        let (offset, jitter) = combine_peers(&peers, instant);
+        // (5 * 1ms + 2.5 * 4ms) / 7.5
+        assert!((offset - 0.002).abs() < 1e-9);
+        // sqrt(2.5 * (3ms)^2 / 7.5)
+        assert!((jitter - 3e-6f64.sqrt()).abs() < 1e-9);
+    }
+
     #[test]
     fn clock_combine_simple() {
         let instant = NtpInstant::now();
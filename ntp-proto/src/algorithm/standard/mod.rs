@@ -34,6 +34,7 @@ pub struct StandardClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug
     config: SystemConfig,
     algo_config: AlgorithmConfig,
     last_reset: Option<NtpInstant>,
+    cold_started: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -166,6 +167,7 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
             config,
             algo_config,
             last_reset: None,
+            cold_started: false,
         }
     }
 
@@ -215,6 +217,25 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
             }
         }
 
+        // With cold start, the first measurement of a usable peer is taken as the truth
+        if self.config.cold_start
+            && !self.cold_started
+            && self.controller.is_startup()
+            && self.peerstate.get(&id).is_some_and(|state| state.usable)
+        {
+            info!(offset = ?measurement.offset, "Cold start: stepping the clock");
+            if let Err(e) = self.clock.step_clock(measurement.offset) {
+                error!(error = %e, "Could not step the clock, exiting");
+                std::process::exit(exitcode::NOPERM);
+            }
+            for peerstate in self.peerstate.values_mut() {
+                peerstate.timestate.reset_measurements();
+            }
+            self.last_reset = Some(now);
+            self.cold_started = true;
+            return StateUpdate::default();
+        }
+
         // Update peer's state and check if the clock needs recalculation
         if !self.run_peer_update(now, id, measurement, packet) {
             return StateUpdate::default();
@@ -237,6 +258,8 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
                 uncertainty: snapshot.statistics.dispersion
                     + NtpDuration::from_seconds(snapshot.statistics.jitter),
                 delay: snapshot.statistics.delay,
+                // not tracked by the standard algorithm
+                delay_jitter: NtpDuration::ZERO,
                 remote_delay: snapshot.root_delay,
                 remote_uncertainty: snapshot.root_dispersion,
                 last_update: self.clock.now().expect("Unable to get current time")
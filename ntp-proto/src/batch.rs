@@ -0,0 +1,265 @@
+use std::net::SocketAddr;
+
+use crate::{
+    packet::{PacketParsingError, ParsingError},
+    DecodedServerCookie, KeySet, NtpAssociationMode, NtpPacket, NtpTimestamp,
+};
+
+/// A client request that passed the checks a server does on every received datagram
+#[derive(Debug)]
+pub struct ValidatedRequest<'a> {
+    pub packet: NtpPacket<'a>,
+    pub decoded_cookie: Option<DecodedServerCookie>,
+    pub peer_addr: SocketAddr,
+    pub recv_timestamp: NtpTimestamp,
+}
+
+#[derive(Debug)]
+pub enum RequestError<'a> {
+    /// The datagram is smaller than an NTP header
+    TooShort(usize),
+    /// The datagram could not be parsed, or its NTS fields could not be decrypted
+    Parse(PacketParsingError<'a>),
+    /// The packet is not a client request or a request of a symmetric association
+    InvalidMode(NtpAssociationMode),
+    /// The NTS cookie of the client request could not be decrypted, or the
+    /// request not be authenticated with its keys. Such clients should be
+    /// told to get new cookies with an NTS NAK.
+    InvalidNts(NtpPacket<'a>),
+}
+
+impl<'a> std::fmt::Display for RequestError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(size) => write!(f, "request of {size} bytes is too small"),
+            Self::Parse(e) => write!(f, "invalid request: {e}"),
+            Self::InvalidMode(mode) => write!(f, "request with unexpected mode {mode:?}"),
+            Self::InvalidNts(_) => write!(f, "NTS request with an invalid cookie"),
+        }
+    }
+}
+
+impl<'a> std::error::Error for RequestError<'a> {}
+
+/// Parse a datagram received by a server, and check that it is a client request
+/// or a request of a symmetric association.
+///
+/// This does not depend on any server state besides the keyset used to decrypt
+/// NTS cookies, so many requests can be validated in parallel.
+#[allow(clippy::result_large_err)]
+pub fn validate_request<'a>(
+    data: &'a [u8],
+    peer_addr: SocketAddr,
+    recv_timestamp: NtpTimestamp,
+    keyset: &KeySet,
+) -> Result<ValidatedRequest<'a>, RequestError<'a>> {
+    validate_request_inner(data, peer_addr, recv_timestamp, keyset, None)
+}
+
+/// Like [`validate_request`], but decrypting the NTS fields of the request into
+/// `scratch` instead of allocating, see [`NtpPacket::deserialize_with_scratch`]
+#[allow(clippy::result_large_err)]
+pub fn validate_request_with_scratch<'a>(
+    data: &'a [u8],
+    peer_addr: SocketAddr,
+    recv_timestamp: NtpTimestamp,
+    keyset: &KeySet,
+    scratch: &'a mut [u8],
+) -> Result<ValidatedRequest<'a>, RequestError<'a>> {
+    validate_request_inner(data, peer_addr, recv_timestamp, keyset, Some(scratch))
+}
+
+#[allow(clippy::result_large_err)]
+fn validate_request_inner<'a>(
+    data: &'a [u8],
+    peer_addr: SocketAddr,
+    recv_timestamp: NtpTimestamp,
+    keyset: &KeySet,
+    scratch: Option<&'a mut [u8]>,
+) -> Result<ValidatedRequest<'a>, RequestError<'a>> {
+    if data.len() < 48 {
+        return Err(RequestError::TooShort(data.len()));
+    }
+
+    let (packet, decoded_cookie) = match scratch {
+        Some(scratch) => NtpPacket::deserialize_with_scratch(data, keyset, scratch),
+        None => NtpPacket::deserialize(data, keyset),
+    }
+    .map_err(|e| match e {
+        ParsingError::DecryptError(packet) if packet.mode() == NtpAssociationMode::Client => {
+            RequestError::InvalidNts(packet)
+        }
+        e => RequestError::Parse(e),
+    })?;
+
+    match packet.mode() {
+        NtpAssociationMode::Client
+        | NtpAssociationMode::SymmetricActive
+        | NtpAssociationMode::SymmetricPassive => Ok(ValidatedRequest {
+            packet,
+            decoded_cookie,
+            peer_addr,
+            recv_timestamp,
+        }),
+        mode => Err(RequestError::InvalidMode(mode)),
+    }
+}
+
+/// Validate a batch of received datagrams, giving one result per datagram in the
+/// same order. With the `rayon` feature enabled the datagrams are validated in
+/// parallel.
+#[allow(clippy::result_large_err)]
+pub fn validate_batch<'a>(
+    datagrams: &[(&'a [u8], SocketAddr, NtpTimestamp)],
+    keyset: &KeySet,
+) -> Vec<Result<ValidatedRequest<'a>, RequestError<'a>>> {
+    let validate = |&(data, peer_addr, recv_timestamp): &(&'a [u8], SocketAddr, NtpTimestamp)| {
+        validate_request(data, peer_addr, recv_timestamp, keyset)
+    };
+
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        datagrams.par_iter().map(validate).collect()
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    {
+        datagrams.iter().map(validate).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        nts_record::AeadAlgorithm, packet::AesSivCmac256, KeySetProvider, PollIntervalLimits,
+    };
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<KeySet>();
+        assert_send_sync::<NtpPacket>();
+        assert_send_sync::<ValidatedRequest>();
+        assert_send_sync::<RequestError>();
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_validate_batch() {
+        let keyset = KeySetProvider::new(1).get();
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let cookie = keyset.encode_cookie(&decoded);
+
+        let mut datagrams = vec![];
+        for i in 0..16_u8 {
+            let (packet, _) = if i % 2 == 0 {
+                NtpPacket::nts_poll_message(&cookie, 1, PollIntervalLimits::default().min)
+            } else {
+                NtpPacket::poll_message(PollIntervalLimits::default().min)
+            };
+            let mut buf = [0u8; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, decoded.c2s.as_ref()).unwrap();
+            let length = cursor.position() as usize;
+            datagrams.push(buf[..length].to_vec());
+        }
+        // a server response and garbage are rejected
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        datagrams.push(response.serialize_without_encryption_vec().unwrap());
+        datagrams.push(vec![0; 12]);
+        datagrams.push(vec![0xff; 64]);
+        // as is a request with a cookie of another server
+        let cookie = KeySetProvider::new(1).get().encode_cookie(&decoded);
+        let (packet, _) =
+            NtpPacket::nts_poll_message(&cookie, 1, PollIntervalLimits::default().min);
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, decoded.c2s.as_ref()).unwrap();
+        let length = cursor.position() as usize;
+        datagrams.push(buf[..length].to_vec());
+
+        let input: Vec<_> = datagrams
+            .iter()
+            .enumerate()
+            .map(|(i, data)| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], 1000 + i as u16));
+                (
+                    data.as_slice(),
+                    addr,
+                    NtpTimestamp::from_fixed_int(i as u64),
+                )
+            })
+            .collect();
+
+        let batch = validate_batch(&input, &keyset);
+        let sequential: Vec<_> = input
+            .iter()
+            .map(|&(data, addr, ts)| validate_request(data, addr, ts, &keyset))
+            .collect();
+        assert_eq!(batch.len(), sequential.len());
+
+        for (i, (batch, sequential)) in batch.iter().zip(sequential.iter()).enumerate() {
+            match (batch, sequential) {
+                (Ok(batch), Ok(sequential)) => {
+                    assert_eq!(batch.packet, sequential.packet);
+                    assert_eq!(batch.peer_addr, sequential.peer_addr);
+                    assert_eq!(batch.recv_timestamp, sequential.recv_timestamp);
+                    assert_eq!(
+                        batch.decoded_cookie.is_some(),
+                        sequential.decoded_cookie.is_some()
+                    );
+                    assert_eq!(batch.decoded_cookie.is_some(), i % 2 == 0);
+
+                    let (data, addr, ts) = input[i];
+                    let mut scratch = [0; 1024];
+                    let with_scratch =
+                        validate_request_with_scratch(data, addr, ts, &keyset, &mut scratch)
+                            .unwrap();
+                    assert_eq!(with_scratch.packet, sequential.packet);
+                }
+                (Err(batch), Err(sequential)) => {
+                    assert_eq!(batch.to_string(), sequential.to_string());
+                    assert!(i >= 16);
+                }
+                _ => panic!("batch and sequential results differ for datagram {i}"),
+            }
+        }
+        assert!(matches!(
+            batch_error(&input, &keyset, 16),
+            RequestError::InvalidMode(NtpAssociationMode::Server)
+        ));
+        assert!(matches!(
+            batch_error(&input, &keyset, 17),
+            RequestError::TooShort(12)
+        ));
+        assert!(matches!(
+            batch_error(&input, &keyset, 18),
+            RequestError::Parse(_)
+        ));
+        assert!(matches!(
+            batch_error(&input, &keyset, 19),
+            RequestError::InvalidNts(_)
+        ));
+    }
+
+    fn batch_error<'a>(
+        input: &[(&'a [u8], SocketAddr, NtpTimestamp)],
+        keyset: &KeySet,
+        index: usize,
+    ) -> RequestError<'a> {
+        validate_batch(input, keyset)
+            .swap_remove(index)
+            .unwrap_err()
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use crate::{
+    packet::PacketParsingError, validate_response, CipherProvider, Measurement, NoCipher,
+    NtpInstant, NtpPacket, NtpTimestamp, RejectReason, ValidationPolicy,
+};
+
+const MAGIC: &[u8; 8] = b"NTPCAP\x00\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedDatagram {
+    pub direction: CaptureDirection,
+    pub timestamp: NtpTimestamp,
+    pub data: Vec<u8>,
+}
+
+/// A recording of the raw datagrams sent and received by a client, together with
+/// the local time at which they were sent or received. Captures can be saved to a
+/// file and replayed through parsing and validation, which turns real-world
+/// exchanges into regression fixtures.
+///
+/// On disk, a capture is a magic header followed by one record per datagram: the
+/// direction (1 byte, 0 for sent and 1 for received), the timestamp (8 bytes, NTP
+/// format), the length (2 bytes, big endian) and the datagram itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketCapture {
+    datagrams: Vec<CapturedDatagram>,
+}
+
+/// A single exchange with a server as it was received, for keeping an audit trail.
+///
+/// Besides the parsed response this keeps the exact bytes received, so the record
+/// shows what the server sent even where parsing is lossy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExchangeRecord {
+    /// The datagram as received
+    pub raw: Vec<u8>,
+    /// The parsed response
+    pub packet: NtpPacket<'static>,
+    /// Local time at which the request was sent (t1)
+    pub send_timestamp: NtpTimestamp,
+    /// Local time at which the response was received (t4)
+    pub recv_timestamp: NtpTimestamp,
+}
+
+impl ExchangeRecord {
+    /// Parse the received datagram `raw` using `cipher`, and keep it with the local
+    /// send and receive timestamps of the exchange.
+    #[allow(clippy::result_large_err)]
+    pub fn new(
+        raw: &[u8],
+        cipher: &impl CipherProvider,
+        send_timestamp: NtpTimestamp,
+        recv_timestamp: NtpTimestamp,
+    ) -> Result<Self, PacketParsingError<'static>> {
+        let packet = NtpPacket::deserialize(raw, cipher)
+            .map_err(PacketParsingError::into_owned)?
+            .0
+            .into_owned();
+
+        Ok(Self {
+            raw: raw.to_vec(),
+            packet,
+            send_timestamp,
+            recv_timestamp,
+        })
+    }
+
+    /// The four timestamps of the exchange: our send time (t1), the server's receive
+    /// (t2) and transmit (t3) times, and our receive time (t4)
+    pub fn timestamps(&self) -> [NtpTimestamp; 4] {
+        [
+            self.send_timestamp,
+            self.packet.receive_timestamp(),
+            self.packet.transmit_timestamp(),
+            self.recv_timestamp,
+        ]
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError<'a> {
+    /// The received datagram could not be parsed
+    Parse(PacketParsingError<'a>),
+    /// No request was sent before the datagram was received
+    NoRequest,
+    /// The response was rejected during validation
+    Rejected(RejectReason),
+}
+
+impl PacketCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a datagram sent at `timestamp`
+    pub fn record_sent(&mut self, data: &[u8], timestamp: NtpTimestamp) {
+        self.record(CaptureDirection::Sent, data, timestamp)
+    }
+
+    /// Record a datagram received at `timestamp`
+    pub fn record_received(&mut self, data: &[u8], timestamp: NtpTimestamp) {
+        self.record(CaptureDirection::Received, data, timestamp)
+    }
+
+    fn record(&mut self, direction: CaptureDirection, data: &[u8], timestamp: NtpTimestamp) {
+        self.datagrams.push(CapturedDatagram {
+            direction,
+            timestamp,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn datagrams(&self) -> &[CapturedDatagram] {
+        &self.datagrams
+    }
+
+    pub fn serialize(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(MAGIC)?;
+        for datagram in &self.datagrams {
+            let length: u16 = datagram.data.len().try_into().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "datagram too large")
+            })?;
+            let direction: u8 = match datagram.direction {
+                CaptureDirection::Sent => 0,
+                CaptureDirection::Received => 1,
+            };
+            w.write_all(&[direction])?;
+            w.write_all(&datagram.timestamp.to_bits())?;
+            w.write_all(&length.to_be_bytes())?;
+            w.write_all(&datagram.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(r: &mut impl Read) -> std::io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not a packet capture",
+            ));
+        }
+
+        let mut datagrams = vec![];
+        loop {
+            let mut direction = [0; 1];
+            if r.read(&mut direction)? == 0 {
+                break;
+            }
+            let direction = match direction[0] {
+                0 => CaptureDirection::Sent,
+                1 => CaptureDirection::Received,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "invalid datagram direction",
+                    ))
+                }
+            };
+
+            let mut timestamp = [0; 8];
+            r.read_exact(&mut timestamp)?;
+            let mut length = [0; 2];
+            r.read_exact(&mut length)?;
+            let mut data = vec![0; u16::from_be_bytes(length) as usize];
+            r.read_exact(&mut data)?;
+
+            datagrams.push(CapturedDatagram {
+                direction,
+                timestamp: NtpTimestamp::from_bits(timestamp),
+                data,
+            });
+        }
+
+        Ok(Self { datagrams })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.serialize(&mut file)?;
+        file.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::deserialize(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Replay the capture through packet parsing and response validation.
+    ///
+    /// Every received datagram is parsed using `cipher` and validated against the
+    /// most recent request sent before it, giving one result per received datagram.
+    /// Once a response to a request is accepted, further responses to it are rejected.
+    pub fn replay<'a>(
+        &'a self,
+        cipher: &impl CipherProvider,
+        policy: &ValidationPolicy,
+    ) -> Vec<Result<Measurement, ReplayError<'a>>> {
+        let mut last_request = None;
+        let mut results = vec![];
+
+        for datagram in &self.datagrams {
+            match datagram.direction {
+                CaptureDirection::Sent => {
+                    // NTS requests can't be decrypted here, but their unique
+                    // identifier is readable without keys.
+                    let request = match NtpPacket::deserialize(&datagram.data, &NoCipher) {
+                        Ok((packet, _)) => Some(packet),
+                        Err(PacketParsingError::DecryptError(packet)) => Some(packet),
+                        Err(_) => None,
+                    };
+                    last_request =
+                        request.map(|request| (request.request_identifier(), datagram.timestamp));
+                }
+                CaptureDirection::Received => {
+                    let result = match NtpPacket::deserialize(&datagram.data, cipher) {
+                        Err(e) => Err(ReplayError::Parse(e)),
+                        Ok((response, _)) => match last_request {
+                            None => Err(ReplayError::NoRequest),
+                            Some((identifier, send_time)) => validate_response(
+                                &identifier,
+                                &response,
+                                policy,
+                                send_time,
+                                datagram.timestamp,
+                                NtpInstant::now(),
+                            )
+                            .map_err(ReplayError::Rejected),
+                        },
+                    };
+                    // like a peer, accept only a single response per request
+                    if result.is_ok() {
+                        last_request = None;
+                    }
+                    results.push(result);
+                }
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NtpDuration, PollInterval};
+
+    use super::*;
+
+    fn serialize(packet: &NtpPacket) -> Vec<u8> {
+        packet.serialize_without_encryption_vec().unwrap()
+    }
+
+    fn capture() -> PacketCapture {
+        let mut capture = PacketCapture::new();
+
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        let mut response = NtpPacket::test();
+        response.set_mode(crate::NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_leap(crate::NtpLeapIndicator::NoWarning);
+        response.set_origin_timestamp(request.transmit_timestamp());
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(11 << 32));
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(11 << 32));
+
+        capture.record_sent(&serialize(&request), NtpTimestamp::from_fixed_int(10 << 32));
+        capture.record_received(
+            &serialize(&response),
+            NtpTimestamp::from_fixed_int(12 << 32),
+        );
+        // a duplicate of the response is rejected
+        capture.record_received(
+            &serialize(&response),
+            NtpTimestamp::from_fixed_int(12 << 32),
+        );
+        // and so is garbage
+        capture.record_received(&[1, 2, 3], NtpTimestamp::from_fixed_int(13 << 32));
+
+        capture
+    }
+
+    #[test]
+    fn test_capture_roundtrip() {
+        let capture = capture();
+
+        let mut buf = vec![];
+        capture.serialize(&mut buf).unwrap();
+        let parsed = PacketCapture::deserialize(&mut buf.as_slice()).unwrap();
+        assert_eq!(parsed, capture);
+
+        let path = std::env::temp_dir().join("ntp-test-capture-1");
+        capture.save(&path).unwrap();
+        assert_eq!(PacketCapture::load(&path).unwrap(), capture);
+        std::fs::remove_file(&path).unwrap();
+
+        let results = parsed.replay(&NoCipher, &ValidationPolicy::default());
+        assert_eq!(results.len(), 3);
+        let measurement = results[0].as_ref().unwrap();
+        assert_eq!(measurement.offset, NtpDuration::ZERO);
+        assert_eq!(measurement.delay, NtpDuration::from_seconds(2.0));
+        assert!(matches!(results[1], Err(ReplayError::NoRequest)));
+        assert!(matches!(results[2], Err(ReplayError::Parse(_))));
+    }
+
+    #[test]
+    fn test_capture_invalid() {
+        let capture = capture();
+        let mut buf = vec![];
+        capture.serialize(&mut buf).unwrap();
+
+        // truncated captures are rejected
+        assert!(PacketCapture::deserialize(&mut &buf[..buf.len() - 1]).is_err());
+
+        // as are files that are not captures at all
+        buf[0] = b'X';
+        assert!(PacketCapture::deserialize(&mut buf.as_slice()).is_err());
+
+        let mut buf = vec![];
+        PacketCapture::new().serialize(&mut buf).unwrap();
+        buf.push(2);
+        assert!(PacketCapture::deserialize(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_exchange_record() {
+        let mut response = NtpPacket::test();
+        response.set_mode(crate::NtpAssociationMode::Server);
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(11 << 32));
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(12 << 32));
+        let raw = serialize(&response);
+
+        let record = ExchangeRecord::new(
+            &raw,
+            &NoCipher,
+            NtpTimestamp::from_fixed_int(10 << 32),
+            NtpTimestamp::from_fixed_int(13 << 32),
+        )
+        .unwrap();
+        // the record owns its data, and outlives the receive buffer
+        drop(raw);
+
+        assert_eq!(record.packet, response);
+        assert_eq!(record.raw, serialize(&response));
+        assert_eq!(
+            record.timestamps(),
+            [10, 11, 12, 13].map(|t| NtpTimestamp::from_fixed_int(t << 32))
+        );
+
+        // a truncated response can't be recorded
+        assert!(matches!(
+            ExchangeRecord::new(
+                &serialize(&response)[..20],
+                &NoCipher,
+                NtpTimestamp::default(),
+                NtpTimestamp::default()
+            ),
+            Err(PacketParsingError::IncorrectLength)
+        ));
+    }
+
+    #[test]
+    fn test_replay_without_request() {
+        let mut capture = PacketCapture::new();
+        capture.record_received(
+            &serialize(&NtpPacket::test()),
+            NtpTimestamp::from_fixed_int(1),
+        );
+        let results = capture.replay(&NoCipher, &ValidationPolicy::default());
+        assert!(matches!(results[..], [Err(ReplayError::NoRequest)]));
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{packet::NtpLeapIndicator, time_types::PollInterval, NtpDuration, NtpTimestamp};
+use crate::{
+    packet::NtpLeapIndicator, time_types::PollInterval, NtpDuration, NtpTimestamp, ReferenceId,
+};
 
 /// Interface for a clock settable by the ntp implementation.
 /// This needs to be a trait as a single system can have multiple clocks
@@ -40,4 +42,33 @@ pub trait NtpClock: Clone + Send + 'static {
     // Change the indicators for upcoming leap seconds and
     // the clocks synchronization status.
     fn status_update(&self, leap_status: NtpLeapIndicator) -> Result<(), Self::Error>;
+
+    // Get the bound on the error of the clock as maintained by the
+    // clock itself (e.g. the kernel's maxerror), which grows while
+    // the clock is not being updated. Clocks that don't keep track
+    // of this return zero.
+    fn error_bound(&self) -> Result<NtpDuration, Self::Error> {
+        Ok(NtpDuration::ZERO)
+    }
+}
+
+/// A single reading of a reference clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefClockReading {
+    /// Time according to the reference clock at the moment of reading
+    pub timestamp: NtpTimestamp,
+    /// Maximum error of the reading with respect to true time
+    pub dispersion: NtpDuration,
+    /// Identifier of the kind of reference clock, e.g. `GPS` or `PPS`
+    pub reference_id: ReferenceId,
+}
+
+/// Interface for a reference clock, such as a PPS or GPS receiver, that provides
+/// time directly rather than through NTP. A server synchronized to a reference
+/// clock is at stratum 1.
+pub trait RefClock {
+    type Error: std::error::Error;
+
+    // Get the current reading of the reference clock
+    fn read(&self) -> Result<RefClockReading, Self::Error>;
 }
@@ -32,6 +32,59 @@ impl StepThreshold {
     }
 }
 
+/// How an NTS client recovers when the server rejects a request with an NTS NAK
+/// (the NTSN kiss code), which indicates the server could not use our cookie.
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NtsNakRecovery {
+    /// Only discard the rejected cookie, and keep using the remaining ones. A
+    /// second NAK in a row indicates that the server no longer accepts any of
+    /// our cookies (e.g. after rotating its keys), and leads to a new key exchange.
+    #[default]
+    DiscardCookie,
+    /// Discard all cookies on any NAK, forcing a new key exchange
+    Rekey,
+}
+
+/// What the client does when no majority of its usable peers agrees on the
+/// time, meaning that at least half of them must be falsetickers.
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoMajorityPolicy {
+    /// Leave the clock alone until a majority agrees again, logging an error
+    /// when this starts
+    #[default]
+    Hold,
+    /// Stop the daemon, as with a step beyond the panic threshold
+    Exit,
+}
+
+/// What the sync watchdog does when the clock was not synchronized for longer
+/// than its timeout
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StaleSyncAction {
+    /// Log a warning
+    #[default]
+    Log,
+    /// Stop the daemon, so a supervisor can restart it or raise an alarm
+    Exit,
+}
+
+/// The NTP version clients use for their requests
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NtpVersion {
+    /// Only use NTPv4
+    #[default]
+    V4,
+    /// Ask servers whether they support the NTPv5 draft, upgrading to it when
+    /// they do, and falling back to NTPv4 when it stops working
+    Auto,
+    /// Only use the NTPv5 draft, for interoperability testing
+    V5,
+}
+
 #[derive(Debug, Copy, Clone)]
 struct ThresholdPart(Option<NtpDuration>);
 
@@ -220,6 +273,13 @@ pub struct SystemConfig {
     #[serde(default = "startup_panic_threshold")]
     pub startup_panic_threshold: StepThreshold,
 
+    /// Accept the first measurement of a usable peer as the truth, and step the
+    /// clock to it regardless of the startup panic threshold. This is meant for
+    /// systems without a real time clock, that boot with a clock far from the
+    /// actual time (e.g. at the unix epoch).
+    #[serde(default)]
+    pub cold_start: bool,
+
     /// The maximum amount distributed amongst all steps except at startup the
     /// daemon is allowed to step the system clock.
     #[serde(deserialize_with = "deserialize_option_threshold", default)]
@@ -238,6 +298,32 @@ pub struct SystemConfig {
     /// Initial poll interval of the system
     #[serde(default = "default_initial_poll")]
     pub initial_poll: PollInterval,
+
+    /// Recovery of NTS clients from a server rejecting their cookies
+    #[serde(default)]
+    pub nts_nak_recovery: NtsNakRecovery,
+
+    /// Behavior when no majority of the peers agrees on the time
+    #[serde(default)]
+    pub no_majority: NoMajorityPolicy,
+
+    /// Maximum time the clock may go without being synchronized before the
+    /// sync watchdog takes its action. The watchdog is disabled when not set.
+    #[serde(deserialize_with = "deserialize_option_threshold", default)]
+    pub sync_watchdog_timeout: Option<NtpDuration>,
+
+    /// Action of the sync watchdog
+    #[serde(default)]
+    pub sync_watchdog_action: StaleSyncAction,
+
+    /// NTP version used by the clients
+    #[serde(default)]
+    pub ntp_version: NtpVersion,
+
+    /// Whether clients send their requests in interleaved mode, so servers
+    /// supporting it can provide more accurate transmit timestamps
+    #[serde(default)]
+    pub interleaved_mode: bool,
 }
 
 impl Default for SystemConfig {
@@ -247,12 +333,19 @@ impl Default for SystemConfig {
 
             panic_threshold: default_panic_threshold(),
             startup_panic_threshold: startup_panic_threshold(),
+            cold_start: false,
             accumulated_threshold: None,
 
             local_stratum: default_local_stratum(),
 
             poll_limits: Default::default(),
             initial_poll: default_initial_poll(),
+            nts_nak_recovery: NtsNakRecovery::default(),
+            no_majority: NoMajorityPolicy::default(),
+            sync_watchdog_timeout: None,
+            sync_watchdog_action: StaleSyncAction::default(),
+            ntp_version: NtpVersion::default(),
+            interleaved_mode: false,
         }
     }
 }
@@ -8,6 +8,48 @@ pub(crate) struct CookieStash {
     cookies: [Vec<u8>; 8],
     read: usize,
     valid: usize,
+    length: Option<CookieLength>,
+}
+
+/// Number of cookies of a new length that must be seen in a row before
+/// that length replaces the learned one
+const LENGTH_CHANGE_THRESHOLD: u8 = 3;
+
+/// Learns the length of the cookies issued by a server. Servers typically
+/// issue fixed length cookies, so a cookie of a different length indicates
+/// corruption. But the length can legitimately change, for instance when
+/// the server rotates its keys, hence a new length is accepted once it
+/// is seen consistently.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CookieLength {
+    expected: Option<usize>,
+    candidate: Option<usize>,
+    candidate_count: u8,
+}
+
+impl CookieLength {
+    fn accept(&mut self, length: usize) -> bool {
+        match self.expected {
+            Some(expected) if expected != length => {
+                if self.candidate == Some(length) {
+                    self.candidate_count += 1;
+                } else {
+                    self.candidate = Some(length);
+                    self.candidate_count = 1;
+                }
+
+                if self.candidate_count < LENGTH_CHANGE_THRESHOLD {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+
+        self.expected = Some(length);
+        self.candidate = None;
+        self.candidate_count = 0;
+        true
+    }
 }
 
 impl std::fmt::Debug for CookieStash {
@@ -16,13 +58,27 @@ impl std::fmt::Debug for CookieStash {
             .field("cookies", &self.cookies.len())
             .field("read", &self.read)
             .field("valid", &self.valid)
+            .field("length", &self.length)
             .finish()
     }
 }
 
 impl CookieStash {
-    /// Store a new cookie
-    pub fn store(&mut self, cookie: Vec<u8>) {
+    /// Reject cookies that deviate from the length of the cookies stored so far
+    pub fn validate_length(&mut self) {
+        if self.length.is_none() {
+            self.length = Some(CookieLength::default());
+        }
+    }
+
+    /// Store a new cookie, returns whether it was accepted
+    pub fn store(&mut self, cookie: Vec<u8>) -> bool {
+        if let Some(length) = self.length.as_mut() {
+            if !length.accept(cookie.len()) {
+                return false;
+            }
+        }
+
         let wpos = (self.read + self.valid) % self.cookies.len();
         self.cookies[wpos] = cookie;
         if self.valid < self.cookies.len() {
@@ -33,6 +89,8 @@ impl CookieStash {
             // newer so just keep the newest cookies.
             self.read = (self.read + 1) % self.cookies.len();
         }
+
+        true
     }
 
     /// Get oldest cookie
@@ -55,6 +113,16 @@ impl CookieStash {
         (self.cookies.len() - self.valid) as u8
     }
 
+    /// The stored cookies, oldest first, without taking them
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.valid).map(|i| self.cookies[(self.read + i) % self.cookies.len()].as_slice())
+    }
+
+    /// Discard all cookies
+    pub fn clear(&mut self) {
+        while self.get().is_some() {}
+    }
+
     #[cfg(test)]
     pub fn len(&self) -> usize {
         self.valid
@@ -79,7 +147,7 @@ mod tests {
     fn test_overfill() {
         let mut stash = CookieStash::default();
         for i in 0..10_u8 {
-            stash.store(vec![i])
+            stash.store(vec![i]);
         }
         assert_eq!(stash.get(), Some(vec![2]));
         assert_eq!(stash.get(), Some(vec![3]));
@@ -100,4 +168,42 @@ mod tests {
             assert_eq!(stash.gap(), 0);
         }
     }
+
+    #[test]
+    fn test_length_validation() {
+        let mut stash = CookieStash::default();
+        stash.validate_length();
+
+        // the first cookie sets the baseline
+        assert!(stash.store(vec![0; 4]));
+        assert!(stash.store(vec![1; 4]));
+
+        // a deviating cookie is rejected
+        assert!(!stash.store(vec![2; 5]));
+        assert_eq!(stash.len(), 2);
+        assert!(stash.store(vec![3; 4]));
+
+        // but a consistent new length is eventually accepted
+        assert!(!stash.store(vec![4; 6]));
+        assert!(!stash.store(vec![5; 6]));
+        assert!(stash.store(vec![6; 6]));
+        assert!(stash.store(vec![7; 6]));
+        assert!(!stash.store(vec![8; 4]));
+        assert_eq!(stash.len(), 5);
+
+        assert_eq!(stash.get(), Some(vec![0; 4]));
+        assert_eq!(stash.get(), Some(vec![1; 4]));
+        assert_eq!(stash.get(), Some(vec![3; 4]));
+        assert_eq!(stash.get(), Some(vec![6; 6]));
+        assert_eq!(stash.get(), Some(vec![7; 6]));
+    }
+
+    #[test]
+    fn test_no_length_validation() {
+        let mut stash = CookieStash::default();
+        for i in 0..8_u8 {
+            assert!(stash.store(vec![i; i as usize]));
+        }
+        assert_eq!(stash.len(), 8);
+    }
 }
@@ -0,0 +1,334 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::PathBuf,
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher_for_aead,
+    cookiestash::CookieStash,
+    encrypted_file::{write_private_file, EncryptedFormat},
+    nts_record::AeadAlgorithm,
+    peer::PeerNtsData,
+    Cipher,
+};
+
+/// Storage for the NTS state of a client, so it can reuse its cookies after a
+/// restart instead of doing a new key exchange.
+///
+/// The stored state contains the keys from the key exchange next to the
+/// cookies, as the cookies can't be used without them. Backends receive it in
+/// serialized form, and must treat it as secret.
+pub trait CookieStore {
+    /// Replace the stored state with `state`
+    fn save(&mut self, state: &[u8]) -> std::io::Result<()>;
+
+    /// The stored state, or `None` if nothing was stored yet
+    fn load(&self) -> std::io::Result<Option<Zeroizing<Vec<u8>>>>;
+}
+
+/// Keeps the state in memory, for instance to carry it over a reconnect.
+#[derive(Debug, Default)]
+pub struct MemoryCookieStore {
+    state: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl CookieStore for MemoryCookieStore {
+    fn save(&mut self, state: &[u8]) -> std::io::Result<()> {
+        self.state = Some(Zeroizing::new(state.to_vec()));
+        Ok(())
+    }
+
+    fn load(&self) -> std::io::Result<Option<Zeroizing<Vec<u8>>>> {
+        Ok(self.state.clone())
+    }
+}
+
+/// Keeps the state in a file, encrypted and authenticated with
+/// AEAD_AES_SIV_CMAC_512 under a key that should be kept elsewhere. A file
+/// that was tampered with, or written under a different key, is rejected.
+pub struct FileCookieStore {
+    path: PathBuf,
+    format: EncryptedFormat,
+}
+
+impl std::fmt::Debug for FileCookieStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileCookieStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl FileCookieStore {
+    // Identifies the file format, and is authenticated along with the contents
+    const MAGIC: &'static [u8] = b"ntpd-rs cookies\x01";
+
+    pub fn new(path: impl Into<PathBuf>, key: [u8; 64]) -> Self {
+        FileCookieStore {
+            path: path.into(),
+            format: EncryptedFormat::new(Self::MAGIC, key.into()),
+        }
+    }
+
+    /// Like [`FileCookieStore::new`], with the key derived from a secret of
+    /// any length using HKDF-SHA256
+    pub fn with_secret(path: impl Into<PathBuf>, secret: &[u8]) -> Self {
+        FileCookieStore {
+            path: path.into(),
+            format: EncryptedFormat::with_secret(Self::MAGIC, b"ntpd-rs cookie store key", secret),
+        }
+    }
+}
+
+impl CookieStore for FileCookieStore {
+    fn save(&mut self, state: &[u8]) -> std::io::Result<()> {
+        write_private_file(&self.path, &self.format.encrypt(state)?)
+    }
+
+    fn load(&self) -> std::io::Result<Option<Zeroizing<Vec<u8>>>> {
+        match std::fs::read(&self.path) {
+            Ok(contents) => self.format.decrypt(&contents).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub(crate) fn encode_nts_data(nts: &PeerNtsData) -> Zeroizing<Vec<u8>> {
+    let mut state = Zeroizing::new(vec![]);
+
+    state.extend_from_slice(&(nts.algorithm as u16).to_be_bytes());
+    let c2s = nts.c2s.key_bytes();
+    let s2c = nts.s2c.key_bytes();
+    state.extend_from_slice(&(c2s.len() as u16).to_be_bytes());
+    state.extend_from_slice(c2s);
+    state.extend_from_slice(s2c);
+
+    for cookie in nts.cookies.iter() {
+        state.extend_from_slice(&(cookie.len() as u16).to_be_bytes());
+        state.extend_from_slice(cookie);
+    }
+
+    state
+}
+
+/// Like [`encode_nts_data`], preceded by the NTP server the state is for
+pub(crate) fn encode_nts_state(remote: &str, port: u16, nts: &PeerNtsData) -> Zeroizing<Vec<u8>> {
+    let mut state = Zeroizing::new(vec![]);
+
+    state.extend_from_slice(&(remote.len() as u16).to_be_bytes());
+    state.extend_from_slice(remote.as_bytes());
+    state.extend_from_slice(&port.to_be_bytes());
+    state.extend_from_slice(&encode_nts_data(nts));
+
+    state
+}
+
+pub(crate) fn decode_nts_state(state: &[u8]) -> std::io::Result<(String, u16, PeerNtsData)> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid stored nts state");
+
+    let mut data = state;
+    let remote_len = take_u16(&mut data).ok_or_else(invalid)? as usize;
+    let remote = take(&mut data, remote_len).ok_or_else(invalid)?;
+    let remote = String::from_utf8(remote.to_vec()).map_err(|_| invalid())?;
+    let port = take_u16(&mut data).ok_or_else(invalid)?;
+
+    Ok((remote, port, decode_nts_data(data)?))
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (taken, rest) = (data.len() >= n).then(|| data.split_at(n))?;
+    *data = rest;
+    Some(taken)
+}
+
+fn take_u16(data: &mut &[u8]) -> Option<u16> {
+    take(data, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub(crate) fn decode_nts_data(state: &[u8]) -> std::io::Result<PeerNtsData> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid stored nts state");
+
+    let mut data = state;
+    let id = take_u16(&mut data).ok_or_else(invalid)?;
+    let algorithm = AeadAlgorithm::try_deserialize(id).ok_or_else(invalid)?;
+    let key_len = take_u16(&mut data).ok_or_else(invalid)? as usize;
+    let c2s = take(&mut data, key_len).ok_or_else(invalid)?;
+    let s2c = take(&mut data, key_len).ok_or_else(invalid)?;
+    let c2s: Box<dyn Cipher> = cipher_for_aead(id, c2s).map_err(|_| invalid())?;
+    let s2c: Box<dyn Cipher> = cipher_for_aead(id, s2c).map_err(|_| invalid())?;
+
+    let mut cookies = CookieStash::default();
+    while !data.is_empty() {
+        let len = take_u16(&mut data).ok_or_else(invalid)? as usize;
+        let cookie = take(&mut data, len).ok_or_else(invalid)?;
+        cookies.store(cookie.to_vec());
+    }
+
+    Ok(PeerNtsData {
+        cookies,
+        c2s,
+        s2c,
+        algorithm,
+        uid_prefix: None,
+        consecutive_naks: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{packet::AesSivCmac256, KeyExchangeResult, PollInterval};
+
+    use super::*;
+
+    fn nts_data() -> PeerNtsData {
+        let mut cookies = CookieStash::default();
+        for i in 0..3 {
+            cookies.store(vec![i; 64]);
+        }
+
+        PeerNtsData {
+            cookies,
+            c2s: Box::new(AesSivCmac256::new([1; 32].into())),
+            s2c: Box::new(AesSivCmac256::new([2; 32].into())),
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            uid_prefix: None,
+            consecutive_naks: 0,
+        }
+    }
+
+    fn assert_restored(mut restored: PeerNtsData) {
+        assert_eq!(restored.algorithm, AeadAlgorithm::AeadAesSivCmac256);
+        assert_eq!(restored.c2s.key_bytes(), &[1; 32]);
+        assert_eq!(restored.s2c.key_bytes(), &[2; 32]);
+        assert_eq!(restored.cookies.len(), 3);
+        for i in 0..3 {
+            assert_eq!(restored.cookies.get(), Some(vec![i; 64]));
+        }
+    }
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ntpd-rs-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryCookieStore::default();
+        assert!(PeerNtsData::load(&store).unwrap().is_none());
+
+        let mut nts = nts_data();
+        nts.save(&mut store).unwrap();
+        // the stored state is independent of later use of the cookies
+        nts.poll_message(PollInterval::default()).unwrap();
+
+        assert_restored(PeerNtsData::load(&store).unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = temporary_path("cookies");
+        let mut store = FileCookieStore::new(&path, [0x55; 64]);
+        assert!(PeerNtsData::load(&store).unwrap().is_none());
+
+        nts_data().save(&mut store).unwrap();
+
+        // the cookies are not stored in the clear
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!contents.windows(64).any(|window| window == [1; 64]));
+
+        let reopened = FileCookieStore::new(&path, [0x55; 64]);
+        assert_restored(PeerNtsData::load(&reopened).unwrap().unwrap());
+
+        // a different key can't read it
+        let other = FileCookieStore::new(&path, [0x56; 64]);
+        assert_eq!(
+            PeerNtsData::load(&other).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_tampered() {
+        let path = temporary_path("cookies-tampered");
+        let mut store = FileCookieStore::new(&path, [0x55; 64]);
+        nts_data().save(&mut store).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+
+        for index in [0, FileCookieStore::MAGIC.len(), contents.len() - 1] {
+            let mut tampered = contents.clone();
+            tampered[index] ^= 1;
+            std::fs::write(&path, tampered).unwrap();
+            assert_eq!(
+                PeerNtsData::load(&store).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+
+        std::fs::write(&path, &contents[..20]).unwrap();
+        assert_eq!(
+            PeerNtsData::load(&store).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_key_exchange_result_store() {
+        let mut store = MemoryCookieStore::default();
+        assert!(KeyExchangeResult::load(&store).unwrap().is_none());
+
+        let result = KeyExchangeResult {
+            remote: "time.example.com".into(),
+            port: 4123,
+            nts: Box::new(nts_data()),
+        };
+        result.save(&mut store).unwrap();
+
+        let restored = KeyExchangeResult::load(&store).unwrap().unwrap();
+        assert_eq!(restored.remote, "time.example.com");
+        assert_eq!(restored.port, 4123);
+        assert_restored(*restored.nts);
+
+        // the state of a peer can be saved later on, for the server it polls
+        let mut nts = nts_data();
+        nts.poll_message(PollInterval::default()).unwrap();
+        nts.save_with_server("192.0.2.1", 123, &mut store).unwrap();
+        let restored = KeyExchangeResult::load(&store).unwrap().unwrap();
+        assert_eq!(restored.remote, "192.0.2.1");
+        assert_eq!(restored.nts.cookies.len(), 2);
+    }
+
+    #[test]
+    fn test_file_store_with_secret() {
+        let path = temporary_path("cookies-secret");
+        let mut store = FileCookieStore::with_secret(&path, b"secret");
+        nts_data().save(&mut store).unwrap();
+
+        let reopened = FileCookieStore::with_secret(&path, b"secret");
+        assert_restored(PeerNtsData::load(&reopened).unwrap().unwrap());
+        let other = FileCookieStore::with_secret(&path, b"other");
+        assert!(PeerNtsData::load(&other).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let state = encode_nts_data(&nts_data());
+        assert!(decode_nts_data(&state).is_ok());
+
+        // truncated cookie
+        assert!(decode_nts_data(&state[..state.len() - 1]).is_err());
+        // unknown algorithm
+        let mut unknown = state.to_vec();
+        unknown[1] = 0;
+        assert!(decode_nts_data(&unknown).is_err());
+        // keys too short for the algorithm
+        assert!(decode_nts_data(&[0, 15, 0, 1, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    io::{Error, ErrorKind, Write},
+    path::Path,
+};
+
+use aes_siv::{siv::Aes256Siv, Key};
+use zeroize::Zeroizing;
+
+use crate::{packet::AesSivCmac512, Cipher};
+
+/// Format of the files in which we keep secrets, such as stored keys and
+/// cookies: magic bytes identifying the contents, followed by the nonce, the
+/// SIV tag and the ciphertext of AEAD_AES_SIV_CMAC_512. The magic bytes are
+/// authenticated along with the contents.
+pub(crate) struct EncryptedFormat {
+    magic: &'static [u8],
+    cipher: AesSivCmac512,
+}
+
+impl EncryptedFormat {
+    pub(crate) fn new(magic: &'static [u8], key: Key<Aes256Siv>) -> Self {
+        EncryptedFormat {
+            magic,
+            cipher: AesSivCmac512::new(key),
+        }
+    }
+
+    /// Like [`EncryptedFormat::new`], with the key derived from a secret of any
+    /// length using HKDF-SHA256. The `info` keeps the keys for different uses of
+    /// the same secret apart.
+    pub(crate) fn with_secret(magic: &'static [u8], info: &[u8], secret: &[u8]) -> Self {
+        let mut key = Key::<Aes256Siv>::default();
+        hkdf::Hkdf::<sha2::Sha256>::new(None, secret)
+            .expand(info, &mut key)
+            .expect("64 bytes is a valid output length for HKDF-SHA256");
+        Self::new(magic, key)
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut ciphertext = Zeroizing::new(plaintext.to_vec());
+        let (tag, nonce) = self
+            .cipher
+            .encrypt_in_place_detached(&mut ciphertext, self.magic)?;
+
+        let mut contents = self.magic.to_vec();
+        contents.extend_from_slice(&nonce);
+        contents.extend_from_slice(&tag);
+        contents.extend_from_slice(&ciphertext);
+        Ok(contents)
+    }
+
+    /// Decrypt `contents` written by [`EncryptedFormat::encrypt`]. Contents
+    /// that were tampered with, or encrypted under another key, are rejected.
+    pub(crate) fn decrypt(&self, contents: &[u8]) -> std::io::Result<Zeroizing<Vec<u8>>> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid or tampered encrypted file");
+
+        let nonce_len = self.cipher.nonce_len();
+        let data = contents
+            .strip_prefix(self.magic)
+            .filter(|data| data.len() >= nonce_len)
+            .ok_or_else(invalid)?;
+        let (nonce, ciphertext) = data.split_at(nonce_len);
+
+        let plaintext = self
+            .cipher
+            .decrypt(nonce, ciphertext, self.magic)
+            .map_err(|_| invalid())?;
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+/// Replace the file at `path` with `contents`, readable by the owner only. The
+/// contents are written to a temporary file that replaces the old one once it
+/// is on disk, so a crash can't leave a truncated file behind.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    // the mode is only used for new files
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_format() {
+        let format = EncryptedFormat::with_secret(b"magic", b"test", b"secret");
+        let contents = format.encrypt(b"plaintext").unwrap();
+        assert!(contents.starts_with(b"magic"));
+        assert!(!contents.windows(9).any(|window| window == b"plaintext"));
+        assert_eq!(format.decrypt(&contents).unwrap().as_slice(), b"plaintext");
+
+        // another key, or other magic bytes, can't read it
+        let other = EncryptedFormat::with_secret(b"magic", b"other", b"secret");
+        assert!(other.decrypt(&contents).is_err());
+        let other = EncryptedFormat::with_secret(b"MAGIC", b"test", b"secret");
+        assert!(other.decrypt(&contents).is_err());
+
+        assert!(format.decrypt(&contents[..20]).is_err());
+        assert!(format.decrypt(b"").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("ntpd-rs-{}-private", std::process::id()));
+        std::fs::write(&path, b"old contents").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private_file(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
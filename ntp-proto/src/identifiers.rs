@@ -16,8 +16,13 @@ impl ReferenceId {
     // Network Time Security (NTS) negative-acknowledgment (NAK), from rfc8915
     pub const KISS_NTSN: ReferenceId = ReferenceId(u32::from_be_bytes(*b"NTSN"));
 
+    /// Reference id of a server with address `addr`, as sent by clients of that
+    /// server. For IPv4 this is the address itself, for IPv6 the first four
+    /// octets of the MD5 hash of the address (rfc5905, section 7.3). IPv4
+    /// addresses mapped into IPv6, as seen on dual-stack sockets, are treated
+    /// as the IPv4 address they are.
     pub fn from_ip(addr: IpAddr) -> ReferenceId {
-        match addr {
+        match addr.to_canonical() {
             IpAddr::V4(addr) => ReferenceId(u32::from_be_bytes(addr.octets())),
             IpAddr::V6(addr) => ReferenceId(u32::from_be_bytes(
                 Md5::digest(addr.octets())[0..4].try_into().unwrap(),
@@ -49,11 +54,67 @@ impl ReferenceId {
         self.0.to_be_bytes()
     }
 
-    pub(crate) fn from_bytes(bits: [u8; 4]) -> ReferenceId {
+    /// Reference id from its raw bytes. For reference clocks, this is a
+    /// four character ascii string such as `b"GPS\0"`
+    pub fn from_bytes(bits: [u8; 4]) -> ReferenceId {
         ReferenceId(u32::from_be_bytes(bits))
     }
 }
 
+/// The code of a Kiss-o'-Death packet, in which a server tells its clients to
+/// change their behavior instead of giving them the time
+/// (rfc5905, section 7.4)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KissCode {
+    /// Access denied, the client must stop sending requests
+    Deny,
+    /// Access restricted, the client must stop sending requests
+    Rstr,
+    /// Rate exceeded, the client must reduce its poll rate
+    Rate,
+    /// The server could not validate our NTS cookie (rfc8915)
+    Ntsn,
+    /// A code we don't know, which is ignored
+    Unknown(ReferenceId),
+}
+
+impl KissCode {
+    pub fn from_reference_id(reference_id: ReferenceId) -> Self {
+        match reference_id {
+            ReferenceId::KISS_DENY => KissCode::Deny,
+            ReferenceId::KISS_RSTR => KissCode::Rstr,
+            ReferenceId::KISS_RATE => KissCode::Rate,
+            ReferenceId::KISS_NTSN => KissCode::Ntsn,
+            other => KissCode::Unknown(other),
+        }
+    }
+
+    /// Whether the server asks us to stop sending requests
+    pub fn is_denial(&self) -> bool {
+        matches!(self, KissCode::Deny | KissCode::Rstr)
+    }
+}
+
+impl std::fmt::Display for KissCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KissCode::Deny => f.write_str("DENY"),
+            KissCode::Rstr => f.write_str("RSTR"),
+            KissCode::Rate => f.write_str("RATE"),
+            KissCode::Ntsn => f.write_str("NTSN"),
+            KissCode::Unknown(reference_id) => {
+                for byte in reference_id.to_bytes() {
+                    match byte {
+                        b' '..=b'~' => write!(f, "{}", byte as char)?,
+                        _ => write!(f, "\\x{byte:02x}")?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -83,6 +144,28 @@ mod tests {
         assert!(b.is_deny());
     }
 
+    #[test]
+    fn kiss_code_from_reference_id() {
+        assert_eq!(
+            KissCode::from_reference_id(ReferenceId::KISS_RATE),
+            KissCode::Rate
+        );
+        assert!(KissCode::from_reference_id(ReferenceId::KISS_DENY).is_denial());
+        assert!(KissCode::from_reference_id(ReferenceId::KISS_RSTR).is_denial());
+        assert!(!KissCode::from_reference_id(ReferenceId::KISS_NTSN).is_denial());
+
+        let unknown = KissCode::from_reference_id(ReferenceId::from_bytes(*b"AUTH"));
+        assert_eq!(
+            unknown,
+            KissCode::Unknown(ReferenceId::from_bytes(*b"AUTH"))
+        );
+        assert_eq!(unknown.to_string(), "AUTH");
+        assert_eq!(
+            KissCode::from_reference_id(ReferenceId::from_bytes(*b"X\0\x01Y")).to_string(),
+            "X\\x00\\x01Y"
+        );
+    }
+
     #[test]
     fn referenceid_from_ipv4() {
         let ip: IpAddr = "12.34.56.78".parse().unwrap();
@@ -90,8 +173,15 @@ mod tests {
         let a = ReferenceId::from_ip(ip);
         let b = ReferenceId::from_bytes(rep);
         assert_eq!(a, b);
+    }
+
+    #[test]
+    fn referenceid_from_ipv6() {
+        // first four octets of the md5 hash of the address
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(ReferenceId::from_ip(ip).to_bytes(), [57, 171, 155, 55]);
 
-        // TODO: Generate and add a testcase for ipv6 adresses once
-        // we have access to an ipv6 network.
+        let mapped: IpAddr = "::ffff:12.34.56.78".parse().unwrap();
+        assert_eq!(ReferenceId::from_ip(mapped).to_bytes(), [12, 34, 56, 78]);
     }
 }
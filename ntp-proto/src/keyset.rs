@@ -4,11 +4,13 @@ use std::{
 };
 
 use aead::{generic_array::GenericArray, KeyInit};
+use zeroize::Zeroizing;
 
 use crate::{
     arrayvec::ArrayVec,
+    encrypted_file::EncryptedFormat,
     nts_record::AeadAlgorithm,
-    packet::{AesSivCmac256, AesSivCmac512, CipherHolder, DecryptError, ExtensionField},
+    packet::{cipher_for_aead, AesSivCmac512, CipherHolder, DecryptError, ExtensionField},
     Cipher, CipherProvider,
 };
 
@@ -18,6 +20,13 @@ pub struct DecodedServerCookie {
     pub c2s: Box<dyn Cipher>,
 }
 
+impl DecodedServerCookie {
+    /// Id of the AEAD algorithm the cookie was issued under
+    pub fn aead_id(&self) -> u16 {
+        self.algorithm as u16
+    }
+}
+
 impl std::fmt::Debug for DecodedServerCookie {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DecodedServerCookie")
@@ -26,10 +35,20 @@ impl std::fmt::Debug for DecodedServerCookie {
     }
 }
 
-#[derive(Debug)]
 pub struct KeySetProvider {
     current: Arc<KeySet>,
     history: usize,
+    master_secret: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for KeySetProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySetProvider")
+            .field("current", &self.current)
+            .field("history", &self.history)
+            .field("derived", &self.master_secret.is_some())
+            .finish()
+    }
 }
 
 impl KeySetProvider {
@@ -46,11 +65,75 @@ impl KeySetProvider {
                 primary: 0,
             }),
             history,
+            master_secret: None,
         }
     }
 
+    /// Create a new keysetprovider that derives its keys from a master
+    /// secret, with one key per epoch. The epoch is used as the key id in the
+    /// cookies, so any server knowing the master secret and the current epoch
+    /// can decode them. The keys of history previous epochs stay valid.
+    pub fn from_master_secret(master_secret: &[u8], epoch: u32, history: usize) -> Self {
+        let mut provider = KeySetProvider {
+            current: Arc::new(KeySet {
+                keys: vec![],
+                id_offset: epoch,
+                primary: 0,
+            }),
+            history,
+            master_secret: Some(Zeroizing::new(master_secret.to_vec())),
+        };
+        provider.rotate_to_epoch(epoch);
+        provider
+    }
+
+    /// Derive the key for an epoch from the master secret, using HKDF-SHA256
+    fn derive_key(master_secret: &[u8], epoch: u32) -> AesSivCmac512 {
+        let mut info = *b"ntpd-rs nts cookie key \0\0\0\0";
+        info[23..].copy_from_slice(&epoch.to_be_bytes());
+
+        let mut key = GenericArray::default();
+        hkdf::Hkdf::<sha2::Sha256>::new(None, master_secret)
+            .expand(&info, &mut key)
+            .expect("64 bytes is a valid output length for HKDF-SHA256");
+        AesSivCmac512::new(key)
+    }
+
+    /// The epoch of the current primary key, if the keys are derived from a
+    /// master secret
+    pub fn epoch(&self) -> Option<u32> {
+        self.master_secret
+            .as_ref()
+            .map(|_| self.current.id_offset.wrapping_add(self.current.primary))
+    }
+
+    /// Make the key of epoch the primary key, keeping the keys of history
+    /// previous epochs valid. Only has an effect when the keys are derived
+    /// from a master secret.
+    pub fn rotate_to_epoch(&mut self, epoch: u32) {
+        let master_secret = match &self.master_secret {
+            Some(master_secret) => master_secret,
+            None => return,
+        };
+
+        let first = epoch.saturating_sub(self.history as u32);
+        let keys: Vec<_> = (first..=epoch)
+            .map(|epoch| Self::derive_key(master_secret, epoch))
+            .collect();
+        self.current = Arc::new(KeySet {
+            id_offset: first,
+            primary: keys.len() as u32 - 1,
+            keys,
+        })
+    }
+
     /// Rotate a new key in as primary, forgetting an old one if needed
     pub fn rotate(&mut self) {
+        if let Some(epoch) = self.epoch() {
+            self.rotate_to_epoch(epoch.wrapping_add(1));
+            return;
+        }
+
         let next_key = AesSivCmac512::new(aes_siv::Aes256SivAead::generate_key(rand::thread_rng()));
         let mut keys = Vec::with_capacity((self.history + 1).min(self.current.keys.len() + 1));
         for key in self.current.keys
@@ -68,7 +151,7 @@ impl KeySetProvider {
                 .current
                 .id_offset
                 .wrapping_add(self.current.keys.len().saturating_sub(self.history) as u32),
-            primary: keys.len() as u32,
+            primary: keys.len() as u32 - 1,
             keys,
         })
     }
@@ -84,7 +167,7 @@ impl KeySetProvider {
         let id_offset = u32::from_be_bytes(buf[8..12].try_into().unwrap());
         let primary = u32::from_be_bytes(buf[12..16].try_into().unwrap());
         let len = u32::from_be_bytes(buf[16..20].try_into().unwrap());
-        if primary > len {
+        if primary >= len {
             return Err(std::io::ErrorKind::Other.into());
         }
         let mut keys = vec![];
@@ -100,6 +183,7 @@ impl KeySetProvider {
                     primary,
                 }),
                 history,
+                master_secret: None,
             },
             time,
         ))
@@ -119,6 +203,45 @@ impl KeySetProvider {
         Ok(())
     }
 
+    /// Magic bytes identifying an encrypted keyset, also authenticated
+    const ENCRYPTED_MAGIC: &'static [u8] = b"NTSKEYS1";
+
+    /// The format of stored keysets, encrypted with a key derived from a secret
+    fn storage_format(storage_secret: &[u8]) -> EncryptedFormat {
+        EncryptedFormat::with_secret(
+            Self::ENCRYPTED_MAGIC,
+            b"ntpd-rs keyset storage key",
+            storage_secret,
+        )
+    }
+
+    /// Like [`KeySetProvider::load`], for a keyset stored with
+    /// [`KeySetProvider::store_encrypted`] under the same `storage_secret`
+    pub fn load_encrypted(
+        reader: &mut impl Read,
+        storage_secret: &[u8],
+        history: usize,
+    ) -> std::io::Result<(Self, std::time::SystemTime)> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        let plaintext = Self::storage_format(storage_secret).decrypt(&data)?;
+        Self::load(&mut plaintext.as_slice(), history)
+    }
+
+    /// Like [`KeySetProvider::store`], but encrypted with a key derived from
+    /// `storage_secret`, so the keys are of no use to anyone reading the file
+    pub fn store_encrypted(
+        &self,
+        writer: &mut impl Write,
+        storage_secret: &[u8],
+    ) -> std::io::Result<()> {
+        let mut plaintext = Zeroizing::new(vec![]);
+        self.store(&mut *plaintext)?;
+
+        writer.write_all(&Self::storage_format(storage_secret).encrypt(&plaintext)?)
+    }
+
     /// Get the current KeySet
     pub fn get(&self) -> Arc<KeySet> {
         self.current.clone()
@@ -172,7 +295,7 @@ impl KeySet {
     }
 
     pub(crate) fn decode_cookie(&self, cookie: &[u8]) -> Result<DecodedServerCookie, DecryptError> {
-        if cookie.len() < 20 {
+        if cookie.len() < 22 {
             return Err(DecryptError);
         }
 
@@ -183,6 +306,9 @@ impl KeySet {
         }
 
         let cipher_text_length = u16::from_be_bytes(cookie[4..6].try_into().unwrap()) as usize;
+        if cipher_text_length > cookie.len() - 22 {
+            return Err(DecryptError);
+        }
 
         let nonce = &cookie[6..22];
         let ciphertext = &cookie[22..][..cipher_text_length];
@@ -192,39 +318,28 @@ impl KeySet {
             AeadAlgorithm::try_deserialize(u16::from_be_bytes(plaintext[0..2].try_into().unwrap()))
                 .ok_or(DecryptError)?;
 
-        Ok(match algorithm {
-            AeadAlgorithm::AeadAesSivCmac256 => {
-                if plaintext.len() != 2 + 32 + 32 {
-                    return Err(DecryptError);
-                }
-                DecodedServerCookie {
-                    algorithm,
-                    s2c: Box::new(AesSivCmac256::new(GenericArray::clone_from_slice(
-                        &plaintext[2..34],
-                    ))),
-                    c2s: Box::new(AesSivCmac256::new(GenericArray::clone_from_slice(
-                        &plaintext[34..66],
-                    ))),
-                }
-            }
-            AeadAlgorithm::AeadAesSivCmac512 => {
-                if plaintext.len() != 2 + 64 + 64 {
-                    return Err(DecryptError);
-                }
-                DecodedServerCookie {
-                    algorithm,
-                    s2c: Box::new(AesSivCmac512::new(GenericArray::clone_from_slice(
-                        &plaintext[2..66],
-                    ))),
-                    c2s: Box::new(AesSivCmac512::new(GenericArray::clone_from_slice(
-                        &plaintext[66..130],
-                    ))),
-                }
-            }
+        let key_size = algorithm.key_size();
+        if plaintext.len() != 2 + 2 * key_size {
+            return Err(DecryptError);
+        }
+
+        let (s2c, c2s) = plaintext[2..].split_at(key_size);
+        Ok(DecodedServerCookie {
+            algorithm,
+            s2c: cipher_for_aead(algorithm as u16, s2c).map_err(|_| DecryptError)?,
+            c2s: cipher_for_aead(algorithm as u16, c2s).map_err(|_| DecryptError)?,
         })
     }
 }
 
+impl KeySet {
+    /// Id of the AEAD algorithm a cookie issued with one of our keys was issued
+    /// under, fails if the cookie was not issued by us or its key has expired
+    pub fn cookie_aead_id(&self, cookie: &[u8]) -> Result<u16, DecryptError> {
+        self.decode_cookie(cookie).map(|decoded| decoded.aead_id())
+    }
+}
+
 impl CipherProvider for KeySet {
     fn get(&self, context: &[ExtensionField<'_>]) -> Option<CipherHolder<'_>> {
         let mut decoded = None;
@@ -258,6 +373,8 @@ mod tests {
 
     use std::io::Cursor;
 
+    use crate::packet::AesSivCmac256;
+
     use super::*;
 
     #[test]
@@ -325,6 +442,31 @@ mod tests {
         assert_eq!(decoded.c2s.key_bytes(), round.c2s.key_bytes());
     }
 
+    #[test]
+    fn cookie_aead_id() {
+        let keyset = KeySetProvider::new(1).get();
+        for algorithm in [
+            AeadAlgorithm::AeadAesSivCmac256,
+            AeadAlgorithm::AeadAesSivCmac512,
+        ] {
+            let key: Vec<u8> = (0..algorithm.key_size() as u8).collect();
+            let decoded = DecodedServerCookie {
+                algorithm,
+                s2c: cipher_for_aead(algorithm as u16, &key).unwrap(),
+                c2s: cipher_for_aead(algorithm as u16, &key).unwrap(),
+            };
+            assert_eq!(decoded.aead_id(), algorithm as u16);
+
+            let encoded = keyset.encode_cookie(&decoded);
+            assert_eq!(keyset.cookie_aead_id(&encoded).unwrap(), algorithm as u16);
+
+            // truncated cookies are rejected
+            for length in [0, 20, 21, 22, encoded.len() - 1] {
+                assert!(keyset.cookie_aead_id(&encoded[..length]).is_err());
+            }
+        }
+    }
+
     #[test]
     fn test_save_restore() {
         let mut provider = KeySetProvider::new(8);
@@ -351,6 +493,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_save_restore_encrypted() {
+        let mut provider = KeySetProvider::new(8);
+        provider.rotate();
+        let mut output = vec![];
+        provider.store_encrypted(&mut output, b"secret").unwrap();
+
+        // the keys are not stored in the clear
+        let mut plain = vec![];
+        provider.store(&mut plain).unwrap();
+        assert!(!output
+            .windows(64)
+            .any(|window| window == provider.get().keys[0].key_bytes()));
+        assert_ne!(output.len(), plain.len());
+
+        let (copy, _) =
+            KeySetProvider::load_encrypted(&mut output.as_slice(), b"secret", 8).unwrap();
+        assert_eq!(provider.get().primary, copy.get().primary);
+        assert_eq!(provider.get().id_offset, copy.get().id_offset);
+        for i in 0..provider.get().keys.len() {
+            assert_eq!(
+                provider.get().keys[i].key_bytes(),
+                copy.get().keys[i].key_bytes()
+            );
+        }
+
+        assert!(KeySetProvider::load_encrypted(&mut output.as_slice(), b"other", 8).is_err());
+        assert!(KeySetProvider::load_encrypted(&mut plain.as_slice(), b"secret", 8).is_err());
+        let last = output.len() - 1;
+        output[last] ^= 1;
+        assert!(KeySetProvider::load_encrypted(&mut output.as_slice(), b"secret", 8).is_err());
+    }
+
     #[test]
     fn old_cookie_still_valid() {
         let decoded = DecodedServerCookie {
@@ -378,4 +553,90 @@ mod tests {
 
         assert!(provider.get().decode_cookie(&encoded).is_err());
     }
+
+    #[test]
+    fn rotated_key_encodes_cookies() {
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+
+        let mut provider = KeySetProvider::new(1);
+        for _ in 0..3 {
+            provider.rotate();
+            // the rotated in key is used for new cookies
+            let encoded = provider.get().encode_cookie(&decoded);
+            assert_eq!(
+                encoded[0..4],
+                (provider.get().id_offset + provider.get().primary).to_be_bytes()
+            );
+            assert!(provider.get().decode_cookie(&encoded).is_ok());
+        }
+    }
+
+    #[test]
+    fn load_rejects_primary_out_of_range() {
+        let mut provider = KeySetProvider::new(1);
+        provider.rotate();
+
+        let mut output = Vec::<u8>::new();
+        provider.store(&mut output).unwrap();
+        // point the primary key just past the stored keys
+        let len = u32::from_be_bytes(output[16..20].try_into().unwrap());
+        output[12..16].copy_from_slice(&len.to_be_bytes());
+
+        let mut input = Cursor::new(output);
+        assert!(KeySetProvider::load(&mut input, 1).is_err());
+    }
+
+    #[test]
+    fn derived_cookie_valid_within_window() {
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let secret = b"some master secret";
+
+        let mut provider = KeySetProvider::from_master_secret(secret, 10, 2);
+        assert_eq!(provider.epoch(), Some(10));
+        let encoded = provider.get().encode_cookie(&decoded);
+        assert_eq!(encoded[0..4], 10_u32.to_be_bytes());
+
+        // another server with the same secret accepts it too
+        for epoch in 10..=12 {
+            let other = KeySetProvider::from_master_secret(secret, epoch, 2);
+            let round = other.get().decode_cookie(&encoded).unwrap();
+            assert_eq!(decoded.algorithm, round.algorithm);
+            assert_eq!(decoded.s2c.key_bytes(), round.s2c.key_bytes());
+            assert_eq!(decoded.c2s.key_bytes(), round.c2s.key_bytes());
+        }
+
+        provider.rotate();
+        assert_eq!(provider.epoch(), Some(11));
+        assert!(provider.get().decode_cookie(&encoded).is_ok());
+        provider.rotate_to_epoch(12);
+        assert!(provider.get().decode_cookie(&encoded).is_ok());
+
+        // outside of the window
+        provider.rotate();
+        assert!(provider.get().decode_cookie(&encoded).is_err());
+        let other = KeySetProvider::from_master_secret(secret, 9, 2);
+        assert!(other.get().decode_cookie(&encoded).is_err());
+
+        // or with a different secret
+        let other = KeySetProvider::from_master_secret(b"other master secret", 10, 2);
+        assert!(other.get().decode_cookie(&encoded).is_err());
+    }
+
+    #[test]
+    fn derived_keys_early_epochs() {
+        let provider = KeySetProvider::from_master_secret(b"secret", 1, 7);
+        assert_eq!(provider.get().keys.len(), 2);
+        assert_eq!(provider.get().id_offset, 0);
+        assert_eq!(provider.epoch(), Some(1));
+
+        assert_eq!(KeySetProvider::new(7).epoch(), None);
+    }
 }
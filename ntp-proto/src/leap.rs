@@ -0,0 +1,160 @@
+use crate::{NtpDuration, NtpLeapIndicator, NtpTimestamp};
+
+/// Timestamps closer than this to a leap second are ambiguous, as the clock
+/// either repeats or skips a second around it.
+const LEAP_AMBIGUITY: NtpDuration = NtpDuration::ONE;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// A leap second announced through the leap indicator, which takes effect at
+/// the end of the (UTC) month in which it is announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeapSchedule {
+    leap_time: NtpTimestamp,
+    indicator: NtpLeapIndicator,
+}
+
+impl LeapSchedule {
+    /// The leap second announced by `indicator` at time `now`, if any.
+    ///
+    /// Shortly after the leap the indicator may still be set, in which case the
+    /// leap that just happened is returned.
+    pub fn from_indicator(indicator: NtpLeapIndicator, now: NtpTimestamp) -> Option<Self> {
+        match indicator {
+            NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59 => Some(LeapSchedule {
+                leap_time: next_month_start(now - LEAP_AMBIGUITY),
+                indicator,
+            }),
+            NtpLeapIndicator::NoWarning | NtpLeapIndicator::Unknown => None,
+        }
+    }
+
+    /// The moment of the leap, midnight at the start of the next month
+    pub fn leap_time(&self) -> NtpTimestamp {
+        self.leap_time
+    }
+
+    pub fn indicator(&self) -> NtpLeapIndicator {
+        self.indicator
+    }
+
+    /// Whether `now` lies in the ambiguous second around the leap, during which
+    /// timestamps can't be trusted to be unique or complete.
+    pub fn in_progress(&self, now: NtpTimestamp) -> bool {
+        (now - self.leap_time).abs() < LEAP_AMBIGUITY
+    }
+}
+
+/// Midnight at the start of the month following `now`
+fn next_month_start(now: NtpTimestamp) -> NtpTimestamp {
+    let (seconds, _) = now.to_unix_time(now.era());
+    let days = seconds.div_euclid(SECONDS_PER_DAY);
+    let (year, month, _) = civil_from_days(days);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+
+    NtpTimestamp::from_unix_time(days_from_civil(year, month, 1) * SECONDS_PER_DAY, 0)
+}
+
+// Conversion between days since the unix epoch and dates in the proleptic
+// gregorian calendar, per http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2017-01-01T00:00:00Z, the most recent leap second
+    const LEAP_2017: u32 = 3_692_217_600;
+
+    fn timestamp(seconds: u32, nanos: u32) -> NtpTimestamp {
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, nanos)
+    }
+
+    #[test]
+    fn test_leap_schedule() {
+        let leap = timestamp(LEAP_2017, 0);
+
+        // announced during the last day of 2016
+        let schedule =
+            LeapSchedule::from_indicator(NtpLeapIndicator::Leap61, timestamp(LEAP_2017 - 43200, 0))
+                .unwrap();
+        assert_eq!(schedule.leap_time(), leap);
+        assert_eq!(schedule.indicator(), NtpLeapIndicator::Leap61);
+
+        // and early in december
+        let schedule = LeapSchedule::from_indicator(
+            NtpLeapIndicator::Leap59,
+            timestamp(LEAP_2017 - 30 * 86400, 0),
+        )
+        .unwrap();
+        assert_eq!(schedule.leap_time(), leap);
+
+        assert!(LeapSchedule::from_indicator(NtpLeapIndicator::NoWarning, leap).is_none());
+        assert!(LeapSchedule::from_indicator(NtpLeapIndicator::Unknown, leap).is_none());
+    }
+
+    #[test]
+    fn test_leap_in_progress() {
+        let in_progress = |seconds, nanos| {
+            let now = timestamp(seconds, nanos);
+            LeapSchedule::from_indicator(NtpLeapIndicator::Leap61, now)
+                .unwrap()
+                .in_progress(now)
+        };
+
+        assert!(!in_progress(LEAP_2017 - 2, 0));
+        assert!(!in_progress(LEAP_2017 - 1, 0));
+        assert!(in_progress(LEAP_2017 - 1, 500_000_000));
+        assert!(in_progress(LEAP_2017, 0));
+        assert!(in_progress(LEAP_2017, 500_000_000));
+        assert!(!in_progress(LEAP_2017 + 1, 0));
+        assert!(!in_progress(LEAP_2017 + 2, 0));
+    }
+
+    #[test]
+    fn test_next_month_start() {
+        // 2016-02-15 to 2016-03-01, a leap year
+        assert_eq!(
+            next_month_start(timestamp(3_663_273_600 + 14 * 86400, 0)),
+            timestamp(3_663_273_600 + 29 * 86400, 0)
+        );
+
+        // in the next ntp era: 2040-06-10 to 2040-07-01
+        let june_2040 = NtpTimestamp::from_unix_time(days_from_civil(2040, 6, 10) * 86400, 0);
+        let july_2040 = NtpTimestamp::from_unix_time(days_from_civil(2040, 7, 1) * 86400, 0);
+        assert_eq!(next_month_start(june_2040), july_2040);
+
+        for days in [-1, 0, 365, 10957, 24837] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+    }
+}
@@ -9,51 +9,88 @@
 
 mod algorithm;
 mod arrayvec;
+mod batch;
+mod capture;
 mod clock;
 mod config;
 mod cookiestash;
+mod cookiestore;
+mod encrypted_file;
 mod identifiers;
 mod keyset;
+mod leap;
 mod nts_record;
 mod packet;
 mod peer;
+mod pps;
+mod sntp;
+mod symmetric_keys;
 mod system;
 mod time_types;
+mod validate;
+mod watchdog;
 
 #[cfg(feature = "fuzz")]
 pub use algorithm::fuzz_find_interval;
 pub use algorithm::{
-    DefaultTimeSyncController, ObservablePeerTimedata, StandardClockController, StateUpdate,
-    TimeSyncController,
+    DefaultTimeSyncController, KalmanState, ObservablePeerTimedata, StandardClockController,
+    StateUpdate, TimeSyncController,
 };
-pub use clock::NtpClock;
-pub use config::{StepThreshold, SystemConfig};
-pub use identifiers::ReferenceId;
+pub use batch::{
+    validate_batch, validate_request, validate_request_with_scratch, RequestError, ValidatedRequest,
+};
+pub use capture::{CaptureDirection, CapturedDatagram, ExchangeRecord, PacketCapture, ReplayError};
+pub use clock::{NtpClock, RefClock, RefClockReading};
+pub use config::{
+    NoMajorityPolicy, NtpVersion, NtsNakRecovery, StaleSyncAction, StepThreshold, SystemConfig,
+};
+pub use cookiestore::{CookieStore, FileCookieStore, MemoryCookieStore};
+pub use encrypted_file::write_private_file;
+pub use identifiers::{KissCode, ReferenceId};
 pub use keyset::{DecodedServerCookie, KeySet, KeySetProvider};
+pub use leap::LeapSchedule;
 
 pub use packet::{
-    Cipher, CipherProvider, NoCipher, NtpAssociationMode, NtpLeapIndicator, NtpPacket,
+    cipher_for_aead, constant_time_eq, nts_encrypted_overhead, nts_response_size, BloomFilter,
+    Cipher, CipherProvider, ControlOpcode, ControlPacket, ControlParsingError,
+    CustomExtensionField, ExtensionField, ExtensionFieldParsingError, ExtensionFieldRegistry,
+    NoCipher, NtpAssociationMode, NtpLeapIndicator, NtpPacket, NtpPacketBuilder, NtpTimescale,
+    PacketField, ParseWarning, PeerStatusWord, PreviousExchange, RequestIdentifier, ServerId,
+    SystemStatusWord, TrailingData, UniqueIdentifierPrefix, UnsupportedAead,
+};
+#[cfg(feature = "packet-serde")]
+pub use packet::{
+    FieldDescription, MacDescription, PacketDescription, PacketTestVector, TestVectorError,
 };
 #[cfg(feature = "fuzz")]
 pub use peer::fuzz_measurement_from_packet;
 #[cfg(feature = "ext-test")]
 pub use peer::peer_snapshot;
 pub use peer::{
-    AcceptSynchronizationError, IgnoreReason, Measurement, Peer, PeerNtsData, PeerSnapshot,
-    PollError, Reach, Update,
+    clock_stepped, AcceptSynchronizationError, ExportedPeerState, IgnoreReason, Measurement, Peer,
+    PeerNtsData, PeerSnapshot, PollError, Reach, Update,
+};
+pub use pps::pps_offset;
+pub use sntp::{SntpClient, SntpError, SntpResult};
+pub use symmetric_keys::{
+    InvalidKeyLength, KeysFileError, MacAlgorithm, MacError, SymmetricKey, SymmetricKeys,
 };
 pub use system::{SystemSnapshot, TimeSnapshot};
 #[cfg(feature = "fuzz")]
 pub use time_types::fuzz_duration_from_seconds;
 pub use time_types::{
-    FrequencyTolerance, NtpDuration, NtpInstant, NtpTimestamp, PollInterval, PollIntervalLimits,
+    DurationConversionError, FrequencyTolerance, NtpDuration, NtpInstant, NtpTimestamp,
+    PollInterval, PollIntervalLimits,
 };
+pub use validate::{validate_response, RejectReason, ValidationPolicy};
+pub use watchdog::SyncWatchdog;
 
 #[cfg(feature = "fuzz")]
 pub use nts_record::fuzz_key_exchange_result_decoder;
 #[cfg(feature = "fuzz")]
 pub use nts_record::fuzz_key_exchange_server_decoder;
 pub use nts_record::{
-    KeyExchangeClient, KeyExchangeError, KeyExchangeResult, KeyExchangeServer, NtsRecord,
-    NtsRecordDecoder, WriteError,
+    nts_exporter_context, supported_aead_algorithms, supported_next_protocols, AeadAlgorithm,
+    Direction, KeyExchangeClient, KeyExchangeError, KeyExchangeResult, KeyExchangeServer,
+    NtsErrorCode, NtsKeys, NtsRecord, NtsRecordDecoder, WriteError,
 };
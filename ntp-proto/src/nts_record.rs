@@ -4,11 +4,13 @@ use std::{
     sync::Arc,
 };
 
-use aead::KeySizeUser;
-use aes_siv::{Aes128SivAead, Aes256SivAead};
+use zeroize::Zeroize;
 
 use crate::{
-    cookiestash::CookieStash, packet::AesSivCmac256, packet::AesSivCmac512, peer::PeerNtsData,
+    cipher_for_aead,
+    cookiestash::CookieStash,
+    cookiestore::{decode_nts_state, CookieStore},
+    peer::PeerNtsData,
     Cipher, DecodedServerCookie, KeySet,
 };
 
@@ -120,7 +122,7 @@ pub enum NtsRecord {
         protocol_ids: Vec<u16>,
     },
     Error {
-        errorcode: u16,
+        errorcode: NtsErrorCode,
     },
     Warning {
         warningcode: u16,
@@ -166,14 +168,15 @@ fn read_bytes_exact(reader: &mut impl Read, length: usize) -> std::io::Result<Ve
 }
 
 impl NtsRecord {
-    pub fn client_key_exchange_records() -> [NtsRecord; 3] {
+    /// The request of a client offering `algorithms`, in order of preference
+    pub fn client_key_exchange_records(algorithms: &[AeadAlgorithm]) -> [NtsRecord; 3] {
         [
             NtsRecord::NextProtocol {
-                protocol_ids: vec![0],
+                protocol_ids: supported_next_protocols().to_vec(),
             },
             NtsRecord::AeadAlgorithm {
                 critical: false,
-                algorithm_ids: AeadAlgorithm::IN_ORDER_OF_PREFERENCE
+                algorithm_ids: algorithms
                     .iter()
                     .map(|algorithm| *algorithm as u16)
                     .collect(),
@@ -182,12 +185,40 @@ impl NtsRecord {
         ]
     }
 
+    /// The NTPv4 Server and Port Negotiation records telling a client where to send
+    /// its NTP requests. Absent records mean the host of the key exchange and the
+    /// default port, so we only send them when that is not where the client should go.
+    fn ntp_endpoint_records(ntp_server: Option<&str>, ntp_port: Option<u16>) -> Vec<NtsRecord> {
+        let mut records = vec![];
+
+        if let Some(name) = ntp_server {
+            records.push(NtsRecord::Server {
+                critical: false,
+                name: name.to_string(),
+            });
+        }
+
+        match ntp_port {
+            Some(port) if port != KeyExchangeClient::NTP_DEFAULT_PORT => {
+                records.push(NtsRecord::Port {
+                    critical: false,
+                    port,
+                });
+            }
+            _ => {}
+        }
+
+        records
+    }
+
     fn server_key_exchange_records(
         protocol: ProtocolId,
         algorithm: AeadAlgorithm,
         keyset: &KeySet,
         keys: NtsKeys,
-    ) -> [NtsRecord; 11] {
+        ntp_server: Option<&str>,
+        ntp_port: Option<u16>,
+    ) -> Vec<NtsRecord> {
         let cookie = DecodedServerCookie {
             algorithm,
             s2c: keys.s2c,
@@ -200,7 +231,7 @@ impl NtsRecord {
             }
         };
 
-        [
+        let mut records = vec![
             NtsRecord::NextProtocol {
                 protocol_ids: vec![protocol as u16],
             },
@@ -216,8 +247,43 @@ impl NtsRecord {
             next_cookie(),
             next_cookie(),
             next_cookie(),
-            NtsRecord::EndOfMessage,
-        ]
+        ];
+        records.extend(Self::ntp_endpoint_records(ntp_server, ntp_port));
+        records.push(NtsRecord::EndOfMessage);
+
+        records
+    }
+
+    /// The records answering a request that can't be served, telling the client
+    /// why as RFC 8915 prescribes
+    fn server_error_records(error: &KeyExchangeError) -> Vec<NtsRecord> {
+        let mut records = match error {
+            KeyExchangeError::UnrecognizedCriticalRecord => vec![NtsRecord::Error {
+                errorcode: NtsErrorCode::UnrecognizedCriticalRecord,
+            }],
+            KeyExchangeError::InternalServerError => vec![NtsRecord::Error {
+                errorcode: NtsErrorCode::InternalServerError,
+            }],
+            // an empty negotiation record means there is nothing in common
+            KeyExchangeError::NoValidProtocol => vec![NtsRecord::NextProtocol {
+                protocol_ids: vec![],
+            }],
+            KeyExchangeError::NoValidAlgorithm => vec![
+                NtsRecord::NextProtocol {
+                    protocol_ids: vec![ProtocolId::NtpV4 as u16],
+                },
+                NtsRecord::AeadAlgorithm {
+                    critical: false,
+                    algorithm_ids: vec![],
+                },
+            ],
+            _ => vec![NtsRecord::Error {
+                errorcode: NtsErrorCode::BadRequest,
+            }],
+        };
+        records.push(NtsRecord::EndOfMessage);
+
+        records
     }
 
     pub fn read<A: Read>(reader: &mut A) -> std::io::Result<NtsRecord> {
@@ -234,7 +300,7 @@ impl NtsRecord {
                 NtsRecord::NextProtocol { protocol_ids }
             }
             2 if record_len == 2 && critical => NtsRecord::Error {
-                errorcode: read_u16_be(reader)?,
+                errorcode: NtsErrorCode::from_u16(read_u16_be(reader)?),
             },
             3 if record_len == 2 && critical => NtsRecord::Warning {
                 warningcode: read_u16_be(reader)?,
@@ -302,7 +368,7 @@ impl NtsRecord {
             }
             NtsRecord::Error { errorcode } => {
                 writer.write_all(&size_of_u16.to_be_bytes())?;
-                writer.write_all(&errorcode.to_be_bytes())?;
+                writer.write_all(&errorcode.to_u16().to_be_bytes())?;
             }
             NtsRecord::Warning { warningcode } => {
                 writer.write_all(&size_of_u16.to_be_bytes())?;
@@ -360,7 +426,7 @@ impl<'a> arbitrary::Arbitrary<'a> for NtsRecord {
                 protocol_ids: u.arbitrary()?,
             },
             2 => Error {
-                errorcode: u.arbitrary()?,
+                errorcode: NtsErrorCode::from_u16(u.arbitrary()?),
             },
             3 => Warning {
                 warningcode: u.arbitrary()?,
@@ -444,8 +510,12 @@ pub enum KeyExchangeError {
     InternalServerError,
     #[error("Remote: Error with unknown code {0}")]
     UnknownErrorCode(u16),
+    #[error("Remote: Warning with unknown code {0}")]
+    UnknownWarningCode(u16),
     #[error("No continuation protocol supported by both us and server")]
     NoValidProtocol,
+    #[error("Server selected continuation protocol {0}, which we did not offer")]
+    UnsupportedNextProtocol(u16),
     #[error("No encryption algorithm supported by both us and server")]
     NoValidAlgorithm,
     #[error("Missing cookies")]
@@ -462,6 +532,48 @@ pub enum KeyExchangeError {
     IncompleteResponse,
 }
 
+/// The code of an Error record, from https://www.rfc-editor.org/rfc/rfc8915.html#section-7.5
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NtsErrorCode {
+    UnrecognizedCriticalRecord,
+    BadRequest,
+    InternalServerError,
+    Unknown(u16),
+}
+
+impl NtsErrorCode {
+    pub const fn from_u16(code: u16) -> Self {
+        match code {
+            0 => Self::UnrecognizedCriticalRecord,
+            1 => Self::BadRequest,
+            2 => Self::InternalServerError,
+            _ => Self::Unknown(code),
+        }
+    }
+
+    pub const fn to_u16(self) -> u16 {
+        match self {
+            Self::UnrecognizedCriticalRecord => 0,
+            Self::BadRequest => 1,
+            Self::InternalServerError => 2,
+            Self::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<NtsErrorCode> for KeyExchangeError {
+    fn from(code: NtsErrorCode) -> Self {
+        match code {
+            NtsErrorCode::UnrecognizedCriticalRecord => {
+                KeyExchangeError::UnrecognizedCriticalRecord
+            }
+            NtsErrorCode::BadRequest => KeyExchangeError::BadRequest,
+            NtsErrorCode::InternalServerError => KeyExchangeError::InternalServerError,
+            NtsErrorCode::Unknown(code) => KeyExchangeError::UnknownErrorCode(code),
+        }
+    }
+}
+
 /// From https://www.rfc-editor.org/rfc/rfc8915.html#name-network-time-security-next-
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 #[repr(u16)]
@@ -473,6 +585,16 @@ pub enum ProtocolId {
 impl ProtocolId {
     const IN_ORDER_OF_PREFERENCE: &'static [Self] = &[Self::NtpV4];
 
+    const SUPPORTED_IDS: [u16; Self::IN_ORDER_OF_PREFERENCE.len()] = {
+        let mut ids = [0; Self::IN_ORDER_OF_PREFERENCE.len()];
+        let mut i = 0;
+        while i < ids.len() {
+            ids[i] = Self::IN_ORDER_OF_PREFERENCE[i] as u16;
+            i += 1;
+        }
+        ids
+    };
+
     pub const fn try_deserialize(number: u16) -> Option<Self> {
         match number {
             0 => Some(Self::NtpV4),
@@ -491,16 +613,12 @@ pub enum AeadAlgorithm {
 }
 
 impl AeadAlgorithm {
-    // per https://www.rfc-editor.org/rfc/rfc8915.html#section-5.1
     pub const fn c2s_context(self) -> [u8; 5] {
-        // The final octet SHALL be 0x00 for the C2S key
-        [0, 0, (self as u16 >> 8) as u8, self as u8, 0]
+        nts_exporter_context(self as u16, Direction::ClientToServer)
     }
 
-    // per https://www.rfc-editor.org/rfc/rfc8915.html#section-5.1
     pub const fn s2c_context(self) -> [u8; 5] {
-        // The final octet SHALL be 0x01 for the S2C key
-        [0, 0, (self as u16 >> 8) as u8, self as u8, 1]
+        nts_exporter_context(self as u16, Direction::ServerToClient)
     }
 
     pub const fn try_deserialize(number: u16) -> Option<AeadAlgorithm> {
@@ -511,50 +629,146 @@ impl AeadAlgorithm {
         }
     }
 
-    const IN_ORDER_OF_PREFERENCE: &'static [Self] =
+    /// The name of the algorithm in the IANA registry
+    pub const fn name(self) -> &'static str {
+        match self {
+            AeadAlgorithm::AeadAesSivCmac256 => "AEAD_AES_SIV_CMAC_256",
+            AeadAlgorithm::AeadAesSivCmac512 => "AEAD_AES_SIV_CMAC_512",
+        }
+    }
+
+    /// The algorithms we support, in the order in which a client offers them
+    /// by default
+    pub const IN_ORDER_OF_PREFERENCE: &'static [Self] =
         &[Self::AeadAesSivCmac512, Self::AeadAesSivCmac256];
 
+    const SUPPORTED_IDS: [u16; Self::IN_ORDER_OF_PREFERENCE.len()] = {
+        let mut ids = [0; Self::IN_ORDER_OF_PREFERENCE.len()];
+        let mut i = 0;
+        while i < ids.len() {
+            ids[i] = Self::IN_ORDER_OF_PREFERENCE[i] as u16;
+            i += 1;
+        }
+        ids
+    };
+
+    /// Length in bytes of the keys used by this algorithm
+    pub const fn key_size(self) -> usize {
+        match self {
+            AeadAlgorithm::AeadAesSivCmac256 => 32,
+            AeadAlgorithm::AeadAesSivCmac512 => 64,
+        }
+    }
+
     fn extract_nts_keys<ConnectionData>(
         &self,
         tls_connection: &rustls::ConnectionCommon<ConnectionData>,
     ) -> Result<NtsKeys, rustls::Error> {
-        match self {
-            AeadAlgorithm::AeadAesSivCmac256 => {
-                let c2s = extract_nts_key::<Aes128SivAead, _>(tls_connection, self.c2s_context())?;
-                let s2c = extract_nts_key::<Aes128SivAead, _>(tls_connection, self.s2c_context())?;
-
-                let c2s = Box::new(AesSivCmac256::new(c2s));
-                let s2c = Box::new(AesSivCmac256::new(s2c));
+        self.extract_nts_keys_with(|label, context, out| {
+            tls_connection.export_keying_material(out, label, Some(context))?;
+            Ok(())
+        })
+    }
 
-                Ok(NtsKeys { c2s, s2c })
-            }
-            AeadAlgorithm::AeadAesSivCmac512 => {
-                let c2s = extract_nts_key::<Aes256SivAead, _>(tls_connection, self.c2s_context())?;
-                let s2c = extract_nts_key::<Aes256SivAead, _>(tls_connection, self.s2c_context())?;
+    /// Derive the NTS keys using a TLS keying material exporter, so any TLS
+    /// implementation can be used for the key exchange.
+    ///
+    /// The exporter is called as `export(label, context, out)`, once for each
+    /// direction, and must fill all of `out` with the keying material the TLS
+    /// connection exports for `label` and `context` (RFC 5705, RFC 8446 section
+    /// 7.5). A context is always given, and must not be treated as absent even
+    /// though it is short. An error returned by the exporter is returned as is.
+    pub fn extract_nts_keys_with<E>(
+        &self,
+        mut export: impl FnMut(&[u8], &[u8], &mut [u8]) -> Result<(), E>,
+    ) -> Result<NtsKeys, E> {
+        let mut c2s = extract_nts_key(&mut export, self.c2s_context(), self.key_size())?;
+        let mut s2c = extract_nts_key(&mut export, self.s2c_context(), self.key_size())?;
+
+        // the keys were extracted with the size this algorithm requires
+        let keys = NtsKeys {
+            c2s: cipher_for_aead(*self as u16, &c2s).expect("key size matches algorithm"),
+            s2c: cipher_for_aead(*self as u16, &s2c).expect("key size matches algorithm"),
+        };
 
-                let c2s = Box::new(AesSivCmac512::new(c2s));
-                let s2c = Box::new(AesSivCmac512::new(s2c));
+        c2s.zeroize();
+        s2c.zeroize();
 
-                Ok(NtsKeys { c2s, s2c })
-            }
-        }
+        Ok(keys)
     }
 }
 
+/// The direction of traffic an NTS key is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// The context passed to the TLS keying material exporter when deriving the key
+/// for the given AEAD algorithm and direction.
+// per https://www.rfc-editor.org/rfc/rfc8915.html#section-5.1
+pub const fn nts_exporter_context(aead_id: u16, direction: Direction) -> [u8; 5] {
+    // The final octet SHALL be 0x00 for the C2S key and 0x01 for the S2C key
+    let direction = match direction {
+        Direction::ClientToServer => 0,
+        Direction::ServerToClient => 1,
+    };
+
+    // the first two octets are the protocol id, which is 0 for NTPv4
+    [0, 0, (aead_id >> 8) as u8, aead_id as u8, direction]
+}
+
+/// The ids of the AEAD algorithms we support for NTS, in order of preference
+pub fn supported_aead_algorithms() -> &'static [u16] {
+    &AeadAlgorithm::SUPPORTED_IDS
+}
+
+/// The ids of the next protocols a client requests during key exchange, in
+/// order of preference. The server must select one of these.
+pub fn supported_next_protocols() -> &'static [u16] {
+    &ProtocolId::SUPPORTED_IDS
+}
+
+/// The keys derived from an NTS key exchange
 pub struct NtsKeys {
     c2s: Box<dyn Cipher>,
     s2c: Box<dyn Cipher>,
 }
 
-fn extract_nts_key<T: KeySizeUser, ConnectionData>(
-    tls_connection: &rustls::ConnectionCommon<ConnectionData>,
+impl NtsKeys {
+    /// The cipher for traffic from the client to the server
+    pub fn c2s(&self) -> &dyn Cipher {
+        self.c2s.as_ref()
+    }
+
+    /// The cipher for traffic from the server to the client
+    pub fn s2c(&self) -> &dyn Cipher {
+        self.s2c.as_ref()
+    }
+
+    /// The client-to-server and server-to-client ciphers, in that order
+    pub fn into_ciphers(self) -> (Box<dyn Cipher>, Box<dyn Cipher>) {
+        (self.c2s, self.s2c)
+    }
+}
+
+impl std::fmt::Debug for NtsKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NtsKeys").finish_non_exhaustive()
+    }
+}
+
+fn extract_nts_key<E>(
+    export: &mut impl FnMut(&[u8], &[u8], &mut [u8]) -> Result<(), E>,
     context: [u8; 5],
-) -> Result<aead::Key<T>, rustls::Error> {
-    let mut key: aead::Key<T> = Default::default();
-    tls_connection.export_keying_material(
-        &mut key,
+    key_size: usize,
+) -> Result<Vec<u8>, E> {
+    let mut key = vec![0; key_size];
+    export(
         b"EXPORTER-network-time-security",
-        Some(context.as_slice()),
+        context.as_slice(),
+        &mut key,
     )?;
 
     Ok(key)
@@ -565,10 +779,11 @@ struct PartialKeyExchangeData {
     remote: Option<String>,
     port: Option<u16>,
     algorithm: Option<AeadAlgorithm>,
+    protocol: Option<ProtocolId>,
     cookies: CookieStash,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct KeyExchangeResultDecoder {
     decoder: NtsRecordDecoder,
     remote: Option<String>,
@@ -576,6 +791,8 @@ struct KeyExchangeResultDecoder {
     algorithm: Option<AeadAlgorithm>,
     protocol: Option<ProtocolId>,
     cookies: CookieStash,
+    /// The algorithms in our request, the server must select one of these
+    offered: Vec<AeadAlgorithm>,
 }
 
 impl KeyExchangeResultDecoder {
@@ -615,6 +832,7 @@ impl KeyExchangeResultDecoder {
                         remote: state.remote,
                         port: state.port,
                         algorithm: state.algorithm,
+                        protocol: state.protocol,
                         cookies: state.cookies,
                     }))
                 }
@@ -631,27 +849,23 @@ impl KeyExchangeResultDecoder {
                 state.port = Some(port);
                 Continue(state)
             }
-            Error { errorcode } => {
-                let error = match errorcode {
-                    0 => UnrecognizedCriticalRecord,
-                    1 => BadRequest,
-                    2 => InternalServerError,
-                    _ => UnknownErrorCode(errorcode),
-                };
-
-                Break(Err(error))
-            }
+            Error { errorcode } => Break(Err(errorcode.into())),
             Warning { warningcode } => {
-                tracing::warn!(warningcode, "Received key exchange warning code");
-
-                Continue(state)
+                // > Unrecognized warning codes MUST be treated as errors
+                //
+                // and RFC 8915 defines no warning codes at all
+                Break(Err(UnknownWarningCode(warningcode)))
             }
             NextProtocol { protocol_ids } => {
-                let selected = ProtocolId::IN_ORDER_OF_PREFERENCE
-                    .iter()
-                    .find_map(|proto| protocol_ids.contains(&(*proto as u16)).then_some(*proto));
+                // The server must only select from the protocols we offered
+                let offered = supported_next_protocols();
+                if let Some(id) = protocol_ids.iter().find(|id| !offered.contains(id)) {
+                    return Break(Err(UnsupportedNextProtocol(*id)));
+                }
 
-                state.protocol = selected;
+                state.protocol = protocol_ids
+                    .first()
+                    .and_then(|id| ProtocolId::try_deserialize(*id));
 
                 match state.protocol {
                     None => Break(Err(NoValidProtocol)),
@@ -659,11 +873,11 @@ impl KeyExchangeResultDecoder {
                 }
             }
             AeadAlgorithm { algorithm_ids, .. } => {
-                let selected = Algorithm::IN_ORDER_OF_PREFERENCE
+                // The server selects the algorithm, but only from those we offered
+                state.algorithm = algorithm_ids
                     .iter()
-                    .find_map(|algo| algorithm_ids.contains(&(*algo as u16)).then_some(*algo));
-
-                state.algorithm = selected;
+                    .filter_map(|id| Algorithm::try_deserialize(*id))
+                    .find(|algorithm| state.offered.contains(algorithm));
 
                 match state.algorithm {
                     None => Break(Err(NoValidAlgorithm)),
@@ -671,12 +885,21 @@ impl KeyExchangeResultDecoder {
                 }
             }
 
+            Unknown { critical: true, .. } => Break(Err(UnrecognizedCriticalRecord)),
             Unknown { .. } => Continue(state),
         }
     }
 
-    fn new() -> Self {
-        Self::default()
+    fn offering(algorithms: &[AeadAlgorithm]) -> Self {
+        KeyExchangeResultDecoder {
+            decoder: NtsRecordDecoder::default(),
+            remote: None,
+            port: None,
+            algorithm: None,
+            protocol: None,
+            cookies: CookieStash::default(),
+            offered: algorithms.to_vec(),
+        }
     }
 }
 
@@ -687,6 +910,50 @@ pub struct KeyExchangeResult {
     pub nts: Box<PeerNtsData>,
 }
 
+impl KeyExchangeResult {
+    /// The AEAD algorithm the server selected, which the keys are for
+    pub fn algorithm(&self) -> AeadAlgorithm {
+        self.nts.algorithm
+    }
+
+    /// Summary of the negotiated parameters, for diagnostics. Contains no key
+    /// material.
+    pub fn describe(&self) -> String {
+        format!(
+            "{}:{} using {} (c2s key {} bytes, s2c key {} bytes, nonce {} bytes), {} cookies",
+            self.remote,
+            self.port,
+            self.nts.algorithm.name(),
+            self.nts.c2s.key_bytes().len(),
+            self.nts.s2c.key_bytes().len(),
+            self.nts.c2s.nonce_len(),
+            self.nts.cookies.iter().count(),
+        )
+    }
+
+    /// Save the result to `store`, so the NTP server can be polled with its
+    /// cookies after a restart, without a new key exchange
+    pub fn save(&self, store: &mut impl CookieStore) -> std::io::Result<()> {
+        self.nts.save_with_server(&self.remote, self.port, store)
+    }
+
+    /// Restore a result saved to `store` with [`KeyExchangeResult::save`] or
+    /// [`PeerNtsData::save_with_server`], if any
+    pub fn load(store: &impl CookieStore) -> std::io::Result<Option<Self>> {
+        match store.load()? {
+            Some(state) => {
+                let (remote, port, nts) = decode_nts_state(&state)?;
+                Ok(Some(KeyExchangeResult {
+                    remote,
+                    port,
+                    nts: Box::new(nts),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
 pub struct KeyExchangeClient {
     tls_connection: rustls::ClientConnection,
     decoder: KeyExchangeResultDecoder,
@@ -726,9 +993,13 @@ impl KeyExchangeClient {
                     self.decoder = match self.decoder.step_with_slice(&buf[..n]) {
                         ControlFlow::Continue(decoder) => decoder,
                         ControlFlow::Break(Ok(result)) => {
-                            let algorithm = result.algorithm.unwrap_or_default();
+                            // RFC 8915 requires the server to tell us which algorithm it selected
+                            let Some(algorithm) = result.algorithm else {
+                                return ControlFlow::Break(Err(KeyExchangeError::NoValidAlgorithm));
+                            };
 
                             tracing::info!(?algorithm, "selected AEAD algorithm");
+                            tracing::debug!(protocol = ?result.protocol, "selected next protocol");
 
                             let keys = match algorithm.extract_nts_keys(&self.tls_connection) {
                                 Ok(keys) => keys,
@@ -739,6 +1010,9 @@ impl KeyExchangeClient {
                                 cookies: result.cookies,
                                 c2s: keys.c2s,
                                 s2c: keys.s2c,
+                                algorithm,
+                                uid_prefix: None,
+                                consecutive_naks: 0,
                             });
 
                             return ControlFlow::Break(Ok(KeyExchangeResult {
@@ -759,9 +1033,26 @@ impl KeyExchangeClient {
     }
 
     pub fn new(
+        server_name: String,
+        tls_config: rustls::ClientConfig,
+    ) -> Result<Self, KeyExchangeError> {
+        Self::with_algorithms(
+            server_name,
+            tls_config,
+            AeadAlgorithm::IN_ORDER_OF_PREFERENCE,
+        )
+    }
+
+    /// Like [`KeyExchangeClient::new`], offering only `algorithms`, in order of preference
+    pub fn with_algorithms(
         server_name: String,
         mut tls_config: rustls::ClientConfig,
+        algorithms: &[AeadAlgorithm],
     ) -> Result<Self, KeyExchangeError> {
+        if algorithms.is_empty() {
+            return Err(KeyExchangeError::NoValidAlgorithm);
+        }
+
         // Ensure we send only ntske/1 as alpn
         tls_config.alpn_protocols.clear();
         tls_config.alpn_protocols.push(b"ntske/1".to_vec());
@@ -776,14 +1067,14 @@ impl KeyExchangeClient {
         // We use an intermediary buffer to ensure that all records are sent at once.
         // This should not be needed, but works around issues in some NTS-ke server implementations
         let mut buffer = Vec::with_capacity(1024);
-        for record in NtsRecord::client_key_exchange_records() {
+        for record in NtsRecord::client_key_exchange_records(algorithms) {
             record.write(&mut buffer)?;
         }
         tls_connection.writer().write_all(&buffer)?;
 
         Ok(KeyExchangeClient {
             tls_connection,
-            decoder: KeyExchangeResultDecoder::new(),
+            decoder: KeyExchangeResultDecoder::offering(algorithms),
             server_name,
         })
     }
@@ -796,8 +1087,9 @@ struct KeyExchangeServerDecoder {
     /// it may be that the server and client supported algorithms have no
     /// intersection!
     algorithm: AeadAlgorithm,
-    /// Protocol (NTP version) that is supported by both client and server
-    protocol: ProtocolId,
+    /// Protocol (NTP version) that is supported by both client and server,
+    /// once the client sent its next protocol negotiation record
+    protocol: Option<ProtocolId>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -836,9 +1128,15 @@ impl KeyExchangeServerDecoder {
 
         match record {
             EndOfMessage => {
+                // > The Next Protocol Negotiation record MUST be included
+                // > exactly once in every NTS-KE request and response
+                let Some(protocol) = state.protocol else {
+                    return Break(Err(BadRequest));
+                };
+
                 let result = ServerKeyExchangeData {
                     algorithm: state.algorithm,
-                    protocol: state.protocol,
+                    protocol,
                 };
 
                 Break(Ok(result))
@@ -867,20 +1165,10 @@ impl KeyExchangeServerDecoder {
                 // we ignore the client's preference
                 Continue(state)
             }
-            Error { errorcode } => {
-                let error = match errorcode {
-                    0 => UnrecognizedCriticalRecord,
-                    1 => BadRequest,
-                    2 => InternalServerError,
-                    _ => UnknownErrorCode(errorcode),
-                };
-
-                Break(Err(error))
-            }
-            Warning { warningcode } => {
-                tracing::warn!(warningcode, "Received key exchange warning code");
-
-                Continue(state)
+            Error { .. } | Warning { .. } => {
+                // > Clients MUST NOT include Error records in their request.
+                // > Clients MUST NOT include Warning records in their request.
+                Break(Err(BadRequest))
             }
             NextProtocol { protocol_ids } => {
                 let selected = protocol_ids
@@ -891,7 +1179,7 @@ impl KeyExchangeServerDecoder {
                 match selected {
                     None => Break(Err(NoValidProtocol)),
                     Some(protocol) => {
-                        state.protocol = protocol;
+                        state.protocol = Some(protocol);
                         Continue(state)
                     }
                 }
@@ -911,6 +1199,7 @@ impl KeyExchangeServerDecoder {
                 }
             }
 
+            Unknown { critical: true, .. } => Break(Err(UnrecognizedCriticalRecord)),
             Unknown { .. } => Continue(state),
         }
     }
@@ -925,6 +1214,10 @@ pub struct KeyExchangeServer {
     tls_connection: rustls::ServerConnection,
     decoder: Option<KeyExchangeServerDecoder>,
     keyset: Arc<KeySet>,
+    ntp_server: Option<String>,
+    ntp_port: Option<u16>,
+    /// Why the request was rejected, reported once the client has its answer
+    failure: Option<KeyExchangeError>,
 }
 
 impl KeyExchangeServer {
@@ -950,8 +1243,14 @@ impl KeyExchangeServer {
         algorithm: AeadAlgorithm,
         keys: NtsKeys,
     ) -> std::io::Result<()> {
-        let records =
-            NtsRecord::server_key_exchange_records(protocol, algorithm, &self.keyset, keys);
+        let records = NtsRecord::server_key_exchange_records(
+            protocol,
+            algorithm,
+            &self.keyset,
+            keys,
+            self.ntp_server.as_deref(),
+            self.ntp_port,
+        );
 
         let mut buffer = Vec::with_capacity(1024);
         for record in records.into_iter() {
@@ -964,6 +1263,25 @@ impl KeyExchangeServer {
         Ok(())
     }
 
+    fn send_error_response(&mut self, error: &KeyExchangeError) -> std::io::Result<()> {
+        let mut buffer = Vec::with_capacity(64);
+        for record in NtsRecord::server_error_records(error) {
+            record.write(&mut buffer)?;
+        }
+
+        self.tls_connection.writer().write_all(&buffer)?;
+        self.tls_connection.send_close_notify();
+
+        Ok(())
+    }
+
+    fn finish(self) -> ControlFlow<Result<Self, KeyExchangeError>, Self> {
+        match self.failure {
+            Some(error) => ControlFlow::Break(Err(error)),
+            None => ControlFlow::Break(Ok(self)),
+        }
+    }
+
     pub fn progress(self) -> ControlFlow<Result<(), KeyExchangeError>, Self> {
         match self.progress_help() {
             ControlFlow::Continue(c) => ControlFlow::Continue(c),
@@ -988,7 +1306,7 @@ impl KeyExchangeServer {
                         }
                         None => {
                             // we're all done
-                            return ControlFlow::Break(Ok(self));
+                            return self.finish();
                         }
                     }
                 }
@@ -1015,7 +1333,19 @@ impl KeyExchangeServer {
                                 Ok(()) => ControlFlow::Continue(self),
                             };
                         }
-                        ControlFlow::Break(Err(error)) => return ControlFlow::Break(Err(error)),
+                        ControlFlow::Break(Err(error)) => {
+                            // tell the client why, the failure is reported when the
+                            // connection is done
+                            self.decoder = None;
+
+                            return match self.send_error_response(&error) {
+                                Err(e) => ControlFlow::Break(Err(KeyExchangeError::Io(e))),
+                                Ok(()) => {
+                                    self.failure = Some(error);
+                                    ControlFlow::Continue(self)
+                                }
+                            };
+                        }
                     },
                     None => {
                         // client is sending more bytes, but we don't expect any more
@@ -1026,7 +1356,7 @@ impl KeyExchangeServer {
                     std::io::ErrorKind::WouldBlock => return ControlFlow::Continue(self),
                     std::io::ErrorKind::UnexpectedEof if self.decoder.is_none() => {
                         // something we need in practice. If we're already done, an EOF is fine
-                        return ControlFlow::Break(Ok(self));
+                        return self.finish();
                     }
                     _ => return ControlFlow::Break(Err(e.into())),
                 },
@@ -1048,8 +1378,19 @@ impl KeyExchangeServer {
             tls_connection,
             decoder: Some(KeyExchangeServerDecoder::new()),
             keyset,
+            ntp_server: None,
+            ntp_port: None,
+            failure: None,
         })
     }
+
+    /// Direct clients to the NTP server `ntp_server` on port `ntp_port`. When not
+    /// given, clients use the host of the key exchange and the default NTP port.
+    pub fn with_ntp_endpoint(mut self, ntp_server: Option<String>, ntp_port: Option<u16>) -> Self {
+        self.ntp_server = ntp_server;
+        self.ntp_port = ntp_port;
+        self
+    }
 }
 
 #[cfg(feature = "fuzz")]
@@ -1075,7 +1416,7 @@ pub fn fuzz_key_exchange_server_decoder(data: &[u8]) {
 
 #[cfg(feature = "fuzz")]
 pub fn fuzz_key_exchange_result_decoder(data: &[u8]) {
-    let decoder = KeyExchangeResultDecoder::new();
+    let decoder = KeyExchangeResultDecoder::offering(AeadAlgorithm::IN_ORDER_OF_PREFERENCE);
 
     let _res = match decoder.step_with_slice(data) {
         ControlFlow::Continue(decoder) => decoder,
@@ -1085,10 +1426,37 @@ pub fn fuzz_key_exchange_result_decoder(data: &[u8]) {
 
 #[cfg(test)]
 mod test {
-    use crate::KeySetProvider;
+    use crate::{packet::AesSivCmac512, KeySetProvider};
 
     use super::*;
 
+    #[test]
+    fn test_key_exchange_result_describe() {
+        let mut cookies = CookieStash::default();
+        for i in 0..5 {
+            cookies.store(vec![i; 100]);
+        }
+
+        let result = KeyExchangeResult {
+            remote: "time.example.com".into(),
+            port: 4460,
+            nts: Box::new(PeerNtsData {
+                cookies,
+                c2s: Box::new(AesSivCmac512::new([1; 64].into())),
+                s2c: Box::new(AesSivCmac512::new([2; 64].into())),
+                algorithm: AeadAlgorithm::AeadAesSivCmac512,
+                uid_prefix: None,
+                consecutive_naks: 0,
+            }),
+        };
+
+        assert_eq!(
+            result.describe(),
+            "time.example.com:4460 using AEAD_AES_SIV_CMAC_512 \
+             (c2s key 64 bytes, s2c key 64 bytes, nonce 16 bytes), 5 cookies"
+        );
+    }
+
     #[test]
     fn test_algorithm_decoding() {
         for i in 0..=u16::MAX {
@@ -1098,6 +1466,35 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_supported_aead_algorithms() {
+        let supported = supported_aead_algorithms();
+        assert!(!supported.is_empty());
+        assert!(supported.contains(&15));
+
+        // exactly the algorithms we can construct a cipher for are supported
+        for i in 0..=u16::MAX {
+            assert_eq!(
+                AeadAlgorithm::try_deserialize(i).is_some(),
+                supported.contains(&i)
+            );
+        }
+        for id in supported {
+            let key_size = AeadAlgorithm::try_deserialize(*id).unwrap().key_size();
+            assert!(cipher_for_aead(*id, &vec![0; key_size]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_error_code_decoding() {
+        for i in 0..=u16::MAX {
+            assert_eq!(NtsErrorCode::from_u16(i).to_u16(), i);
+        }
+
+        assert_eq!(NtsErrorCode::from_u16(1), NtsErrorCode::BadRequest);
+        assert_eq!(NtsErrorCode::from_u16(3), NtsErrorCode::Unknown(3));
+    }
+
     #[test]
     fn test_protocol_decoding() {
         for i in 0..=u16::MAX {
@@ -1110,7 +1507,8 @@ mod test {
     #[test]
     fn test_client_key_exchange_records() {
         let mut buffer = Vec::with_capacity(1024);
-        for record in NtsRecord::client_key_exchange_records() {
+        for record in NtsRecord::client_key_exchange_records(AeadAlgorithm::IN_ORDER_OF_PREFERENCE)
+        {
             record.write(&mut buffer).unwrap();
         }
 
@@ -1133,14 +1531,14 @@ mod test {
                 decoder.step().unwrap().unwrap(),
                 decoder.step().unwrap().unwrap(),
             ],
-            NtsRecord::client_key_exchange_records()
+            NtsRecord::client_key_exchange_records(AeadAlgorithm::IN_ORDER_OF_PREFERENCE)
         );
 
         assert!(decoder.step().unwrap().is_none());
     }
 
     fn roundtrip(records: &[NtsRecord]) -> Result<PartialKeyExchangeData, KeyExchangeError> {
-        let mut decoder = KeyExchangeResultDecoder::new();
+        let mut decoder = KeyExchangeResultDecoder::offering(AeadAlgorithm::IN_ORDER_OF_PREFERENCE);
 
         for record in records {
             let mut buffer = Vec::with_capacity(1024);
@@ -1178,6 +1576,109 @@ mod test {
         ));
     }
 
+    #[test]
+    fn algorithm_must_be_offered() {
+        let algorithm = NtsRecord::AeadAlgorithm {
+            critical: true,
+            algorithm_ids: vec![AeadAlgorithm::AeadAesSivCmac512 as u16],
+        };
+        let mut buffer = Vec::with_capacity(1024);
+        algorithm.write(&mut buffer).unwrap();
+
+        let decoder = KeyExchangeResultDecoder::offering(&[AeadAlgorithm::AeadAesSivCmac256]);
+        assert!(matches!(
+            decoder.step_with_slice(&buffer),
+            ControlFlow::Break(Err(KeyExchangeError::NoValidAlgorithm))
+        ));
+
+        let decoder = KeyExchangeResultDecoder::offering(&[AeadAlgorithm::AeadAesSivCmac512]);
+        let ControlFlow::Continue(decoder) = decoder.step_with_slice(&buffer) else {
+            panic!("the offered algorithm was rejected");
+        };
+        assert_eq!(decoder.algorithm, Some(AeadAlgorithm::AeadAesSivCmac512));
+    }
+
+    #[test]
+    fn error_and_warning_records() {
+        let error = |code| NtsRecord::Error {
+            errorcode: NtsErrorCode::from_u16(code),
+        };
+
+        assert!(matches!(
+            roundtrip(&[error(0)]),
+            Err(KeyExchangeError::UnrecognizedCriticalRecord)
+        ));
+        assert!(matches!(
+            roundtrip(&[error(1)]),
+            Err(KeyExchangeError::BadRequest)
+        ));
+        assert!(matches!(
+            roundtrip(&[error(2)]),
+            Err(KeyExchangeError::InternalServerError)
+        ));
+        assert!(matches!(
+            roundtrip(&[error(42)]),
+            Err(KeyExchangeError::UnknownErrorCode(42))
+        ));
+
+        // no warnings are defined, and unrecognized warnings are errors
+        let warning = NtsRecord::Warning { warningcode: 7 };
+        assert!(matches!(
+            roundtrip(&[warning]),
+            Err(KeyExchangeError::UnknownWarningCode(7))
+        ));
+    }
+
+    #[test]
+    fn unrecognized_critical_record() {
+        let unknown = |critical| NtsRecord::Unknown {
+            record_type: 0x4000,
+            critical,
+            data: vec![1, 2, 3],
+        };
+
+        assert!(matches!(
+            roundtrip(&[unknown(true)]),
+            Err(KeyExchangeError::UnrecognizedCriticalRecord)
+        ));
+        assert!(matches!(
+            roundtrip(&[unknown(false)]),
+            Err(KeyExchangeError::IncompleteResponse)
+        ));
+    }
+
+    #[test]
+    fn unsupported_next_protocol() {
+        let protocol = NtsRecord::NextProtocol {
+            protocol_ids: vec![1],
+        };
+
+        assert!(matches!(
+            roundtrip(&[protocol]),
+            Err(KeyExchangeError::UnsupportedNextProtocol(1))
+        ));
+
+        // all selected protocols must have been offered
+        let protocol = NtsRecord::NextProtocol {
+            protocol_ids: vec![0, 0x8001],
+        };
+
+        assert!(matches!(
+            roundtrip(&[protocol]),
+            Err(KeyExchangeError::UnsupportedNextProtocol(0x8001))
+        ));
+
+        // the server rejecting all our protocols
+        let protocol = NtsRecord::NextProtocol {
+            protocol_ids: vec![],
+        };
+
+        assert!(matches!(
+            roundtrip(&[protocol]),
+            Err(KeyExchangeError::NoValidProtocol)
+        ));
+    }
+
     #[test]
     fn host_port_updates() {
         let name = String::from("ntp.time.nl");
@@ -1363,6 +1864,7 @@ mod test {
 
         assert_eq!(state.remote, None);
         assert_eq!(state.port, None);
+        assert_eq!(state.protocol, Some(ProtocolId::NtpV4));
         assert_eq!(state.cookies.gap(), 0);
     }
 
@@ -1396,7 +1898,8 @@ mod test {
     #[test]
     fn server_decoder_finds_algorithm() {
         let mut bytes = Vec::with_capacity(1024);
-        for record in NtsRecord::client_key_exchange_records() {
+        for record in NtsRecord::client_key_exchange_records(AeadAlgorithm::IN_ORDER_OF_PREFERENCE)
+        {
             record.write(&mut bytes).unwrap();
         }
 
@@ -1418,6 +1921,65 @@ mod test {
         assert_eq!(result.algorithm, AeadAlgorithm::AeadAesSivCmac512);
     }
 
+    #[test]
+    fn test_nts_exporter_context() {
+        assert_eq!(
+            nts_exporter_context(15, Direction::ClientToServer),
+            [0, 0, 0, 15, 0]
+        );
+        assert_eq!(
+            nts_exporter_context(15, Direction::ServerToClient),
+            [0, 0, 0, 15, 1]
+        );
+        assert_eq!(
+            nts_exporter_context(17, Direction::ClientToServer),
+            [0, 0, 0, 17, 0]
+        );
+        assert_eq!(
+            nts_exporter_context(17, Direction::ServerToClient),
+            [0, 0, 0, 17, 1]
+        );
+        assert_eq!(
+            nts_exporter_context(0x1234, Direction::ServerToClient),
+            [0, 0, 0x12, 0x34, 1]
+        );
+
+        assert_eq!(
+            AeadAlgorithm::AeadAesSivCmac256.c2s_context(),
+            [0, 0, 0, 15, 0]
+        );
+        assert_eq!(
+            AeadAlgorithm::AeadAesSivCmac512.s2c_context(),
+            [0, 0, 0, 17, 1]
+        );
+    }
+
+    #[test]
+    fn test_extract_nts_keys_with_stub_exporter() {
+        let stub = |label: &[u8], context: &[u8], out: &mut [u8]| {
+            assert_eq!(label, b"EXPORTER-network-time-security");
+            // the final octet of the context distinguishes the c2s and s2c keys
+            out.fill(context[4] + 1);
+            Ok::<(), std::convert::Infallible>(())
+        };
+
+        for algorithm in AeadAlgorithm::IN_ORDER_OF_PREFERENCE {
+            let keys = algorithm.extract_nts_keys_with(stub).unwrap();
+            assert_eq!(keys.c2s().key_bytes(), vec![1; algorithm.key_size()]);
+            assert_eq!(keys.s2c().key_bytes(), vec![2; algorithm.key_size()]);
+
+            let (c2s, s2c) = keys.into_ciphers();
+            assert_eq!(c2s.key_bytes(), vec![1; algorithm.key_size()]);
+            assert_eq!(s2c.key_bytes(), vec![2; algorithm.key_size()]);
+        }
+
+        let failing = |_: &[u8], _: &[u8], _: &mut [u8]| Err("no keying material");
+        assert!(matches!(
+            AeadAlgorithm::AeadAesSivCmac256.extract_nts_keys_with(failing),
+            Err("no keying material")
+        ));
+    }
+
     #[test]
     fn test_keyexchange_client() {
         let cert_chain: Vec<rustls::Certificate> =
@@ -1492,8 +2054,33 @@ mod test {
         assert_eq!(result.port, 123);
     }
 
-    #[test]
-    fn test_keyexchange_roundtrip() {
+    fn keyexchange_roundtrip(
+        ntp_server: Option<String>,
+        ntp_port: Option<u16>,
+    ) -> KeyExchangeResult {
+        keyexchange_roundtrip_with_keyset(KeySetProvider::new(8).get(), ntp_server, ntp_port)
+    }
+
+    fn keyexchange_roundtrip_with_keyset(
+        keyset: Arc<KeySet>,
+        ntp_server: Option<String>,
+        ntp_port: Option<u16>,
+    ) -> KeyExchangeResult {
+        keyexchange_roundtrip_with_records(
+            keyset,
+            NtsRecord::client_key_exchange_records(AeadAlgorithm::IN_ORDER_OF_PREFERENCE),
+            ntp_server,
+            ntp_port,
+        )
+        .unwrap()
+    }
+
+    fn keyexchange_roundtrip_with_records(
+        keyset: Arc<KeySet>,
+        records: impl IntoIterator<Item = NtsRecord>,
+        ntp_server: Option<String>,
+        ntp_port: Option<u16>,
+    ) -> Result<KeyExchangeResult, KeyExchangeError> {
         let cert_chain: Vec<rustls::Certificate> =
             rustls_pemfile::certs(&mut std::io::BufReader::new(include_bytes!(
                 "../../test-keys/end.fullchain.pem"
@@ -1529,24 +2116,36 @@ mod test {
             .unwrap(),
         );
 
-        let clientconfig = rustls::ClientConfig::builder()
+        let mut clientconfig = rustls::ClientConfig::builder()
             .with_safe_defaults()
             .with_root_certificates(root_store)
             .with_no_client_auth();
+        clientconfig.alpn_protocols.push(b"ntske/1".to_vec());
 
-        let keyset = KeySetProvider::new(8).get();
-        let mut server = KeyExchangeServer::new(Arc::new(serverconfig), keyset).unwrap();
-        let mut client = KeyExchangeClient::new("localhost".into(), clientconfig).unwrap();
+        let mut server = KeyExchangeServer::new(Arc::new(serverconfig), keyset)
+            .unwrap()
+            .with_ntp_endpoint(ntp_server, ntp_port);
+
+        // not KeyExchangeClient::new, which already queues the default request
+        let mut client = KeyExchangeClient {
+            tls_connection: rustls::ClientConnection::new(
+                Arc::new(clientconfig),
+                "localhost".try_into().unwrap(),
+            )
+            .unwrap(),
+            decoder: KeyExchangeResultDecoder::offering(AeadAlgorithm::IN_ORDER_OF_PREFERENCE),
+            server_name: "localhost".into(),
+        };
 
         let mut bytes = Vec::with_capacity(1024);
-        for record in NtsRecord::client_key_exchange_records() {
+        for record in records {
             record.write(&mut bytes).unwrap();
         }
 
         client.tls_connection.writer().write_all(&bytes).unwrap();
 
         let mut buf = [0; 4096];
-        let result = 'result: loop {
+        'result: loop {
             while server.wants_write() {
                 let size = server.write_socket(&mut &mut buf[..]).unwrap();
                 let mut offset = 0;
@@ -1572,7 +2171,7 @@ mod test {
                     match server.progress_help() {
                         ControlFlow::Continue(new) => server = new,
                         ControlFlow::Break(result) => {
-                            server = result.unwrap();
+                            server = result?;
 
                             break 'client_write;
                         }
@@ -1580,11 +2179,240 @@ mod test {
                 }
             }
         }
+    }
+
+    #[test]
+    fn test_keyexchange_roundtrip() {
+        let result = keyexchange_roundtrip(None, None);
+
+        assert_eq!(&result.remote, "localhost");
+        assert_eq!(result.port, 123);
+
+        assert_eq!(result.nts.cookies.len(), 8);
+    }
+
+    #[test]
+    fn test_keyexchange_algorithm_preference() {
+        use AeadAlgorithm::*;
+
+        // the server follows the preference of the client
+        for algorithms in [
+            [AeadAesSivCmac256, AeadAesSivCmac512],
+            [AeadAesSivCmac512, AeadAesSivCmac256],
+        ] {
+            let result = keyexchange_roundtrip_with_records(
+                KeySetProvider::new(8).get(),
+                NtsRecord::client_key_exchange_records(&algorithms),
+                None,
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(result.algorithm(), algorithms[0]);
+            assert_eq!(result.nts.c2s.key_bytes().len(), algorithms[0].key_size());
+            assert_eq!(result.nts.s2c.key_bytes().len(), algorithms[0].key_size());
+        }
+    }
+
+    #[test]
+    fn test_keyexchange_client_offers_algorithms() {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+
+        assert!(matches!(
+            KeyExchangeClient::with_algorithms("localhost".into(), config.clone(), &[]),
+            Err(KeyExchangeError::NoValidAlgorithm)
+        ));
+
+        let client = KeyExchangeClient::with_algorithms(
+            "localhost".into(),
+            config,
+            &[AeadAlgorithm::AeadAesSivCmac256],
+        )
         .unwrap();
+        assert_eq!(client.decoder.offered, [AeadAlgorithm::AeadAesSivCmac256]);
+    }
+
+    #[test]
+    fn test_keyexchange_no_valid_algorithm() {
+        let records = [
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![1],
+            },
+            NtsRecord::EndOfMessage,
+        ];
+
+        let result =
+            keyexchange_roundtrip_with_records(KeySetProvider::new(8).get(), records, None, None);
+        assert!(matches!(result, Err(KeyExchangeError::NoValidAlgorithm)));
+    }
+
+    #[test]
+    fn test_keyexchange_no_valid_protocol() {
+        let records = [
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0x8001],
+            },
+            NtsRecord::EndOfMessage,
+        ];
+
+        let result =
+            keyexchange_roundtrip_with_records(KeySetProvider::new(8).get(), records, None, None);
+        assert!(matches!(result, Err(KeyExchangeError::NoValidProtocol)));
+    }
+
+    #[test]
+    fn test_keyexchange_unrecognized_critical_record() {
+        let mut records = vec![NtsRecord::Unknown {
+            record_type: 0x4000,
+            critical: true,
+            data: vec![],
+        }];
+        records.extend(NtsRecord::client_key_exchange_records(
+            AeadAlgorithm::IN_ORDER_OF_PREFERENCE,
+        ));
+
+        let result =
+            keyexchange_roundtrip_with_records(KeySetProvider::new(8).get(), records, None, None);
+        assert!(matches!(
+            result,
+            Err(KeyExchangeError::UnrecognizedCriticalRecord)
+        ));
+    }
+
+    #[test]
+    fn test_keyexchange_client_error_record() {
+        // clients must not send errors or warnings
+        for record in [
+            NtsRecord::Error {
+                errorcode: NtsErrorCode::InternalServerError,
+            },
+            NtsRecord::Warning { warningcode: 0 },
+        ] {
+            let mut records = vec![record];
+            records.extend(NtsRecord::client_key_exchange_records(
+                AeadAlgorithm::IN_ORDER_OF_PREFERENCE,
+            ));
+
+            let result = keyexchange_roundtrip_with_records(
+                KeySetProvider::new(8).get(),
+                records,
+                None,
+                None,
+            );
+            assert!(matches!(result, Err(KeyExchangeError::BadRequest)));
+        }
+    }
+
+    #[test]
+    fn test_server_error_records() {
+        assert_eq!(
+            NtsRecord::server_error_records(&KeyExchangeError::InternalServerError),
+            [
+                NtsRecord::Error {
+                    errorcode: NtsErrorCode::InternalServerError
+                },
+                NtsRecord::EndOfMessage
+            ]
+        );
+        assert_eq!(
+            NtsRecord::server_error_records(&KeyExchangeError::UnrecognizedCriticalRecord),
+            [
+                NtsRecord::Error {
+                    errorcode: NtsErrorCode::UnrecognizedCriticalRecord
+                },
+                NtsRecord::EndOfMessage
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keyexchange_missing_next_protocol() {
+        let records = [
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![AeadAlgorithm::AeadAesSivCmac256 as u16],
+            },
+            NtsRecord::EndOfMessage,
+        ];
+
+        let result =
+            keyexchange_roundtrip_with_records(KeySetProvider::new(8).get(), records, None, None);
+        assert!(matches!(result, Err(KeyExchangeError::BadRequest)));
+    }
+
+    #[test]
+    fn test_keyexchange_cookie_aead_id() {
+        let keyset = KeySetProvider::new(8).get();
+        let mut result = keyexchange_roundtrip_with_keyset(keyset.clone(), None, None);
+
+        // we prefer the strongest algorithm, and the server supports it
+        assert_eq!(
+            result.nts.aead_id(),
+            AeadAlgorithm::AeadAesSivCmac512 as u16
+        );
+        assert_eq!(
+            result.nts.c2s.key_bytes().len(),
+            AeadAlgorithm::AeadAesSivCmac512.key_size()
+        );
+
+        while let Some(cookie) = result.nts.cookies.get() {
+            assert_eq!(
+                keyset.cookie_aead_id(&cookie).unwrap(),
+                result.nts.aead_id()
+            );
+        }
 
+        // a cookie not issued with our keys tells us nothing
+        let cookie = keyexchange_roundtrip(None, None).nts.cookies.get().unwrap();
+        assert!(keyset.cookie_aead_id(&cookie).is_err());
+    }
+
+    #[test]
+    fn test_keyexchange_roundtrip_ntp_endpoint() {
+        // the default port is the same as no port record at all
+        let result = keyexchange_roundtrip(None, Some(123));
         assert_eq!(&result.remote, "localhost");
         assert_eq!(result.port, 123);
 
+        let result = keyexchange_roundtrip(Some("ntp.example.com".into()), None);
+        assert_eq!(&result.remote, "ntp.example.com");
+        assert_eq!(result.port, 123);
+
+        let result = keyexchange_roundtrip(None, Some(4460));
+        assert_eq!(&result.remote, "localhost");
+        assert_eq!(result.port, 4460);
+
+        let result = keyexchange_roundtrip(Some("ntp.example.com".into()), Some(4460));
+        assert_eq!(&result.remote, "ntp.example.com");
+        assert_eq!(result.port, 4460);
         assert_eq!(result.nts.cookies.len(), 8);
     }
+
+    #[test]
+    fn test_ntp_endpoint_records() {
+        // co-located with the default port, nothing to negotiate
+        assert!(NtsRecord::ntp_endpoint_records(None, None).is_empty());
+        assert!(NtsRecord::ntp_endpoint_records(None, Some(123)).is_empty());
+
+        assert_eq!(
+            NtsRecord::ntp_endpoint_records(Some("ntp.example.com"), Some(4460)),
+            vec![
+                NtsRecord::Server {
+                    critical: false,
+                    name: "ntp.example.com".into()
+                },
+                NtsRecord::Port {
+                    critical: false,
+                    port: 4460
+                },
+            ]
+        );
+    }
 }
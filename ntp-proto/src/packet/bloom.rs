@@ -0,0 +1,185 @@
+use rand::Rng;
+
+use super::ExtensionField;
+
+/// Random identifier of a server in the bloom filters of NTPv5, which replace
+/// the reference ids of NTPv4 for loop detection. The default id is all zeros,
+/// real servers should use [`ServerId::new`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ServerId([u8; 15]);
+
+impl ServerId {
+    pub fn new(rng: &mut impl Rng) -> Self {
+        let mut id = [0; 15];
+        rng.fill(&mut id);
+        ServerId(id)
+    }
+
+    /// The bits this id sets in a bloom filter: its 120 bits taken 12 at a time
+    fn bit_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.chunks_exact(3).flat_map(|bytes| {
+            let [a, b, c] = [bytes[0], bytes[1], bytes[2]].map(usize::from);
+            [(a << 4) | (b >> 4), ((b & 0xF) << 8) | c]
+        })
+    }
+}
+
+/// The NTPv5 bloom filter of the servers a server synchronizes to, directly or
+/// through its sources. A server that finds its own id in the filter of a source
+/// would create a synchronization loop by using it.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct BloomFilter([u8; BloomFilter::LENGTH]);
+
+impl BloomFilter {
+    /// Length of the filter in bytes, 4096 bits
+    pub const LENGTH: usize = 512;
+
+    pub const fn new() -> Self {
+        BloomFilter([0; Self::LENGTH])
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::LENGTH]) -> Self {
+        BloomFilter(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::LENGTH] {
+        &self.0
+    }
+
+    pub fn add_id(&mut self, id: &ServerId) {
+        for index in id.bit_indices() {
+            self.0[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Add all ids in `other` to this filter
+    pub fn add(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= other;
+        }
+    }
+
+    /// Whether `id` is in the filter. False positives are possible, but rare
+    /// for the small number of servers in a synchronization chain.
+    pub fn contains_id(&self, id: &ServerId) -> bool {
+        id.bit_indices()
+            .all(|index| self.0[index / 8] & (0x80 >> (index % 8)) != 0)
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bits: u32 = self.0.iter().map(|byte| byte.count_ones()).sum();
+        f.debug_struct("BloomFilter").field("bits", &bits).finish()
+    }
+}
+
+/// The bloom filter of an NTPv5 server, which is too big to fetch in one request.
+/// It is requested a chunk at a time, and only used once all chunks arrived.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RemoteBloomFilter {
+    partial: BloomFilter,
+    next_offset: u16,
+    requested_offset: Option<u16>,
+    complete: Option<BloomFilter>,
+}
+
+impl RemoteBloomFilter {
+    /// Bytes of the filter requested at once, so requests stay reasonably small
+    const CHUNK_LENGTH: u16 = 128;
+
+    /// The field requesting the next chunk of the filter
+    pub(crate) fn next_request(&mut self) -> ExtensionField<'static> {
+        self.requested_offset = Some(self.next_offset);
+        ExtensionField::ReferenceIdRequest {
+            offset: self.next_offset,
+            length: Self::CHUNK_LENGTH,
+        }
+    }
+
+    /// Store the chunk of the filter the server sent in response to our last request
+    pub(crate) fn handle_response(&mut self, chunk: &[u8]) {
+        let offset = match self.requested_offset.take() {
+            Some(offset) if chunk.len() == Self::CHUNK_LENGTH as usize => offset as usize,
+            _ => return,
+        };
+
+        self.partial.0[offset..offset + chunk.len()].copy_from_slice(chunk);
+
+        self.next_offset += Self::CHUNK_LENGTH;
+        if self.next_offset as usize == BloomFilter::LENGTH {
+            // keep refreshing the filter, the sources of the server may change
+            self.complete = Some(self.partial);
+            self.next_offset = 0;
+        }
+    }
+
+    /// The last complete filter of the server
+    pub(crate) fn full_filter(&self) -> Option<BloomFilter> {
+        self.complete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_filter_contains_added_ids() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let ids: Vec<_> = (0..4).map(|_| ServerId::new(&mut rng)).collect();
+        let other = ServerId::new(&mut rng);
+
+        let mut filter = BloomFilter::new();
+        assert!(!filter.contains_id(&ids[0]));
+        filter.add_id(&ids[0]);
+        filter.add_id(&ids[1]);
+
+        let mut upstream = BloomFilter::new();
+        upstream.add_id(&ids[2]);
+        upstream.add_id(&ids[3]);
+        filter.add(&upstream);
+
+        assert!(ids.iter().all(|id| filter.contains_id(id)));
+        assert!(!filter.contains_id(&other));
+        assert!(!upstream.contains_id(&ids[0]));
+    }
+
+    #[test]
+    fn test_remote_filter_in_chunks() {
+        let mut filter = BloomFilter::new();
+        filter.add_id(&ServerId::new(&mut StdRng::seed_from_u64(2)));
+
+        let mut remote = RemoteBloomFilter::default();
+        // an unrequested chunk is ignored
+        remote.handle_response(&filter.as_bytes()[..128]);
+
+        for _ in 0..BloomFilter::LENGTH / 128 {
+            assert_eq!(remote.full_filter(), None);
+            let (offset, length) = match remote.next_request() {
+                ExtensionField::ReferenceIdRequest { offset, length } => {
+                    (offset as usize, length as usize)
+                }
+                other => panic!("unexpected request {other:?}"),
+            };
+            remote.handle_response(&filter.as_bytes()[offset..][..length]);
+        }
+        assert_eq!(remote.full_filter(), Some(filter));
+
+        // a chunk of the wrong length is requested again
+        remote.next_request();
+        remote.handle_response(&[0; 4]);
+        assert!(matches!(
+            remote.next_request(),
+            ExtensionField::ReferenceIdRequest { offset: 0, .. }
+        ));
+    }
+}
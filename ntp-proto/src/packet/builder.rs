@@ -0,0 +1,228 @@
+use crate::{NtpDuration, NtpTimestamp, ReferenceId, SymmetricKey};
+
+use super::{
+    extensionfields::ExtensionFieldData, ExtensionField, NtpAssociationMode, NtpHeader,
+    NtpHeaderV3V4, NtpLeapIndicator, NtpPacket,
+};
+
+/// Builder for arbitrary NTPv3 and NTPv4 packets, for when the requests and
+/// responses of [`NtpPacket`] don't fit. It starts out as an NTPv4 client
+/// request with all fields zero.
+#[derive(Debug, Clone)]
+pub struct NtpPacketBuilder<'a> {
+    header: NtpHeaderV3V4,
+    v3: bool,
+    efdata: ExtensionFieldData<'a>,
+    mac: Option<(u32, SymmetricKey)>,
+}
+
+impl Default for NtpPacketBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> NtpPacketBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            header: NtpHeaderV3V4::new(),
+            v3: false,
+            efdata: Default::default(),
+            mac: None,
+        }
+    }
+
+    /// Build an NTPv3 packet instead, which has no extension fields. Any
+    /// extension fields that are added are left out.
+    pub fn v3(mut self) -> Self {
+        self.v3 = true;
+        self
+    }
+
+    pub fn leap(mut self, leap: NtpLeapIndicator) -> Self {
+        self.header.leap = leap;
+        self
+    }
+
+    pub fn mode(mut self, mode: NtpAssociationMode) -> Self {
+        self.header.mode = mode;
+        self
+    }
+
+    pub fn stratum(mut self, stratum: u8) -> Self {
+        self.header.stratum = stratum;
+        self
+    }
+
+    /// The poll interval, as the log2 of the number of seconds
+    pub fn poll(mut self, poll: i8) -> Self {
+        self.header.poll = poll;
+        self
+    }
+
+    /// The precision, as the log2 of the number of seconds
+    pub fn precision(mut self, precision: i8) -> Self {
+        self.header.precision = precision;
+        self
+    }
+
+    pub fn root_delay(mut self, root_delay: NtpDuration) -> Self {
+        self.header.root_delay = root_delay;
+        self
+    }
+
+    pub fn root_dispersion(mut self, root_dispersion: NtpDuration) -> Self {
+        self.header.root_dispersion = root_dispersion;
+        self
+    }
+
+    pub fn reference_id(mut self, reference_id: ReferenceId) -> Self {
+        self.header.reference_id = reference_id;
+        self
+    }
+
+    pub fn reference_timestamp(mut self, timestamp: NtpTimestamp) -> Self {
+        self.header.reference_timestamp = timestamp;
+        self
+    }
+
+    pub fn origin_timestamp(mut self, timestamp: NtpTimestamp) -> Self {
+        self.header.origin_timestamp = timestamp;
+        self
+    }
+
+    pub fn receive_timestamp(mut self, timestamp: NtpTimestamp) -> Self {
+        self.header.receive_timestamp = timestamp;
+        self
+    }
+
+    pub fn transmit_timestamp(mut self, timestamp: NtpTimestamp) -> Self {
+        self.header.transmit_timestamp = timestamp;
+        self
+    }
+
+    /// Add an extension field that is covered by the NTS authentication, but
+    /// not encrypted
+    pub fn authenticated_field(mut self, field: ExtensionField<'a>) -> Self {
+        self.efdata.authenticated.push(field);
+        self
+    }
+
+    /// Add an extension field that is encrypted with the cipher given when
+    /// serializing the packet
+    pub fn encrypted_field(mut self, field: ExtensionField<'a>) -> Self {
+        self.efdata.encrypted.push(field);
+        self
+    }
+
+    /// Add an extension field that is not authenticated
+    pub fn untrusted_field(mut self, field: ExtensionField<'a>) -> Self {
+        self.efdata.untrusted.push(field);
+        self
+    }
+
+    /// Authenticate the packet with a MAC, using symmetric key `key` with id
+    /// `keyid`. Packets with a MAC should not use NTS.
+    pub fn mac(mut self, keyid: u32, key: &SymmetricKey) -> Self {
+        self.mac = Some((keyid, key.clone()));
+        self
+    }
+
+    pub fn build(self) -> std::io::Result<NtpPacket<'a>> {
+        let mut packet = NtpPacket {
+            header: match self.v3 {
+                true => NtpHeader::V3(self.header),
+                false => NtpHeader::V4(self.header),
+            },
+            efdata: match self.v3 {
+                true => Default::default(),
+                false => self.efdata,
+            },
+            mac: None,
+        };
+
+        if let Some((keyid, key)) = &self.mac {
+            packet.add_mac(*keyid, key)?;
+        }
+
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{NoCipher, PollInterval, SymmetricKeys};
+
+    use super::*;
+
+    #[test]
+    fn test_build_response() {
+        let packet = NtpPacketBuilder::new()
+            .leap(NtpLeapIndicator::Leap61)
+            .mode(NtpAssociationMode::Server)
+            .stratum(2)
+            .poll(6)
+            .precision(-20)
+            .root_delay(NtpDuration::from_fixed_int(1 << 30))
+            .reference_id(ReferenceId::KISS_RATE)
+            .origin_timestamp(NtpTimestamp::from_fixed_int(1))
+            .receive_timestamp(NtpTimestamp::from_fixed_int(2))
+            .transmit_timestamp(NtpTimestamp::from_fixed_int(3))
+            .untrusted_field(ExtensionField::UniqueIdentifier(Cow::Borrowed(&[7; 32])))
+            .build()
+            .unwrap();
+
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let parsed = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(parsed, packet);
+        assert_eq!(parsed.version(), 4);
+        assert_eq!(parsed.leap(), NtpLeapIndicator::Leap61);
+        assert_eq!(parsed.mode(), NtpAssociationMode::Server);
+        assert_eq!(parsed.stratum(), 2);
+        assert_eq!(parsed.poll(), PollInterval::from_log(6));
+        assert_eq!(parsed.precision(), -20);
+        assert_eq!(parsed.root_delay(), NtpDuration::from_fixed_int(1 << 30));
+        assert_eq!(parsed.reference_id(), ReferenceId::KISS_RATE);
+        assert_eq!(parsed.origin_timestamp(), NtpTimestamp::from_fixed_int(1));
+        assert_eq!(parsed.receive_timestamp(), NtpTimestamp::from_fixed_int(2));
+        assert_eq!(parsed.transmit_timestamp(), NtpTimestamp::from_fixed_int(3));
+    }
+
+    #[test]
+    fn test_build_v3() {
+        let packet = NtpPacketBuilder::new()
+            .v3()
+            .untrusted_field(ExtensionField::UniqueIdentifier(Cow::Borrowed(&[7; 32])))
+            .build()
+            .unwrap();
+
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        assert_eq!(data.len(), 48);
+        assert_eq!(
+            NtpPacket::deserialize(&data, &NoCipher)
+                .unwrap()
+                .0
+                .version(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_build_with_mac() {
+        let keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = keys.get(1).unwrap();
+
+        let packet = NtpPacketBuilder::new()
+            .transmit_timestamp(NtpTimestamp::from_fixed_int(3))
+            .mac(1, key)
+            .build()
+            .unwrap();
+        assert_eq!(packet.mac_keyid(), Some(1));
+
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let parsed = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(parsed.verify_mac(&data, 1, key), Ok(()));
+    }
+}
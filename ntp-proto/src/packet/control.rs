@@ -0,0 +1,444 @@
+use std::borrow::Cow;
+
+use super::{NtpAssociationMode, NtpLeapIndicator};
+
+/// The operation of a control message (rfc1305, appendix B)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ControlOpcode {
+    ReadStatus,
+    ReadVariables,
+    WriteVariables,
+    ReadClockVariables,
+    WriteClockVariables,
+    SetTrap,
+    AsyncMessage,
+    UnsetTrap,
+    Unknown(u8),
+}
+
+impl ControlOpcode {
+    // This function should only ever be called with 5 bit values
+    fn from_bits(bits: u8) -> ControlOpcode {
+        match bits {
+            1 => ControlOpcode::ReadStatus,
+            2 => ControlOpcode::ReadVariables,
+            3 => ControlOpcode::WriteVariables,
+            4 => ControlOpcode::ReadClockVariables,
+            5 => ControlOpcode::WriteClockVariables,
+            6 => ControlOpcode::SetTrap,
+            7 => ControlOpcode::AsyncMessage,
+            31 => ControlOpcode::UnsetTrap,
+            other => ControlOpcode::Unknown(other),
+        }
+    }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            ControlOpcode::ReadStatus => 1,
+            ControlOpcode::ReadVariables => 2,
+            ControlOpcode::WriteVariables => 3,
+            ControlOpcode::ReadClockVariables => 4,
+            ControlOpcode::WriteClockVariables => 5,
+            ControlOpcode::SetTrap => 6,
+            ControlOpcode::AsyncMessage => 7,
+            ControlOpcode::UnsetTrap => 31,
+            ControlOpcode::Unknown(bits) => bits & 0x1F,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ControlParsingError {
+    #[error("Incorrect control message length")]
+    IncorrectLength,
+    #[error("Invalid version {0}")]
+    InvalidVersion(u8),
+    #[error("Not a control message, mode is {0:?}")]
+    InvalidMode(NtpAssociationMode),
+    #[error("Malformed variables in control message")]
+    MalformedVariables,
+}
+
+/// The status word of the system, in responses about association 0
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SystemStatusWord {
+    pub leap: NtpLeapIndicator,
+    /// The kind of source the system synchronizes to
+    pub clock_source: u8,
+    pub event_count: u8,
+    pub event_code: u8,
+}
+
+impl SystemStatusWord {
+    pub fn from_bits(bits: u16) -> Self {
+        SystemStatusWord {
+            leap: NtpLeapIndicator::from_bits((bits >> 14) as u8),
+            clock_source: ((bits >> 8) & 0x3F) as u8,
+            event_count: ((bits >> 4) & 0xF) as u8,
+            event_code: (bits & 0xF) as u8,
+        }
+    }
+
+    pub fn to_bits(self) -> u16 {
+        (self.leap.to_bits() as u16) << 14
+            | (self.clock_source as u16 & 0x3F) << 8
+            | (self.event_count as u16 & 0xF) << 4
+            | (self.event_code as u16 & 0xF)
+    }
+}
+
+/// The status word of a peer association
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PeerStatusWord {
+    /// Flags for whether the association is configured, uses authentication,
+    /// is authenticated, is reachable and is a broadcast association
+    pub status: u8,
+    /// How far the peer made it through clock selection
+    pub selection: u8,
+    pub event_count: u8,
+    pub event_code: u8,
+}
+
+impl PeerStatusWord {
+    pub fn from_bits(bits: u16) -> Self {
+        PeerStatusWord {
+            status: (bits >> 11) as u8,
+            selection: ((bits >> 8) & 0x7) as u8,
+            event_count: ((bits >> 4) & 0xF) as u8,
+            event_code: (bits & 0xF) as u8,
+        }
+    }
+
+    pub fn to_bits(self) -> u16 {
+        (self.status as u16 & 0x1F) << 11
+            | (self.selection as u16 & 0x7) << 8
+            | (self.event_count as u16 & 0xF) << 4
+            | (self.event_code as u16 & 0xF)
+    }
+}
+
+/// An NTP mode 6 control message, as used by ntpq to monitor servers. Large
+/// responses are split over several messages, each carrying the `offset` of
+/// its data and all but the last one with `more` set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlPacket<'a> {
+    pub version: u8,
+    pub response: bool,
+    pub error: bool,
+    pub more: bool,
+    pub opcode: ControlOpcode,
+    pub sequence: u16,
+    pub status: u16,
+    pub association_id: u16,
+    pub offset: u16,
+    pub data: Cow<'a, [u8]>,
+}
+
+impl<'a> ControlPacket<'a> {
+    pub const HEADER_LENGTH: usize = 12;
+    /// The most data a single message may carry
+    pub const MAX_DATA_LENGTH: usize = 468;
+
+    pub fn request(opcode: ControlOpcode, sequence: u16, association_id: u16) -> Self {
+        ControlPacket {
+            version: 4,
+            response: false,
+            error: false,
+            more: false,
+            opcode,
+            sequence,
+            status: 0,
+            association_id,
+            offset: 0,
+            data: Cow::Borrowed(&[]),
+        }
+    }
+
+    /// A response to this request, carrying `data`
+    pub fn response<'b>(&self, status: u16, data: impl Into<Cow<'b, [u8]>>) -> ControlPacket<'b> {
+        ControlPacket {
+            version: self.version,
+            response: true,
+            error: false,
+            more: false,
+            opcode: self.opcode,
+            sequence: self.sequence,
+            status,
+            association_id: self.association_id,
+            offset: 0,
+            data: data.into(),
+        }
+    }
+
+    /// Parse a control message. A MAC following the data is not verified,
+    /// and left out.
+    pub fn deserialize(data: &'a [u8]) -> Result<Self, ControlParsingError> {
+        if data.len() < Self::HEADER_LENGTH {
+            return Err(ControlParsingError::IncorrectLength);
+        }
+
+        let version = (data[0] & 0x38) >> 3;
+        if !(1..=4).contains(&version) {
+            return Err(ControlParsingError::InvalidVersion(version));
+        }
+
+        let mode = NtpAssociationMode::from_bits(data[0] & 0x07);
+        if mode != NtpAssociationMode::Control {
+            return Err(ControlParsingError::InvalidMode(mode));
+        }
+
+        let count = u16::from_be_bytes([data[10], data[11]]) as usize;
+        let payload = data[Self::HEADER_LENGTH..]
+            .get(..count)
+            .ok_or(ControlParsingError::IncorrectLength)?;
+
+        Ok(ControlPacket {
+            version,
+            response: data[1] & 0x80 != 0,
+            error: data[1] & 0x40 != 0,
+            more: data[1] & 0x20 != 0,
+            opcode: ControlOpcode::from_bits(data[1] & 0x1F),
+            sequence: u16::from_be_bytes([data[2], data[3]]),
+            status: u16::from_be_bytes([data[4], data[5]]),
+            association_id: u16::from_be_bytes([data[6], data[7]]),
+            offset: u16::from_be_bytes([data[8], data[9]]),
+            data: Cow::Borrowed(payload),
+        })
+    }
+
+    /// Write the message, with its data padded to a multiple of four octets
+    pub fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        if self.data.len() > Self::MAX_DATA_LENGTH {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        w.write_all(&[(self.version & 0x7) << 3 | NtpAssociationMode::Control.to_bits()])?;
+        w.write_all(&[(self.response as u8) << 7
+            | (self.error as u8) << 6
+            | (self.more as u8) << 5
+            | self.opcode.to_bits()])?;
+        w.write_all(&self.sequence.to_be_bytes())?;
+        w.write_all(&self.status.to_be_bytes())?;
+        w.write_all(&self.association_id.to_be_bytes())?;
+        w.write_all(&self.offset.to_be_bytes())?;
+        w.write_all(&(self.data.len() as u16).to_be_bytes())?;
+        w.write_all(&self.data)?;
+
+        let padding = self.data.len().next_multiple_of(4) - self.data.len();
+        w.write_all(&[0; 3][..padding])?;
+
+        Ok(())
+    }
+
+    pub fn into_owned(self) -> ControlPacket<'static> {
+        ControlPacket {
+            data: Cow::Owned(self.data.into_owned()),
+            ..self
+        }
+    }
+
+    /// The status word as that of the system, for messages about association 0
+    pub fn system_status(&self) -> SystemStatusWord {
+        SystemStatusWord::from_bits(self.status)
+    }
+
+    /// The status word as that of the peer of the association
+    pub fn peer_status(&self) -> PeerStatusWord {
+        PeerStatusWord::from_bits(self.status)
+    }
+
+    /// The associations and their status in a response to reading the status
+    /// of association 0
+    pub fn association_statuses(&self) -> impl Iterator<Item = (u16, PeerStatusWord)> + '_ {
+        self.data.chunks_exact(4).map(|chunk| {
+            (
+                u16::from_be_bytes([chunk[0], chunk[1]]),
+                PeerStatusWord::from_bits(u16::from_be_bytes([chunk[2], chunk[3]])),
+            )
+        })
+    }
+
+    /// The variables in the data, a comma separated list of `name` or
+    /// `name=value`, where values containing commas are quoted
+    pub fn variables(&self) -> Result<Vec<(&str, Option<&str>)>, ControlParsingError> {
+        let text =
+            std::str::from_utf8(&self.data).map_err(|_| ControlParsingError::MalformedVariables)?;
+
+        let mut variables = vec![];
+        let mut rest = text.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        while !rest.is_empty() {
+            let name_end = rest.find([',', '=']).unwrap_or(rest.len());
+            let name = rest[..name_end].trim();
+            rest = &rest[name_end..];
+
+            let value = match rest.strip_prefix('=') {
+                Some(quoted) if quoted.trim_start().starts_with('"') => {
+                    let quoted = &quoted.trim_start()[1..];
+                    let end = quoted
+                        .find('"')
+                        .ok_or(ControlParsingError::MalformedVariables)?;
+                    rest = &quoted[end + 1..];
+                    Some(&quoted[..end])
+                }
+                Some(value) => {
+                    let end = value.find(',').unwrap_or(value.len());
+                    rest = &value[end..];
+                    Some(value[..end].trim())
+                }
+                None => None,
+            };
+
+            if name.is_empty() {
+                return Err(ControlParsingError::MalformedVariables);
+            }
+            variables.push((name, value));
+
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        }
+
+        Ok(variables)
+    }
+
+    /// Data for a message carrying `variables`, in the form [`ControlPacket::variables`] reads
+    pub fn encode_variables<'b>(
+        variables: impl IntoIterator<Item = (&'b str, Option<&'b str>)>,
+    ) -> Vec<u8> {
+        let mut text = String::new();
+        for (name, value) in variables {
+            if !text.is_empty() {
+                text.push_str(", ");
+            }
+            text.push_str(name);
+            match value {
+                Some(value) if value.contains([',', '"', '=']) || value != value.trim() => {
+                    text.push_str("=\"");
+                    text.push_str(value);
+                    text.push('"');
+                }
+                Some(value) => {
+                    text.push('=');
+                    text.push_str(value);
+                }
+                None => {}
+            }
+        }
+        text.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_roundtrip() {
+        let request = ControlPacket::request(ControlOpcode::ReadVariables, 7, 0);
+        let mut buf = vec![];
+        request.serialize(&mut buf).unwrap();
+        assert_eq!(buf, [0x26, 0x02, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ControlPacket::deserialize(&buf).unwrap(), request);
+
+        let status = SystemStatusWord {
+            leap: NtpLeapIndicator::NoWarning,
+            clock_source: 6,
+            event_count: 1,
+            event_code: 5,
+        };
+        let response = request.response(status.to_bits(), b"version=\"ntpd-rs\"".to_vec());
+        let mut buf = vec![];
+        response.serialize(&mut buf).unwrap();
+        // data is padded to a multiple of four octets
+        assert_eq!(buf.len(), ControlPacket::HEADER_LENGTH + 20);
+        assert_eq!(buf[1], 0x82);
+
+        let parsed = ControlPacket::deserialize(&buf).unwrap();
+        assert_eq!(parsed, response);
+        assert_eq!(parsed.system_status(), status);
+        assert_eq!(parsed.variables().unwrap(), [("version", Some("ntpd-rs"))]);
+    }
+
+    #[test]
+    fn test_control_invalid() {
+        assert_eq!(
+            ControlPacket::deserialize(&[0x26, 0x02, 0, 7]),
+            Err(ControlParsingError::IncorrectLength)
+        );
+        // count beyond the end of the message
+        assert_eq!(
+            ControlPacket::deserialize(&[0x26, 0x02, 0, 7, 0, 0, 0, 0, 0, 0, 0, 4]),
+            Err(ControlParsingError::IncorrectLength)
+        );
+        assert_eq!(
+            ControlPacket::deserialize(&[0x23, 0x02, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(ControlParsingError::InvalidMode(NtpAssociationMode::Client))
+        );
+        assert_eq!(
+            ControlPacket::deserialize(&[0x3E, 0x02, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(ControlParsingError::InvalidVersion(7))
+        );
+
+        let mut request = ControlPacket::request(ControlOpcode::WriteVariables, 1, 0);
+        request.data = vec![b'a'; ControlPacket::MAX_DATA_LENGTH + 1].into();
+        assert!(request.serialize(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_control_variables() {
+        let data = ControlPacket::encode_variables([
+            ("stratum", Some("2")),
+            ("refid", Some("192.0.2.1")),
+            ("version", Some("ntpd-rs 1.0, linux")),
+            ("clock", None),
+        ]);
+        assert_eq!(
+            data,
+            b"stratum=2, refid=192.0.2.1, version=\"ntpd-rs 1.0, linux\", clock"
+        );
+
+        let mut packet = ControlPacket::request(ControlOpcode::ReadVariables, 1, 0);
+        packet.data = data.into();
+        assert_eq!(
+            packet.variables().unwrap(),
+            [
+                ("stratum", Some("2")),
+                ("refid", Some("192.0.2.1")),
+                ("version", Some("ntpd-rs 1.0, linux")),
+                ("clock", None),
+            ]
+        );
+
+        // ntpd breaks long lists over lines
+        packet.data = b"offset=0.123,\r\nfrequency=-1.5,\r\n".to_vec().into();
+        assert_eq!(
+            packet.variables().unwrap(),
+            [("offset", Some("0.123")), ("frequency", Some("-1.5"))]
+        );
+
+        packet.data = b"version=\"unterminated".to_vec().into();
+        assert_eq!(
+            packet.variables(),
+            Err(ControlParsingError::MalformedVariables)
+        );
+    }
+
+    #[test]
+    fn test_association_statuses() {
+        let status = PeerStatusWord {
+            status: 0x1F,
+            selection: 6,
+            event_count: 2,
+            event_code: 4,
+        };
+        assert_eq!(PeerStatusWord::from_bits(status.to_bits()), status);
+
+        let request = ControlPacket::request(ControlOpcode::ReadStatus, 3, 0);
+        let mut data = vec![];
+        for id in [1_u16, 2] {
+            data.extend_from_slice(&id.to_be_bytes());
+            data.extend_from_slice(&status.to_bits().to_be_bytes());
+        }
+        let response = request.response(0, data);
+        let associations: Vec<_> = response.association_statuses().collect();
+        assert_eq!(associations, [(1, status), (2, status)]);
+    }
+}
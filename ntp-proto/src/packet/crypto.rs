@@ -3,7 +3,7 @@ use rand::Rng;
 use tracing::error;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use crate::DecodedServerCookie;
+use crate::{nts_record::AeadAlgorithm, DecodedServerCookie};
 
 use super::extensionfields::ExtensionField;
 
@@ -11,12 +11,78 @@ use super::extensionfields::ExtensionField;
 #[error("Could not decrypt ciphertext")]
 pub struct DecryptError;
 
+/// Compare two byte strings in time independent of their contents.
+///
+/// Only the lengths of the inputs leak through timing. Any comparison of
+/// cookies, keys or other secret-derived material on the server path should
+/// go through this function rather than `==`, which may return early on the
+/// first mismatching byte. Authentication tags are already checked in
+/// constant time by the AEAD implementation itself.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let difference = a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y));
+
+    std::hint::black_box(difference) == 0
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum UnsupportedAead {
+    #[error("Unsupported AEAD algorithm {0}")]
+    UnknownAlgorithm(u16),
+    #[error("Invalid key length {actual} for AEAD algorithm, expected {expected}")]
+    InvalidKeyLength { expected: usize, actual: usize },
+}
+
+/// Construct the cipher for the AEAD algorithm with the given id, using `key`.
+///
+/// The key must have exactly the length the algorithm requires: 32 bytes for
+/// AEAD_AES_SIV_CMAC_256 (15) and 64 bytes for AEAD_AES_SIV_CMAC_512 (17).
+pub fn cipher_for_aead(id: u16, key: &[u8]) -> Result<Box<dyn Cipher>, UnsupportedAead> {
+    let algorithm =
+        AeadAlgorithm::try_deserialize(id).ok_or(UnsupportedAead::UnknownAlgorithm(id))?;
+
+    if key.len() != algorithm.key_size() {
+        return Err(UnsupportedAead::InvalidKeyLength {
+            expected: algorithm.key_size(),
+            actual: key.len(),
+        });
+    }
+
+    Ok(match algorithm {
+        AeadAlgorithm::AeadAesSivCmac256 => {
+            Box::new(AesSivCmac256::new(Key::<Aes128Siv>::clone_from_slice(key)))
+        }
+        AeadAlgorithm::AeadAesSivCmac512 => {
+            Box::new(AesSivCmac512::new(Key::<Aes256Siv>::clone_from_slice(key)))
+        }
+    })
+}
+
 pub trait Cipher: Sync + Send + ZeroizeOnDrop + 'static {
+    /// Encrypt `plaintext` in place with `nonce`, returning the authentication tag.
+    /// A nonce must never be used twice with the same key.
+    fn encrypt_in_place_detached_with_nonce(
+        &self,
+        nonce: &aes_siv::Nonce,
+        plaintext: &mut [u8],
+        associated_data: &[u8],
+    ) -> std::io::Result<aes_siv::Tag>;
+
+    /// Encrypt `plaintext` in place with a fresh random nonce, returning the
+    /// authentication tag and the nonce
     fn encrypt_in_place_detached(
         &self,
         plaintext: &mut [u8],
         associated_data: &[u8],
-    ) -> std::io::Result<(aes_siv::Tag, aes_siv::Nonce)>;
+    ) -> std::io::Result<(aes_siv::Tag, aes_siv::Nonce)> {
+        let nonce: aes_siv::Nonce = rand::thread_rng().gen::<[u8; 16]>().into();
+        let siv_tag =
+            self.encrypt_in_place_detached_with_nonce(&nonce, plaintext, associated_data)?;
+        Ok((siv_tag, nonce))
+    }
 
     fn decrypt(
         &self,
@@ -25,7 +91,34 @@ pub trait Cipher: Sync + Send + ZeroizeOnDrop + 'static {
         associated_data: &[u8],
     ) -> Result<Vec<u8>, DecryptError>;
 
+    /// Decrypt `ciphertext` into the start of `buffer`, returning the length of the
+    /// plaintext. Fails when `buffer` can't hold the plaintext.
+    fn decrypt_into(
+        &self,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, DecryptError> {
+        let plaintext = self.decrypt(nonce, ciphertext, associated_data)?;
+        buffer
+            .get_mut(..plaintext.len())
+            .ok_or(DecryptError)?
+            .copy_from_slice(&plaintext);
+        Ok(plaintext.len())
+    }
+
     fn key_bytes(&self) -> &[u8];
+
+    /// Length in bytes of the nonces generated when encrypting
+    fn nonce_len(&self) -> usize {
+        std::mem::size_of::<aes_siv::Nonce>()
+    }
+
+    /// Length in bytes of the authentication tag added to the ciphertext
+    fn tag_len(&self) -> usize {
+        std::mem::size_of::<aes_siv::Tag>()
+    }
 }
 
 pub enum CipherHolder<'a> {
@@ -78,6 +171,23 @@ impl<C: Cipher> CipherProvider for Option<C> {
     }
 }
 
+/// Split the SIV tag off the front of `ciphertext` and copy the rest into
+/// `buffer`, to be decrypted in place
+fn split_siv_ciphertext<'b>(
+    ciphertext: &[u8],
+    buffer: &'b mut [u8],
+) -> Result<(aes_siv::Tag, &'b mut [u8]), DecryptError> {
+    let tag_size = std::mem::size_of::<aes_siv::Tag>();
+    if ciphertext.len() < tag_size {
+        return Err(DecryptError);
+    }
+    let (tag, ciphertext) = ciphertext.split_at(tag_size);
+    let buffer = buffer.get_mut(..ciphertext.len()).ok_or(DecryptError)?;
+    buffer.copy_from_slice(ciphertext);
+
+    Ok((*aes_siv::Tag::from_slice(tag), buffer))
+}
+
 pub struct AesSivCmac256 {
     // 128 vs 256 difference is due to using the official name (us) vs
     // the number of bits of security (aes_siv crate)
@@ -99,24 +209,22 @@ impl Drop for AesSivCmac256 {
 }
 
 impl Cipher for AesSivCmac256 {
-    fn encrypt_in_place_detached(
+    fn encrypt_in_place_detached_with_nonce(
         &self,
+        nonce: &aes_siv::Nonce,
         plaintext: &mut [u8],
         associated_data: &[u8],
-    ) -> std::io::Result<(aes_siv::Tag, aes_siv::Nonce)> {
+    ) -> std::io::Result<aes_siv::Tag> {
         let mut siv = Aes128Siv::new(&self.key);
-        let nonce: [u8; 16] = rand::thread_rng().gen();
 
-        let siv_tag = match siv.encrypt_in_place_detached([associated_data, &nonce], plaintext) {
-            Ok(tag) => tag,
+        match siv.encrypt_in_place_detached([associated_data, nonce], plaintext) {
+            Ok(tag) => Ok(tag),
             Err(e) => {
                 // This should probably never happen, so log as an error
                 error!(error = ?e, "Encryption failed");
-                return Err(std::io::Error::from(std::io::ErrorKind::Other));
+                Err(std::io::Error::from(std::io::ErrorKind::Other))
             }
-        };
-
-        Ok((siv_tag, nonce.into()))
+        }
     }
 
     fn decrypt(
@@ -130,6 +238,20 @@ impl Cipher for AesSivCmac256 {
             .map_err(|_| DecryptError)
     }
 
+    fn decrypt_into(
+        &self,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, DecryptError> {
+        let (tag, plaintext) = split_siv_ciphertext(ciphertext, buffer)?;
+        let mut siv = Aes128Siv::new(&self.key);
+        siv.decrypt_in_place_detached([associated_data, nonce], plaintext, &tag)
+            .map_err(|_| DecryptError)?;
+        Ok(plaintext.len())
+    }
+
     fn key_bytes(&self) -> &[u8] {
         &self.key
     }
@@ -163,24 +285,22 @@ impl Drop for AesSivCmac512 {
 }
 
 impl Cipher for AesSivCmac512 {
-    fn encrypt_in_place_detached(
+    fn encrypt_in_place_detached_with_nonce(
         &self,
+        nonce: &aes_siv::Nonce,
         plaintext: &mut [u8],
         associated_data: &[u8],
-    ) -> std::io::Result<(aes_siv::Tag, aes_siv::Nonce)> {
+    ) -> std::io::Result<aes_siv::Tag> {
         let mut siv = Aes256Siv::new(&self.key);
-        let nonce: [u8; 16] = rand::thread_rng().gen();
 
-        let siv_tag = match siv.encrypt_in_place_detached([associated_data, &nonce], plaintext) {
-            Ok(tag) => tag,
+        match siv.encrypt_in_place_detached([associated_data, nonce], plaintext) {
+            Ok(tag) => Ok(tag),
             Err(e) => {
                 // This should probably never happen, so log as an error
                 error!(error = ?e, "Encryption failed");
-                return Err(std::io::Error::from(std::io::ErrorKind::Other));
+                Err(std::io::Error::from(std::io::ErrorKind::Other))
             }
-        };
-
-        Ok((siv_tag, nonce.into()))
+        }
     }
 
     fn decrypt(
@@ -194,6 +314,20 @@ impl Cipher for AesSivCmac512 {
             .map_err(|_| DecryptError)
     }
 
+    fn decrypt_into(
+        &self,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, DecryptError> {
+        let (tag, plaintext) = split_siv_ciphertext(ciphertext, buffer)?;
+        let mut siv = Aes256Siv::new(&self.key);
+        siv.decrypt_in_place_detached([associated_data, nonce], plaintext, &tag)
+            .map_err(|_| DecryptError)?;
+        Ok(plaintext.len())
+    }
+
     fn key_bytes(&self) -> &[u8] {
         &self.key
     }
@@ -205,3 +339,94 @@ impl std::fmt::Debug for AesSivCmac512 {
         f.debug_struct("AesSivCmac512").finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(&[], &[]));
+        assert!(constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!constant_time_eq(&[0, 2, 3], &[1, 2, 3]));
+
+        // length is checked, a prefix is not equal
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2]));
+        assert!(!constant_time_eq(&[1, 2], &[1, 2, 3]));
+        assert!(!constant_time_eq(&[], &[0]));
+    }
+
+    #[test]
+    fn test_cipher_for_aead() {
+        let key: Vec<u8> = (0..32).collect();
+        let cipher = cipher_for_aead(15, &key).unwrap();
+        assert_eq!(cipher.key_bytes(), key.as_slice());
+
+        let key: Vec<u8> = (0..64).collect();
+        let cipher = cipher_for_aead(17, &key).unwrap();
+        assert_eq!(cipher.key_bytes(), key.as_slice());
+
+        // the cipher actually works
+        let mut plaintext = *b"hello world";
+        let (tag, nonce) = cipher
+            .encrypt_in_place_detached(&mut plaintext, b"aad")
+            .unwrap();
+        let ciphertext: Vec<u8> = tag.iter().chain(plaintext.iter()).copied().collect();
+        let decrypted = cipher.decrypt(&nonce, &ciphertext, b"aad").unwrap();
+        assert_eq!(decrypted, b"hello world");
+    }
+
+    #[test]
+    fn test_decrypt_single_bit_flips() {
+        for (id, key_size) in [(15, 32), (17, 64)] {
+            let cipher = cipher_for_aead(id, &vec![0x33; key_size]).unwrap();
+
+            let mut plaintext = *b"hello world";
+            let (tag, nonce) = cipher
+                .encrypt_in_place_detached(&mut plaintext, b"aad")
+                .unwrap();
+            let ciphertext: Vec<u8> = tag.iter().chain(plaintext.iter()).copied().collect();
+            assert!(cipher.decrypt(&nonce, &ciphertext, b"aad").is_ok());
+
+            for bit in 0..8 {
+                let mut nonce = nonce;
+                nonce[3] ^= 1 << bit;
+                assert!(cipher.decrypt(&nonce, &ciphertext, b"aad").is_err());
+            }
+
+            // both in the tag and in the encrypted data
+            for index in [0, tag.len() - 1, tag.len(), ciphertext.len() - 1] {
+                let mut ciphertext = ciphertext.clone();
+                ciphertext[index] ^= 0x80;
+                assert!(cipher.decrypt(&nonce, &ciphertext, b"aad").is_err());
+            }
+
+            assert!(cipher.decrypt(&nonce, &ciphertext, b"aae").is_err());
+            assert!(cipher.decrypt(&nonce, &ciphertext, b"").is_err());
+        }
+    }
+
+    #[test]
+    fn test_cipher_for_aead_invalid() {
+        assert!(matches!(
+            cipher_for_aead(16, &[0; 32]),
+            Err(UnsupportedAead::UnknownAlgorithm(16))
+        ));
+        assert!(matches!(
+            cipher_for_aead(15, &[0; 64]),
+            Err(UnsupportedAead::InvalidKeyLength {
+                expected: 32,
+                actual: 64
+            })
+        ));
+        assert!(matches!(
+            cipher_for_aead(17, &[0; 32]),
+            Err(UnsupportedAead::InvalidKeyLength {
+                expected: 64,
+                actual: 32
+            })
+        ));
+    }
+}
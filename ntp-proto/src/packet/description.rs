@@ -0,0 +1,333 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    error::ParsingError, extensionfields::ExtensionFieldData, ExtensionFieldParsingError, Mac,
+    NoCipher, NtpAssociationMode, NtpLeapIndicator, NtpPacket,
+};
+
+/// Field by field description of an NTPv3 or NTPv4 packet as it is on the
+/// wire, for storing packets as data, such as interop captures of other
+/// implementations. Timestamps, durations and the reference id are the raw
+/// values of the wire format, so a description always maps back onto
+/// exactly the same bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PacketDescription {
+    pub version: u8,
+    pub leap: NtpLeapIndicator,
+    pub mode: NtpAssociationMode,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: u32,
+    pub reference_timestamp: u64,
+    pub origin_timestamp: u64,
+    pub receive_timestamp: u64,
+    pub transmit_timestamp: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extension_fields: Vec<FieldDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<MacDescription>,
+}
+
+/// An extension field as it is on the wire. The data includes any padding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FieldDescription {
+    pub field_type: u16,
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MacDescription {
+    pub keyid: u32,
+    #[serde(with = "hex")]
+    pub digest: Vec<u8>,
+}
+
+impl PacketDescription {
+    const HEADER_LENGTH: usize = 48;
+
+    /// Describe the packet in `data`, without interpreting its extension fields
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ExtensionFieldParsingError> {
+        if data.len() < Self::HEADER_LENGTH {
+            return Err(ParsingError::IncorrectLength);
+        }
+
+        let version = (data[0] & 0b0011_1000) >> 3;
+        let (extension_fields, rest) = match version {
+            3 => (vec![], &data[Self::HEADER_LENGTH..]),
+            4 => {
+                let (fields, length) =
+                    ExtensionFieldData::raw_fields(&data[Self::HEADER_LENGTH..])?;
+                let fields = fields
+                    .into_iter()
+                    .map(|(field_type, data)| FieldDescription {
+                        field_type,
+                        data: data.to_vec(),
+                    })
+                    .collect();
+                (fields, &data[Self::HEADER_LENGTH + length..])
+            }
+            _ => return Err(ParsingError::InvalidVersion(version)),
+        };
+
+        let mac = match rest {
+            [] => None,
+            rest => {
+                let mac = Mac::deserialize(rest)?;
+                Some(MacDescription {
+                    keyid: mac.keyid(),
+                    digest: mac.digest().to_vec(),
+                })
+            }
+        };
+
+        let u32_at =
+            |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        Ok(Self {
+            version,
+            leap: NtpLeapIndicator::from_bits((data[0] & 0xC0) >> 6),
+            mode: NtpAssociationMode::from_bits(data[0] & 0x07),
+            stratum: data[1],
+            poll: data[2] as i8,
+            precision: data[3] as i8,
+            root_delay: u32_at(4),
+            root_dispersion: u32_at(8),
+            reference_id: u32_at(12),
+            reference_timestamp: u64_at(16),
+            origin_timestamp: u64_at(24),
+            receive_timestamp: u64_at(32),
+            transmit_timestamp: u64_at(40),
+            extension_fields,
+            mac,
+        })
+    }
+
+    /// The packet this describes, as it is on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::HEADER_LENGTH);
+
+        data.push((self.leap.to_bits() << 6) | ((self.version & 0b111) << 3) | self.mode.to_bits());
+        data.extend([self.stratum, self.poll as u8, self.precision as u8]);
+        data.extend(self.root_delay.to_be_bytes());
+        data.extend(self.root_dispersion.to_be_bytes());
+        data.extend(self.reference_id.to_be_bytes());
+        data.extend(self.reference_timestamp.to_be_bytes());
+        data.extend(self.origin_timestamp.to_be_bytes());
+        data.extend(self.receive_timestamp.to_be_bytes());
+        data.extend(self.transmit_timestamp.to_be_bytes());
+
+        for field in &self.extension_fields {
+            data.extend(field.field_type.to_be_bytes());
+            data.extend(((field.data.len() + 4) as u16).to_be_bytes());
+            data.extend(&field.data);
+        }
+
+        if let Some(mac) = &self.mac {
+            data.extend(mac.keyid.to_be_bytes());
+            data.extend(&mac.digest);
+        }
+
+        data
+    }
+}
+
+/// A packet stored as data, with what it should parse as. Captures of other
+/// implementations can be kept as a list of these in a JSON or YAML file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PacketTestVector {
+    pub name: String,
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+    /// What the data should parse as, or none when it should be rejected
+    pub packet: Option<PacketDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TestVectorError {
+    #[error("{name}: expected a valid packet, but parsing failed with {error}")]
+    Rejected { name: String, error: String },
+    #[error("{name}: expected parsing to fail, but the packet was accepted")]
+    Accepted { name: String },
+    #[error("{name}: the packet differs from its description")]
+    Mismatch { name: String },
+}
+
+impl PacketTestVector {
+    /// Read a list of test vectors from JSON
+    pub fn load_json(json: &str) -> serde_json::Result<Vec<Self>> {
+        serde_json::from_str(json)
+    }
+
+    /// Check that the data parses as described. Packets with NTS encrypted
+    /// fields are valid when only their decryption fails, as the keys to
+    /// decrypt captures are generally not known.
+    pub fn check(&self) -> Result<(), TestVectorError> {
+        let parsed = match NtpPacket::deserialize(&self.data, &NoCipher) {
+            Ok(_) | Err(ParsingError::DecryptError(_)) => {
+                PacketDescription::from_bytes(&self.data).map_err(|e| format!("{e:?}"))
+            }
+            Err(e) => Err(format!("{e:?}")),
+        };
+
+        match (parsed, &self.packet) {
+            (Ok(parsed), Some(expected)) => {
+                if &parsed == expected && expected.to_bytes() == self.data {
+                    Ok(())
+                } else {
+                    Err(TestVectorError::Mismatch {
+                        name: self.name.clone(),
+                    })
+                }
+            }
+            (Err(error), Some(_)) => Err(TestVectorError::Rejected {
+                name: self.name.clone(),
+                error,
+            }),
+            (Ok(_), None) => Err(TestVectorError::Accepted {
+                name: self.name.clone(),
+            }),
+            (Err(_), None) => Ok(()),
+        }
+    }
+}
+
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+
+        // whitespace is allowed, so long packets can be split over lines
+        let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        let pairs = digits.chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+
+        pairs
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| D::Error::custom("invalid hex digit"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{ExtensionField, NtpPacketBuilder, NtpTimestamp, SymmetricKeys};
+
+    use super::*;
+
+    #[test]
+    fn test_packet_vectors() {
+        let path =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/packet-vectors.json");
+        let vectors = PacketTestVector::load_json(&std::fs::read_to_string(path).unwrap()).unwrap();
+
+        assert!(!vectors.is_empty());
+        for vector in &vectors {
+            assert_eq!(vector.check(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_description_roundtrip() {
+        let keys: SymmetricKeys = "3 SHA256 secret".parse().unwrap();
+        let packet = NtpPacketBuilder::new()
+            .mode(NtpAssociationMode::Server)
+            .stratum(2)
+            .precision(-20)
+            .transmit_timestamp(NtpTimestamp::from_fixed_int(0xE5F6_6366_7B64_995D))
+            .untrusted_field(ExtensionField::UniqueIdentifier(Cow::Borrowed(&[7; 32])))
+            .mac(3, keys.get(3).unwrap())
+            .build()
+            .unwrap();
+
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let description = PacketDescription::from_bytes(&data).unwrap();
+        assert_eq!(description.version, 4);
+        assert_eq!(description.mode, NtpAssociationMode::Server);
+        assert_eq!(description.precision, -20);
+        assert_eq!(description.transmit_timestamp, 0xE5F6_6366_7B64_995D);
+        assert_eq!(description.extension_fields.len(), 1);
+        assert_eq!(description.extension_fields[0].field_type, 0x104);
+        assert_eq!(description.mac.as_ref().unwrap().keyid, 3);
+        assert_eq!(description.mac.as_ref().unwrap().digest.len(), 32);
+
+        let json = serde_json::to_string(&description).unwrap();
+        let parsed: PacketDescription = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, description);
+        assert_eq!(parsed.to_bytes(), data);
+        assert_eq!(
+            NtpPacket::deserialize(&parsed.to_bytes(), &NoCipher)
+                .unwrap()
+                .0,
+            packet
+        );
+    }
+
+    #[test]
+    fn test_check_reports_differences() {
+        let data = NtpPacketBuilder::new()
+            .build()
+            .unwrap()
+            .serialize_without_encryption_vec()
+            .unwrap();
+        let mut vector = PacketTestVector {
+            name: "request".into(),
+            data: data.clone(),
+            packet: Some(PacketDescription::from_bytes(&data).unwrap()),
+        };
+        assert_eq!(vector.check(), Ok(()));
+
+        vector.packet.as_mut().unwrap().stratum = 1;
+        assert!(matches!(
+            vector.check(),
+            Err(TestVectorError::Mismatch { .. })
+        ));
+
+        vector.packet = None;
+        assert!(matches!(
+            vector.check(),
+            Err(TestVectorError::Accepted { .. })
+        ));
+
+        vector.data.truncate(47);
+        assert_eq!(vector.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_hex_data() {
+        let json = r#"{ "name": "split", "data": "0a0B 0c\n0d", "packet": null }"#;
+        let vector: PacketTestVector = serde_json::from_str(json).unwrap();
+        assert_eq!(vector.data, [0x0a, 0x0b, 0x0c, 0x0d]);
+
+        let json = r#"{ "name": "odd", "data": "0a0", "packet": null }"#;
+        assert!(serde_json::from_str::<PacketTestVector>(json).is_err());
+
+        let json = r#"{ "name": "invalid", "data": "0g", "packet": null }"#;
+        assert!(serde_json::from_str::<PacketTestVector>(json).is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use crate::NtpPacket;
+use crate::{NtpAssociationMode, NtpPacket};
 
 #[derive(Debug)]
 pub enum ParsingError<T> {
@@ -9,6 +9,30 @@ pub enum ParsingError<T> {
     MalformedNtsExtensionFields,
     MalformedNonce,
     DecryptError(T),
+    /// The error occurred in the part of the packet starting at byte `offset`
+    InField {
+        offset: usize,
+        field: PacketField,
+        error: Box<ParsingError<std::convert::Infallible>>,
+    },
+}
+
+/// The part of a packet a [`ParsingError`] occurred in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketField {
+    ExtensionField { type_id: u16 },
+    Mac,
+}
+
+impl Display for PacketField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExtensionField { type_id } => {
+                f.write_fmt(format_args!("extension field of type {type_id:#x}"))
+            }
+            Self::Mac => f.write_str("MAC"),
+        }
+    }
 }
 
 impl<T> ParsingError<T> {
@@ -21,6 +45,23 @@ impl<T> ParsingError<T> {
             }
             ParsingError::MalformedNonce => Err(ParsingError::MalformedNonce),
             ParsingError::DecryptError(decrypt_error) => Ok(decrypt_error),
+            ParsingError::InField {
+                offset,
+                field,
+                error,
+            } => Err(ParsingError::InField {
+                offset,
+                field,
+                error,
+            }),
+        }
+    }
+
+    /// The byte offset and part of the packet the error occurred in, if known
+    pub fn context(&self) -> Option<(usize, PacketField)> {
+        match self {
+            ParsingError::InField { offset, field, .. } => Some((*offset, *field)),
+            _ => None,
         }
     }
 }
@@ -33,11 +74,49 @@ impl ParsingError<std::convert::Infallible> {
             ParsingError::MalformedNtsExtensionFields => ParsingError::MalformedNtsExtensionFields,
             ParsingError::MalformedNonce => ParsingError::MalformedNonce,
             ParsingError::DecryptError(decrypt_error) => match decrypt_error {},
+            ParsingError::InField {
+                offset,
+                field,
+                error,
+            } => ParsingError::InField {
+                offset,
+                field,
+                error,
+            },
+        }
+    }
+
+    /// The same error, noting it occurred in `field` starting at byte `offset`
+    pub(super) fn in_field<U>(self, offset: usize, field: PacketField) -> ParsingError<U> {
+        match self {
+            // keep the outermost context, offsets of nested errors are relative
+            // to decrypted data rather than the packet
+            ParsingError::InField { error, .. } => ParsingError::InField {
+                offset,
+                field,
+                error,
+            },
+            error => ParsingError::InField {
+                offset,
+                field,
+                error: Box::new(error),
+            },
         }
     }
 }
 
 pub type PacketParsingError<'a> = ParsingError<NtpPacket<'a>>;
+pub type ExtensionFieldParsingError = ParsingError<std::convert::Infallible>;
+
+impl PacketParsingError<'_> {
+    /// The same error, no longer borrowing from the data that was parsed
+    pub fn into_owned(self) -> PacketParsingError<'static> {
+        match self.get_decrypt_error() {
+            Ok(packet) => ParsingError::DecryptError(packet.into_owned()),
+            Err(error) => error,
+        }
+    }
+}
 
 impl<T> Display for ParsingError<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -47,8 +126,56 @@ impl<T> Display for ParsingError<T> {
             Self::MalformedNtsExtensionFields => f.write_str("Malformed nts extension fields"),
             Self::MalformedNonce => f.write_str("Malformed nonce (likely invalid length)"),
             Self::DecryptError(_) => f.write_str("Failed to decrypt NTS extension fields"),
+            Self::InField {
+                offset,
+                field,
+                error,
+            } => match **error {
+                ParsingError::IncorrectLength => {
+                    f.write_fmt(format_args!("Bad length of {field} at offset {offset}"))
+                }
+                ref error => f.write_fmt(format_args!("{error} in {field} at offset {offset}")),
+            },
         }
     }
 }
 
 impl<T: std::fmt::Debug> std::error::Error for ParsingError<T> {}
+
+/// Where a packet parsed by [`NtpPacket::deserialize_with_trailing`] ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrailingData<'a> {
+    /// Number of bytes the packet takes up, including its extension fields and MAC
+    pub consumed: usize,
+    /// The bytes after the packet, which are neither extension fields nor a MAC
+    pub unexpected: &'a [u8],
+}
+
+/// A deviation from the specification that parsing tolerated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// The packet uses an association mode that has no meaning for the
+    /// exchanges we support
+    UnexpectedMode(NtpAssociationMode),
+    /// An extension field is shorter than the 16 octets RFC 7822 requires
+    UndersizedExtensionField { type_id: u16, length: usize },
+    /// An extension field of a type we don't know, which is ignored
+    UnknownExtensionField { type_id: u16 },
+    /// The packet carries a MAC, which parsing does not verify
+    IgnoredMac,
+}
+
+impl Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedMode(mode) => f.write_fmt(format_args!("Unexpected mode {mode:?}")),
+            Self::UndersizedExtensionField { type_id, length } => f.write_fmt(format_args!(
+                "Extension field of type {type_id:#x} is only {length} octets long"
+            )),
+            Self::UnknownExtensionField { type_id } => {
+                f.write_fmt(format_args!("Unknown extension field type {type_id:#x}"))
+            }
+            Self::IgnoredMac => f.write_str("Ignored MAC"),
+        }
+    }
+}
@@ -1,11 +1,21 @@
 use std::{
     borrow::Cow,
     io::{Cursor, Write},
+    sync::Arc,
 };
 
+use rand::Rng;
+
 use crate::{arrayvec::ArrayVec, DecodedServerCookie};
 
-use super::{error::ParsingError, Cipher, CipherProvider, Mac};
+use super::{
+    error::{ExtensionFieldParsingError, PacketField, ParseWarning, ParsingError},
+    registry::CustomExtensionField,
+    Cipher, CipherProvider, Mac,
+};
+
+// RFC 8915 requires the unique identifier to be at least 32 octets long
+const MIN_UNIQUE_IDENTIFIER_LENGTH: usize = 32;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum ExtensionFieldTypeId {
@@ -13,6 +23,8 @@ enum ExtensionFieldTypeId {
     NtsCookie,
     NtsCookiePlaceholder,
     NtsEncryptedField,
+    ReferenceIdRequest,
+    ReferenceIdResponse,
     Unknown { type_id: u16 },
 }
 
@@ -23,6 +35,8 @@ impl ExtensionFieldTypeId {
             0x204 => Self::NtsCookie,
             0x304 => Self::NtsCookiePlaceholder,
             0x404 => Self::NtsEncryptedField,
+            0xF503 => Self::ReferenceIdRequest,
+            0xF504 => Self::ReferenceIdResponse,
             _ => Self::Unknown { type_id },
         }
     }
@@ -33,6 +47,8 @@ impl ExtensionFieldTypeId {
             ExtensionFieldTypeId::NtsCookie => 0x204,
             ExtensionFieldTypeId::NtsCookiePlaceholder => 0x304,
             ExtensionFieldTypeId::NtsEncryptedField => 0x404,
+            ExtensionFieldTypeId::ReferenceIdRequest => 0xF503,
+            ExtensionFieldTypeId::ReferenceIdResponse => 0xF504,
             ExtensionFieldTypeId::Unknown { type_id } => type_id,
         }
     }
@@ -42,9 +58,24 @@ impl ExtensionFieldTypeId {
 pub enum ExtensionField<'a> {
     UniqueIdentifier(Cow<'a, [u8]>),
     NtsCookie(Cow<'a, [u8]>),
-    NtsCookiePlaceholder { cookie_length: u16 },
+    NtsCookiePlaceholder {
+        cookie_length: u16,
+    },
     InvalidNtsEncryptedField,
-    Unknown { type_id: u16, data: Cow<'a, [u8]> },
+    /// NTPv5 request for `length` bytes of the bloom filter of the server, starting
+    /// at `offset`. The field is as long as the requested part of the filter.
+    ReferenceIdRequest {
+        offset: u16,
+        length: u16,
+    },
+    /// NTPv5 response with the part of the bloom filter of the server that was requested
+    ReferenceIdResponse(Cow<'a, [u8]>),
+    Unknown {
+        type_id: u16,
+        data: Cow<'a, [u8]>,
+    },
+    /// A field of a type registered in an [`ExtensionFieldRegistry`](super::ExtensionFieldRegistry)
+    Custom(Arc<dyn CustomExtensionField>),
 }
 
 impl<'a> std::fmt::Debug for ExtensionField<'a> {
@@ -59,6 +90,14 @@ impl<'a> std::fmt::Debug for ExtensionField<'a> {
                 .field("body_length", body_length)
                 .finish(),
             Self::InvalidNtsEncryptedField => f.debug_struct("InvalidNtsEncryptedField").finish(),
+            Self::ReferenceIdRequest { offset, length } => f
+                .debug_struct("ReferenceIdRequest")
+                .field("offset", offset)
+                .field("length", length)
+                .finish(),
+            Self::ReferenceIdResponse(arg0) => {
+                f.debug_tuple("ReferenceIdResponse").field(arg0).finish()
+            }
             Self::Unknown {
                 type_id: typeid,
                 data,
@@ -68,11 +107,32 @@ impl<'a> std::fmt::Debug for ExtensionField<'a> {
                 .field("length", &data.len())
                 .field("data", data)
                 .finish(),
+            Self::Custom(field) => f.debug_tuple("Custom").field(field).finish(),
         }
     }
 }
 
 impl<'a> ExtensionField<'a> {
+    /// Type of the padding field of the NTPv5 draft, which receivers ignore
+    pub(super) const PADDING_TYPE_ID: u16 = 0xF501;
+
+    /// A field carrying an NTS cookie, as received from the server
+    pub fn nts_cookie(cookie: impl Into<Cow<'a, [u8]>>) -> Self {
+        ExtensionField::NtsCookie(cookie.into())
+    }
+
+    /// A unique identifier field, which must be at least 32 bytes long
+    pub fn unique_identifier(
+        identifier: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<Self, ExtensionFieldParsingError> {
+        let identifier = identifier.into();
+        if identifier.len() < MIN_UNIQUE_IDENTIFIER_LENGTH {
+            return Err(ParsingError::IncorrectLength);
+        }
+
+        Ok(ExtensionField::UniqueIdentifier(identifier))
+    }
+
     pub fn into_owned(self) -> ExtensionField<'static> {
         use ExtensionField::*;
 
@@ -92,9 +152,58 @@ impl<'a> ExtensionField<'a> {
                 cookie_length: body_length,
             },
             InvalidNtsEncryptedField => InvalidNtsEncryptedField,
+            ReferenceIdRequest { offset, length } => ReferenceIdRequest { offset, length },
+            ReferenceIdResponse(data) => ReferenceIdResponse(Cow::Owned(data.into_owned())),
+            Custom(field) => Custom(field),
+        }
+    }
+
+    /// Number of bytes of data in this field on the wire, without header and padding
+    fn data_len(&self) -> usize {
+        use ExtensionField::*;
+
+        match self {
+            Unknown { data, .. } => data.len(),
+            UniqueIdentifier(identifier) => identifier.len(),
+            NtsCookie(cookie) => cookie.len(),
+            NtsCookiePlaceholder { cookie_length } => *cookie_length as usize,
+            // can't be serialized
+            InvalidNtsEncryptedField => 0,
+            ReferenceIdRequest { length, .. } => *length as usize,
+            ReferenceIdResponse(data) => data.len(),
+            Custom(field) => field.encode().len(),
         }
     }
 
+    /// Number of bytes a field with `data_length` bytes of data takes up on the wire,
+    /// including header and padding
+    pub(super) fn framed_len(data_length: usize, minimum_size: u16) -> usize {
+        // u16 for the type_id, u16 for the length
+        let header_width = 4;
+
+        (data_length + header_width)
+            .max(minimum_size as usize)
+            .next_multiple_of(4)
+    }
+
+    /// Number of bytes an encrypted extension field takes up on the wire, given the
+    /// serialized length of the fields it contains
+    pub(super) fn encrypted_len(plaintext_len: usize) -> usize {
+        // both supported AEAD algorithms use a 16 byte nonce and a 16 byte siv tag
+        Self::encrypted_len_with(plaintext_len, 16, 16)
+    }
+
+    /// Like [`ExtensionField::encrypted_len`], for a cipher with the given nonce
+    /// and tag lengths
+    pub(super) fn encrypted_len_with(
+        plaintext_len: usize,
+        nonce_len: usize,
+        tag_len: usize,
+    ) -> usize {
+        // extension field header (4 bytes) and nonce/cypher text length (2 bytes each)
+        8 + nonce_len.next_multiple_of(4) + (tag_len + plaintext_len).next_multiple_of(4)
+    }
+
     fn serialize<W: std::io::Write>(&self, w: &mut W, minimum_size: u16) -> std::io::Result<()> {
         use ExtensionField::*;
 
@@ -108,6 +217,13 @@ impl<'a> ExtensionField<'a> {
                 cookie_length: body_length,
             } => Self::encode_nts_cookie_placeholder(w, *body_length, minimum_size),
             InvalidNtsEncryptedField => Err(std::io::ErrorKind::Other.into()),
+            ReferenceIdRequest { offset, length } => {
+                Self::encode_reference_id_request(w, *offset, *length, minimum_size)
+            }
+            ReferenceIdResponse(data) => Self::encode_reference_id_response(w, data, minimum_size),
+            Custom(field) => {
+                Self::encode_unknown(w, field.field_type(), &field.encode(), minimum_size)
+            }
         }
     }
 
@@ -220,6 +336,44 @@ impl<'a> ExtensionField<'a> {
         Ok(())
     }
 
+    fn encode_reference_id_request<W: std::io::Write>(
+        w: &mut W,
+        offset: u16,
+        length: u16,
+        minimum_size: u16,
+    ) -> std::io::Result<()> {
+        // the offset and 2 reserved bytes, padded to the requested length so the
+        // response is never larger than the request
+        if length < 4 {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        Self::encode_framing(
+            w,
+            ExtensionFieldTypeId::ReferenceIdRequest,
+            length as usize,
+            minimum_size,
+        )?;
+        w.write_all(&offset.to_be_bytes())?;
+        Self::write_zeros(w, length - 2)?;
+        Self::encode_padding(w, length as usize, minimum_size)
+    }
+
+    fn encode_reference_id_response<W: std::io::Write>(
+        w: &mut W,
+        data: &[u8],
+        minimum_size: u16,
+    ) -> std::io::Result<()> {
+        Self::encode_framing(
+            w,
+            ExtensionFieldTypeId::ReferenceIdResponse,
+            data.len(),
+            minimum_size,
+        )?;
+        w.write_all(data)?;
+        Self::encode_padding(w, data.len(), minimum_size)
+    }
+
     fn encode_unknown<W: std::io::Write>(
         w: &mut W,
         type_id: u16,
@@ -244,6 +398,7 @@ impl<'a> ExtensionField<'a> {
         w: &mut Cursor<&mut [u8]>,
         fields_to_encrypt: &[ExtensionField],
         cipher: &dyn Cipher,
+        rng: &mut impl Rng,
     ) -> std::io::Result<()> {
         let padding = [0; 4];
 
@@ -260,9 +415,15 @@ impl<'a> ExtensionField<'a> {
             field.serialize(&mut plaintext, minimum_size)?;
         }
 
-        let (siv_tag, nonce) = cipher
-            .encrypt_in_place_detached(plaintext.as_mut(), packet_so_far)
-            .unwrap();
+        // a fresh nonce for every packet, as reusing one with the same key breaks
+        // the confidentiality of the encrypted fields
+        let mut nonce = aes_siv::Nonce::default();
+        rng.fill_bytes(&mut nonce);
+        let siv_tag = cipher.encrypt_in_place_detached_with_nonce(
+            &nonce,
+            plaintext.as_mut(),
+            packet_so_far,
+        )?;
         let ciphertext = plaintext.as_slice();
 
         w.write_all(
@@ -275,9 +436,8 @@ impl<'a> ExtensionField<'a> {
         let nonce_octet_count = nonce.len();
         let ct_octet_count = siv_tag.len() + ciphertext.len();
 
-        // + 8 for the extension field header (4 bytes) and nonce/cypher text length (2 bytes each)
         let signature_octet_count =
-            8 + next_multiple_of((nonce_octet_count + ct_octet_count) as u16, 4);
+            Self::encrypted_len_with(ciphertext.len(), nonce_octet_count, siv_tag.len()) as u16;
 
         w.write_all(&signature_octet_count.to_be_bytes())?;
         w.write_all(&(nonce_octet_count as u16).to_be_bytes())?;
@@ -298,9 +458,8 @@ impl<'a> ExtensionField<'a> {
     fn decode_unique_identifier(
         message: &'a [u8],
     ) -> Result<Self, ParsingError<std::convert::Infallible>> {
-        // The string MUST be at least 32 octets long
         // TODO: Discuss if we really want this check here
-        if message.len() < 32 {
+        if message.len() < MIN_UNIQUE_IDENTIFIER_LENGTH {
             return Err(ParsingError::IncorrectLength);
         }
 
@@ -325,6 +484,18 @@ impl<'a> ExtensionField<'a> {
         }
     }
 
+    fn decode_reference_id_request(
+        message: &'a [u8],
+    ) -> Result<Self, ParsingError<std::convert::Infallible>> {
+        match message {
+            [a, b, _, _, ..] => Ok(ExtensionField::ReferenceIdRequest {
+                offset: u16::from_be_bytes([*a, *b]),
+                length: message.len() as u16,
+            }),
+            _ => Err(ParsingError::IncorrectLength),
+        }
+    }
+
     fn decode_unknown(
         type_id: u16,
         message: &'a [u8],
@@ -345,6 +516,8 @@ impl<'a> ExtensionField<'a> {
             TypeId::UniqueIdentifier => EF::decode_unique_identifier(message),
             TypeId::NtsCookie => EF::decode_nts_cookie(message),
             TypeId::NtsCookiePlaceholder => EF::decode_nts_cookie_placeholder(message),
+            TypeId::ReferenceIdRequest => EF::decode_reference_id_request(message),
+            TypeId::ReferenceIdResponse => Ok(EF::ReferenceIdResponse(message[..].into())),
             type_id => EF::decode_unknown(type_id.to_type_id(), message),
         }
     }
@@ -373,6 +546,7 @@ impl<'a> ExtensionFieldData<'a> {
         &self,
         w: &mut Cursor<&mut [u8]>,
         cipher: &(impl CipherProvider + ?Sized),
+        rng: &mut impl Rng,
     ) -> std::io::Result<()> {
         if !self.authenticated.is_empty() || !self.encrypted.is_empty() {
             let cipher = match cipher.get(&self.authenticated) {
@@ -391,7 +565,7 @@ impl<'a> ExtensionFieldData<'a> {
             // RFC 8915, section 5.5: contrary to the RFC 7822 requirement that fields have a minimum length of 16 or 28 octets,
             // encrypted extension fields MAY be arbitrarily short (but still MUST be a multiple of 4 octets in length)
             // hence we don't provide a minimum size here
-            ExtensionField::encode_encrypted(w, &self.encrypted, cipher.as_ref())?;
+            ExtensionField::encode_encrypted(w, &self.encrypted, cipher.as_ref(), rng)?;
         }
 
         // per RFC 7822, section 7.5.1.4.
@@ -405,11 +579,117 @@ impl<'a> ExtensionFieldData<'a> {
         Ok(())
     }
 
+    /// Number of bytes these fields take up on the wire when serialized with `cipher`.
+    /// Without a cipher, the nonce and tag lengths of AES-SIV are assumed.
+    pub(super) fn wire_size(&self, cipher: &(impl CipherProvider + ?Sized)) -> usize {
+        let mut size = 0;
+
+        if !self.authenticated.is_empty() || !self.encrypted.is_empty() {
+            let (nonce_len, tag_len) = match cipher.get(&self.authenticated) {
+                Some(cipher) => (cipher.as_ref().nonce_len(), cipher.as_ref().tag_len()),
+                None => (16, 16),
+            };
+
+            size += self
+                .authenticated
+                .iter()
+                .map(|field| ExtensionField::framed_len(field.data_len(), 16))
+                .sum::<usize>();
+
+            let plaintext_len = self
+                .encrypted
+                .iter()
+                .map(|field| ExtensionField::framed_len(field.data_len(), 0))
+                .sum();
+            size += ExtensionField::encrypted_len_with(plaintext_len, nonce_len, tag_len);
+        }
+
+        let mut it = self.untrusted.iter().peekable();
+        while let Some(field) = it.next() {
+            let minimum_size = if it.peek().is_none() { 28 } else { 16 };
+            size += ExtensionField::framed_len(field.data_len(), minimum_size);
+        }
+
+        size
+    }
+
+    /// The deviations from RFC 7822 in the extension fields at the start of
+    /// `data` that deserializing them tolerates. The contents of encrypted
+    /// fields are not inspected.
+    pub(super) fn warnings(data: &[u8]) -> Vec<ParseWarning> {
+        // The last field is always large enough, as trailing data shorter than
+        // `Mac::AMBIGUOUS_SIZE` octets is taken to be a MAC
+        let minimum_size = 16;
+
+        let mut warnings = vec![];
+        for field in RawExtensionField::deserialize_sequence(
+            data,
+            true,
+            RawExtensionField::V4_UNENCRYPTED_MINIMUM_SIZE,
+        ) {
+            let field = match field {
+                Ok((_, field)) => field,
+                Err(_) => break,
+            };
+            let type_id = field.type_id.to_type_id();
+
+            if field.wire_length() < minimum_size {
+                warnings.push(ParseWarning::UndersizedExtensionField {
+                    type_id,
+                    length: field.wire_length(),
+                });
+            }
+
+            if let ExtensionFieldTypeId::Unknown { type_id } = field.type_id {
+                warnings.push(ParseWarning::UnknownExtensionField { type_id });
+            }
+        }
+
+        warnings
+    }
+
+    /// The type ids of the extension fields at the start of `data`, in order.
+    /// Scanning stops at the first malformed field.
+    pub(super) fn type_ids(data: &[u8]) -> Vec<u16> {
+        RawExtensionField::deserialize_sequence(
+            data,
+            true,
+            RawExtensionField::V4_UNENCRYPTED_MINIMUM_SIZE,
+        )
+        .map_while(|field| field.ok())
+        .map(|(_, field)| field.type_id.to_type_id())
+        .collect()
+    }
+
+    /// The type ids and contents of the extension fields at the start of
+    /// `data` as they are on the wire, with the number of bytes they take up
+    #[cfg(feature = "packet-serde")]
+    #[allow(clippy::type_complexity)]
+    pub(super) fn raw_fields(
+        data: &[u8],
+    ) -> Result<(Vec<(u16, &[u8])>, usize), ExtensionFieldParsingError> {
+        let mut fields = vec![];
+        let mut length = 0;
+        for field in RawExtensionField::deserialize_sequence(
+            data,
+            true,
+            RawExtensionField::V4_UNENCRYPTED_MINIMUM_SIZE,
+        ) {
+            let (offset, field) = field?;
+            length = offset + field.wire_length();
+            fields.push((field.type_id.to_type_id(), field.message_bytes));
+        }
+
+        Ok((fields, length))
+    }
+
     #[allow(clippy::type_complexity)]
     pub(super) fn deserialize(
         data: &'a [u8],
         header_size: usize,
         cipher: &impl CipherProvider,
+        mut scratch: Option<&'a mut [u8]>,
+        allow_trailing: bool,
     ) -> Result<
         (Self, usize, Option<DecodedServerCookie>),
         ParsingError<(ExtensionFieldData<'a>, usize)>,
@@ -420,15 +700,29 @@ impl<'a> ExtensionFieldData<'a> {
         let mut cookie = None;
         for field in RawExtensionField::deserialize_sequence(
             &data[header_size..],
-            Mac::MAXIMUM_SIZE,
+            true,
             RawExtensionField::V4_UNENCRYPTED_MINIMUM_SIZE,
         ) {
-            let (offset, field) = field.map_err(|e| e.generalize())?;
+            // with trailing data allowed, the fields end at the first one
+            // that is malformed
+            let (offset, field) = match field {
+                Ok(field) => field,
+                Err(_) if allow_trailing => break,
+                Err(e) => {
+                    // the malformed field starts where the last valid one ends
+                    let start = header_size + size;
+                    let type_id = u16::from_be_bytes([data[start], data[start + 1]]);
+                    return Err(e.in_field(start, PacketField::ExtensionField { type_id }));
+                }
+            };
             size = offset + field.wire_length();
+            let context = PacketField::ExtensionField {
+                type_id: field.type_id.to_type_id(),
+            };
             match field.type_id {
                 ExtensionFieldTypeId::NtsEncryptedField => {
                     let encrypted = RawEncryptedField::from_message_bytes(field.message_bytes)
-                        .map_err(|e| e.generalize())?;
+                        .map_err(|e| e.in_field(header_size + offset, context))?;
 
                     let cipher = match cipher.get(&this.untrusted) {
                         Some(cipher) => cipher,
@@ -440,17 +734,21 @@ impl<'a> ExtensionFieldData<'a> {
                         }
                     };
 
-                    let encrypted_fields =
-                        match encrypted.decrypt(cipher.as_ref(), &data[..header_size + offset]) {
-                            Ok(encrypted_fields) => encrypted_fields,
-                            Err(e) => {
-                                e.get_decrypt_error()?;
-                                this.untrusted
-                                    .push(ExtensionField::InvalidNtsEncryptedField);
-                                has_invalid_nts = true;
-                                continue;
-                            }
-                        };
+                    let encrypted_fields = match encrypted.decrypt(
+                        cipher.as_ref(),
+                        &data[..header_size + offset],
+                        &mut scratch,
+                    ) {
+                        Ok(encrypted_fields) => encrypted_fields,
+                        Err(e) => {
+                            e.get_decrypt_error::<std::convert::Infallible>()
+                                .map_err(|e| e.in_field(header_size + offset, context))?;
+                            this.untrusted
+                                .push(ExtensionField::InvalidNtsEncryptedField);
+                            has_invalid_nts = true;
+                            continue;
+                        }
+                    };
 
                     this.encrypted.extend(encrypted_fields.into_iter());
                     cookie = match cipher {
@@ -461,9 +759,10 @@ impl<'a> ExtensionFieldData<'a> {
                     // All previous untrusted fields are now validated
                     this.authenticated.append(&mut this.untrusted);
                 }
-                _ => this
-                    .untrusted
-                    .push(ExtensionField::decode(field).map_err(|e| e.generalize())?),
+                _ => this.untrusted.push(
+                    ExtensionField::decode(field)
+                        .map_err(|e| e.in_field(header_size + offset, context))?,
+                ),
             }
         }
         if has_invalid_nts {
@@ -513,33 +812,73 @@ impl<'a> RawEncryptedField<'a> {
         Ok(Self { nonce, ciphertext })
     }
 
+    /// Decrypt the field, returning the extension fields it contains.
+    ///
+    /// An encrypted field whose plaintext is empty is valid: it authenticates the
+    /// packet without carrying any fields. Clients send exactly that, and a server
+    /// response may hold no cookies when none fit. Whether the packet carries the
+    /// fields it needs (e.g. a unique identifier or cookies) is up to the caller.
+    ///
+    /// When `scratch` can hold the plaintext, it is decrypted into the front of
+    /// `scratch` and the fields borrow from it, leaving the remainder in `scratch`
+    /// for later fields. Otherwise the plaintext is allocated and the fields owned.
     fn decrypt(
         &self,
         cipher: &dyn Cipher,
         aad: &[u8],
+        scratch: &mut Option<&'a mut [u8]>,
     ) -> Result<Vec<ExtensionField<'a>>, ParsingError<ExtensionField<'a>>> {
-        let plaintext = match cipher.decrypt(self.nonce, self.ciphertext, aad) {
-            Ok(plain) => plain,
-            Err(_) => {
-                return Err(ParsingError::DecryptError(
-                    ExtensionField::InvalidNtsEncryptedField,
-                ))
+        const INVALID: ParsingError<ExtensionField<'static>> =
+            ParsingError::DecryptError(ExtensionField::InvalidNtsEncryptedField);
+
+        // the plaintext is never longer than the ciphertext
+        match scratch.take() {
+            Some(buffer) if buffer.len() >= self.ciphertext.len() => {
+                let length = match cipher.decrypt_into(self.nonce, self.ciphertext, aad, buffer) {
+                    Ok(length) => length,
+                    Err(_) => {
+                        *scratch = Some(buffer);
+                        return Err(INVALID);
+                    }
+                };
+                let (plaintext, rest) = buffer.split_at_mut(length);
+                *scratch = Some(rest);
+
+                let plaintext: &'a [u8] = plaintext;
+                Self::decode_plaintext(plaintext).map_err(|e| e.generalize())
             }
-        };
+            buffer => {
+                *scratch = buffer;
 
-        RawExtensionField::deserialize_sequence(&plaintext, 0, RawExtensionField::BARE_MINIMUM_SIZE)
-            .map(|encrypted_field| {
-                let encrypted_field = encrypted_field.map_err(|e| e.generalize())?.1;
-                if encrypted_field.type_id == ExtensionFieldTypeId::NtsEncryptedField {
-                    // TODO: Discuss whether we want this check
-                    Err(ParsingError::MalformedNtsExtensionFields)
-                } else {
-                    Ok(ExtensionField::decode(encrypted_field)
-                        .map_err(|e| e.generalize())?
-                        .into_owned())
-                }
-            })
-            .collect()
+                let plaintext = cipher
+                    .decrypt(self.nonce, self.ciphertext, aad)
+                    .map_err(|_| INVALID)?;
+
+                Self::decode_plaintext(&plaintext)
+                    .map(|fields| fields.into_iter().map(|f| f.into_owned()).collect())
+                    .map_err(|e| e.generalize())
+            }
+        }
+    }
+
+    fn decode_plaintext(
+        plaintext: &[u8],
+    ) -> Result<Vec<ExtensionField<'_>>, ParsingError<std::convert::Infallible>> {
+        RawExtensionField::deserialize_sequence(
+            plaintext,
+            false,
+            RawExtensionField::BARE_MINIMUM_SIZE,
+        )
+        .map(|encrypted_field| {
+            let encrypted_field = encrypted_field?.1;
+            if encrypted_field.type_id == ExtensionFieldTypeId::NtsEncryptedField {
+                // TODO: Discuss whether we want this check
+                Err(ParsingError::MalformedNtsExtensionFields)
+            } else {
+                ExtensionField::decode(encrypted_field)
+            }
+        })
+        .collect()
     }
 }
 
@@ -585,14 +924,14 @@ impl<'a> RawExtensionField<'a> {
 
     fn deserialize_sequence(
         buffer: &'a [u8],
-        cutoff: usize,
+        trailing_mac: bool,
         minimum_size: usize,
     ) -> impl Iterator<
         Item = Result<(usize, RawExtensionField<'a>), ParsingError<std::convert::Infallible>>,
     > + 'a {
         ExtensionFieldStreamer {
             buffer,
-            cutoff,
+            trailing_mac,
             minimum_size,
             offset: 0,
         }
@@ -600,7 +939,8 @@ impl<'a> RawExtensionField<'a> {
 }
 struct ExtensionFieldStreamer<'a> {
     buffer: &'a [u8],
-    cutoff: usize,
+    /// Whether the fields may be followed by a MAC
+    trailing_mac: bool,
     minimum_size: usize,
     offset: usize,
 }
@@ -609,10 +949,28 @@ impl<'a> Iterator for ExtensionFieldStreamer<'a> {
     type Item = Result<(usize, RawExtensionField<'a>), ParsingError<std::convert::Infallible>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.buffer.len() - self.offset <= self.cutoff {
+        let remaining = self.buffer.len() - self.offset;
+        if remaining == 0 {
             return None;
         }
 
+        if self.trailing_mac {
+            let data = &self.buffer[self.offset..];
+            if remaining < Mac::AMBIGUOUS_SIZE {
+                return None;
+            }
+
+            // Longer trailing data of the length of a MAC is one when it does
+            // not parse as a field, or when its type would be 0: key ids are
+            // at most 65535, so the first two octets of a MAC are zero.
+            if Mac::is_long_mac_length(remaining)
+                && (data[0..2] == [0, 0]
+                    || RawExtensionField::deserialize(data, self.minimum_size).is_err())
+            {
+                return None;
+            }
+        }
+
         match RawExtensionField::deserialize(&self.buffer[self.offset..], self.minimum_size) {
             Ok(field) => {
                 let offset = self.offset;
@@ -660,6 +1018,45 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unique_identifier_constructor() {
+        let identifier: Vec<_> = (0..32).collect();
+        let field = ExtensionField::unique_identifier(identifier.as_slice()).unwrap();
+        assert!(
+            matches!(&field, ExtensionField::UniqueIdentifier(data) if data[..] == identifier[..])
+        );
+
+        let field = ExtensionField::unique_identifier(vec![0; 64]).unwrap();
+        assert!(matches!(
+            field,
+            ExtensionField::UniqueIdentifier(Cow::Owned(_))
+        ));
+
+        assert!(matches!(
+            ExtensionField::unique_identifier(&identifier[..31]),
+            Err(ParsingError::IncorrectLength)
+        ));
+        assert!(matches!(
+            ExtensionField::unique_identifier(vec![]),
+            Err(ParsingError::IncorrectLength)
+        ));
+    }
+
+    #[test]
+    fn test_nts_cookie_constructor() {
+        let cookie: Vec<_> = (0..16).collect();
+        let field = ExtensionField::nts_cookie(cookie.as_slice());
+        assert!(matches!(field, ExtensionField::NtsCookie(Cow::Borrowed(data)) if data == cookie));
+
+        let field = ExtensionField::nts_cookie(cookie.clone());
+        let mut w = vec![];
+        field.serialize(&mut w, 0).unwrap();
+        assert_eq!(
+            w,
+            &[2, 4, 0, 20, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+    }
+
     #[test]
     fn test_nts_cookie() {
         let cookie: Vec<_> = (0..16).collect();
@@ -721,6 +1118,79 @@ mod tests {
         // NOTE: encryped fields do not have a minimum_size
     }
 
+    #[test]
+    fn extension_field_framed_len() {
+        for length in 0..40 {
+            let data: Vec<_> = (0..length as u8).collect();
+            let fields = [
+                ExtensionField::UniqueIdentifier(Cow::Borrowed(data.as_slice())),
+                ExtensionField::NtsCookie(Cow::Borrowed(data.as_slice())),
+                ExtensionField::NtsCookiePlaceholder {
+                    cookie_length: length as u16,
+                },
+                ExtensionField::Unknown {
+                    type_id: 42,
+                    data: Cow::Borrowed(data.as_slice()),
+                },
+            ];
+
+            for field in &fields {
+                for minimum_size in [0, 16, 28] {
+                    let mut w = vec![];
+                    field.serialize(&mut w, minimum_size).unwrap();
+                    assert_eq!(ExtensionField::framed_len(length, minimum_size), w.len());
+                }
+            }
+
+            let mut w = [0u8; 256];
+            let mut cursor = Cursor::new(w.as_mut_slice());
+            let cipher = AesSivCmac256::new([0; 32].into());
+            ExtensionField::encode_encrypted(
+                &mut cursor,
+                &fields,
+                &cipher,
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+            let plaintext_len = fields.len() * ExtensionField::framed_len(length, 0);
+            assert_eq!(
+                ExtensionField::encrypted_len(plaintext_len),
+                cursor.position() as usize
+            );
+        }
+    }
+
+    #[test]
+    fn decrypt_empty_plaintext() {
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let identifier: Vec<_> = (0..32).collect();
+        let data = ExtensionFieldData {
+            authenticated: vec![ExtensionField::UniqueIdentifier(Cow::Borrowed(&identifier))],
+            encrypted: vec![],
+            untrusted: vec![],
+        };
+
+        let mut w = [0u8; 256];
+        let mut cursor = Cursor::new(w.as_mut_slice());
+        cursor.write_all(&[0; 48]).unwrap();
+        data.serialize(&mut cursor, &cipher, &mut rand::thread_rng())
+            .unwrap();
+        let length = cursor.position() as usize;
+
+        // the encrypted field is accepted, and authenticates the fields before it
+        let (parsed, size, _) =
+            ExtensionFieldData::deserialize(&w[..length], 48, &cipher, None, false).unwrap();
+        assert_eq!(size, length);
+        assert_eq!(parsed, data);
+
+        // but only when it actually is authentic
+        let other = AesSivCmac256::new([1; 32].into());
+        assert!(matches!(
+            ExtensionFieldData::deserialize(&w[..length], 48, &other, None, false),
+            Err(ParsingError::DecryptError(_))
+        ));
+    }
+
     #[test]
     fn extension_field_padding() {
         let minimum_size = 0;
@@ -751,7 +1221,13 @@ mod tests {
         let fields_to_encrypt = [ExtensionField::UniqueIdentifier(Cow::Borrowed(
             data.as_slice(),
         ))];
-        ExtensionField::encode_encrypted(&mut cursor, &fields_to_encrypt, &cipher).unwrap();
+        ExtensionField::encode_encrypted(
+            &mut cursor,
+            &fields_to_encrypt,
+            &cipher,
+            &mut rand::thread_rng(),
+        )
+        .unwrap();
         assert_eq!(
             cursor.position() as usize,
             2 + 6 + c2s.len() + expected_size
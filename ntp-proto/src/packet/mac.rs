@@ -1,15 +1,72 @@
 use std::borrow::Cow;
 
-use super::error::ParsingError;
+use crate::MacAlgorithm;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+use super::{crypto::constant_time_eq, error::ParsingError};
+
+#[derive(Debug, Clone)]
 pub(super) struct Mac<'a> {
     keyid: u32,
     mac: Cow<'a, [u8]>,
 }
 
+// The digest is compared in constant time, so comparing against a MAC we
+// computed doesn't leak how much of it a forgery got right
+impl PartialEq for Mac<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.keyid == other.keyid && constant_time_eq(&self.mac, &other.mac)
+    }
+}
+
+impl Eq for Mac<'_> {}
+
 impl<'a> Mac<'a> {
-    pub(super) const MAXIMUM_SIZE: usize = 28;
+    /// Trailing data shorter than this is always a MAC. RFC 7822 requires the
+    /// last extension field to be at least 28 octets long, so longer MACs can
+    /// be mistaken for one.
+    pub(super) const AMBIGUOUS_SIZE: usize = 28;
+
+    /// Whether trailing data of `len` octets that is at least
+    /// [`Mac::AMBIGUOUS_SIZE`] long has the length of a MAC of one of the
+    /// supported algorithms
+    pub(super) fn is_long_mac_length(len: usize) -> bool {
+        len >= Self::AMBIGUOUS_SIZE
+            && MacAlgorithm::ALL
+                .iter()
+                .any(|algorithm| 4 + algorithm.digest_len() == len)
+    }
+
+    pub(super) fn new(keyid: u32, mac: Vec<u8>) -> Self {
+        Mac {
+            keyid,
+            mac: Cow::Owned(mac),
+        }
+    }
+
+    /// The MAC of a crypto-NAK, four zero octets: key id 0 without a digest
+    pub(super) fn crypto_nak() -> Self {
+        Mac {
+            keyid: 0,
+            mac: Cow::Borrowed(&[]),
+        }
+    }
+
+    pub(super) fn is_crypto_nak(&self) -> bool {
+        self.keyid == 0 && self.mac.is_empty()
+    }
+
+    pub(super) fn keyid(&self) -> u32 {
+        self.keyid
+    }
+
+    pub(super) fn digest(&self) -> &[u8] {
+        &self.mac
+    }
+
+    /// Length of the serialized MAC, including the key id
+    pub(super) fn len(&self) -> usize {
+        4 + self.mac.len()
+    }
 
     pub(super) fn into_owned(self) -> Mac<'static> {
         Mac {
@@ -26,7 +83,9 @@ impl<'a> Mac<'a> {
     pub(super) fn deserialize(
         data: &'a [u8],
     ) -> Result<Mac<'a>, ParsingError<std::convert::Infallible>> {
-        if data.len() < 4 || data.len() >= Self::MAXIMUM_SIZE {
+        if data.len() < 4
+            || !(data.len() < Self::AMBIGUOUS_SIZE || Self::is_long_mac_length(data.len()))
+        {
             return Err(ParsingError::IncorrectLength);
         }
 
@@ -36,3 +95,28 @@ impl<'a> Mac<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_eq() {
+        let mac = |keyid, digest: &[u8]| Mac {
+            keyid,
+            mac: Cow::Owned(digest.to_vec()),
+        };
+
+        assert_eq!(mac(1, &[1, 2, 3]), mac(1, &[1, 2, 3]));
+        assert_eq!(
+            mac(1, &[1, 2, 3]),
+            Mac::deserialize(&[0, 0, 0, 1, 1, 2, 3]).unwrap()
+        );
+
+        assert_ne!(mac(1, &[1, 2, 3]), mac(2, &[1, 2, 3]));
+        assert_ne!(mac(1, &[1, 2, 3]), mac(1, &[1, 2, 4]));
+        assert_ne!(mac(1, &[1, 2, 3]), mac(1, &[0, 2, 3]));
+        assert_ne!(mac(1, &[1, 2, 3]), mac(1, &[1, 2]));
+        assert_ne!(mac(1, &[]), mac(1, &[0]));
+    }
+}
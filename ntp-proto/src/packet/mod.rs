@@ -4,22 +4,51 @@ use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    DecodedServerCookie, KeySet, NtpClock, NtpDuration, NtpTimestamp, PollInterval, ReferenceId,
-    SystemSnapshot,
+    DecodedServerCookie, KeySet, KissCode, LeapSchedule, MacError, NtpClock, NtpDuration,
+    NtpTimestamp, PollInterval, ReferenceId, SymmetricKey, SystemSnapshot,
 };
 
-use self::{error::ParsingError, extensionfields::ExtensionFieldData, mac::Mac};
+use self::{
+    extensionfields::ExtensionFieldData,
+    mac::Mac,
+    v5::{NtpFlags, NtpHeaderV5, UPGRADE_TIMESTAMP},
+};
 
+mod bloom;
+mod builder;
+mod control;
 mod crypto;
+#[cfg(feature = "packet-serde")]
+mod description;
 mod error;
 mod extensionfields;
 mod mac;
-
+mod registry;
+mod v5;
+
+pub(crate) use bloom::RemoteBloomFilter;
+pub use bloom::{BloomFilter, ServerId};
+pub use builder::NtpPacketBuilder;
+pub use control::{
+    ControlOpcode, ControlPacket, ControlParsingError, PeerStatusWord, SystemStatusWord,
+};
+#[cfg(test)]
+pub use crypto::AesSivCmac256;
 pub use crypto::{
-    AesSivCmac256, AesSivCmac512, Cipher, CipherHolder, CipherProvider, DecryptError, NoCipher,
+    cipher_for_aead, constant_time_eq, AesSivCmac512, Cipher, CipherHolder, CipherProvider,
+    DecryptError, NoCipher, UnsupportedAead,
+};
+#[cfg(feature = "packet-serde")]
+pub use description::{
+    FieldDescription, MacDescription, PacketDescription, PacketTestVector, TestVectorError,
+};
+pub(crate) use error::ParsingError;
+pub use error::{
+    ExtensionFieldParsingError, PacketField, PacketParsingError, ParseWarning, TrailingData,
 };
-pub use error::PacketParsingError;
 pub use extensionfields::ExtensionField;
+pub use registry::{CustomExtensionField, ExtensionFieldRegistry};
+pub use v5::NtpTimescale;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NtpLeapIndicator {
@@ -59,6 +88,7 @@ impl NtpLeapIndicator {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "packet-serde", derive(Serialize, Deserialize))]
 pub enum NtpAssociationMode {
     Reserved,
     SymmetricActive,
@@ -101,6 +131,16 @@ impl NtpAssociationMode {
             NtpAssociationMode::Private => 7,
         }
     }
+
+    /// The mode of the answer to a request in this mode. In a symmetric
+    /// association, each side answers in the opposite symmetric mode.
+    pub fn response_mode(self) -> NtpAssociationMode {
+        match self {
+            NtpAssociationMode::SymmetricActive => NtpAssociationMode::SymmetricPassive,
+            NtpAssociationMode::SymmetricPassive => NtpAssociationMode::SymmetricActive,
+            _ => NtpAssociationMode::Server,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -114,6 +154,7 @@ pub struct NtpPacket<'a> {
 enum NtpHeader {
     V3(NtpHeaderV3V4),
     V4(NtpHeaderV3V4),
+    V5(NtpHeaderV5),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -135,10 +176,60 @@ struct NtpHeaderV3V4 {
     transmit_timestamp: NtpTimestamp,
 }
 
+/// A stable prefix for the unique identifiers of the NTS requests of a client
+/// instance, making it possible to correlate requests (and the cookies used in
+/// them) across restarts when debugging.
+///
+/// Only the first [`UniqueIdentifierPrefix::LENGTH`] bytes of the identifier
+/// are taken from the prefix. The majority of the identifier must remain random,
+/// as the server relies on it to be unique and unpredictable to protect against
+/// replayed responses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UniqueIdentifierPrefix([u8; Self::LENGTH]);
+
+impl UniqueIdentifierPrefix {
+    pub const LENGTH: usize = 8;
+
+    /// Derive the prefix from a stable id of the client instance, such as its
+    /// hostname combined with the configured server
+    pub fn from_instance_id(instance_id: &[u8]) -> Self {
+        use sha2::Digest;
+
+        let digest = sha2::Sha256::new()
+            .chain_update(b"ntpd-rs unique identifier prefix")
+            .chain_update(instance_id)
+            .finalize();
+
+        let mut prefix = [0; Self::LENGTH];
+        prefix.copy_from_slice(&digest[..Self::LENGTH]);
+        Self(prefix)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct RequestIdentifier {
-    expected_origin_timestamp: NtpTimestamp,
-    uid: Option<[u8; 32]>,
+    pub(crate) expected_origin_timestamp: NtpTimestamp,
+    /// Origin timestamp of a response in interleaved mode, for interleaved requests
+    pub(crate) interleaved_origin_timestamp: Option<NtpTimestamp>,
+    pub(crate) uid: Option<[u8; 32]>,
+}
+
+/// The timestamps of the last exchange with a client, which a server keeps to
+/// answer the next request of that client in interleaved mode.
+///
+/// In interleaved mode, the response to a request carries the transmit timestamp
+/// of the previous response. That timestamp can be captured after the previous
+/// response was sent, so it is more accurate than one captured before sending.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PreviousExchange {
+    /// Time at the server when the last request arrived from the client
+    pub receive_timestamp: NtpTimestamp,
+    /// Time at the server when the response to it left for the client
+    pub transmit_timestamp: NtpTimestamp,
 }
 
 impl NtpHeaderV3V4 {
@@ -199,7 +290,7 @@ impl NtpHeaderV3V4 {
         Ok(())
     }
 
-    fn poll_message(poll_interval: PollInterval) -> (Self, RequestIdentifier) {
+    fn poll_message(poll_interval: PollInterval, rng: &mut impl Rng) -> (Self, RequestIdentifier) {
         let mut packet = Self::new();
         let poll_interval = poll_interval;
         packet.poll = poll_interval.as_log();
@@ -209,36 +300,62 @@ impl NtpHeaderV3V4 {
         // it is just a randomly generated timestamp.
         // We then expect to get it back identically from the remote
         // in the origin field.
-        let transmit_timestamp = thread_rng().gen();
+        let transmit_timestamp = rng.gen();
         packet.transmit_timestamp = transmit_timestamp;
 
         (
             packet,
             RequestIdentifier {
                 expected_origin_timestamp: transmit_timestamp,
+                interleaved_origin_timestamp: None,
                 uid: None,
             },
         )
     }
 
+    /// Whether the client sent this request in interleaved mode, referring to
+    /// `previous` exchange
+    fn is_interleaved_request(&self, previous: PreviousExchange) -> bool {
+        self.origin_timestamp != NtpTimestamp::default()
+            && self.origin_timestamp == previous.receive_timestamp
+            && self.origin_timestamp != self.transmit_timestamp
+    }
+
     fn timestamp_response<C: NtpClock>(
         system: &SystemSnapshot,
         input: Self,
         recv_timestamp: NtpTimestamp,
         clock: &C,
+        previous: Option<PreviousExchange>,
     ) -> Self {
+        let interleaved = previous.filter(|previous| input.is_interleaved_request(*previous));
+
         Self {
-            mode: NtpAssociationMode::Server,
+            mode: input.mode.response_mode(),
             stratum: system.stratum,
-            origin_timestamp: input.transmit_timestamp,
+            // In interleaved mode, the client recognizes the response by its own
+            // receive timestamp of the previous response
+            origin_timestamp: match interleaved {
+                Some(_) => input.receive_timestamp,
+                None => input.transmit_timestamp,
+            },
             receive_timestamp: recv_timestamp,
             reference_id: system.reference_id,
             poll: input.poll,
             precision: system.time_snapshot.precision.log2(),
             root_delay: system.time_snapshot.root_delay,
             root_dispersion: system.time_snapshot.root_dispersion,
+            // Let clients asking to upgrade know we support NTPv5, which takes
+            // precedence over telling when our clock was last updated
+            reference_timestamp: match input.reference_timestamp == UPGRADE_TIMESTAMP {
+                true => UPGRADE_TIMESTAMP,
+                false => system.reference_timestamp,
+            },
             // Timestamp must be last to make it as accurate as possible.
-            transmit_timestamp: clock.now().expect("Failed to read time"),
+            transmit_timestamp: match interleaved {
+                Some(previous) => previous.transmit_timestamp,
+                None => clock.now().expect("Failed to read time"),
+            },
             ..Self::new()
         }
     }
@@ -262,6 +379,99 @@ impl NtpHeaderV3V4 {
             ..Self::new()
         }
     }
+
+    fn nts_nak_response(packet_from_client: Self) -> Self {
+        Self {
+            mode: NtpAssociationMode::Server,
+            stratum: 0, // indicates a kiss code
+            reference_id: ReferenceId::KISS_NTSN,
+            origin_timestamp: packet_from_client.transmit_timestamp,
+            ..Self::new()
+        }
+    }
+
+    fn crypto_nak_response(packet_from_client: Self) -> Self {
+        Self {
+            leap: NtpLeapIndicator::Unknown,
+            mode: packet_from_client.mode.response_mode(),
+            stratum: 16,
+            origin_timestamp: packet_from_client.transmit_timestamp,
+            ..Self::new()
+        }
+    }
+}
+
+impl NtpHeader {
+    fn timestamp_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+        previous: Option<PreviousExchange>,
+    ) -> Self {
+        match input {
+            NtpHeader::V3(header) => NtpHeader::V3(NtpHeaderV3V4::timestamp_response(
+                system,
+                header,
+                recv_timestamp,
+                clock,
+                None,
+            )),
+            NtpHeader::V4(header) => NtpHeader::V4(NtpHeaderV3V4::timestamp_response(
+                system,
+                header,
+                recv_timestamp,
+                clock,
+                previous,
+            )),
+            NtpHeader::V5(header) => NtpHeader::V5(NtpHeaderV5::timestamp_response(
+                system,
+                header,
+                recv_timestamp,
+                clock,
+            )),
+        }
+    }
+
+    fn rate_limit_response(packet_from_client: Self) -> Self {
+        match packet_from_client {
+            NtpHeader::V3(header) => NtpHeader::V3(NtpHeaderV3V4::rate_limit_response(header)),
+            NtpHeader::V4(header) => NtpHeader::V4(NtpHeaderV3V4::rate_limit_response(header)),
+            NtpHeader::V5(header) => {
+                NtpHeader::V4(NtpHeaderV3V4::rate_limit_response(header.to_v4_request()))
+            }
+        }
+    }
+
+    fn deny_response(packet_from_client: Self) -> Self {
+        match packet_from_client {
+            NtpHeader::V3(header) => NtpHeader::V3(NtpHeaderV3V4::deny_response(header)),
+            NtpHeader::V4(header) => NtpHeader::V4(NtpHeaderV3V4::deny_response(header)),
+            NtpHeader::V5(header) => {
+                NtpHeader::V4(NtpHeaderV3V4::deny_response(header.to_v4_request()))
+            }
+        }
+    }
+
+    fn nts_nak_response(packet_from_client: Self) -> Self {
+        match packet_from_client {
+            NtpHeader::V3(header) => NtpHeader::V3(NtpHeaderV3V4::nts_nak_response(header)),
+            NtpHeader::V4(header) => NtpHeader::V4(NtpHeaderV3V4::nts_nak_response(header)),
+            NtpHeader::V5(header) => {
+                NtpHeader::V4(NtpHeaderV3V4::nts_nak_response(header.to_v4_request()))
+            }
+        }
+    }
+
+    fn crypto_nak_response(packet_from_client: Self) -> Self {
+        match packet_from_client {
+            NtpHeader::V3(header) => NtpHeader::V3(NtpHeaderV3V4::crypto_nak_response(header)),
+            NtpHeader::V4(header) => NtpHeader::V4(NtpHeaderV3V4::crypto_nak_response(header)),
+            NtpHeader::V5(header) => {
+                NtpHeader::V4(NtpHeaderV3V4::crypto_nak_response(header.to_v4_request()))
+            }
+        }
+    }
 }
 
 impl<'a> NtpPacket<'a> {
@@ -278,6 +488,55 @@ impl<'a> NtpPacket<'a> {
         data: &'a [u8],
         cipher: &impl CipherProvider,
     ) -> Result<(Self, Option<DecodedServerCookie>), PacketParsingError<'a>> {
+        Self::deserialize_inner(data, cipher, None, false)
+            .map(|(packet, cookie, _)| (packet, cookie))
+    }
+
+    /// Like [`NtpPacket::deserialize`], but decrypting NTS encrypted fields into
+    /// `scratch` instead of allocating, with the decrypted fields borrowing from it.
+    /// A `scratch` the size of `data` always suffices. When it is too small, the
+    /// decrypted fields are allocated as usual.
+    #[allow(clippy::result_large_err)]
+    pub fn deserialize_with_scratch(
+        data: &'a [u8],
+        cipher: &impl CipherProvider,
+        scratch: &'a mut [u8],
+    ) -> Result<(Self, Option<DecodedServerCookie>), PacketParsingError<'a>> {
+        Self::deserialize_inner(data, cipher, Some(scratch), false)
+            .map(|(packet, cookie, _)| (packet, cookie))
+    }
+
+    /// Like [`NtpPacket::deserialize`], but parsing only the packet at the start
+    /// of `data`. Data after the packet that is neither a valid extension field
+    /// nor a MAC is returned, instead of making the packet invalid.
+    #[allow(clippy::result_large_err)]
+    pub fn deserialize_with_trailing(
+        data: &'a [u8],
+        cipher: &impl CipherProvider,
+    ) -> Result<(Self, Option<DecodedServerCookie>, TrailingData<'a>), PacketParsingError<'a>> {
+        let (packet, cookie, consumed) = Self::deserialize_inner(data, cipher, None, true)?;
+        let trailing = TrailingData {
+            consumed,
+            unexpected: &data[consumed..],
+        };
+        Ok((packet, cookie, trailing))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn deserialize_inner(
+        data: &'a [u8],
+        cipher: &impl CipherProvider,
+        scratch: Option<&'a mut [u8]>,
+        allow_trailing: bool,
+    ) -> Result<(Self, Option<DecodedServerCookie>, usize), PacketParsingError<'a>> {
+        // The MAC at `offset`, if any, and the length of the packet with it
+        let mac_at = |offset: usize| match Mac::deserialize(&data[offset..]) {
+            _ if offset == data.len() => Ok((None, offset)),
+            Ok(mac) => Ok((Some(mac), data.len())),
+            Err(_) if allow_trailing => Ok((None, offset)),
+            Err(e) => Err(e.in_field(offset, PacketField::Mac)),
+        };
+
         if data.is_empty() {
             return Err(PacketParsingError::IncorrectLength);
         }
@@ -288,11 +547,7 @@ impl<'a> NtpPacket<'a> {
             3 => {
                 let (header, header_size) =
                     NtpHeaderV3V4::deserialize(data).map_err(|e| e.generalize())?;
-                let mac = if header_size != data.len() {
-                    Some(Mac::deserialize(&data[header_size..]).map_err(|e| e.generalize())?)
-                } else {
-                    None
-                };
+                let (mac, consumed) = mac_at(header_size)?;
                 Ok((
                     NtpPacket {
                         header: NtpHeader::V3(header),
@@ -300,78 +555,207 @@ impl<'a> NtpPacket<'a> {
                         mac,
                     },
                     None,
+                    consumed,
                 ))
             }
-            4 => {
+            4 | 5 => {
                 let mut has_invalid_nts = false;
 
-                let (header, header_size) =
-                    NtpHeaderV3V4::deserialize(data).map_err(|e| e.generalize())?;
-                let (efdata, header_plus_fields_len, cookie) =
-                    match ExtensionFieldData::deserialize(data, header_size, cipher) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            let ret = e.get_decrypt_error()?;
-                            has_invalid_nts = true;
-                            (ret.0, ret.1, None)
-                        }
-                    };
-
-                let mac = if header_plus_fields_len != data.len() {
-                    Some(
-                        Mac::deserialize(&data[header_plus_fields_len..])
-                            .map_err(|e| e.generalize())?,
-                    )
-                } else {
-                    None
+                let (header, header_size) = match version {
+                    4 => NtpHeaderV3V4::deserialize(data)
+                        .map(|(header, size)| (NtpHeader::V4(header), size)),
+                    _ => NtpHeaderV5::deserialize(data)
+                        .map(|(header, size)| (NtpHeader::V5(header), size)),
+                }
+                .map_err(|e| e.generalize())?;
+                let (efdata, header_plus_fields_len, cookie) = match ExtensionFieldData::deserialize(
+                    data,
+                    header_size,
+                    cipher,
+                    scratch,
+                    allow_trailing,
+                ) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let ret = e.get_decrypt_error()?;
+                        has_invalid_nts = true;
+                        (ret.0, ret.1, None)
+                    }
                 };
 
+                let (mac, consumed) = mac_at(header_plus_fields_len)?;
+
                 if has_invalid_nts {
                     Err(ParsingError::DecryptError(NtpPacket {
-                        header: NtpHeader::V4(header),
+                        header,
                         efdata,
                         mac,
                     }))
                 } else {
                     let packet = NtpPacket {
-                        header: NtpHeader::V4(header),
+                        header,
                         efdata,
                         mac,
                     };
 
-                    Ok((packet, cookie))
+                    Ok((packet, cookie, consumed))
                 }
             }
             _ => Err(PacketParsingError::InvalidVersion(version)),
         }
     }
 
+    /// Like [`NtpPacket::deserialize`], decoding the extension fields of the types
+    /// in `registry` into [`ExtensionField::Custom`]
+    #[allow(clippy::result_large_err)]
+    pub fn deserialize_with_registry(
+        data: &'a [u8],
+        cipher: &impl CipherProvider,
+        registry: &ExtensionFieldRegistry,
+    ) -> Result<(Self, Option<DecodedServerCookie>), PacketParsingError<'a>> {
+        let (mut packet, cookie) = Self::deserialize(data, cipher)?;
+        registry.decode_all(&mut packet.efdata);
+        Ok((packet, cookie))
+    }
+
+    /// Like [`NtpPacket::deserialize`], additionally returning the deviations
+    /// from the specification that parsing tolerated. This helps diagnosing
+    /// interoperability issues with packets that are accepted.
+    #[allow(clippy::result_large_err)]
+    pub fn deserialize_with_warnings(
+        data: &'a [u8],
+        cipher: &impl CipherProvider,
+    ) -> Result<(Self, Vec<ParseWarning>), PacketParsingError<'a>> {
+        let (packet, _) = Self::deserialize(data, cipher)?;
+
+        let mut warnings = vec![];
+
+        let mode = packet.mode();
+        if matches!(
+            mode,
+            NtpAssociationMode::Reserved
+                | NtpAssociationMode::Control
+                | NtpAssociationMode::Private
+        ) {
+            warnings.push(ParseWarning::UnexpectedMode(mode));
+        }
+
+        match packet.header {
+            NtpHeader::V3(_) => { /* No extension fields in V3 */ }
+            NtpHeader::V4(_) => {
+                warnings.extend(ExtensionFieldData::warnings(&data[NtpHeaderV3V4::LENGTH..]))
+            }
+            NtpHeader::V5(_) => {
+                warnings.extend(ExtensionFieldData::warnings(&data[NtpHeaderV5::LENGTH..]))
+            }
+        }
+
+        if packet.mac.is_some() {
+            warnings.push(ParseWarning::IgnoredMac);
+        }
+
+        Ok((packet, warnings))
+    }
+
+    /// The type ids of the extension fields in `data`, in order, without
+    /// decrypting or decoding their contents. This is cheap enough to classify
+    /// all traffic, e.g. for metrics on the use of NTS.
+    ///
+    /// Only NTPv4 and NTPv5 packets carry extension fields. Scanning stops at the first
+    /// malformed field, so the fields of an invalid packet may be incomplete.
+    pub fn extension_field_type_ids(data: &[u8]) -> Vec<u16> {
+        match data.first().map(|first| (first & 0b0011_1000) >> 3) {
+            Some(4) if data.len() >= NtpHeaderV3V4::LENGTH => {
+                ExtensionFieldData::type_ids(&data[NtpHeaderV3V4::LENGTH..])
+            }
+            Some(5) if data.len() >= NtpHeaderV5::LENGTH => {
+                ExtensionFieldData::type_ids(&data[NtpHeaderV5::LENGTH..])
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Parse an NTS request on the server side, unsealing its cookie with the
+    /// given keyset and using the recovered client-to-server key to verify and
+    /// decrypt the request. On success the session keys from the cookie are
+    /// returned alongside the packet, ready to be used for the response.
+    ///
+    /// Requests that do not carry a valid cookie and matching authenticator
+    /// result in a [`ParsingError::DecryptError`].
+    #[allow(clippy::result_large_err)]
+    pub fn server_unseal_and_verify(
+        data: &'a [u8],
+        keyset: &KeySet,
+    ) -> Result<(Self, DecodedServerCookie), PacketParsingError<'a>> {
+        match Self::deserialize(data, keyset)? {
+            (packet, Some(session_keys)) => Ok((packet, session_keys)),
+            (packet, None) => Err(ParsingError::DecryptError(packet)),
+        }
+    }
+
     #[cfg(test)]
     pub fn serialize_without_encryption_vec(&self) -> std::io::Result<Vec<u8>> {
-        let mut buffer = vec![0u8; 1024];
+        let mut buffer = vec![0u8; self.wire_size(&NoCipher)];
         let mut cursor = Cursor::new(buffer.as_mut_slice());
 
         self.serialize(&mut cursor, &NoCipher)?;
 
         let length = cursor.position() as usize;
-        let buffer = cursor.into_inner()[..length].to_vec();
+        buffer.truncate(length);
 
         Ok(buffer)
     }
 
+    /// Number of bytes this packet takes up when serialized with `cipher`, for
+    /// sizing the buffer passed to [`NtpPacket::serialize`]
+    pub fn wire_size(&self, cipher: &(impl CipherProvider + ?Sized)) -> usize {
+        let efdata_size = match self.header {
+            NtpHeader::V3(_) => 0,
+            NtpHeader::V4(_) | NtpHeader::V5(_) => self.efdata.wire_size(cipher),
+        };
+        let header_size = match self.header {
+            NtpHeader::V3(_) | NtpHeader::V4(_) => NtpHeaderV3V4::LENGTH,
+            NtpHeader::V5(_) => NtpHeaderV5::LENGTH,
+        };
+
+        header_size + efdata_size + self.mac.as_ref().map_or(0, |mac| mac.len())
+    }
+
+    /// Copy of this packet without any extension fields or mac
+    pub(crate) fn header_only(&self) -> NtpPacket<'static> {
+        NtpPacket {
+            header: self.header,
+            efdata: ExtensionFieldData::default(),
+            mac: None,
+        }
+    }
+
     pub fn serialize(
         &self,
         w: &mut Cursor<&mut [u8]>,
         cipher: &(impl CipherProvider + ?Sized),
+    ) -> std::io::Result<()> {
+        self.serialize_with_rng(w, cipher, &mut thread_rng())
+    }
+
+    /// Like [`NtpPacket::serialize`], generating the nonce of the NTS encrypted
+    /// extension field with `rng`. Only a cryptographically secure `rng` should be
+    /// used outside of tests.
+    pub fn serialize_with_rng(
+        &self,
+        w: &mut Cursor<&mut [u8]>,
+        cipher: &(impl CipherProvider + ?Sized),
+        rng: &mut impl Rng,
     ) -> std::io::Result<()> {
         match self.header {
             NtpHeader::V3(header) => header.serialize(w, 3)?,
             NtpHeader::V4(header) => header.serialize(w, 4)?,
+            NtpHeader::V5(header) => header.serialize(w)?,
         };
 
         match self.header {
             NtpHeader::V3(_) => { /* No extension fields in V3 */ }
-            NtpHeader::V4(_) => self.efdata.serialize(w, cipher)?,
+            NtpHeader::V4(_) | NtpHeader::V5(_) => self.efdata.serialize(w, cipher, rng)?,
         }
 
         if let Some(ref mac) = self.mac {
@@ -386,19 +770,73 @@ impl<'a> NtpPacket<'a> {
         new_cookies: u8,
         poll_interval: PollInterval,
     ) -> (NtpPacket<'static>, RequestIdentifier) {
-        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval);
+        Self::nts_poll_message_with_rng(cookie, new_cookies, poll_interval, &mut thread_rng())
+    }
 
-        let identifier: [u8; 32] = rand::thread_rng().gen();
+    /// Like [`NtpPacket::nts_poll_message`], taking the randomness for the request
+    /// from `rng`
+    pub fn nts_poll_message_with_rng(
+        cookie: &'a [u8],
+        new_cookies: u8,
+        poll_interval: PollInterval,
+        rng: &mut impl Rng,
+    ) -> (NtpPacket<'static>, RequestIdentifier) {
+        Self::nts_poll_message_inner(Some(cookie), new_cookies, poll_interval, None, rng)
+    }
 
-        let mut authenticated = vec![
-            ExtensionField::UniqueIdentifier(identifier.to_vec().into()),
-            ExtensionField::NtsCookie(cookie.to_vec().into()),
-        ];
+    /// An NTS request without a cookie, consisting of just a unique identifier
+    /// and an (empty) encrypted extension field. A server can't authenticate
+    /// this request, and should answer with an NTS negative-acknowledgment.
+    ///
+    /// This is only useful for probing how a server handles invalid requests;
+    /// a real client must always send a cookie.
+    pub fn nts_probe_message(
+        poll_interval: PollInterval,
+    ) -> (NtpPacket<'static>, RequestIdentifier) {
+        Self::nts_poll_message_inner(None, 0, poll_interval, None, &mut thread_rng())
+    }
 
-        for _ in 1..new_cookies {
-            authenticated.push(ExtensionField::NtsCookiePlaceholder {
-                cookie_length: cookie.len() as u16,
-            });
+    /// Like [`NtpPacket::nts_poll_message`], with the unique identifier of the
+    /// request starting with `prefix`. The rest of the identifier is random.
+    pub fn nts_poll_message_with_prefix(
+        cookie: &'a [u8],
+        new_cookies: u8,
+        poll_interval: PollInterval,
+        prefix: &UniqueIdentifierPrefix,
+    ) -> (NtpPacket<'static>, RequestIdentifier) {
+        Self::nts_poll_message_inner(
+            Some(cookie),
+            new_cookies,
+            poll_interval,
+            Some(prefix),
+            &mut thread_rng(),
+        )
+    }
+
+    fn nts_poll_message_inner(
+        cookie: Option<&'a [u8]>,
+        new_cookies: u8,
+        poll_interval: PollInterval,
+        prefix: Option<&UniqueIdentifierPrefix>,
+        rng: &mut impl Rng,
+    ) -> (NtpPacket<'static>, RequestIdentifier) {
+        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval, rng);
+
+        let mut identifier: [u8; 32] = rng.gen();
+        if let Some(prefix) = prefix {
+            identifier[..UniqueIdentifierPrefix::LENGTH].copy_from_slice(prefix.as_bytes());
+        }
+
+        let mut authenticated = vec![ExtensionField::UniqueIdentifier(identifier.to_vec().into())];
+
+        if let Some(cookie) = cookie {
+            authenticated.push(ExtensionField::NtsCookie(cookie.to_vec().into()));
+
+            for _ in 1..new_cookies {
+                authenticated.push(ExtensionField::NtsCookiePlaceholder {
+                    cookie_length: cookie.len() as u16,
+                });
+            }
         }
 
         (
@@ -419,7 +857,16 @@ impl<'a> NtpPacket<'a> {
     }
 
     pub fn poll_message(poll_interval: PollInterval) -> (Self, RequestIdentifier) {
-        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval);
+        Self::poll_message_with_rng(poll_interval, &mut thread_rng())
+    }
+
+    /// Like [`NtpPacket::poll_message`], taking the randomness for the request
+    /// from `rng`
+    pub fn poll_message_with_rng(
+        poll_interval: PollInterval,
+        rng: &mut impl Rng,
+    ) -> (Self, RequestIdentifier) {
+        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval, rng);
         (
             NtpPacket {
                 header: NtpHeader::V4(header),
@@ -436,38 +883,51 @@ impl<'a> NtpPacket<'a> {
         recv_timestamp: NtpTimestamp,
         clock: &C,
     ) -> Self {
-        match input.header {
-            NtpHeader::V3(header) => NtpPacket {
-                header: NtpHeader::V3(NtpHeaderV3V4::timestamp_response(
-                    system,
-                    header,
-                    recv_timestamp,
-                    clock,
-                )),
-                efdata: Default::default(),
-                mac: None,
-            },
-            NtpHeader::V4(header) => NtpPacket {
-                header: NtpHeader::V4(NtpHeaderV3V4::timestamp_response(
-                    system,
-                    header,
-                    recv_timestamp,
-                    clock,
-                )),
-                efdata: ExtensionFieldData {
-                    authenticated: vec![],
-                    encrypted: vec![],
-                    // Ignore encrypted so as not to accidentaly leak anything
-                    untrusted: input
-                        .efdata
-                        .untrusted
-                        .into_iter()
-                        .chain(input.efdata.authenticated.into_iter())
-                        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
-                        .collect(),
-                },
-                mac: None,
+        Self::interleaved_timestamp_response(system, input, recv_timestamp, clock, None)
+    }
+
+    /// Like [`NtpPacket::timestamp_response`], answering in interleaved mode when
+    /// the request is an interleaved one referring to the `previous` exchange with
+    /// the client. Other requests get a regular response.
+    pub fn interleaved_timestamp_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+        previous: Option<PreviousExchange>,
+    ) -> Self {
+        let reference_ids = Self::answer_reference_id_request(
+            system,
+            &input.header,
+            input
+                .efdata
+                .untrusted
+                .iter()
+                .chain(&input.efdata.authenticated),
+        );
+        let header =
+            NtpHeader::timestamp_response(system, input.header, recv_timestamp, clock, previous);
+        let efdata = match header {
+            NtpHeader::V3(_) => Default::default(),
+            NtpHeader::V4(_) | NtpHeader::V5(_) => ExtensionFieldData {
+                authenticated: vec![],
+                encrypted: vec![],
+                // Ignore encrypted so as not to accidentaly leak anything
+                untrusted: input
+                    .efdata
+                    .untrusted
+                    .into_iter()
+                    .chain(input.efdata.authenticated.into_iter())
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .chain(reference_ids)
+                    .collect(),
             },
+        };
+
+        NtpPacket {
+            header,
+            efdata,
+            mac: None,
         }
     }
 
@@ -479,149 +939,346 @@ impl<'a> NtpPacket<'a> {
         cookie: &DecodedServerCookie,
         keyset: &KeySet,
     ) -> Self {
-        match input.header {
-            NtpHeader::V3(_) => unreachable!("NTS shouldn't work with NTPv3"),
-            NtpHeader::V4(header) => NtpPacket {
-                header: NtpHeader::V4(NtpHeaderV3V4::timestamp_response(
-                    system,
-                    header,
-                    recv_timestamp,
-                    clock,
-                )),
-                efdata: ExtensionFieldData {
-                    encrypted: input
-                        .efdata
-                        .authenticated
-                        .iter()
-                        .chain(input.efdata.encrypted.iter())
-                        .filter_map(|f| match f {
-                            ExtensionField::NtsCookiePlaceholder { cookie_length } => {
-                                let new_cookie = keyset.encode_cookie(cookie);
-                                if new_cookie.len() > *cookie_length as usize {
-                                    None
-                                } else {
-                                    Some(ExtensionField::NtsCookie(Cow::Owned(new_cookie)))
-                                }
+        Self::nts_interleaved_timestamp_response(
+            system,
+            input,
+            recv_timestamp,
+            clock,
+            cookie,
+            keyset,
+            None,
+        )
+    }
+
+    /// Like [`NtpPacket::nts_timestamp_response`], answering in interleaved mode
+    /// when the request refers to the `previous` exchange with the client
+    pub fn nts_interleaved_timestamp_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+        cookie: &DecodedServerCookie,
+        keyset: &KeySet,
+        previous: Option<PreviousExchange>,
+    ) -> Self {
+        if let NtpHeader::V3(_) = input.header {
+            unreachable!("NTS shouldn't work with NTPv3")
+        }
+
+        // only answer authenticated requests for our bloom filter, with an
+        // authenticated response
+        let reference_ids =
+            Self::answer_reference_id_request(system, &input.header, &input.efdata.authenticated);
+
+        NtpPacket {
+            header: NtpHeader::timestamp_response(
+                system,
+                input.header,
+                recv_timestamp,
+                clock,
+                previous,
+            ),
+            efdata: ExtensionFieldData {
+                encrypted: input
+                    .efdata
+                    .authenticated
+                    .iter()
+                    .chain(input.efdata.encrypted.iter())
+                    .filter_map(|f| match f {
+                        ExtensionField::NtsCookiePlaceholder { cookie_length } => {
+                            let new_cookie = keyset.encode_cookie(cookie);
+                            if new_cookie.len() > *cookie_length as usize {
+                                None
+                            } else {
+                                Some(ExtensionField::NtsCookie(Cow::Owned(new_cookie)))
                             }
-                            ExtensionField::NtsCookie(old_cookie) => {
-                                let new_cookie = keyset.encode_cookie(cookie);
-                                if new_cookie.len() > old_cookie.len() {
-                                    None
-                                } else {
-                                    Some(ExtensionField::NtsCookie(Cow::Owned(new_cookie)))
-                                }
+                        }
+                        ExtensionField::NtsCookie(old_cookie) => {
+                            let new_cookie = keyset.encode_cookie(cookie);
+                            if new_cookie.len() > old_cookie.len() {
+                                None
+                            } else {
+                                Some(ExtensionField::NtsCookie(Cow::Owned(new_cookie)))
                             }
-                            _ => None,
-                        })
-                        .collect(),
-                    authenticated: input
-                        .efdata
-                        .authenticated
-                        .into_iter()
-                        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
-                        .collect(),
-                    // Ignore encrypted so as not to accidentaly leak anything
-                    untrusted: vec![],
-                },
-                mac: None,
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                authenticated: input
+                    .efdata
+                    .authenticated
+                    .into_iter()
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .chain(reference_ids)
+                    .collect(),
+                // Ignore encrypted so as not to accidentaly leak anything
+                untrusted: vec![],
             },
+            mac: None,
         }
     }
 
+    /// Make this NTS response at most `max_size` bytes on the wire when
+    /// encrypted with `cipher`, by dropping cookies. Servers pass the size of
+    /// the request, so they never send more than they received. With `pad`,
+    /// the space left is filled with a padding field inside the encrypted
+    /// field, so the size of the response does not reveal how many cookies it
+    /// carries. Returns whether the response fits, otherwise it must not be sent.
+    pub fn fit_nts_response(&mut self, max_size: usize, cipher: &dyn Cipher, pad: bool) -> bool {
+        // the size follows from the lengths of the fields, as the ciphertext of
+        // the encrypted field is exactly as long as its plaintext
+        let size = loop {
+            let size = self.wire_size(cipher);
+            if size <= max_size {
+                break size;
+            }
+
+            let last_cookie = self
+                .efdata
+                .encrypted
+                .iter()
+                .rposition(|field| matches!(field, ExtensionField::NtsCookie(_)));
+            match last_cookie {
+                Some(index) => {
+                    self.efdata.encrypted.remove(index);
+                }
+                None => return false,
+            }
+        };
+
+        // fields in the encrypted field have no minimum size, and grow its
+        // ciphertext by exactly their (4 byte aligned) size
+        let padding = (max_size - size) / 4 * 4;
+        if pad && padding >= 4 {
+            self.efdata.encrypted.push(ExtensionField::Unknown {
+                type_id: ExtensionField::PADDING_TYPE_ID,
+                data: Cow::Owned(vec![0; padding - 4]),
+            });
+        }
+
+        true
+    }
+
+    /// The part of our bloom filter that an NTPv5 request asks for in `fields`
+    fn answer_reference_id_request<'b>(
+        system: &SystemSnapshot,
+        header: &NtpHeader,
+        fields: impl IntoIterator<Item = &'b ExtensionField<'b>>,
+    ) -> Option<ExtensionField<'static>> {
+        if !matches!(header, NtpHeader::V5(_)) {
+            return None;
+        }
+
+        fields.into_iter().find_map(|field| match field {
+            ExtensionField::ReferenceIdRequest { offset, length } => {
+                let chunk = system
+                    .bloom_filter
+                    .as_bytes()
+                    .get(*offset as usize..)?
+                    .get(..*length as usize)?;
+                Some(ExtensionField::ReferenceIdResponse(Cow::Owned(
+                    chunk.to_vec(),
+                )))
+            }
+            _ => None,
+        })
+    }
+
     pub fn rate_limit_response(packet_from_client: Self) -> Self {
-        match packet_from_client.header {
-            NtpHeader::V3(header) => NtpPacket {
-                header: NtpHeader::V3(NtpHeaderV3V4::rate_limit_response(header)),
-                efdata: Default::default(),
-                mac: None,
-            },
-            NtpHeader::V4(header) => NtpPacket {
-                header: NtpHeader::V4(NtpHeaderV3V4::rate_limit_response(header)),
-                efdata: ExtensionFieldData {
-                    authenticated: vec![],
-                    encrypted: vec![],
-                    // Ignore encrypted so as not to accidentaly leak anything
-                    untrusted: packet_from_client
-                        .efdata
-                        .untrusted
-                        .into_iter()
-                        .chain(packet_from_client.efdata.authenticated.into_iter())
-                        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
-                        .collect(),
-                },
-                mac: None,
+        let header = NtpHeader::rate_limit_response(packet_from_client.header);
+        let efdata = match header {
+            NtpHeader::V3(_) => Default::default(),
+            NtpHeader::V4(_) | NtpHeader::V5(_) => ExtensionFieldData {
+                authenticated: vec![],
+                encrypted: vec![],
+                // Ignore encrypted so as not to accidentaly leak anything
+                untrusted: packet_from_client
+                    .efdata
+                    .untrusted
+                    .into_iter()
+                    .chain(packet_from_client.efdata.authenticated.into_iter())
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .collect(),
             },
+        };
+
+        NtpPacket {
+            header,
+            efdata,
+            mac: None,
         }
     }
 
     pub fn nts_rate_limit_response(packet_from_client: Self) -> Self {
-        match packet_from_client.header {
-            NtpHeader::V3(_) => unreachable!("NTS shouldn't work with NTPv3"),
-            NtpHeader::V4(header) => NtpPacket {
-                header: NtpHeader::V4(NtpHeaderV3V4::rate_limit_response(header)),
-                efdata: ExtensionFieldData {
-                    authenticated: packet_from_client
-                        .efdata
-                        .authenticated
-                        .into_iter()
-                        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
-                        .collect(),
-                    encrypted: vec![],
-                    untrusted: vec![],
-                },
-                mac: None,
+        if let NtpHeader::V3(_) = packet_from_client.header {
+            unreachable!("NTS shouldn't work with NTPv3")
+        }
+
+        NtpPacket {
+            header: NtpHeader::rate_limit_response(packet_from_client.header),
+            efdata: ExtensionFieldData {
+                authenticated: packet_from_client
+                    .efdata
+                    .authenticated
+                    .into_iter()
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .collect(),
+                encrypted: vec![],
+                untrusted: vec![],
             },
+            mac: None,
         }
     }
 
     pub fn deny_response(packet_from_client: Self) -> Self {
-        match packet_from_client.header {
-            NtpHeader::V3(header) => NtpPacket {
-                header: NtpHeader::V3(NtpHeaderV3V4::deny_response(header)),
-                efdata: Default::default(),
-                mac: None,
-            },
-            NtpHeader::V4(header) => NtpPacket {
-                header: NtpHeader::V4(NtpHeaderV3V4::deny_response(header)),
-                efdata: ExtensionFieldData {
-                    authenticated: vec![],
-                    encrypted: vec![],
-                    // Ignore encrypted so as not to accidentaly leak anything
-                    untrusted: packet_from_client
-                        .efdata
-                        .untrusted
-                        .into_iter()
-                        .chain(packet_from_client.efdata.authenticated.into_iter())
-                        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
-                        .collect(),
-                },
-                mac: None,
+        let header = NtpHeader::deny_response(packet_from_client.header);
+        let efdata = match header {
+            NtpHeader::V3(_) => Default::default(),
+            NtpHeader::V4(_) | NtpHeader::V5(_) => ExtensionFieldData {
+                authenticated: vec![],
+                encrypted: vec![],
+                // Ignore encrypted so as not to accidentaly leak anything
+                untrusted: packet_from_client
+                    .efdata
+                    .untrusted
+                    .into_iter()
+                    .chain(packet_from_client.efdata.authenticated.into_iter())
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .collect(),
             },
+        };
+
+        NtpPacket {
+            header,
+            efdata,
+            mac: None,
         }
     }
 
     pub fn nts_deny_response(packet_from_client: Self) -> Self {
-        match packet_from_client.header {
-            NtpHeader::V3(_) => unreachable!("NTS shouldn't work with NTPv3"),
-            NtpHeader::V4(header) => NtpPacket {
-                header: NtpHeader::V4(NtpHeaderV3V4::deny_response(header)),
-                efdata: ExtensionFieldData {
-                    authenticated: packet_from_client
-                        .efdata
-                        .authenticated
-                        .into_iter()
-                        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
-                        .collect(),
-                    encrypted: vec![],
-                    untrusted: vec![],
-                },
-                mac: None,
+        if let NtpHeader::V3(_) = packet_from_client.header {
+            unreachable!("NTS shouldn't work with NTPv3")
+        }
+
+        NtpPacket {
+            header: NtpHeader::deny_response(packet_from_client.header),
+            efdata: ExtensionFieldData {
+                authenticated: packet_from_client
+                    .efdata
+                    .authenticated
+                    .into_iter()
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .collect(),
+                encrypted: vec![],
+                untrusted: vec![],
             },
+            mac: None,
+        }
+    }
+
+    /// Response to an NTS request of which the cookie could not be decrypted or
+    /// the fields not be authenticated: a kiss-o'-death with code NTSN, telling
+    /// the client to get new cookies. The unique identifier is echoed
+    /// unauthenticated, as there are no keys to authenticate it with.
+    pub fn nts_nak_response(packet_from_client: Self) -> Self {
+        if let NtpHeader::V3(_) = packet_from_client.header {
+            unreachable!("NTS shouldn't work with NTPv3")
+        }
+
+        NtpPacket {
+            header: NtpHeader::nts_nak_response(packet_from_client.header),
+            efdata: ExtensionFieldData {
+                authenticated: vec![],
+                encrypted: vec![],
+                untrusted: packet_from_client
+                    .efdata
+                    .untrusted
+                    .into_iter()
+                    .chain(packet_from_client.efdata.authenticated)
+                    .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+                    .collect(),
+            },
+            mac: None,
+        }
+    }
+
+    /// Response to a request that failed MAC authentication: a crypto-NAK, which
+    /// carries a MAC of four zero octets and no extension fields. The header
+    /// advertises stratum 16, so that clients unaware of crypto-NAKs don't use it.
+    pub fn crypto_nak_response(packet_from_client: Self) -> Self {
+        NtpPacket {
+            header: NtpHeader::crypto_nak_response(packet_from_client.header),
+            efdata: Default::default(),
+            mac: Some(Mac::crypto_nak()),
         }
     }
 }
 
 impl<'a> NtpPacket<'a> {
+    /// The untrusted unknown extension fields of this packet with one of the given
+    /// type ids, to be echoed back in a response for diagnostic purposes
+    pub fn echo_fields(&self, type_ids: &[u16]) -> Vec<ExtensionField<'static>> {
+        self.efdata
+            .untrusted
+            .iter()
+            .filter(|ef| match ef {
+                ExtensionField::Unknown { type_id, .. } => type_ids.contains(type_id),
+                _ => false,
+            })
+            .map(|ef| ef.clone().into_owned())
+            .collect()
+    }
+
+    /// Append fields obtained from [`NtpPacket::echo_fields`] to the untrusted
+    /// extension fields of this (response) packet
+    pub fn add_echo_fields(&mut self, fields: Vec<ExtensionField<'static>>) {
+        match self.header {
+            NtpHeader::V3(_) => { /* No extension fields in V3 */ }
+            NtpHeader::V4(_) | NtpHeader::V5(_) => self.efdata.untrusted.extend(fields),
+        }
+    }
+
+    /// Add a MAC to this packet with symmetric key `key`, which has id `keyid`,
+    /// replacing any MAC it already had. Only for packets without NTS.
+    pub fn add_mac(&mut self, keyid: u32, key: &SymmetricKey) -> std::io::Result<()> {
+        self.mac = None;
+
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        self.serialize(&mut cursor, &NoCipher)?;
+        let used = cursor.position() as usize;
+
+        self.mac = Some(Mac::new(keyid, key.digest(&buf[..used])));
+        Ok(())
+    }
+
+    /// Whether this packet is a crypto-NAK, with which a server signals that our
+    /// request failed authentication. Crypto-NAKs are not authenticated themselves.
+    pub fn is_crypto_nak(&self) -> bool {
+        self.mac.as_ref().is_some_and(|mac| mac.is_crypto_nak())
+    }
+
+    /// The key id of the MAC of this packet, if it has one
+    pub fn mac_keyid(&self) -> Option<u32> {
+        self.mac.as_ref().map(|mac| mac.keyid())
+    }
+
+    /// Check the MAC of this packet, deserialized from `data`, against the
+    /// symmetric key `key` with id `keyid`
+    pub fn verify_mac(&self, data: &[u8], keyid: u32, key: &SymmetricKey) -> Result<(), MacError> {
+        let mac = self.mac.as_ref().ok_or(MacError::Missing)?;
+        if mac.keyid() != keyid {
+            return Err(MacError::UnknownKey(mac.keyid()));
+        }
+
+        // the MAC always is at the end of the packet
+        let authenticated = &data[..data.len().saturating_sub(mac.len())];
+        match key.verify(authenticated, mac.digest()) {
+            true => Ok(()),
+            false => Err(MacError::Mismatch),
+        }
+    }
+
     pub fn new_cookies<'b: 'a>(&'b self) -> impl Iterator<Item = Vec<u8>> + 'b {
         self.efdata.encrypted.iter().filter_map(|ef| match ef {
             ExtensionField::NtsCookie(cookie) => Some(cookie.to_vec()),
@@ -629,17 +1286,167 @@ impl<'a> NtpPacket<'a> {
         })
     }
 
+    /// The extension fields that were covered by the NTS authentication, but
+    /// not encrypted
+    pub fn authenticated_fields(&self) -> impl Iterator<Item = &ExtensionField<'a>> {
+        self.efdata.authenticated.iter()
+    }
+
+    /// The extension fields that were encrypted. For received packets, these are
+    /// only present when they were deserialized with the right key.
+    pub fn encrypted_fields(&self) -> impl Iterator<Item = &ExtensionField<'a>> {
+        self.efdata.encrypted.iter()
+    }
+
+    /// The extension fields that are not authenticated in any way
+    pub fn untrusted_fields(&self) -> impl Iterator<Item = &ExtensionField<'a>> {
+        self.efdata.untrusted.iter()
+    }
+
+    /// The unique identifier of this packet, wherever it was in the packet
+    pub fn unique_identifier(&self) -> Option<&[u8]> {
+        self.authenticated_fields()
+            .chain(self.encrypted_fields())
+            .chain(self.untrusted_fields())
+            .find_map(|ef| match ef {
+                ExtensionField::UniqueIdentifier(uid) => Some(uid.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// The NTP version of this packet
+    pub fn version(&self) -> u8 {
+        match self.header {
+            NtpHeader::V3(_) => 3,
+            NtpHeader::V4(_) => 4,
+            NtpHeader::V5(_) => 5,
+        }
+    }
+
+    /// The timescale of the timestamps, only known for NTPv5 packets
+    pub fn timescale(&self) -> Option<NtpTimescale> {
+        match self.header {
+            NtpHeader::V3(_) | NtpHeader::V4(_) => None,
+            NtpHeader::V5(header) => Some(header.timescale),
+        }
+    }
+
+    /// The NTP era of the timestamps, only known for NTPv5 packets
+    pub fn era(&self) -> Option<u8> {
+        match self.header {
+            NtpHeader::V3(_) | NtpHeader::V4(_) => None,
+            NtpHeader::V5(header) => Some(header.era),
+        }
+    }
+
+    /// Whether this NTPv4 packet signals support for NTPv5: in a request, the
+    /// client would like to upgrade, and in a response, the server can upgrade
+    pub fn offers_v5_upgrade(&self) -> bool {
+        match self.header {
+            NtpHeader::V4(header) => header.reference_timestamp == UPGRADE_TIMESTAMP,
+            NtpHeader::V3(_) | NtpHeader::V5(_) => false,
+        }
+    }
+
+    /// Turn a client request into a request of a symmetric association in `mode`
+    pub fn into_symmetric_request(mut self, mode: NtpAssociationMode) -> Self {
+        match self.header {
+            NtpHeader::V3(ref mut header) | NtpHeader::V4(ref mut header) => header.mode = mode,
+            NtpHeader::V5(_) => { /* Symmetric associations are not part of NTPv5 */ }
+        }
+        self
+    }
+
+    /// Turn an NTPv4 request into one that asks the server whether it supports
+    /// NTPv5. Servers without support answer it as a regular NTPv4 request.
+    pub fn into_upgrade_request(mut self) -> Self {
+        if let NtpHeader::V4(ref mut header) = self.header {
+            header.reference_timestamp = UPGRADE_TIMESTAMP;
+        }
+        self
+    }
+
+    /// Turn an NTPv4 request into an interleaved one, referring to the previous
+    /// exchange with the server: the time the server received our last request,
+    /// and the time we received its response. A server supporting interleaved
+    /// mode then answers with the transmit timestamp of its previous response.
+    pub fn into_interleaved_request(
+        mut self,
+        identifier: RequestIdentifier,
+        server_receive_timestamp: NtpTimestamp,
+        receive_timestamp: NtpTimestamp,
+    ) -> (Self, RequestIdentifier) {
+        match self.header {
+            NtpHeader::V4(ref mut header) => {
+                header.origin_timestamp = server_receive_timestamp;
+                header.receive_timestamp = receive_timestamp;
+                (
+                    self,
+                    RequestIdentifier {
+                        interleaved_origin_timestamp: Some(receive_timestamp),
+                        ..identifier
+                    },
+                )
+            }
+            NtpHeader::V3(_) | NtpHeader::V5(_) => (self, identifier),
+        }
+    }
+
+    /// Add a request for part of the bloom filter of an NTPv5 server. With NTS,
+    /// the request is authenticated, so the server authenticates its response.
+    pub(crate) fn with_reference_id_request(mut self, request: ExtensionField<'a>) -> Self {
+        match self.efdata.authenticated.is_empty() && self.efdata.encrypted.is_empty() {
+            true => self.efdata.untrusted.push(request),
+            false => self.efdata.authenticated.push(request),
+        }
+        self
+    }
+
+    /// The part of its bloom filter an NTPv5 server sent, ignoring unauthenticated
+    /// fields when `authenticated` is set
+    pub(crate) fn reference_id_response(&self, authenticated: bool) -> Option<&[u8]> {
+        let fields = match authenticated {
+            true => &self.efdata.authenticated,
+            false => &self.efdata.untrusted,
+        };
+
+        fields.iter().find_map(|field| match field {
+            ExtensionField::ReferenceIdResponse(chunk) => Some(&**chunk),
+            _ => None,
+        })
+    }
+
+    /// Turn an NTPv4 request into the equivalent NTPv5 request, keeping its
+    /// extension fields. Responses to it match the request identifier of the
+    /// original request.
+    pub fn into_v5_request(mut self) -> Self {
+        if let NtpHeader::V4(header) = self.header {
+            self.header = NtpHeader::V5(NtpHeaderV5::from_v4_request(header));
+        }
+        self
+    }
+
     pub fn leap(&self) -> NtpLeapIndicator {
         match self.header {
             NtpHeader::V3(header) => header.leap,
             NtpHeader::V4(header) => header.leap,
+            NtpHeader::V5(header) => header.leap(),
         }
     }
 
+    /// Whether the sender announces a leap second, and sent this packet during
+    /// the ambiguous second around it
+    pub fn leap_in_progress(&self) -> bool {
+        let transmit_timestamp = self.transmit_timestamp();
+        LeapSchedule::from_indicator(self.leap(), transmit_timestamp)
+            .is_some_and(|schedule| schedule.in_progress(transmit_timestamp))
+    }
+
     pub fn mode(&self) -> NtpAssociationMode {
         match self.header {
             NtpHeader::V3(header) => header.mode,
             NtpHeader::V4(header) => header.mode,
+            NtpHeader::V5(header) => header.mode,
         }
     }
 
@@ -647,6 +1454,7 @@ impl<'a> NtpPacket<'a> {
         match self.header {
             NtpHeader::V3(header) => header.stratum,
             NtpHeader::V4(header) => header.stratum,
+            NtpHeader::V5(header) => header.stratum,
         }
     }
 
@@ -654,6 +1462,7 @@ impl<'a> NtpPacket<'a> {
         match self.header {
             NtpHeader::V3(header) => header.precision,
             NtpHeader::V4(header) => header.precision,
+            NtpHeader::V5(header) => header.precision,
         }
     }
 
@@ -661,13 +1470,23 @@ impl<'a> NtpPacket<'a> {
         match self.header {
             NtpHeader::V3(header) => header.root_delay,
             NtpHeader::V4(header) => header.root_delay,
+            NtpHeader::V5(header) => header.root_delay,
+        }
+    }
+
+    pub fn root_dispersion(&self) -> NtpDuration {
+        match self.header {
+            NtpHeader::V3(header) => header.root_dispersion,
+            NtpHeader::V4(header) => header.root_dispersion,
+            NtpHeader::V5(header) => header.root_dispersion,
         }
     }
 
-    pub fn root_dispersion(&self) -> NtpDuration {
+    pub fn origin_timestamp(&self) -> NtpTimestamp {
         match self.header {
-            NtpHeader::V3(header) => header.root_dispersion,
-            NtpHeader::V4(header) => header.root_dispersion,
+            NtpHeader::V3(header) => header.origin_timestamp,
+            NtpHeader::V4(header) => header.origin_timestamp,
+            NtpHeader::V5(header) => header.client_cookie_timestamp(),
         }
     }
 
@@ -675,6 +1494,7 @@ impl<'a> NtpPacket<'a> {
         match self.header {
             NtpHeader::V3(header) => header.receive_timestamp,
             NtpHeader::V4(header) => header.receive_timestamp,
+            NtpHeader::V5(header) => header.receive_timestamp,
         }
     }
 
@@ -682,20 +1502,53 @@ impl<'a> NtpPacket<'a> {
         match self.header {
             NtpHeader::V3(header) => header.transmit_timestamp,
             NtpHeader::V4(header) => header.transmit_timestamp,
+            NtpHeader::V5(header) => header.transmit_timestamp,
         }
     }
 
+    /// Whether the sender claims to have transmitted this packet before
+    /// receiving the request it responds to, which is impossible
+    pub fn has_inconsistent_timestamps(&self) -> bool {
+        self.transmit_timestamp() - self.receive_timestamp() < NtpDuration::ZERO
+    }
+
     pub fn reference_id(&self) -> ReferenceId {
         match self.header {
             NtpHeader::V3(header) => header.reference_id,
             NtpHeader::V4(header) => header.reference_id,
+            // NTPv5 has no reference ids
+            NtpHeader::V5(_) => ReferenceId::NONE,
+        }
+    }
+
+    /// When the clock of the sender was last set or corrected. Requests offering
+    /// an NTPv5 upgrade, and the responses to them, carry the upgrade signal here
+    /// instead.
+    pub fn reference_timestamp(&self) -> NtpTimestamp {
+        match self.header {
+            NtpHeader::V3(header) => header.reference_timestamp,
+            NtpHeader::V4(header) => header.reference_timestamp,
+            // NTPv5 has no reference timestamp
+            NtpHeader::V5(_) => NtpTimestamp::default(),
         }
     }
 
+    /// The poll interval of the sender, `None` when it is not a valid one. In
+    /// responses this echoes the poll interval of the request, or the longer
+    /// interval the server wants its clients to use.
+    pub fn poll(&self) -> Option<PollInterval> {
+        PollInterval::from_log(match self.header {
+            NtpHeader::V3(header) => header.poll,
+            NtpHeader::V4(header) => header.poll,
+            NtpHeader::V5(header) => header.poll,
+        })
+    }
+
     pub fn is_kiss(&self) -> bool {
         match self.header {
             NtpHeader::V3(header) => header.stratum == 0,
             NtpHeader::V4(header) => header.stratum == 0,
+            NtpHeader::V5(header) => header.stratum == 0,
         }
     }
 
@@ -712,7 +1565,62 @@ impl<'a> NtpPacket<'a> {
     }
 
     pub fn is_kiss_ntsn(&self) -> bool {
-        self.is_kiss() && self.reference_id().is_ntsn()
+        match self.header {
+            // NTPv5 signals the NAK with a flag instead of a kiss code
+            NtpHeader::V5(header) => header.flags.contains(NtpFlags::AUTHNAK),
+            _ => self.is_kiss() && self.reference_id().is_ntsn(),
+        }
+    }
+
+    /// The kiss code of a Kiss-o'-Death packet, `None` for other packets
+    pub fn kiss_code(&self) -> Option<KissCode> {
+        if self.is_kiss_ntsn() {
+            Some(KissCode::Ntsn)
+        } else if self.is_kiss() {
+            Some(KissCode::from_reference_id(self.reference_id()))
+        } else {
+            None
+        }
+    }
+
+    /// The identifier a response to this packet, sent by us as a request, is expected to match.
+    pub(crate) fn request_identifier(&self) -> RequestIdentifier {
+        let uid = self
+            .efdata
+            .authenticated
+            .iter()
+            .chain(self.efdata.untrusted.iter())
+            .find_map(|ef| match ef {
+                ExtensionField::UniqueIdentifier(uid) => uid.get(..32)?.try_into().ok(),
+                _ => None,
+            });
+
+        let expected_origin_timestamp = match self.header {
+            NtpHeader::V5(header) => header.client_cookie_timestamp(),
+            _ => self.transmit_timestamp(),
+        };
+        let interleaved_origin_timestamp = match self.header {
+            NtpHeader::V4(header) if header.origin_timestamp != NtpTimestamp::default() => {
+                Some(header.receive_timestamp)
+            }
+            _ => None,
+        };
+
+        RequestIdentifier {
+            expected_origin_timestamp,
+            interleaved_origin_timestamp,
+            uid,
+        }
+    }
+
+    /// Whether the packet carries a unique identifier authenticated through NTS. For
+    /// received packets, this only holds when they were deserialized with the right key.
+    pub fn is_authenticated(&self) -> bool {
+        self.efdata
+            .authenticated
+            .iter()
+            .chain(self.efdata.encrypted.iter())
+            .any(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
     }
 
     pub fn valid_server_response(&self, identifier: RequestIdentifier, nts_enabled: bool) -> bool {
@@ -735,16 +1643,48 @@ impl<'a> NtpPacket<'a> {
             }
         }
         match self.header {
-            NtpHeader::V3(header) => {
-                header.origin_timestamp == identifier.expected_origin_timestamp
-            }
+            NtpHeader::V3(header) => header
+                .origin_timestamp
+                .constant_time_eq(identifier.expected_origin_timestamp),
             NtpHeader::V4(header) => {
-                header.origin_timestamp == identifier.expected_origin_timestamp
+                // compare against both, so timing doesn't reveal which one matched
+                let expected = header
+                    .origin_timestamp
+                    .constant_time_eq(identifier.expected_origin_timestamp);
+                let interleaved = identifier
+                    .interleaved_origin_timestamp
+                    .is_some_and(|origin| header.origin_timestamp.constant_time_eq(origin));
+                expected | interleaved
             }
+            NtpHeader::V5(header) => header
+                .client_cookie_timestamp()
+                .constant_time_eq(identifier.expected_origin_timestamp),
         }
     }
 }
 
+/// Size on the wire of an NTS response that returns `cookie_count` new cookies of
+/// `cookie_len` bytes each. `base_fields` is the size of everything preceding the
+/// encrypted extension field: the NTP header and the authenticated extension
+/// fields (in a response, just the unique identifier).
+///
+/// This allows a server to pick the largest number of cookies that still fits
+/// within a target MTU.
+pub fn nts_response_size(base_fields: usize, cookie_len: usize, cookie_count: usize) -> usize {
+    // RFC 8915, section 5.5: fields inside the encrypted extension field have no minimum size
+    let plaintext_len = cookie_count * ExtensionField::framed_len(cookie_len, 0);
+
+    base_fields + ExtensionField::encrypted_len(plaintext_len)
+}
+
+/// Number of bytes encrypting `plaintext_len` bytes of extension fields with
+/// `cipher` adds on the wire: the header of the encrypted extension field, the
+/// nonce, the authentication tag, and the padding of the ciphertext.
+pub fn nts_encrypted_overhead(plaintext_len: usize, cipher: &dyn Cipher) -> usize {
+    ExtensionField::encrypted_len_with(plaintext_len, cipher.nonce_len(), cipher.tag_len())
+        - plaintext_len
+}
+
 // Returns whether all uid extension fields found match the given uid, or
 // None if there were none.
 fn check_uid_extensionfield<'a, I: IntoIterator<Item = &'a ExtensionField<'a>>>(
@@ -754,7 +1694,7 @@ fn check_uid_extensionfield<'a, I: IntoIterator<Item = &'a ExtensionField<'a>>>(
     let mut found_uid = false;
     for ef in iter {
         if let ExtensionField::UniqueIdentifier(pid) = ef {
-            if pid.len() < uid.len() || &pid[0..uid.len()] != uid {
+            if pid.len() < uid.len() || !constant_time_eq(&pid[0..uid.len()], uid) {
                 return Some(false);
             }
             found_uid = true;
@@ -777,6 +1717,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.mode = mode,
             NtpHeader::V4(ref mut header) => header.mode = mode,
+            NtpHeader::V5(ref mut header) => header.mode = mode,
         }
     }
 
@@ -784,6 +1725,9 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.origin_timestamp = timestamp,
             NtpHeader::V4(ref mut header) => header.origin_timestamp = timestamp,
+            NtpHeader::V5(ref mut header) => {
+                header.client_cookie = u64::from_be_bytes(timestamp.to_bits())
+            }
         }
     }
 
@@ -791,6 +1735,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.transmit_timestamp = timestamp,
             NtpHeader::V4(ref mut header) => header.transmit_timestamp = timestamp,
+            NtpHeader::V5(ref mut header) => header.transmit_timestamp = timestamp,
         }
     }
 
@@ -798,6 +1743,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.receive_timestamp = timestamp,
             NtpHeader::V4(ref mut header) => header.receive_timestamp = timestamp,
+            NtpHeader::V5(ref mut header) => header.receive_timestamp = timestamp,
         }
     }
 
@@ -805,6 +1751,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.precision = precision,
             NtpHeader::V4(ref mut header) => header.precision = precision,
+            NtpHeader::V5(ref mut header) => header.precision = precision,
         }
     }
 
@@ -812,6 +1759,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.leap = leap,
             NtpHeader::V4(ref mut header) => header.leap = leap,
+            NtpHeader::V5(ref mut header) => header.leap = leap,
         }
     }
 
@@ -819,6 +1767,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.stratum = stratum,
             NtpHeader::V4(ref mut header) => header.stratum = stratum,
+            NtpHeader::V5(ref mut header) => header.stratum = stratum,
         }
     }
 
@@ -826,6 +1775,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.reference_id = reference_id,
             NtpHeader::V4(ref mut header) => header.reference_id = reference_id,
+            NtpHeader::V5(_) => { /* No reference id in V5 */ }
         }
     }
 
@@ -833,6 +1783,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.root_delay = root_delay,
             NtpHeader::V4(ref mut header) => header.root_delay = root_delay,
+            NtpHeader::V5(ref mut header) => header.root_delay = root_delay,
         }
     }
 
@@ -840,6 +1791,7 @@ impl<'a> NtpPacket<'a> {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.root_dispersion = root_dispersion,
             NtpHeader::V4(ref mut header) => header.root_dispersion = root_dispersion,
+            NtpHeader::V5(ref mut header) => header.root_dispersion = root_dispersion,
         }
     }
 }
@@ -911,6 +1863,59 @@ mod tests {
         }
     }
 
+    /// Generates the same nonce every time, making the encryption deterministic so
+    /// serialized NTS packets can be compared byte for byte
+    fn fixed_nonce_rng() -> rand::rngs::StdRng {
+        rand::SeedableRng::seed_from_u64(0x42)
+    }
+
+    /// Deserialize `data` and serialize the result again, which must give back
+    /// exactly the same bytes
+    #[track_caller]
+    fn assert_roundtrip(data: &[u8], cipher: &impl CipherProvider) {
+        let (packet, _) = NtpPacket::deserialize(data, cipher).unwrap();
+
+        let mut buf = [0u8; 4096];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet
+            .serialize_with_rng(&mut cursor, cipher, &mut fixed_nonce_rng())
+            .unwrap();
+        let used = cursor.position() as usize;
+
+        assert_eq!(data, &buf[..used]);
+    }
+
+    /// Byte ranges of the nonce and of the ciphertext (which starts with the
+    /// SIV tag) of the encrypted extension field in the serialized NTS packet `data`
+    fn encrypted_field_ranges(data: &[u8]) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let mut offset = NtpHeaderV3V4::LENGTH;
+        loop {
+            let type_id = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            if type_id == 0x404 {
+                let body = offset + 4;
+                let nonce_length = u16::from_be_bytes([data[body], data[body + 1]]) as usize;
+                let ciphertext_length =
+                    u16::from_be_bytes([data[body + 2], data[body + 3]]) as usize;
+                let nonce = body + 4..body + 4 + nonce_length;
+                // the nonce is padded to a multiple of 4 octets
+                let ciphertext_start = body + 4 + nonce_length.next_multiple_of(4);
+                return (
+                    nonce,
+                    ciphertext_start..ciphertext_start + ciphertext_length,
+                );
+            }
+            offset += length;
+        }
+    }
+
+    /// Copy of `data` with a single bit flipped at `index`
+    fn flip_bit(data: &[u8], index: usize) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data[index] ^= 0x10;
+        data
+    }
+
     #[test]
     fn roundtrip_bitrep_leap() {
         for i in 0..4u8 {
@@ -959,10 +1964,7 @@ mod tests {
             reference,
             NtpPacket::deserialize(packet, &NoCipher).unwrap().0
         );
-        match reference.serialize_without_encryption_vec() {
-            Ok(buf) => assert_eq!(packet[..], buf[..]),
-            Err(e) => panic!("{e:?}"),
-        }
+        assert_roundtrip(packet, &NoCipher);
 
         let packet = b"\x1B\x02\x06\xe8\x00\x00\x03\xff\x00\x00\x03\x7d\x5e\xc6\x9f\x0f\xe5\xf6\x62\x98\x7b\x61\xb9\xaf\xe5\xf6\x63\x66\x7b\x64\x99\x5d\xe5\xf6\x63\x66\x81\x40\x55\x90\xe5\xf6\x63\xa8\x76\x1d\xde\x48";
         let reference = NtpPacket {
@@ -988,10 +1990,7 @@ mod tests {
             reference,
             NtpPacket::deserialize(packet, &NoCipher).unwrap().0
         );
-        match reference.serialize_without_encryption_vec() {
-            Ok(buf) => assert_eq!(packet[..], buf[..]),
-            Err(e) => panic!("{e:?}"),
-        }
+        assert_roundtrip(packet, &NoCipher);
     }
 
     #[test]
@@ -1020,10 +2019,306 @@ mod tests {
             reference,
             NtpPacket::deserialize(packet, &NoCipher).unwrap().0
         );
-        match reference.serialize_without_encryption_vec() {
-            Ok(buf) => assert_eq!(packet[..], buf[..]),
-            Err(e) => panic!("{e:?}"),
+        assert_roundtrip(packet, &NoCipher);
+    }
+
+    #[test]
+    fn test_roundtrip_fixtures() {
+        let header = b"\x23\x02\x06\xe8\x00\x00\x03\xff\x00\x00\x03\x7d\x5e\xc6\x9f\x0f\xe5\xf6\x62\x98\x7b\x61\xb9\xaf\xe5\xf6\x63\x66\x7b\x64\x99\x5d\xe5\xf6\x63\x66\x81\x40\x55\x90\xe5\xf6\x63\xa8\x76\x1d\xde\x48";
+
+        // a MAC at the end of the packet
+        let mut data = header.to_vec();
+        data.extend([0, 0, 0, 1]);
+        data.extend([0xaa; 16]);
+        assert_roundtrip(&data, &NoCipher);
+
+        // the same for NTPv3
+        data[0] = 0x1b;
+        assert_roundtrip(&data, &NoCipher);
+
+        // unknown extension fields, the last one padded to 28 bytes
+        let mut data = header.to_vec();
+        data.extend([0x77, 0x77, 0, 16]);
+        data.extend([1; 12]);
+        data.extend([0x77, 0x78, 0, 28]);
+        data.extend([2; 17]);
+        data.extend([0; 7]);
+        assert_roundtrip(&data, &NoCipher);
+
+        // and followed by a MAC
+        data.extend([0, 0, 0, 1]);
+        data.extend([0xaa; 20]);
+        assert_roundtrip(&data, &NoCipher);
+
+        // NTS requests and responses
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let cipher = AesSivCmac256::new((0..32).collect());
+        let serialize = |packet: &NtpPacket| {
+            let mut buf = [0u8; 4096];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet
+                .serialize_with_rng(&mut cursor, &cipher, &mut fixed_nonce_rng())
+                .unwrap();
+            let used = cursor.position() as usize;
+            buf[..used].to_vec()
+        };
+
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let keyset = KeySetProvider::new(1).get();
+        let cookie = keyset.encode_cookie(&decoded);
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(1),
+        };
+
+        for new_cookies in [1, 3] {
+            let (request, _) = NtpPacket::nts_poll_message_with_rng(
+                &cookie,
+                new_cookies,
+                PollIntervalLimits::default().min,
+                &mut StdRng::seed_from_u64(1),
+            );
+            assert_roundtrip(&serialize(&request), &cipher);
+
+            let response = NtpPacket::nts_timestamp_response(
+                &SystemSnapshot::default(),
+                request.clone(),
+                NtpTimestamp::from_fixed_int(0),
+                &clock,
+                &decoded,
+                &keyset,
+            );
+            assert_roundtrip(&serialize(&response), &cipher);
+
+            let response = NtpPacket::nts_deny_response(request.clone());
+            assert_roundtrip(&serialize(&response), &cipher);
+
+            let response = NtpPacket::nts_rate_limit_response(request);
+            assert_roundtrip(&serialize(&response), &cipher);
+        }
+
+        let (probe, _) = NtpPacket::nts_probe_message(PollIntervalLimits::default().min);
+        assert_roundtrip(&serialize(&probe), &cipher);
+    }
+
+    #[test]
+    fn test_corrupted_nts_packets() {
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let serialize = |packet: &NtpPacket| {
+            let mut buf = [0u8; 4096];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, &cipher).unwrap();
+            let used = cursor.position() as usize;
+            buf[..used].to_vec()
+        };
+
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new([0; 32].into())),
+            c2s: Box::new(AesSivCmac256::new([0; 32].into())),
+        };
+        let keyset = KeySetProvider::new(1).get();
+        let (request, _) = NtpPacket::nts_poll_message(
+            &keyset.encode_cookie(&decoded),
+            2,
+            PollIntervalLimits::default().min,
+        );
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(1),
+        };
+        // the response carries a new cookie in its encrypted field
+        let response = NtpPacket::nts_timestamp_response(
+            &SystemSnapshot::default(),
+            request,
+            NtpTimestamp::from_fixed_int(0),
+            &clock,
+            &decoded,
+            &keyset,
+        );
+        let data = serialize(&response);
+        assert!(NtpPacket::deserialize(&data, &cipher).is_ok());
+
+        let (nonce, ciphertext) = encrypted_field_ranges(&data);
+        assert_eq!(nonce.len(), cipher.nonce_len());
+        assert!(ciphertext.len() > cipher.tag_len());
+
+        let corrupted = [
+            ("nonce", nonce.start),
+            ("tag", ciphertext.start),
+            ("ciphertext", ciphertext.end - 1),
+            // the header and the fields before the encrypted field are associated data
+            ("associated header", 40),
+            ("associated field", NtpHeaderV3V4::LENGTH + 4),
+        ];
+        for (name, index) in corrupted {
+            assert!(
+                matches!(
+                    NtpPacket::deserialize(&flip_bit(&data, index), &cipher),
+                    Err(ParsingError::DecryptError(_))
+                ),
+                "corrupted {name} was not rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_fit_nts_response() {
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let serialize = |packet: &NtpPacket| {
+            let mut buf = [0u8; 4096];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, &cipher).unwrap();
+            let used = cursor.position() as usize;
+            buf[..used].to_vec()
+        };
+
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new([0; 32].into())),
+            c2s: Box::new(AesSivCmac256::new([0; 32].into())),
+        };
+        let keyset = KeySetProvider::new(1).get();
+        let (request, _) = NtpPacket::nts_poll_message(
+            &keyset.encode_cookie(&decoded),
+            4,
+            PollIntervalLimits::default().min,
+        );
+        let request_size = serialize(&request).len();
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(1),
+        };
+        let response = NtpPacket::nts_timestamp_response(
+            &SystemSnapshot::default(),
+            request,
+            NtpTimestamp::from_fixed_int(0),
+            &clock,
+            &decoded,
+            &keyset,
+        );
+        // about the size of a cookie, keeping sizes 4 byte aligned
+        let cookie_size = serialize(&response).len() / 16 * 4;
+
+        let fit = |max_size: usize, pad: bool| {
+            let mut response = response.clone();
+            if !response.fit_nts_response(max_size, &cipher, pad) {
+                return None;
+            }
+            let data = serialize(&response);
+            assert!(data.len() <= max_size);
+            let parsed = NtpPacket::deserialize(&data, &cipher).unwrap().0;
+            Some((data.len(), parsed.new_cookies().count()))
+        };
+
+        // a response to a full request fits, with all its cookies
+        assert_eq!(
+            fit(request_size, false).map(|(_, cookies)| cookies),
+            Some(4)
+        );
+
+        // to a smaller size only by dropping cookies
+        let (size, cookies) = fit(request_size - cookie_size, false).unwrap();
+        assert!(cookies < 4);
+        assert!(size < request_size - cookie_size);
+
+        // with padding, the size doesn't depend on the number of cookies
+        assert_eq!(
+            fit(request_size - cookie_size, true),
+            Some((request_size - cookie_size, cookies))
+        );
+
+        // even without cookies a response doesn't fit in a bare header
+        assert_eq!(fit(NtpHeaderV3V4::LENGTH, false), None);
+    }
+
+    #[test]
+    fn test_nts_nonce() {
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let (packet, _) =
+            NtpPacket::nts_poll_message(&[0; 16], 1, PollIntervalLimits::default().min);
+        let serialize = |rng: Option<rand::rngs::StdRng>| {
+            let mut buf = [0u8; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            match rng {
+                Some(mut rng) => packet.serialize_with_rng(&mut cursor, &cipher, &mut rng),
+                None => packet.serialize(&mut cursor, &cipher),
+            }
+            .unwrap();
+            let used = cursor.position() as usize;
+            buf[..used].to_vec()
+        };
+
+        // every packet gets a fresh nonce
+        let first = serialize(None);
+        let second = serialize(None);
+        let (nonce, _) = encrypted_field_ranges(&first);
+        assert_ne!(first[nonce.clone()], second[nonce.clone()]);
+        assert!(NtpPacket::deserialize(&first, &cipher).is_ok());
+        assert!(NtpPacket::deserialize(&second, &cipher).is_ok());
+
+        // unless the rng is deterministic
+        let mut nonce_bytes = [0u8; 16];
+        fixed_nonce_rng().fill(&mut nonce_bytes);
+        let fixed = serialize(Some(fixed_nonce_rng()));
+        assert_eq!(fixed, serialize(Some(fixed_nonce_rng())));
+        assert_eq!(fixed[nonce], nonce_bytes);
+        assert!(NtpPacket::deserialize(&fixed, &cipher).is_ok());
+    }
+
+    #[test]
+    fn test_deserialize_with_scratch() {
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let allowed = [1; 32];
+        let packet = NtpPacket {
+            header: NtpHeader::V4(
+                NtpHeaderV3V4::poll_message(PollIntervalLimits::default().min, &mut thread_rng()).0,
+            ),
+            efdata: ExtensionFieldData {
+                authenticated: vec![ExtensionField::UniqueIdentifier(Cow::Borrowed(&[2; 32]))],
+                encrypted: vec![ExtensionField::NtsCookie(Cow::Borrowed(&allowed))],
+                untrusted: vec![],
+            },
+            mac: None,
+        };
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &cipher).unwrap();
+        let used = cursor.position() as usize;
+        let data = &buf[..used];
+
+        let mut scratch = vec![0; data.len()];
+        let scratch_range = scratch.as_ptr_range();
+        let (parsed, _) = NtpPacket::deserialize_with_scratch(data, &cipher, &mut scratch).unwrap();
+        assert_eq!(parsed, NtpPacket::deserialize(data, &cipher).unwrap().0);
+        assert_eq!(parsed.efdata, packet.efdata);
+
+        // the decrypted cookie borrows from the scratch buffer
+        match parsed.encrypted_fields().next() {
+            Some(ExtensionField::NtsCookie(Cow::Borrowed(cookie))) => {
+                assert!(scratch_range.contains(&cookie.as_ptr()));
+            }
+            other => panic!("expected a borrowed cookie, got {other:?}"),
         }
+
+        // a scratch buffer that is too small is not used
+        let mut scratch = [0; 8];
+        let (parsed, _) = NtpPacket::deserialize_with_scratch(data, &cipher, &mut scratch).unwrap();
+        assert_eq!(parsed.efdata, packet.efdata);
+        assert!(matches!(
+            parsed.encrypted_fields().next(),
+            Some(ExtensionField::NtsCookie(Cow::Owned(_)))
+        ));
+
+        // decryption failures are reported as usual
+        let other = AesSivCmac256::new([1; 32].into());
+        let mut scratch = vec![0; data.len()];
+        assert!(matches!(
+            NtpPacket::deserialize_with_scratch(data, &other, &mut scratch),
+            Err(ParsingError::DecryptError(_))
+        ));
     }
 
     #[test]
@@ -1034,8 +2329,9 @@ mod tests {
         assert!(NtpPacket::deserialize(packet, &NoCipher).is_err());
         let packet = b"\x14\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b";
         assert!(NtpPacket::deserialize(packet, &NoCipher).is_err());
+        // version 5 is the NTPv5 draft
         let packet = b"\x2B\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b";
-        assert!(NtpPacket::deserialize(packet, &NoCipher).is_err());
+        assert!(NtpPacket::deserialize(packet, &NoCipher).is_ok());
         let packet = b"\x34\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b";
         assert!(NtpPacket::deserialize(packet, &NoCipher).is_err());
         let packet = b"\x3B\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b";
@@ -1070,6 +2366,301 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deserialize_with_warnings() {
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let header = packet.serialize_without_encryption_vec().unwrap();
+
+        let warnings = |data: &[u8]| {
+            NtpPacket::deserialize_with_warnings(data, &NoCipher)
+                .unwrap()
+                .1
+        };
+
+        // a well-formed packet
+        assert_eq!(warnings(&header), vec![]);
+
+        // a reserved mode
+        let mut data = header.clone();
+        data[0] &= !0x07;
+        assert_eq!(
+            warnings(&data),
+            vec![ParseWarning::UnexpectedMode(NtpAssociationMode::Reserved)]
+        );
+
+        // an unknown field
+        let mut data = header.clone();
+        data.extend([0x77, 0x77, 0, 36]);
+        data.extend([0; 32]);
+        assert_eq!(
+            warnings(&data),
+            vec![ParseWarning::UnknownExtensionField { type_id: 0x7777 }]
+        );
+
+        // followed by a MAC
+        data.extend([0; 20]);
+        assert_eq!(
+            warnings(&data),
+            vec![
+                ParseWarning::UnknownExtensionField { type_id: 0x7777 },
+                ParseWarning::IgnoredMac,
+            ]
+        );
+
+        // a cookie that is not padded to the minimum size, followed by another field
+        let mut data = header;
+        data.extend([0x02, 0x04, 0, 12]);
+        data.extend([0; 8]);
+        data.extend([0x01, 0x04, 0, 36]);
+        data.extend([0; 32]);
+        assert_eq!(
+            warnings(&data),
+            vec![ParseWarning::UndersizedExtensionField {
+                type_id: 0x204,
+                length: 12
+            }]
+        );
+    }
+
+    #[test]
+    fn test_deserialize_with_trailing() {
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let header = packet.serialize_without_encryption_vec().unwrap();
+
+        let trailing = |data: &[u8]| {
+            let (parsed, _, trailing) =
+                NtpPacket::deserialize_with_trailing(data, &NoCipher).unwrap();
+            assert_eq!(parsed.transmit_timestamp(), packet.transmit_timestamp());
+            (trailing.consumed, trailing.unexpected.to_vec())
+        };
+
+        // a well-formed packet, with a field and a MAC
+        let mut data = header.clone();
+        data.extend([0x77, 0x77, 0, 36]);
+        data.extend([0; 32]);
+        data.extend([0; 20]);
+        assert_eq!(trailing(&data), (data.len(), vec![]));
+
+        // too short for a MAC
+        let mut data = header.clone();
+        data.extend([1, 2]);
+        assert!(NtpPacket::deserialize(&data, &NoCipher).is_err());
+        assert_eq!(trailing(&data), (48, vec![1, 2]));
+
+        // a field, followed by data that is neither a field nor a MAC
+        let mut data = header.clone();
+        data.extend([0x77, 0x77, 0, 36]);
+        data.extend([0; 32]);
+        data.extend([0xFF; 30]);
+        assert!(NtpPacket::deserialize(&data, &NoCipher).is_err());
+        assert_eq!(trailing(&data), (84, vec![0xFF; 30]));
+
+        // NTPv3 has no fields
+        let mut data = header.clone();
+        data[0] = (data[0] & !0x38) | (3 << 3);
+        data.extend([0x77, 0x77, 0, 40]);
+        data.extend([0; 36]);
+        assert_eq!(trailing(&data).0, 48);
+
+        // a malformed header is still an error
+        let mut data = header;
+        data[0] |= 0x38;
+        assert!(matches!(
+            NtpPacket::deserialize_with_trailing(&data, &NoCipher),
+            Err(ParsingError::InvalidVersion(7))
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_context() {
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let header = packet.serialize_without_encryption_vec().unwrap();
+
+        let error = |data: &[u8]| {
+            NtpPacket::deserialize(data, &NoCipher)
+                .unwrap_err()
+                .into_owned()
+        };
+
+        // a field claiming to be longer than the packet, after a valid one
+        let mut data = header.clone();
+        data.extend([0x77, 0x77, 0, 36]);
+        data.extend([0; 32]);
+        data.extend([0x20, 0x05, 0, 64]);
+        data.extend([0; 36]);
+        let e = error(&data);
+        assert_eq!(
+            e.context(),
+            Some((84, PacketField::ExtensionField { type_id: 0x2005 }))
+        );
+        assert_eq!(
+            e.to_string(),
+            "Bad length of extension field of type 0x2005 at offset 84"
+        );
+
+        // an encrypted field with a nonce of the wrong length
+        let mut data = header.clone();
+        data.extend([0x04, 0x04, 0, 40]);
+        data.extend([0, 12, 0, 16]);
+        data.extend([0; 32]);
+        assert_eq!(
+            error(&data).context(),
+            Some((48, PacketField::ExtensionField { type_id: 0x404 }))
+        );
+
+        // too short for a MAC
+        let mut data = header.clone();
+        data.extend([1, 2]);
+        let e = error(&data);
+        assert_eq!(e.context(), Some((48, PacketField::Mac)));
+        assert_eq!(e.to_string(), "Bad length of MAC at offset 48");
+
+        // a truncated header has no field context
+        assert!(matches!(
+            error(&header[..40]),
+            ParsingError::IncorrectLength
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_received_length() {
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let data = packet.serialize_without_encryption_vec().unwrap();
+
+        // a datagram received into a larger buffer
+        let mut buf = [0u8; 1024];
+        buf[..data.len()].copy_from_slice(&data);
+        let size = data.len();
+
+        // only the received bytes make up the packet
+        let parsed = NtpPacket::deserialize(&buf[..size], &NoCipher).unwrap().0;
+        assert_eq!(parsed, packet);
+
+        // the remainder of the buffer is not part of the packet
+        assert!(NtpPacket::deserialize(&buf, &NoCipher).map_or(true, |(p, _)| p != packet));
+
+        // a short datagram errors cleanly, even though the buffer is large enough
+        let mut buf = [0u8; 1024];
+        buf[..20].copy_from_slice(&data[..20]);
+        assert!(matches!(
+            NtpPacket::deserialize(&buf[..20], &NoCipher),
+            Err(ParsingError::IncorrectLength)
+        ));
+    }
+
+    #[test]
+    fn test_leap_in_progress() {
+        // 2017-01-01T00:00:00Z
+        let leap = 3_692_217_600;
+        let mut packet = NtpPacket::test();
+
+        packet.set_leap(NtpLeapIndicator::Leap61);
+        packet.set_transmit_timestamp(NtpTimestamp::from_seconds_nanos_since_ntp_era(
+            leap - 1,
+            500_000_000,
+        ));
+        assert!(packet.leap_in_progress());
+        packet.set_transmit_timestamp(NtpTimestamp::from_seconds_nanos_since_ntp_era(
+            leap,
+            500_000_000,
+        ));
+        assert!(packet.leap_in_progress());
+        packet.set_transmit_timestamp(NtpTimestamp::from_seconds_nanos_since_ntp_era(leap + 1, 0));
+        assert!(!packet.leap_in_progress());
+
+        // without an announcement there is no leap second
+        packet.set_leap(NtpLeapIndicator::NoWarning);
+        packet.set_transmit_timestamp(NtpTimestamp::from_seconds_nanos_since_ntp_era(leap, 0));
+        assert!(!packet.leap_in_progress());
+    }
+
+    #[test]
+    fn test_poll_message_with_rng() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let poll_interval = PollIntervalLimits::default().min;
+        let (a, id_a) =
+            NtpPacket::poll_message_with_rng(poll_interval, &mut StdRng::seed_from_u64(1));
+        let (b, id_b) =
+            NtpPacket::poll_message_with_rng(poll_interval, &mut StdRng::seed_from_u64(1));
+        assert_eq!(a.transmit_timestamp(), b.transmit_timestamp());
+        assert_eq!(id_a, id_b);
+
+        let (c, _) = NtpPacket::poll_message_with_rng(poll_interval, &mut StdRng::seed_from_u64(2));
+        assert_ne!(a.transmit_timestamp(), c.transmit_timestamp());
+
+        let cookie = [0; 16];
+        let (a, id_a) = NtpPacket::nts_poll_message_with_rng(
+            &cookie,
+            1,
+            poll_interval,
+            &mut StdRng::seed_from_u64(1),
+        );
+        let (b, id_b) = NtpPacket::nts_poll_message_with_rng(
+            &cookie,
+            1,
+            poll_interval,
+            &mut StdRng::seed_from_u64(1),
+        );
+        assert_eq!(a, b);
+        assert_eq!(id_a, id_b);
+    }
+
+    #[test]
+    fn test_nts_poll_message_with_prefix() {
+        let cookie = [0; 16];
+        let poll_interval = PollIntervalLimits::default().min;
+        let prefix = UniqueIdentifierPrefix::from_instance_id(b"client.example.com");
+        assert_eq!(
+            prefix,
+            UniqueIdentifierPrefix::from_instance_id(b"client.example.com")
+        );
+        assert_ne!(
+            prefix,
+            UniqueIdentifierPrefix::from_instance_id(b"other.example.com")
+        );
+
+        let uid = |packet: &NtpPacket| {
+            packet
+                .efdata
+                .authenticated
+                .iter()
+                .find_map(|ef| match ef {
+                    ExtensionField::UniqueIdentifier(uid) => Some(uid.to_vec()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let (a, id_a) = NtpPacket::nts_poll_message_with_prefix(&cookie, 1, poll_interval, &prefix);
+        let (b, id_b) = NtpPacket::nts_poll_message_with_prefix(&cookie, 1, poll_interval, &prefix);
+
+        // the prefix is stable, but every request still gets its own identifier
+        assert_eq!(
+            &uid(&a)[..UniqueIdentifierPrefix::LENGTH],
+            prefix.as_bytes()
+        );
+        assert_eq!(
+            &uid(&b)[..UniqueIdentifierPrefix::LENGTH],
+            prefix.as_bytes()
+        );
+        assert_ne!(uid(&a), uid(&b));
+        assert_ne!(id_a, id_b);
+        assert_eq!(id_a.uid.unwrap().as_slice(), uid(&a));
+    }
+
+    #[test]
+    fn test_poll_message_mode() {
+        // we must never send packets with the reserved mode
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        assert_eq!(packet.mode(), NtpAssociationMode::Client);
+
+        let cookie = [0; 16];
+        let (packet, _) =
+            NtpPacket::nts_poll_message(&cookie, 1, PollIntervalLimits::default().min);
+        assert_eq!(packet.mode(), NtpAssociationMode::Client);
+    }
+
     #[test]
     fn test_nts_poll_message() {
         let cookie = [0; 16];
@@ -1140,6 +2731,76 @@ mod tests {
         assert_eq!(nplaceholders, 2);
     }
 
+    #[test]
+    fn test_extension_field_type_ids() {
+        let cookie = [0xcc; 64];
+        let (packet, _) =
+            NtpPacket::nts_poll_message(&cookie, 2, PollIntervalLimits::default().min);
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &cipher).unwrap();
+        let used = cursor.position() as usize;
+
+        // unique identifier, cookie, a placeholder for the second cookie, and the encrypted field
+        assert_eq!(
+            NtpPacket::extension_field_type_ids(&buf[..used]),
+            vec![0x104, 0x204, 0x304, 0x404]
+        );
+
+        // unknown fields are listed as well, a MAC is not
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut data = packet.serialize_without_encryption_vec().unwrap();
+        assert!(NtpPacket::extension_field_type_ids(&data).is_empty());
+        data.extend([0x77, 0x77, 0, 28]);
+        data.extend([0; 24]);
+        data.extend([0, 0, 0, 1]);
+        data.extend([0xaa; 16]);
+        assert_eq!(NtpPacket::extension_field_type_ids(&data), vec![0x7777]);
+
+        // scanning stops at a malformed field
+        let mut truncated = buf[..used].to_vec();
+        truncated[48 + 2..48 + 4].copy_from_slice(&1000u16.to_be_bytes());
+        assert!(NtpPacket::extension_field_type_ids(&truncated).is_empty());
+
+        // NTPv3 has no extension fields
+        data[0] = (data[0] & !0b0011_1000) | (3 << 3);
+        assert!(NtpPacket::extension_field_type_ids(&data).is_empty());
+        assert!(NtpPacket::extension_field_type_ids(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_nts_probe_message() {
+        let (packet, id) = NtpPacket::nts_probe_message(PollIntervalLimits::default().min);
+        assert_eq!(packet.mode(), NtpAssociationMode::Client);
+        assert_eq!(
+            packet.efdata.authenticated,
+            vec![ExtensionField::UniqueIdentifier(Cow::Borrowed(
+                id.uid.as_ref().unwrap()
+            ))]
+        );
+        assert!(packet.efdata.encrypted.is_empty());
+
+        // the request carries an empty encrypted field, but no cookie
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &cipher).unwrap();
+        let used = cursor.position() as usize;
+        assert_eq!(used, 48 + 36 + nts_encrypted_overhead(0, &cipher));
+
+        let (parsed, _) = NtpPacket::deserialize(&buf[..used], &cipher).unwrap();
+        assert_eq!(parsed.efdata.authenticated, packet.efdata.authenticated);
+        assert!(parsed.efdata.encrypted.is_empty());
+
+        // so a server can't decrypt it
+        let provider = KeySetProvider::new(1);
+        assert!(matches!(
+            NtpPacket::server_unseal_and_verify(&buf[..used], &provider.get()),
+            Err(ParsingError::DecryptError(_))
+        ));
+    }
+
     #[test]
     fn test_nts_response_validation() {
         let cookie = [0; 16];
@@ -1196,6 +2857,126 @@ mod tests {
         assert!(!response.valid_server_response(id, true));
     }
 
+    #[test]
+    fn test_timestamp_response_reference_id() {
+        let upstream = |addr: &str| crate::PeerSnapshot {
+            peer_id: ReferenceId::from_ip(addr.parse().unwrap()),
+            our_id: ReferenceId::NONE,
+            poll_interval: PollIntervalLimits::default().min,
+            reach: Default::default(),
+            stratum: 1,
+            reference_id: ReferenceId::from_bytes(*b"GPS\0"),
+            frozen: false,
+            bloom_filter: None,
+            last_kiss: None,
+            kiss_count: 0,
+        };
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(1),
+        };
+        let mut system = SystemSnapshot::default();
+
+        system.update_used_peers(std::iter::once(upstream("192.0.2.1")));
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(0),
+            &clock,
+        );
+        assert_eq!(response.stratum(), 2);
+        assert_eq!(
+            response.reference_id(),
+            ReferenceId::from_ip("192.0.2.1".parse().unwrap())
+        );
+
+        // selecting a different upstream changes what we advertise
+        system.update_used_peers(std::iter::once(upstream("2001:db8::1")));
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(0),
+            &clock,
+        );
+        assert_eq!(
+            response.reference_id(),
+            ReferenceId::from_ip("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_timestamp_response_reference_timestamp() {
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(2 << 32),
+        };
+        let mut system = SystemSnapshot::default();
+
+        // we never updated our clock
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(1 << 32),
+            &clock,
+        );
+        assert_eq!(response.reference_timestamp(), NtpTimestamp::default());
+
+        system.update_reference_timestamp(&clock).unwrap();
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(3 << 32),
+            &clock,
+        );
+        assert_eq!(
+            response.reference_timestamp(),
+            NtpTimestamp::from_fixed_int(2 << 32)
+        );
+
+        // the upgrade signal takes precedence
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request.into_upgrade_request(),
+            NtpTimestamp::from_fixed_int(3 << 32),
+            &clock,
+        );
+        assert!(response.offers_v5_upgrade());
+    }
+
+    #[test]
+    fn test_symmetric_timestamp_response() {
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(1),
+        };
+        for (mode, response_mode) in [
+            (NtpAssociationMode::Client, NtpAssociationMode::Server),
+            (
+                NtpAssociationMode::SymmetricActive,
+                NtpAssociationMode::SymmetricPassive,
+            ),
+            (
+                NtpAssociationMode::SymmetricPassive,
+                NtpAssociationMode::SymmetricActive,
+            ),
+        ] {
+            let (request, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let request = request.into_symmetric_request(mode);
+            assert_eq!(request.mode(), mode);
+
+            let response = NtpPacket::timestamp_response(
+                &SystemSnapshot::default(),
+                request,
+                NtpTimestamp::from_fixed_int(0),
+                &clock,
+            );
+            assert_eq!(response.mode(), response_mode);
+            assert!(response.valid_server_response(id, false));
+        }
+    }
+
     #[test]
     fn test_timestamp_response() {
         let decoded = DecodedServerCookie {
@@ -1382,6 +3163,61 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_echo_fields() {
+        let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let diagnostic: Vec<u8> = (0..32).collect();
+        packet.efdata.untrusted = vec![
+            ExtensionField::Unknown {
+                type_id: 0x7777,
+                data: Cow::Borrowed(&diagnostic),
+            },
+            ExtensionField::Unknown {
+                type_id: 0x7778,
+                data: Cow::Borrowed(&[5, 6, 7, 8]),
+            },
+            ExtensionField::UniqueIdentifier(Cow::Borrowed(&[0; 32])),
+        ];
+
+        // only opted-in unknown fields are echoed
+        assert!(packet.echo_fields(&[]).is_empty());
+        let echo = packet.echo_fields(&[0x7777, 0x0104]);
+        assert_eq!(
+            echo,
+            [ExtensionField::Unknown {
+                type_id: 0x7777,
+                data: Cow::Borrowed(&diagnostic),
+            }]
+        );
+
+        let mut response = NtpPacket::timestamp_response(
+            &SystemSnapshot::default(),
+            packet,
+            NtpTimestamp::from_fixed_int(0),
+            &TestClock {
+                now: NtpTimestamp::from_fixed_int(1),
+            },
+        );
+        response.add_echo_fields(echo);
+
+        let data = response.serialize_without_encryption_vec().unwrap();
+        let (response, _) = NtpPacket::deserialize(&data, &NoCipher).unwrap();
+        assert!(response
+            .efdata
+            .untrusted
+            .contains(&ExtensionField::Unknown {
+                type_id: 0x7777,
+                data: Cow::Borrowed(&diagnostic),
+            }));
+        assert!(!response.efdata.untrusted.iter().any(|ef| matches!(
+            ef,
+            ExtensionField::Unknown {
+                type_id: 0x7778,
+                ..
+            }
+        )));
+    }
+
     #[test]
     fn test_timestamp_cookies() {
         let decoded = DecodedServerCookie {
@@ -1449,6 +3285,280 @@ mod tests {
         assert_eq!(response.new_cookies().count(), 4);
     }
 
+    #[test]
+    fn test_nts_response_size() {
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let keysetprovider = KeySetProvider::new(1);
+        let cookie = keysetprovider.get().encode_cookie(&decoded);
+
+        // the header plus the 32 byte unique identifier, padded to the minimum of 16 bytes
+        let base_fields = 48 + 36;
+
+        for cookie_count in 1..8 {
+            let (packet, _) = NtpPacket::nts_poll_message(
+                &cookie,
+                cookie_count,
+                PollIntervalLimits::default().min,
+            );
+            let response = NtpPacket::nts_timestamp_response(
+                &SystemSnapshot::default(),
+                packet,
+                NtpTimestamp::from_fixed_int(0),
+                &TestClock {
+                    now: NtpTimestamp::from_fixed_int(1),
+                },
+                &decoded,
+                &keysetprovider.get(),
+            );
+            assert_eq!(response.new_cookies().count(), cookie_count as usize);
+            let cookie_len = response.new_cookies().next().unwrap().len();
+
+            let mut buf = [0u8; 2048];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            response
+                .serialize(&mut cursor, decoded.s2c.as_ref())
+                .unwrap();
+
+            assert_eq!(
+                nts_response_size(base_fields, cookie_len, cookie_count as usize),
+                cursor.position() as usize
+            );
+        }
+    }
+
+    #[test]
+    fn test_nts_response_size_mtu() {
+        // the IPv6 minimum MTU, minus the IPv6 and UDP headers
+        let budget = 1280 - 40 - 8;
+        let base_fields = 48 + 36;
+
+        let max_cookies = |cookie_len| {
+            (0..)
+                .take_while(|count| nts_response_size(base_fields, cookie_len, *count) <= budget)
+                .last()
+                .unwrap()
+        };
+
+        // 100 byte cookies take up 104 bytes each
+        assert_eq!(nts_response_size(base_fields, 100, 0), base_fields + 40);
+        assert_eq!(nts_response_size(base_fields, 100, 1), base_fields + 144);
+        assert_eq!(nts_response_size(base_fields, 100, 8), base_fields + 872);
+        assert_eq!(max_cookies(100), 10);
+
+        // cookies that need padding
+        assert_eq!(nts_response_size(base_fields, 97, 2), base_fields + 248);
+        assert_eq!(max_cookies(97), 10);
+
+        assert_eq!(max_cookies(200), 5);
+        assert_eq!(max_cookies(1200), 0);
+    }
+
+    #[test]
+    fn test_wire_size() {
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let check = |packet: &NtpPacket, cipher: &dyn Cipher| {
+            let mut buf = [0u8; 4096];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize(&mut cursor, cipher).unwrap();
+            assert_eq!(packet.wire_size(cipher), cursor.position() as usize);
+
+            // a buffer of exactly that size suffices
+            let mut buf = vec![0u8; packet.wire_size(cipher)];
+            packet
+                .serialize(&mut Cursor::new(buf.as_mut_slice()), cipher)
+                .unwrap();
+        };
+
+        let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        assert_eq!(packet.wire_size(&NoCipher), 48);
+        check(&packet, &cipher);
+
+        packet.add_echo_fields(vec![
+            ExtensionField::Unknown {
+                type_id: 0xF000,
+                data: Cow::Borrowed(&[1; 3]),
+            },
+            ExtensionField::Unknown {
+                type_id: 0xF001,
+                data: Cow::Borrowed(&[1; 5]),
+            },
+        ]);
+        assert_eq!(packet.wire_size(&NoCipher), 48 + 16 + 28);
+        check(&packet, &cipher);
+
+        let key = crate::SymmetricKey::new(crate::MacAlgorithm::Sha1, b"secret".to_vec()).unwrap();
+        packet.add_mac(1, &key).unwrap();
+        assert_eq!(packet.wire_size(&NoCipher), 48 + 16 + 28 + 24);
+        check(&packet, &cipher);
+
+        for cookies in [1, 3] {
+            let (packet, _) =
+                NtpPacket::nts_poll_message(&[0; 100], cookies, PollIntervalLimits::default().min);
+            check(&packet, &cipher);
+
+            let decoded = DecodedServerCookie {
+                algorithm: AeadAlgorithm::AeadAesSivCmac512,
+                s2c: Box::new(AesSivCmac512::new([0; 64].into())),
+                c2s: Box::new(AesSivCmac512::new([0; 64].into())),
+            };
+            let response = NtpPacket::nts_timestamp_response(
+                &SystemSnapshot::default(),
+                packet,
+                NtpTimestamp::from_fixed_int(0),
+                &TestClock {
+                    now: NtpTimestamp::from_fixed_int(1),
+                },
+                &decoded,
+                &KeySetProvider::new(1).get(),
+            );
+            check(&response, decoded.s2c.as_ref());
+        }
+    }
+
+    #[test]
+    fn test_nts_encrypted_overhead() {
+        let ciphers: [Box<dyn Cipher>; 2] = [
+            Box::new(AesSivCmac256::new([0; 32].into())),
+            Box::new(AesSivCmac512::new([0; 64].into())),
+        ];
+
+        for cipher in &ciphers {
+            let cipher = cipher.as_ref();
+
+            // 8 bytes of headers, a 16 byte nonce and 16 byte tag, plus padding
+            assert_eq!(nts_encrypted_overhead(0, cipher), 40);
+            assert_eq!(nts_encrypted_overhead(1, cipher), 43);
+            assert_eq!(nts_encrypted_overhead(3, cipher), 41);
+            assert_eq!(nts_encrypted_overhead(4, cipher), 40);
+            assert_eq!(nts_encrypted_overhead(97, cipher), 43);
+            assert_eq!(nts_encrypted_overhead(100, cipher), 40);
+
+            // the size of a serialized packet follows from it
+            let identifier: Vec<_> = (0..32).collect();
+            for length in [0, 4, 30, 100] {
+                let packet = NtpPacket {
+                    header: NtpHeader::V4(NtpHeaderV3V4::new()),
+                    efdata: ExtensionFieldData {
+                        authenticated: vec![ExtensionField::UniqueIdentifier(Cow::Borrowed(
+                            &identifier,
+                        ))],
+                        encrypted: vec![ExtensionField::NtsCookie(vec![1; length].into())],
+                        untrusted: vec![],
+                    },
+                    mac: None,
+                };
+
+                let mut buf = [0u8; 1024];
+                let mut cursor = Cursor::new(buf.as_mut_slice());
+                packet.serialize(&mut cursor, cipher).unwrap();
+
+                let plaintext_len = ExtensionField::framed_len(length, 0);
+                assert_eq!(
+                    cursor.position() as usize,
+                    48 + 36 + plaintext_len + nts_encrypted_overhead(plaintext_len, cipher)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_server_unseal_and_verify() {
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let keysetprovider = KeySetProvider::new(1);
+        let keyset = keysetprovider.get();
+        let cookie = keyset.encode_cookie(&decoded);
+
+        // client side: build and seal the request
+        let (packet, id) =
+            NtpPacket::nts_poll_message(&cookie, 2, PollIntervalLimits::default().min);
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, decoded.c2s.as_ref()).unwrap();
+        let length = cursor.position() as usize;
+        let data = &buf[..length];
+
+        // server side: unseal the cookie and verify the request
+        let (request, session_keys) = NtpPacket::server_unseal_and_verify(data, &keyset).unwrap();
+        assert_eq!(session_keys.algorithm, decoded.algorithm);
+        assert_eq!(session_keys.s2c.key_bytes(), decoded.s2c.key_bytes());
+        assert_eq!(session_keys.c2s.key_bytes(), decoded.c2s.key_bytes());
+        assert!(request
+            .efdata
+            .authenticated
+            .contains(&ExtensionField::UniqueIdentifier(Cow::Borrowed(
+                id.uid.as_ref().unwrap()
+            ))));
+
+        // any modification of the request invalidates it
+        let mut tampered = data.to_vec();
+        tampered[length - 1] ^= 1;
+        assert!(matches!(
+            NtpPacket::server_unseal_and_verify(&tampered, &keyset),
+            Err(ParsingError::DecryptError(_))
+        ));
+
+        // a cookie from another keyset cannot be unsealed
+        let other_keyset = KeySetProvider::new(1).get();
+        assert!(matches!(
+            NtpPacket::server_unseal_and_verify(data, &other_keyset),
+            Err(ParsingError::DecryptError(_))
+        ));
+
+        // a plain request has no session keys
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        assert!(matches!(
+            NtpPacket::server_unseal_and_verify(&data, &keyset),
+            Err(ParsingError::DecryptError(_))
+        ));
+    }
+
+    #[test]
+    fn test_nts_nak_response() {
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let cookie = KeySetProvider::new(1).get().encode_cookie(&decoded);
+
+        let (packet, id) =
+            NtpPacket::nts_poll_message(&cookie, 2, PollIntervalLimits::default().min);
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, decoded.c2s.as_ref()).unwrap();
+        let length = cursor.position() as usize;
+
+        // a server that rotated out the key of the cookie can't unseal it
+        let keyset = KeySetProvider::new(1).get();
+        let request = match NtpPacket::server_unseal_and_verify(&buf[..length], &keyset) {
+            Err(ParsingError::DecryptError(request)) => request,
+            _ => panic!("cookie should not be valid"),
+        };
+
+        let response = NtpPacket::nts_nak_response(request);
+        let data = response.serialize_without_encryption_vec().unwrap();
+        assert!(data.len() <= length);
+
+        let response = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert!(response.is_kiss_ntsn());
+        assert_eq!(response.mode(), NtpAssociationMode::Server);
+        assert!(!response.is_authenticated());
+        // the client accepts the unauthenticated NAK through its unique identifier
+        assert!(response.valid_server_response(id, true));
+
+        let (_, other) = NtpPacket::nts_poll_message(&cookie, 2, PollIntervalLimits::default().min);
+        assert!(!response.valid_server_response(other, true));
+    }
+
     #[test]
     fn test_deny_response() {
         let decoded = DecodedServerCookie {
@@ -1714,7 +3824,9 @@ mod tests {
         let allowed: [u8; 16] = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         let disallowed: [u8; 16] = [2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         let packet = NtpPacket {
-            header: NtpHeader::V4(NtpHeaderV3V4::poll_message(PollIntervalLimits::default().min).0),
+            header: NtpHeader::V4(
+                NtpHeaderV3V4::poll_message(PollIntervalLimits::default().min, &mut thread_rng()).0,
+            ),
             efdata: ExtensionFieldData {
                 authenticated: vec![ExtensionField::NtsCookie(Cow::Borrowed(&disallowed))],
                 encrypted: vec![ExtensionField::NtsCookie(Cow::Borrowed(&allowed))],
@@ -1729,6 +3841,89 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_extension_field_accessors() {
+        let cookie = [1; 16];
+        let (packet, id) =
+            NtpPacket::nts_poll_message(&cookie, 0, PollIntervalLimits::default().min);
+        let mut packet = packet.into_owned();
+        packet
+            .efdata
+            .encrypted
+            .push(ExtensionField::NtsCookie(Cow::Borrowed(&[3; 16])));
+
+        let cipher = AesSivCmac256::new([0; 32].into());
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &cipher).unwrap();
+        let used = cursor.position() as usize;
+        let parsed = NtpPacket::deserialize(&buf[..used], &cipher).unwrap().0;
+
+        assert_eq!(
+            parsed.unique_identifier(),
+            id.uid.as_ref().map(|uid| &uid[..])
+        );
+        assert!(parsed
+            .authenticated_fields()
+            .any(|ef| matches!(ef, ExtensionField::NtsCookie(c) if c.as_ref() == cookie)));
+        assert!(parsed
+            .encrypted_fields()
+            .all(|ef| matches!(ef, ExtensionField::NtsCookie(c) if c.as_ref() == [3; 16])));
+        assert_eq!(parsed.encrypted_fields().count(), 1);
+        assert_eq!(parsed.untrusted_fields().count(), 0);
+
+        // without NTS, all extension fields are untrusted
+        let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.add_echo_fields(vec![ExtensionField::Unknown {
+            type_id: 0xF000,
+            data: Cow::Borrowed(&[2; 24]),
+        }]);
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let parsed = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(parsed.authenticated_fields().count(), 0);
+        assert!(parsed.untrusted_fields().any(|ef| matches!(
+            ef,
+            ExtensionField::Unknown { type_id: 0xF000, data } if data.as_ref() == [2; 24]
+        )));
+        assert_eq!(parsed.unique_identifier(), None);
+    }
+
+    fn serialize_plain(packet: &NtpPacket) -> Vec<u8> {
+        let mut buf = [0u8; 4096 * 4];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &NoCipher).unwrap();
+        let used = cursor.position() as usize;
+        buf[..used].to_vec()
+    }
+
+    /// Parsing any input must not panic, and whatever parses must survive a
+    /// serialize/deserialize roundtrip. Inputs found by fuzzing can be added to
+    /// the corpus directory to keep them covered by the regular tests.
+    #[test]
+    fn test_packet_corpus() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/packet-corpus");
+
+        let mut count = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+
+            let result = std::panic::catch_unwind(|| {
+                if let Ok((packet, _)) = NtpPacket::deserialize(&data, &NoCipher) {
+                    let serialized = serialize_plain(&packet);
+                    let reparsed = NtpPacket::deserialize(&serialized, &NoCipher).unwrap().0;
+                    assert_eq!(packet, reparsed);
+                    assert_eq!(serialized, serialize_plain(&reparsed));
+                }
+            });
+            assert!(result.is_ok(), "failure on corpus entry {}", path.display());
+
+            count += 1;
+        }
+
+        assert!(count > 0, "no corpus entries found in {}", dir.display());
+    }
+
     #[test]
     fn test_undersized_ef_in_encrypted_data() {
         let cipher = AesSivCmac256::new([0_u8; 32].into());
@@ -1776,4 +3971,469 @@ mod tests {
         ];
         assert!(NtpPacket::deserialize(&input, &NoCipher).is_err());
     }
+
+    #[test]
+    fn test_v5_roundtrip() {
+        let data: [u8; 48] = [
+            0x6c, 2, 6, 0xec, // leap, version, mode, stratum, poll and precision
+            1, 0, 0, 0, // timescale, era and flags
+            0, 0x80, 0, 0, // root delay
+            0x10, 0, 0, 0, // root dispersion
+            1, 2, 3, 4, 5, 6, 7, 8, // server cookie
+            9, 10, 11, 12, 13, 14, 15, 16, // client cookie
+            0xe7, 0, 0, 0, 0, 0, 0, 1, // receive timestamp
+            0xe7, 0, 0, 0, 0, 0, 0, 2, // transmit timestamp
+        ];
+
+        let packet = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(packet.version(), 5);
+        assert_eq!(packet.leap(), NtpLeapIndicator::Leap61);
+        assert_eq!(packet.mode(), NtpAssociationMode::Server);
+        assert_eq!(packet.stratum(), 2);
+        assert_eq!(packet.precision(), -20);
+        assert_eq!(packet.timescale(), Some(NtpTimescale::Tai));
+        assert_eq!(packet.era(), Some(0));
+        assert_eq!(packet.root_delay(), NtpDuration::from_fixed_int(1 << 27));
+        assert_eq!(packet.root_dispersion(), NtpDuration::ONE);
+        assert_eq!(
+            packet.origin_timestamp(),
+            NtpTimestamp::from_bits([9, 10, 11, 12, 13, 14, 15, 16])
+        );
+        assert_eq!(
+            packet.transmit_timestamp(),
+            NtpTimestamp::from_bits([0xe7, 0, 0, 0, 0, 0, 0, 2])
+        );
+        assert_eq!(packet.reference_id(), ReferenceId::NONE);
+        assert!(!packet.offers_v5_upgrade());
+
+        assert_eq!(packet.serialize_without_encryption_vec().unwrap(), data);
+    }
+
+    #[test]
+    fn test_v5_unknown_leap() {
+        let mut data = [0u8; 48];
+        data[0] = 0xec;
+        data[7] = 0x1;
+
+        let packet = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(packet.leap(), NtpLeapIndicator::Unknown);
+        assert_eq!(packet.serialize_without_encryption_vec().unwrap(), data);
+
+        // the flag overrides the leap indicator
+        data[0] = 0x6c;
+        let packet = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(packet.leap(), NtpLeapIndicator::Unknown);
+        assert_eq!(packet.serialize_without_encryption_vec().unwrap(), data);
+
+        // servers without a known leap indicator set the flag
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::timestamp_response(
+            &SystemSnapshot::default(),
+            request.into_v5_request(),
+            NtpTimestamp::from_fixed_int(1),
+            &TestClock {
+                now: NtpTimestamp::from_fixed_int(2),
+            },
+        );
+        let data = response.serialize_without_encryption_vec().unwrap();
+        assert_eq!(data[7] & 0x1, 0x1);
+        let response = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(response.leap(), NtpLeapIndicator::Unknown);
+    }
+
+    #[test]
+    fn test_v5_upgrade() {
+        let system = SystemSnapshot::default();
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(2),
+        };
+
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        assert!(!request.offers_v5_upgrade());
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(1),
+            &clock,
+        );
+        assert!(!response.offers_v5_upgrade());
+
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let data = request
+            .into_upgrade_request()
+            .serialize_without_encryption_vec()
+            .unwrap();
+        let request = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(request.version(), 4);
+        assert!(request.offers_v5_upgrade());
+
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(1),
+            &clock,
+        );
+        assert_eq!(response.version(), 4);
+        assert!(response.offers_v5_upgrade());
+    }
+
+    #[test]
+    fn test_v5_request_response() {
+        let system = SystemSnapshot::default();
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(2),
+        };
+
+        let (request, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let data = request
+            .into_v5_request()
+            .serialize_without_encryption_vec()
+            .unwrap();
+        assert_eq!(data.len(), 48);
+        assert_eq!((data[0] & 0b0011_1000) >> 3, 5);
+
+        let request = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(request.version(), 5);
+        assert_eq!(request.mode(), NtpAssociationMode::Client);
+        assert_eq!(request.timescale(), Some(NtpTimescale::Utc));
+        assert_eq!(request.request_identifier(), id);
+
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request.clone(),
+            NtpTimestamp::from_fixed_int(1),
+            &clock,
+        );
+        let data = response.serialize_without_encryption_vec().unwrap();
+        let response = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(response.version(), 5);
+        assert_eq!(response.mode(), NtpAssociationMode::Server);
+        assert_eq!(
+            response.receive_timestamp(),
+            NtpTimestamp::from_fixed_int(1)
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(2)
+        );
+        // a receive timestamp this close to zero lies after the 2036 rollover
+        assert_eq!(response.era(), Some(1));
+        assert!(response.valid_server_response(id, false));
+
+        let (_, other) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        assert!(!response.valid_server_response(other, false));
+
+        let mut nak = data.clone();
+        nak[7] = 0x4;
+        let nak = NtpPacket::deserialize(&nak, &NoCipher).unwrap().0;
+        assert!(nak.is_kiss_ntsn());
+        assert!(!response.is_kiss_ntsn());
+
+        // kiss codes fall back to NTPv4, as NTPv5 has none
+        let response = NtpPacket::deny_response(request.clone());
+        assert_eq!(response.version(), 4);
+        assert!(response.is_kiss_deny());
+        assert!(response.valid_server_response(id, false));
+
+        let response = NtpPacket::rate_limit_response(request);
+        assert_eq!(response.version(), 4);
+        assert!(response.is_kiss_rate());
+        assert!(response.valid_server_response(id, false));
+    }
+
+    #[test]
+    fn test_v5_reference_ids() {
+        let system = SystemSnapshot::default();
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(2),
+        };
+        let request_field = ExtensionField::ReferenceIdRequest {
+            offset: 128,
+            length: 128,
+        };
+
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let data = request
+            .into_v5_request()
+            .with_reference_id_request(request_field.clone())
+            .serialize_without_encryption_vec()
+            .unwrap();
+        let request = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(request.untrusted_fields().next(), Some(&request_field));
+
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(1),
+            &clock,
+        );
+        let response_data = response.serialize_without_encryption_vec().unwrap();
+        // the response is no larger than the request
+        assert_eq!(response_data.len(), data.len());
+        let response = NtpPacket::deserialize(&response_data, &NoCipher).unwrap().0;
+        assert_eq!(
+            response.reference_id_response(false),
+            Some(&system.bloom_filter.as_bytes()[128..256])
+        );
+        assert_eq!(response.reference_id_response(true), None);
+
+        // requests beyond the end of the filter are not answered
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let request = request.into_v5_request().with_reference_id_request(
+            ExtensionField::ReferenceIdRequest {
+                offset: 448,
+                length: 128,
+            },
+        );
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(1),
+            &clock,
+        );
+        assert_eq!(response.reference_id_response(false), None);
+
+        // and NTPv4 has reference ids instead
+        let (request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let request = request.with_reference_id_request(request_field);
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(1),
+            &clock,
+        );
+        assert_eq!(response.reference_id_response(false), None);
+    }
+
+    #[test]
+    fn test_interleaved_response() {
+        let system = SystemSnapshot::default();
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(4),
+        };
+        let previous = PreviousExchange {
+            receive_timestamp: NtpTimestamp::from_fixed_int(1),
+            transmit_timestamp: NtpTimestamp::from_fixed_int(2),
+        };
+
+        // a basic request is answered in basic mode, whatever happened before
+        let (request, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let response = NtpPacket::interleaved_timestamp_response(
+            &system,
+            request.clone(),
+            NtpTimestamp::from_fixed_int(3),
+            &clock,
+            Some(previous),
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(4)
+        );
+        assert!(response.valid_server_response(id, false));
+
+        // an interleaved request gets the transmit timestamp of the previous response
+        let (request, id) = request.into_interleaved_request(
+            id,
+            previous.receive_timestamp,
+            NtpTimestamp::from_fixed_int(5),
+        );
+        let data = request.serialize_without_encryption_vec().unwrap();
+        let request = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        let response = NtpPacket::interleaved_timestamp_response(
+            &system,
+            request.clone(),
+            NtpTimestamp::from_fixed_int(3),
+            &clock,
+            Some(previous),
+        );
+        assert_eq!(response.origin_timestamp(), NtpTimestamp::from_fixed_int(5));
+        assert_eq!(
+            response.receive_timestamp(),
+            NtpTimestamp::from_fixed_int(3)
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(2)
+        );
+        assert!(response.valid_server_response(id, false));
+
+        // unless the server does not remember the exchange it refers to
+        let response = NtpPacket::interleaved_timestamp_response(
+            &system,
+            request.clone(),
+            NtpTimestamp::from_fixed_int(3),
+            &clock,
+            None,
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(4)
+        );
+        assert!(response.valid_server_response(id, false));
+
+        let other = PreviousExchange {
+            receive_timestamp: NtpTimestamp::from_fixed_int(6),
+            ..previous
+        };
+        let response = NtpPacket::interleaved_timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(3),
+            &clock,
+            Some(other),
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(4)
+        );
+    }
+
+    #[test]
+    fn test_mac() {
+        use crate::{MacAlgorithm, SymmetricKey};
+
+        for algorithm in [MacAlgorithm::Md5, MacAlgorithm::Sha1, MacAlgorithm::Sha256] {
+            let key = SymmetricKey::new(algorithm, b"secret".to_vec()).unwrap();
+            let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            packet.add_mac(7, &key).unwrap();
+            let data = packet.serialize_without_encryption_vec().unwrap();
+            assert_eq!(data.len(), 48 + 4 + algorithm.digest_len());
+
+            let packet = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+            assert_eq!(packet.mac_keyid(), Some(7));
+            assert_eq!(packet.verify_mac(&data, 7, &key), Ok(()));
+            assert_eq!(
+                packet.verify_mac(&data, 8, &key),
+                Err(MacError::UnknownKey(7))
+            );
+            let other = SymmetricKey::new(algorithm, b"other".to_vec()).unwrap();
+            assert_eq!(packet.verify_mac(&data, 7, &other), Err(MacError::Mismatch));
+
+            // any change to the packet is detected
+            let mut tampered = data.clone();
+            tampered[40] ^= 1;
+            let packet = NtpPacket::deserialize(&tampered, &NoCipher).unwrap().0;
+            assert_eq!(
+                packet.verify_mac(&tampered, 7, &key),
+                Err(MacError::Mismatch)
+            );
+        }
+
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let key = SymmetricKey::new(MacAlgorithm::Md5, b"secret".to_vec()).unwrap();
+        assert_eq!(packet.mac_keyid(), None);
+        assert_eq!(packet.verify_mac(&data, 7, &key), Err(MacError::Missing));
+    }
+
+    #[test]
+    fn test_mac_digest_lengths() {
+        use crate::{MacAlgorithm, SymmetricKey};
+
+        for (algorithm, digest_len) in [
+            (MacAlgorithm::Md5, 16),
+            (MacAlgorithm::Sha1, 20),
+            (MacAlgorithm::Sha256, 32),
+        ] {
+            let key = SymmetricKey::new(algorithm, b"secret".to_vec()).unwrap();
+            // key id 36 would be a field of type 0 of 36 octets
+            for keyid in [7, 36] {
+                let v4 = NtpPacketBuilder::new()
+                    .untrusted_field(ExtensionField::UniqueIdentifier(Cow::Borrowed(&[1; 32])))
+                    .mac(keyid, &key);
+                for builder in [v4.clone(), v4.v3()] {
+                    let packet = builder.build().unwrap();
+                    let data = packet.serialize_without_encryption_vec().unwrap();
+                    assert_eq!(
+                        data[data.len() - digest_len - 4..][..4],
+                        keyid.to_be_bytes()
+                    );
+
+                    let parsed = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+                    assert_eq!(parsed, packet);
+                    assert_eq!(parsed.mac_keyid(), Some(keyid));
+                    assert_eq!(parsed.verify_mac(&data, keyid, &key), Ok(()));
+                }
+            }
+        }
+
+        // a last field of the length of a long MAC is still a field
+        let packet = NtpPacketBuilder::new()
+            .untrusted_field(ExtensionField::Unknown {
+                type_id: 0xF124,
+                data: Cow::Borrowed(&[1; 32]),
+            })
+            .build()
+            .unwrap();
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        assert_eq!(data.len(), 48 + 36);
+        let parsed = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(parsed, packet);
+        assert_eq!(parsed.mac_keyid(), None);
+
+        // but MACs longer than those of the supported algorithms are not
+        let mut data = data;
+        data[48..52].copy_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&[0; 4]);
+        assert!(NtpPacket::deserialize(&data, &NoCipher).is_err());
+    }
+
+    #[test]
+    fn test_crypto_nak() {
+        use crate::{MacAlgorithm, SymmetricKey};
+
+        let key = SymmetricKey::new(MacAlgorithm::Sha1, b"secret".to_vec()).unwrap();
+        let (mut request, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        request.add_mac(7, &key).unwrap();
+        assert!(!request.is_crypto_nak());
+
+        let response = NtpPacket::crypto_nak_response(request);
+        let data = response.serialize_without_encryption_vec().unwrap();
+        assert_eq!(data.len(), 48 + 4);
+        assert_eq!(data[48..], [0; 4]);
+
+        let response = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert!(response.is_crypto_nak());
+        assert!(response.valid_server_response(id, false));
+        assert_eq!(response.mode(), NtpAssociationMode::Server);
+        assert_eq!(response.stratum(), 16);
+        assert!(!response.is_kiss());
+        assert_eq!(
+            response.verify_mac(&data, 7, &key),
+            Err(MacError::UnknownKey(0))
+        );
+    }
+
+    #[test]
+    fn test_cmac_mac() {
+        use crate::{MacAlgorithm, SymmetricKey};
+
+        let key =
+            SymmetricKey::new(MacAlgorithm::Aes128Cmac, b"sixteen byte key".to_vec()).unwrap();
+        let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.add_mac(7, &key).unwrap();
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        assert_eq!(data.len(), 48 + 4 + 16);
+        // the MAC is the CMAC of the header
+        assert_eq!(data[52..], key.digest(&data[..48]));
+
+        let packet = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert_eq!(packet.mac_keyid(), Some(7));
+        assert_eq!(packet.verify_mac(&data, 7, &key), Ok(()));
+
+        // a legacy key with the same key material does not verify it
+        let md5 = SymmetricKey::new(MacAlgorithm::Md5, b"sixteen byte key".to_vec()).unwrap();
+        assert_eq!(packet.verify_mac(&data, 7, &md5), Err(MacError::Mismatch));
+        let other =
+            SymmetricKey::new(MacAlgorithm::Aes128Cmac, b"other16 byte key".to_vec()).unwrap();
+        assert_eq!(packet.verify_mac(&data, 7, &other), Err(MacError::Mismatch));
+
+        let mut tampered = data.clone();
+        tampered[40] ^= 1;
+        let packet = NtpPacket::deserialize(&tampered, &NoCipher).unwrap().0;
+        assert_eq!(
+            packet.verify_mac(&tampered, 7, &key),
+            Err(MacError::Mismatch)
+        );
+    }
 }
@@ -0,0 +1,199 @@
+use std::{any::Any, collections::HashMap, fmt::Debug, panic::RefUnwindSafe, sync::Arc};
+
+use super::extensionfields::{ExtensionField, ExtensionFieldData};
+
+/// An extension field type that is not built into this crate, such as an
+/// experimental or draft field. Fields of registered types are decoded into
+/// [`ExtensionField::Custom`] instead of [`ExtensionField::Unknown`].
+pub trait CustomExtensionField: Debug + Send + Sync + RefUnwindSafe + Any {
+    /// The extension field type this field is sent as
+    fn field_type(&self) -> u16;
+
+    /// The contents of the field on the wire, without the header and padding
+    fn encode(&self) -> Vec<u8>;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl dyn CustomExtensionField {
+    /// The field as its concrete type, if it is one
+    pub fn downcast_ref<T: CustomExtensionField>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+}
+
+// Two custom fields are the same when they are the same on the wire
+impl PartialEq for dyn CustomExtensionField {
+    fn eq(&self, other: &Self) -> bool {
+        self.field_type() == other.field_type() && self.encode() == other.encode()
+    }
+}
+
+impl Eq for dyn CustomExtensionField {}
+
+type Decoder = dyn Fn(&[u8]) -> Option<Arc<dyn CustomExtensionField>> + Send + Sync;
+
+/// The custom extension field types that should be decoded when deserializing
+/// packets with [`NtpPacket::deserialize_with_registry`](super::NtpPacket::deserialize_with_registry)
+#[derive(Clone, Default)]
+pub struct ExtensionFieldRegistry {
+    decoders: HashMap<u16, Arc<Decoder>>,
+}
+
+impl Debug for ExtensionFieldRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtensionFieldRegistry")
+            .field("types", &self.decoders.keys())
+            .finish()
+    }
+}
+
+impl ExtensionFieldRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode fields of type `field_type` with `decode`, replacing any decoder
+    /// registered earlier for that type. Fields for which `decode` returns `None`
+    /// are kept as [`ExtensionField::Unknown`]. The types used by NTS cannot be
+    /// overridden.
+    pub fn register<T, F>(&mut self, field_type: u16, decode: F)
+    where
+        T: CustomExtensionField,
+        F: Fn(&[u8]) -> Option<T> + Send + Sync + 'static,
+    {
+        self.decoders.insert(
+            field_type,
+            Arc::new(move |data| {
+                decode(data).map(|field| Arc::new(field) as Arc<dyn CustomExtensionField>)
+            }),
+        );
+    }
+
+    pub fn is_registered(&self, field_type: u16) -> bool {
+        self.decoders.contains_key(&field_type)
+    }
+
+    fn decode<'a>(&self, field: ExtensionField<'a>) -> ExtensionField<'a> {
+        match &field {
+            ExtensionField::Unknown { type_id, data } => match self.decoders.get(type_id) {
+                Some(decode) => decode(data).map(ExtensionField::Custom).unwrap_or(field),
+                None => field,
+            },
+            _ => field,
+        }
+    }
+
+    pub(super) fn decode_all(&self, efdata: &mut ExtensionFieldData<'_>) {
+        for fields in [
+            &mut efdata.authenticated,
+            &mut efdata.encrypted,
+            &mut efdata.untrusted,
+        ] {
+            *fields = std::mem::take(fields)
+                .into_iter()
+                .map(|field| self.decode(field))
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{NoCipher, NtpPacket, NtpPacketBuilder};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Monitoring {
+        sequence: u32,
+    }
+
+    impl CustomExtensionField for Monitoring {
+        fn field_type(&self) -> u16 {
+            0xF123
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            self.sequence.to_be_bytes().to_vec()
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn registry() -> ExtensionFieldRegistry {
+        let mut registry = ExtensionFieldRegistry::new();
+        registry.register(0xF123, |data| {
+            Some(Monitoring {
+                sequence: u32::from_be_bytes(data.get(..4)?.try_into().ok()?),
+            })
+        });
+        registry
+    }
+
+    #[test]
+    fn test_custom_field_roundtrip() {
+        let registry = registry();
+        assert!(registry.is_registered(0xF123));
+        assert!(!registry.is_registered(0xF124));
+
+        let packet = NtpPacketBuilder::new()
+            .untrusted_field(ExtensionField::Custom(Arc::new(Monitoring {
+                sequence: 42,
+            })))
+            .untrusted_field(ExtensionField::Unknown {
+                type_id: 0xF124,
+                data: Cow::Borrowed(&[1; 24]),
+            })
+            .build()
+            .unwrap();
+        let data = packet.serialize_without_encryption_vec().unwrap();
+
+        let parsed = NtpPacket::deserialize_with_registry(&data, &NoCipher, &registry)
+            .unwrap()
+            .0;
+        let mut fields = parsed.untrusted_fields();
+        match fields.next() {
+            Some(ExtensionField::Custom(field)) => {
+                assert_eq!(
+                    field.downcast_ref::<Monitoring>(),
+                    Some(&Monitoring { sequence: 42 })
+                );
+            }
+            other => panic!("expected a custom field, got {other:?}"),
+        }
+        assert!(matches!(
+            fields.next(),
+            Some(ExtensionField::Unknown {
+                type_id: 0xF124,
+                ..
+            })
+        ));
+
+        assert_eq!(parsed.serialize_without_encryption_vec().unwrap(), data);
+
+        // without the registry, the field stays unknown
+        let parsed = NtpPacket::deserialize(&data, &NoCipher).unwrap().0;
+        assert!(matches!(
+            parsed.untrusted_fields().next(),
+            Some(ExtensionField::Unknown {
+                type_id: 0xF123,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_undecodable_field_stays_unknown() {
+        let registry = registry();
+        let field = ExtensionField::Unknown {
+            type_id: 0xF123,
+            data: Cow::Borrowed(&[1, 2]),
+        };
+        assert_eq!(registry.decode(field.clone()), field);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{NtpClock, NtpDuration, NtpTimestamp, SystemSnapshot};
+
+use super::{error::ParsingError, NtpAssociationMode, NtpHeaderV3V4, NtpLeapIndicator};
+
+/// Reference timestamp of NTPv4 requests from clients that would like to
+/// upgrade to NTPv5. Servers supporting NTPv5 echo it in their responses.
+pub(crate) const UPGRADE_TIMESTAMP: NtpTimestamp = NtpTimestamp::from_bits(*b"NTP5DRFT");
+
+/// The timescale of the timestamps in an NTPv5 packet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NtpTimescale {
+    Utc,
+    Tai,
+    Ut1,
+    LeapSmearedUtc,
+    Unknown(u8),
+}
+
+impl NtpTimescale {
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            0 => NtpTimescale::Utc,
+            1 => NtpTimescale::Tai,
+            2 => NtpTimescale::Ut1,
+            3 => NtpTimescale::LeapSmearedUtc,
+            _ => NtpTimescale::Unknown(bits),
+        }
+    }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            NtpTimescale::Utc => 0,
+            NtpTimescale::Tai => 1,
+            NtpTimescale::Ut1 => 2,
+            NtpTimescale::LeapSmearedUtc => 3,
+            NtpTimescale::Unknown(bits) => bits,
+        }
+    }
+}
+
+/// The flags of an NTPv5 packet. Unknown flags are kept as they are.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub(super) struct NtpFlags(u16);
+
+impl NtpFlags {
+    /// The leap indicator of the sender is not meaningful, as it does not know
+    /// about upcoming leap seconds
+    pub(super) const UNKNOWN_LEAP: u16 = 0x1;
+    /// NTS authentication of the request failed
+    pub(super) const AUTHNAK: u16 = 0x4;
+
+    pub(super) fn contains(self, flag: u16) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+/// The header of the NTPv5 draft (draft-ietf-ntp-ntpv5). Requests are matched
+/// to responses through the client cookie, and there is no reference id or
+/// reference timestamp.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) struct NtpHeaderV5 {
+    pub(super) leap: NtpLeapIndicator,
+    pub(super) mode: NtpAssociationMode,
+    pub(super) stratum: u8,
+    pub(super) poll: i8,
+    pub(super) precision: i8,
+    pub(super) timescale: NtpTimescale,
+    /// The NTP era of the timestamps, counting from 1900
+    pub(super) era: u8,
+    pub(super) flags: NtpFlags,
+    pub(super) root_delay: NtpDuration,
+    pub(super) root_dispersion: NtpDuration,
+    pub(super) server_cookie: u64,
+    /// Chosen by the client, and echoed by the server
+    pub(super) client_cookie: u64,
+    /// Time at the server when the request arrived from the client
+    pub(super) receive_timestamp: NtpTimestamp,
+    /// Time at the server when the response left for the client
+    pub(super) transmit_timestamp: NtpTimestamp,
+}
+
+impl NtpHeaderV5 {
+    pub(super) const LENGTH: usize = 48;
+
+    /// A new, empty NtpHeaderV5
+    pub(super) fn new() -> Self {
+        Self {
+            leap: NtpLeapIndicator::NoWarning,
+            mode: NtpAssociationMode::Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            timescale: NtpTimescale::Utc,
+            era: 0,
+            flags: NtpFlags::default(),
+            root_delay: NtpDuration::default(),
+            root_dispersion: NtpDuration::default(),
+            server_cookie: 0,
+            client_cookie: 0,
+            receive_timestamp: NtpTimestamp::default(),
+            transmit_timestamp: NtpTimestamp::default(),
+        }
+    }
+
+    pub(super) fn deserialize(
+        data: &[u8],
+    ) -> Result<(Self, usize), ParsingError<std::convert::Infallible>> {
+        if data.len() < Self::LENGTH {
+            return Err(ParsingError::IncorrectLength);
+        }
+
+        Ok((
+            Self {
+                leap: NtpLeapIndicator::from_bits((data[0] & 0xC0) >> 6),
+                mode: NtpAssociationMode::from_bits(data[0] & 0x07),
+                stratum: data[1],
+                poll: data[2] as i8,
+                precision: data[3] as i8,
+                timescale: NtpTimescale::from_bits(data[4]),
+                era: data[5],
+                flags: NtpFlags(u16::from_be_bytes(data[6..8].try_into().unwrap())),
+                root_delay: NtpDuration::from_bits_time32(data[8..12].try_into().unwrap()),
+                root_dispersion: NtpDuration::from_bits_time32(data[12..16].try_into().unwrap()),
+                server_cookie: u64::from_be_bytes(data[16..24].try_into().unwrap()),
+                client_cookie: u64::from_be_bytes(data[24..32].try_into().unwrap()),
+                receive_timestamp: NtpTimestamp::from_bits(data[32..40].try_into().unwrap()),
+                transmit_timestamp: NtpTimestamp::from_bits(data[40..48].try_into().unwrap()),
+            },
+            Self::LENGTH,
+        ))
+    }
+
+    pub(super) fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&[(self.leap.to_bits() << 6) | (5 << 3) | self.mode.to_bits()])?;
+        w.write_all(&[self.stratum, self.poll as u8, self.precision as u8])?;
+        w.write_all(&[self.timescale.to_bits(), self.era])?;
+        w.write_all(&self.flags.0.to_be_bytes())?;
+        w.write_all(&self.root_delay.to_bits_time32())?;
+        w.write_all(&self.root_dispersion.to_bits_time32())?;
+        w.write_all(&self.server_cookie.to_be_bytes())?;
+        w.write_all(&self.client_cookie.to_be_bytes())?;
+        w.write_all(&self.receive_timestamp.to_bits())?;
+        w.write_all(&self.transmit_timestamp.to_bits())?;
+        Ok(())
+    }
+
+    /// The leap indicator, taking into account that the sender may not know it
+    pub(super) fn leap(&self) -> NtpLeapIndicator {
+        match self.flags.contains(NtpFlags::UNKNOWN_LEAP) {
+            true => NtpLeapIndicator::Unknown,
+            false => self.leap,
+        }
+    }
+
+    /// The client cookie, in the form of the origin timestamp the request
+    /// identifier of NTPv4 expects
+    pub(super) fn client_cookie_timestamp(&self) -> NtpTimestamp {
+        NtpTimestamp::from_bits(self.client_cookie.to_be_bytes())
+    }
+
+    /// The NTPv5 equivalent of an NTPv4 request. The random transmit timestamp
+    /// of the request becomes the client cookie, so responses to both match
+    /// the same request identifier.
+    pub(super) fn from_v4_request(header: NtpHeaderV3V4) -> Self {
+        Self {
+            mode: header.mode,
+            poll: header.poll,
+            client_cookie: u64::from_be_bytes(header.transmit_timestamp.to_bits()),
+            ..Self::new()
+        }
+    }
+
+    /// Responses always use UTC, whatever timescale the client asked for
+    pub(super) fn timestamp_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+    ) -> Self {
+        let leap = system.time_snapshot.leap_indicator;
+
+        Self {
+            leap,
+            mode: NtpAssociationMode::Server,
+            stratum: system.stratum,
+            poll: input.poll,
+            precision: system.time_snapshot.precision.log2(),
+            root_delay: system.time_snapshot.root_delay,
+            root_dispersion: system.time_snapshot.root_dispersion,
+            flags: match leap {
+                NtpLeapIndicator::Unknown => NtpFlags(NtpFlags::UNKNOWN_LEAP),
+                _ => NtpFlags::default(),
+            },
+            era: recv_timestamp.era() as u8,
+            client_cookie: input.client_cookie,
+            receive_timestamp: recv_timestamp,
+            // Timestamp must be last to make it as accurate as possible.
+            transmit_timestamp: clock.now().expect("Failed to read time"),
+            ..Self::new()
+        }
+    }
+
+    /// The NTPv4 equivalent of an NTPv5 request, with the client cookie as
+    /// the transmit timestamp. NTPv5 has no kiss codes, so rate limiting and
+    /// denying service are answered with NTPv4 kiss codes, which the client
+    /// matches to its request just like an NTPv5 response.
+    pub(super) fn to_v4_request(self) -> NtpHeaderV3V4 {
+        NtpHeaderV3V4 {
+            mode: self.mode,
+            poll: self.poll,
+            transmit_timestamp: self.client_cookie_timestamp(),
+            ..NtpHeaderV3V4::new()
+        }
+    }
+}
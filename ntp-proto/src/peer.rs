@@ -2,9 +2,16 @@ use std::io::Cursor;
 
 use crate::{
     cookiestash::CookieStash,
-    packet::{Cipher, NtpAssociationMode, RequestIdentifier},
+    cookiestore::{decode_nts_data, encode_nts_data, encode_nts_state, CookieStore},
+    nts_record::AeadAlgorithm,
+    packet::{
+        Cipher, NoCipher, NtpAssociationMode, RemoteBloomFilter, RequestIdentifier,
+        UniqueIdentifierPrefix,
+    },
     time_types::NtpInstant,
-    NtpDuration, NtpPacket, NtpTimestamp, PollInterval, ReferenceId, SystemConfig, SystemSnapshot,
+    BloomFilter, KissCode, NtpDuration, NtpPacket, NtpTimescale, NtpTimestamp, NtpVersion,
+    NtsNakRecovery, PollInterval, ReferenceId, ServerId, SymmetricKey, SystemConfig,
+    SystemSnapshot,
 };
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, instrument, trace, warn};
@@ -12,6 +19,16 @@ use tracing::{debug, info, instrument, trace, warn};
 const MAX_STRATUM: u8 = 16;
 const POLL_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
 const STARTUP_TRIES_THRESHOLD: usize = 3;
+// Divergence between the local and monotonic clocks over a single exchange beyond
+// which we assume the local clock was stepped (1/8 of a second)
+const STEP_DETECTION_THRESHOLD: NtpDuration = NtpDuration::from_bits((1_i64 << 29).to_be_bytes());
+// Number of requests asking the server to upgrade to NTPv5 before giving up on it
+const V5_UPGRADE_TRIES: u8 = 8;
+// Number of consecutive unanswered NTPv5 requests after which we fall back to NTPv4
+const V5_FALLBACK_TRIES: u8 = 4;
+// Number of kiss codes we can't do anything about, RATE while already polling at the
+// maximum interval or unknown codes, after which we give up on the server
+const MAX_KISS_RETRIES: u8 = 3;
 
 #[derive(Debug, thiserror::Error)]
 pub enum NtsError {
@@ -26,6 +43,11 @@ pub struct PeerNtsData {
     // in the key information they need to keep.
     pub(crate) c2s: Box<dyn Cipher>,
     pub(crate) s2c: Box<dyn Cipher>,
+    // Negotiated during key exchange, the server encodes it in the cookies
+    pub(crate) algorithm: AeadAlgorithm,
+    pub(crate) uid_prefix: Option<UniqueIdentifierPrefix>,
+    // NAKs received since the last valid response
+    pub(crate) consecutive_naks: u8,
 }
 
 #[cfg(feature = "ext-test")]
@@ -39,10 +61,92 @@ impl PeerNtsData {
     }
 }
 
+impl PeerNtsData {
+    /// Learn the length of the cookies issued by the server, and reject new cookies
+    /// whose length deviates from it. A new length is accepted once the server
+    /// consistently issues cookies of that length.
+    pub fn validate_cookie_length(&mut self) {
+        self.cookies.validate_length()
+    }
+
+    /// Id of the AEAD algorithm negotiated with the server. The cookies we
+    /// receive are issued under it, and the keys are for it.
+    pub fn aead_id(&self) -> u16 {
+        self.algorithm as u16
+    }
+
+    /// Start the unique identifiers of our requests with a prefix derived from
+    /// `instance_id`, so requests can be correlated across restarts. The rest of
+    /// each identifier stays random.
+    pub fn set_instance_id(&mut self, instance_id: &[u8]) {
+        self.uid_prefix = Some(UniqueIdentifierPrefix::from_instance_id(instance_id));
+    }
+
+    /// Save the keys and remaining cookies to `store`, so they can be used
+    /// again after a restart without a new key exchange.
+    pub fn save(&self, store: &mut impl CookieStore) -> std::io::Result<()> {
+        store.save(&encode_nts_data(self))
+    }
+
+    /// Restore the keys and cookies saved to `store`, if any
+    pub fn load(store: &impl CookieStore) -> std::io::Result<Option<Self>> {
+        match store.load()? {
+            Some(state) => decode_nts_data(&state).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`PeerNtsData::save`], along with the NTP server `remote` on
+    /// `port` the cookies are for, to be restored with [`KeyExchangeResult::load`]
+    pub fn save_with_server(
+        &self,
+        remote: &str,
+        port: u16,
+        store: &mut impl CookieStore,
+    ) -> std::io::Result<()> {
+        store.save(&encode_nts_state(remote, port, self))
+    }
+
+    /// An NTS request using one of our cookies, or `None` when we ran out
+    pub(crate) fn poll_message(
+        &mut self,
+        poll_interval: PollInterval,
+    ) -> Option<(NtpPacket<'static>, RequestIdentifier)> {
+        let cookie = self.cookies.get()?;
+        let new_cookies = self.cookies.gap();
+
+        Some(match &self.uid_prefix {
+            Some(prefix) => {
+                NtpPacket::nts_poll_message_with_prefix(&cookie, new_cookies, poll_interval, prefix)
+            }
+            None => NtpPacket::nts_poll_message(&cookie, new_cookies, poll_interval),
+        })
+    }
+
+    /// Recover from an NTS NAK for our last request. The cookie used for that
+    /// request was already taken from the stash. Returns whether all cookies were
+    /// discarded, so a new key exchange is needed.
+    pub(crate) fn handle_nak(&mut self, recovery: NtsNakRecovery) -> bool {
+        self.consecutive_naks = self.consecutive_naks.saturating_add(1);
+
+        let rekey = match recovery {
+            NtsNakRecovery::DiscardCookie => self.consecutive_naks > 1,
+            NtsNakRecovery::Rekey => true,
+        };
+        if rekey {
+            self.cookies.clear();
+        }
+
+        rekey
+    }
+}
+
 impl std::fmt::Debug for PeerNtsData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("PeerNtsData")
             .field("cookies", &self.cookies)
+            .field("uid_prefix", &self.uid_prefix)
+            .field("consecutive_naks", &self.consecutive_naks)
             .finish()
     }
 }
@@ -50,6 +154,10 @@ impl std::fmt::Debug for PeerNtsData {
 #[derive(Debug)]
 pub struct Peer {
     nts: Option<Box<PeerNtsData>>,
+    // Key id and key with which requests and responses are authenticated
+    symmetric_key: Option<(u32, SymmetricKey)>,
+    // Mode of our requests, client or one of the symmetric modes
+    mode: NtpAssociationMode,
 
     // Poll interval dictated by unreachability backoff
     backoff_interval: PollInterval,
@@ -59,6 +167,12 @@ pub struct Peer {
     // Must be increased when the server sends the RATE kiss code.
     remote_min_poll_interval: PollInterval,
 
+    // The last kiss code of the server, and how many it sent in total
+    last_kiss: Option<KissCode>,
+    kiss_count: u32,
+    // Kiss codes since the last valid response that we could not act upon
+    kiss_retries: u8,
+
     // Identifier of the last request sent to the server. This is correlated
     // with any received response from the server to guard against replay
     // attacks and packet reordering.
@@ -72,36 +186,174 @@ pub struct Peer {
     reach: Reach,
     tries: usize,
 
+    // Transmit timestamp of the last valid response, a server repeating it is frozen
+    last_transmit_timestamp: Option<NtpTimestamp>,
+    frozen: bool,
+
+    protocol_version: ProtocolVersion,
+    // The bloom filter of an NTPv5 server, for loop detection
+    bloom_filter: RemoteBloomFilter,
+
+    // The last exchange with the server, and the one the outstanding request
+    // refers to when it is interleaved
+    last_exchange: Option<Exchange>,
+    interleaved_exchange: Option<Exchange>,
+
+    // Round trip delay of the last client/server exchange. Broadcast packets only
+    // travel one way, so they are corrected with half of it.
+    broadcast_delay: Option<NtpDuration>,
+
     system_config: SystemConfig,
 }
 
+/// The timestamps of a completed exchange with the server
 #[derive(Debug, Copy, Clone)]
+struct Exchange {
+    /// Time at which our request left, as precisely as we know it
+    send_timestamp: NtpTimestamp,
+    /// Time at the server when our request arrived
+    server_receive_timestamp: NtpTimestamp,
+    /// Time at which the response arrived
+    receive_timestamp: NtpTimestamp,
+    local_clock_time: NtpInstant,
+}
+
+/// The NTP version a client currently uses for its requests
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ProtocolVersion {
+    V4,
+    /// Using NTPv4, while asking the server whether it supports NTPv5
+    V4UpgradingToV5 {
+        tries_left: u8,
+    },
+    V5 {
+        unanswered: u8,
+    },
+}
+
+impl ProtocolVersion {
+    fn new(version: NtpVersion) -> Self {
+        match version {
+            NtpVersion::V4 => ProtocolVersion::V4,
+            NtpVersion::Auto => ProtocolVersion::V4UpgradingToV5 {
+                tries_left: V5_UPGRADE_TRIES,
+            },
+            NtpVersion::V5 => ProtocolVersion::V5 { unanswered: 0 },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Measurement {
     pub delay: NtpDuration,
     pub offset: NtpDuration,
     pub localtime: NtpTimestamp,
     pub monotime: NtpInstant,
+    /// Precision advertised by the server
+    pub remote_precision: NtpDuration,
+    /// Root delay advertised by the server
+    pub root_delay: NtpDuration,
+    /// Root dispersion advertised by the server
+    pub root_dispersion: NtpDuration,
 }
 
 impl Measurement {
-    fn from_packet(
+    pub(crate) fn from_packet(
         packet: &NtpPacket,
         send_timestamp: NtpTimestamp,
         recv_timestamp: NtpTimestamp,
         local_clock_time: NtpInstant,
         precision: NtpDuration,
+    ) -> Self {
+        Self::from_timestamps(
+            send_timestamp,
+            packet.receive_timestamp(),
+            packet.transmit_timestamp(),
+            recv_timestamp,
+            packet,
+            local_clock_time,
+            precision,
+        )
+    }
+
+    /// Measurement from the four timestamps of an exchange, which in interleaved
+    /// mode come from more than one packet. The server's precision, root delay
+    /// and root dispersion are taken from `packet`.
+    fn from_timestamps(
+        send_timestamp: NtpTimestamp,
+        server_receive_timestamp: NtpTimestamp,
+        server_transmit_timestamp: NtpTimestamp,
+        recv_timestamp: NtpTimestamp,
+        packet: &NtpPacket,
+        local_clock_time: NtpInstant,
+        precision: NtpDuration,
     ) -> Self {
         Self {
             delay: ((recv_timestamp - send_timestamp)
-                - (packet.transmit_timestamp() - packet.receive_timestamp()))
-            .max(precision),
-            offset: ((packet.receive_timestamp() - send_timestamp)
-                + (packet.transmit_timestamp() - recv_timestamp))
+                - (server_transmit_timestamp - server_receive_timestamp))
+                .max(precision),
+            offset: ((server_receive_timestamp - send_timestamp)
+                + (server_transmit_timestamp - recv_timestamp))
                 / 2,
             localtime: send_timestamp + (recv_timestamp - send_timestamp) / 2,
             monotime: local_clock_time,
+            remote_precision: NtpDuration::from_exponent(packet.precision()),
+            root_delay: packet.root_delay(),
+            root_dispersion: packet.root_dispersion(),
+        }
+    }
+
+    /// Measurement from a broadcast packet, which only has the transmit timestamp
+    /// of the server. The one way delay is assumed to be half of the round trip
+    /// `delay` measured earlier.
+    fn from_broadcast(
+        packet: &NtpPacket,
+        delay: NtpDuration,
+        recv_timestamp: NtpTimestamp,
+        local_clock_time: NtpInstant,
+    ) -> Self {
+        Self {
+            delay,
+            offset: (packet.transmit_timestamp() - recv_timestamp) + delay / 2,
+            localtime: recv_timestamp,
+            monotime: local_clock_time,
+            remote_precision: NtpDuration::from_exponent(packet.precision()),
+            root_delay: packet.root_delay(),
+            root_dispersion: packet.root_dispersion(),
         }
     }
+
+    /// Bounds on the true offset of the local clock from the primary reference,
+    /// based on this measurement.
+    ///
+    /// We don't know how the round trip delay is split over the two directions, so the
+    /// true offset can be anywhere within half the delay of the measured offset. The
+    /// same holds for the root delay of the server, and its root dispersion bounds how
+    /// far the server's own clock may be off. Together these give the root distance
+    /// of the measurement, without the dispersion that accumulates locally over time.
+    pub fn offset_interval(&self) -> (NtpDuration, NtpDuration) {
+        let distance = (self.delay + self.root_delay) / 2 + self.root_dispersion;
+        (self.offset - distance, self.offset + distance)
+    }
+}
+
+/// Detect whether the local clock was stepped during an exchange.
+///
+/// The time that elapsed between sending the request and receiving the response
+/// according to the local clock is compared with the time that elapsed according to
+/// the monotonic clock. Slewing makes these differ only slightly, so a large
+/// divergence means the offset computed from the exchange can't be trusted.
+/// The instants must be read alongside the corresponding timestamps.
+pub fn clock_stepped(
+    send_timestamp: NtpTimestamp,
+    send_instant: NtpInstant,
+    recv_timestamp: NtpTimestamp,
+    recv_instant: NtpInstant,
+) -> bool {
+    let local_elapsed = recv_timestamp - send_timestamp;
+    let monotonic_elapsed = recv_instant.abs_diff(send_instant);
+
+    (local_elapsed - monotonic_elapsed).abs() > STEP_DETECTION_THRESHOLD
 }
 
 /// Used to determine whether the server is reachable and the data are fresh
@@ -111,7 +363,7 @@ impl Measurement {
 /// As valid packets arrive, the rightmost bit is set to one.
 /// If the register contains any nonzero bits, the server is considered reachable;
 /// otherwise, it is unreachable.
-#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Reach(u8);
 
 impl std::fmt::Debug for Reach {
@@ -156,6 +408,20 @@ impl Reach {
     }
 }
 
+/// State of a peer that is kept across a reload of the configuration, so the peer
+/// doesn't have to start over. NTS keys and cookies, and any request in flight, are
+/// not part of it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ExportedPeerState {
+    backoff_interval: PollInterval,
+    last_poll_interval: PollInterval,
+    remote_min_poll_interval: PollInterval,
+    stratum: u8,
+    reference_id: ReferenceId,
+    reach: Reach,
+    tries: usize,
+}
+
 #[derive(Debug)]
 pub enum IgnoreReason {
     /// The packet doesn't parse
@@ -168,6 +434,12 @@ pub enum IgnoreReason {
     InvalidStratum,
     /// The send time on the received packet is not the time we sent it at
     InvalidPacketTime,
+    /// The server claims to have sent the packet before receiving our request
+    InconsistentServerTimestamps,
+    /// The timestamps of the server are not in UTC
+    InvalidTimescale,
+    /// The MAC of the packet is missing or does not match our symmetric key
+    InvalidMac,
     /// Received a Kiss-o'-Death https://datatracker.ietf.org/doc/html/rfc5905#section-7.4
     KissIgnore,
     /// Received a DENY or RSTR Kiss-o'-Death, and must demobilize the association
@@ -176,6 +448,11 @@ pub enum IgnoreReason {
     KissNtsNack,
     /// The best packet is older than the peer's current time
     TooOld,
+    /// A broadcast packet arrived before the delay to the server was measured
+    Uncalibrated,
+    /// Received a crypto-NAK: the server could not authenticate our request with
+    /// our symmetric key, and the association must be demobilized
+    CryptoNak,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -188,12 +465,24 @@ pub struct PeerSnapshot {
 
     pub stratum: u8,
     pub reference_id: ReferenceId,
+
+    /// The transmit timestamp of the server did not advance between its last responses
+    pub frozen: bool,
+
+    /// The bloom filter of an NTPv5 server, once it is complete
+    pub bloom_filter: Option<BloomFilter>,
+
+    /// The last Kiss-o'-Death code the server sent
+    pub last_kiss: Option<KissCode>,
+    /// The number of Kiss-o'-Death packets the server sent
+    pub kiss_count: u32,
 }
 
 impl PeerSnapshot {
     pub fn accept_synchronization(
         &self,
         local_stratum: u8,
+        server_id: &ServerId,
     ) -> Result<(), AcceptSynchronizationError> {
         use AcceptSynchronizationError::*;
 
@@ -214,12 +503,27 @@ impl PeerSnapshot {
             return Err(Loop);
         }
 
+        // NTPv5 has no reference ids, its servers advertise all servers they
+        // synchronize to, directly or indirectly, in a bloom filter instead
+        if self
+            .bloom_filter
+            .is_some_and(|filter| filter.contains_id(server_id))
+        {
+            debug!("Peer rejected because we are in its bloom filter, a synchronization loop");
+            return Err(Loop);
+        }
+
         // An unreachable error occurs if the server is unreachable.
         if !self.reach.is_reachable() {
             warn!("Peer unreachable");
             return Err(ServerUnreachable);
         }
 
+        if self.frozen {
+            warn!("Peer rejected because its transmit timestamp does not advance");
+            return Err(Frozen);
+        }
+
         Ok(())
     }
 
@@ -231,6 +535,10 @@ impl PeerSnapshot {
             reference_id: peer.reference_id,
             reach: peer.reach,
             poll_interval: peer.last_poll_interval,
+            frozen: peer.frozen,
+            bloom_filter: peer.bloom_filter.full_filter(),
+            last_kiss: peer.last_kiss,
+            kiss_count: peer.kiss_count,
         }
     }
 }
@@ -248,6 +556,10 @@ pub fn peer_snapshot() -> crate::PeerSnapshot {
         our_id: ReferenceId::from_int(1),
         reach,
         poll_interval: crate::time_types::PollIntervalLimits::default().min,
+        frozen: false,
+        bloom_filter: None,
+        last_kiss: None,
+        kiss_count: 0,
     }
 }
 
@@ -258,6 +570,7 @@ pub enum AcceptSynchronizationError {
     Loop,
     Distance,
     Stratum,
+    Frozen,
 }
 
 #[derive(Debug)]
@@ -273,6 +586,8 @@ pub enum PollError {
     Io(#[from] std::io::Error),
     #[error("peer unreachable")]
     Unreachable,
+    #[error("previous request still awaiting a response")]
+    RequestInFlight,
 }
 
 impl Peer {
@@ -285,10 +600,15 @@ impl Peer {
     ) -> Self {
         Self {
             nts: None,
+            symmetric_key: None,
+            mode: NtpAssociationMode::Client,
 
             last_poll_interval: system_config.poll_limits.min,
             backoff_interval: system_config.poll_limits.min,
             remote_min_poll_interval: system_config.poll_limits.min,
+            last_kiss: None,
+            kiss_count: 0,
+            kiss_retries: 0,
 
             current_request_identifier: None,
             our_id,
@@ -299,6 +619,17 @@ impl Peer {
             stratum: 16,
             reference_id: ReferenceId::NONE,
 
+            last_transmit_timestamp: None,
+            frozen: false,
+
+            protocol_version: ProtocolVersion::new(system_config.ntp_version),
+            bloom_filter: RemoteBloomFilter::default(),
+
+            last_exchange: None,
+            interleaved_exchange: None,
+
+            broadcast_delay: None,
+
             system_config,
         }
     }
@@ -317,11 +648,66 @@ impl Peer {
         }
     }
 
+    /// A peer authenticating its requests and the responses of the server with a
+    /// MAC, using the symmetric key `key` with id `keyid`
+    #[instrument(skip(key))]
+    pub fn new_symmetric(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        system_config: SystemConfig,
+        keyid: u32,
+        key: SymmetricKey,
+    ) -> Self {
+        Self {
+            symmetric_key: Some((keyid, key)),
+            ..Self::new(our_id, peer_id, local_clock_time, system_config)
+        }
+    }
+
+    /// The NTS state of the peer, if it uses NTS
+    pub fn nts(&self) -> Option<&PeerNtsData> {
+        self.nts.as_deref()
+    }
+
+    /// Turn this peer into one side of a symmetric association, sending its
+    /// requests in `mode`. Symmetric associations always use NTPv4.
+    pub fn with_mode(self, mode: NtpAssociationMode) -> Self {
+        Self { mode, ..self }
+    }
+
     pub fn update_config(&mut self, system_config: SystemConfig) {
+        if system_config.ntp_version != self.system_config.ntp_version {
+            self.protocol_version = ProtocolVersion::new(system_config.ntp_version);
+            self.bloom_filter = RemoteBloomFilter::default();
+        }
         self.system_config = system_config;
     }
 
-    pub fn current_poll_interval(&self, system: SystemSnapshot) -> PollInterval {
+    pub fn export_state(&self) -> ExportedPeerState {
+        ExportedPeerState {
+            backoff_interval: self.backoff_interval,
+            last_poll_interval: self.last_poll_interval,
+            remote_min_poll_interval: self.remote_min_poll_interval,
+            stratum: self.stratum,
+            reference_id: self.reference_id,
+            reach: self.reach,
+            tries: self.tries,
+        }
+    }
+
+    /// Restore state exported from the peer before a reload
+    pub fn import_state(&mut self, state: ExportedPeerState) {
+        self.backoff_interval = state.backoff_interval;
+        self.last_poll_interval = state.last_poll_interval;
+        self.remote_min_poll_interval = state.remote_min_poll_interval;
+        self.stratum = state.stratum;
+        self.reference_id = state.reference_id;
+        self.reach = state.reach;
+        self.tries = state.tries;
+    }
+
+    pub fn current_poll_interval(&self, system: &SystemSnapshot) -> PollInterval {
         system
             .time_snapshot
             .poll_interval
@@ -329,29 +715,55 @@ impl Peer {
             .max(self.remote_min_poll_interval)
     }
 
+    /// The shortest poll interval allowed for this peer, taking into account
+    /// both our own limits and any rate limit requested by the remote.
+    /// Polls outside the normal schedule should never be sent more often.
+    pub fn min_poll_interval(&self) -> PollInterval {
+        self.system_config
+            .poll_limits
+            .min
+            .max(self.remote_min_poll_interval)
+    }
+
     pub fn generate_poll_message<'a>(
         &mut self,
         buf: &'a mut [u8],
-        system: SystemSnapshot,
+        system: &SystemSnapshot,
         system_config: &SystemConfig,
     ) -> Result<&'a [u8], PollError> {
         if !self.reach.is_reachable() && self.tries >= STARTUP_TRIES_THRESHOLD {
             return Err(PollError::Unreachable);
         }
 
+        // Only have one request outstanding at a time. Once its window has
+        // passed without a response, it has counted as unanswered in the
+        // reach register, and we can move on to the next request.
+        if let Some((_, validity)) = self.current_request_identifier {
+            if validity > NtpInstant::now() {
+                return Err(PollError::RequestInFlight);
+            }
+        }
+        let previous_unanswered = self.current_request_identifier.is_some();
+
         self.reach.poll();
         self.tries = self.tries.saturating_add(1);
 
         let poll_interval = self.current_poll_interval(system);
         let (packet, identifier) = match &mut self.nts {
-            Some(nts) => {
-                let cookie = nts.cookies.get().ok_or_else(|| {
-                    std::io::Error::new(std::io::ErrorKind::Other, NtsError::OutOfCookies)
-                })?;
-                NtpPacket::nts_poll_message(&cookie, nts.cookies.gap(), poll_interval)
-            }
+            Some(nts) => nts.poll_message(poll_interval).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::Other, NtsError::OutOfCookies)
+            })?,
             None => NtpPacket::poll_message(poll_interval),
         };
+        let packet = match self.mode {
+            NtpAssociationMode::Client => self.apply_protocol_version(packet, previous_unanswered),
+            mode => packet.into_symmetric_request(mode),
+        };
+        let (mut packet, identifier) =
+            self.apply_interleaved(packet, identifier, previous_unanswered);
+        if let Some((keyid, key)) = &self.symmetric_key {
+            packet.add_mac(*keyid, key)?;
+        }
         self.current_request_identifier = Some((identifier, NtpInstant::now() + POLL_WINDOW));
 
         // Ensure we don't spam the remote with polls if it is not reachable
@@ -366,23 +778,101 @@ impl Peer {
         Ok(result)
     }
 
+    /// Turn an NTPv4 request into one for the protocol version we currently use
+    fn apply_protocol_version<'a>(
+        &mut self,
+        packet: NtpPacket<'a>,
+        previous_unanswered: bool,
+    ) -> NtpPacket<'a> {
+        match self.protocol_version {
+            ProtocolVersion::V4 => packet,
+            ProtocolVersion::V4UpgradingToV5 { tries_left: 0 } => {
+                info!("Peer does not support NTPv5, staying on NTPv4");
+                self.protocol_version = ProtocolVersion::V4;
+                packet
+            }
+            ProtocolVersion::V4UpgradingToV5 { tries_left } => {
+                self.protocol_version = ProtocolVersion::V4UpgradingToV5 {
+                    tries_left: tries_left - 1,
+                };
+                packet.into_upgrade_request()
+            }
+            ProtocolVersion::V5 { unanswered } => {
+                let unanswered = match previous_unanswered {
+                    true => unanswered.saturating_add(1),
+                    false => 0,
+                };
+
+                // Only fall back when we negotiated the upgrade ourselves
+                if unanswered >= V5_FALLBACK_TRIES
+                    && self.system_config.ntp_version == NtpVersion::Auto
+                {
+                    warn!("Peer stopped answering NTPv5 requests, falling back to NTPv4");
+                    self.protocol_version = ProtocolVersion::V4;
+                    self.bloom_filter = RemoteBloomFilter::default();
+                    packet
+                } else {
+                    self.protocol_version = ProtocolVersion::V5 { unanswered };
+                    packet
+                        .into_v5_request()
+                        .with_reference_id_request(self.bloom_filter.next_request())
+                }
+            }
+        }
+    }
+
+    /// Refer to the last exchange with the server in the request, when using
+    /// interleaved mode and there is one
+    fn apply_interleaved<'a>(
+        &mut self,
+        packet: NtpPacket<'a>,
+        identifier: RequestIdentifier,
+        previous_unanswered: bool,
+    ) -> (NtpPacket<'a>, RequestIdentifier) {
+        // The server may have received the unanswered request, and then only
+        // remembers that exchange, which we know nothing about
+        if previous_unanswered {
+            self.last_exchange = None;
+        }
+
+        self.interleaved_exchange = self
+            .last_exchange
+            .filter(|_| self.system_config.interleaved_mode && packet.version() == 4);
+
+        match self.interleaved_exchange {
+            Some(exchange) => packet.into_interleaved_request(
+                identifier,
+                exchange.server_receive_timestamp,
+                exchange.receive_timestamp,
+            ),
+            None => (packet, identifier),
+        }
+    }
+
     #[instrument(skip(self, system), fields(peer = debug(self.peer_id)))]
     pub fn handle_incoming(
         &mut self,
-        system: SystemSnapshot,
+        system: &SystemSnapshot,
         message: &[u8],
         local_clock_time: NtpInstant,
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
     ) -> Result<Update, IgnoreReason> {
-        let message =
-            match NtpPacket::deserialize(message, &self.nts.as_ref().map(|nts| nts.s2c.as_ref())) {
-                Ok((packet, _)) => packet,
-                Err(e) => {
-                    warn!("received invalid packet: {}", e);
-                    return Err(IgnoreReason::InvalidPacket);
+        let data = message;
+        let cipher = self.nts.as_ref().map(|nts| nts.s2c.as_ref());
+        let message = match NtpPacket::deserialize(data, &cipher) {
+            Ok((packet, _)) => packet,
+            Err(e) => {
+                match NtpPacket::deserialize_with_trailing(data, &cipher) {
+                    Ok((_, _, trailing)) if !trailing.unexpected.is_empty() => warn!(
+                        "received packet followed by {} unexpected bytes",
+                        trailing.unexpected.len()
+                    ),
+                    _ => warn!("received invalid packet: {}", e),
                 }
-            };
+                return Err(IgnoreReason::InvalidPacket);
+            }
+        };
 
         let request_identifier = match self.current_request_identifier {
             Some((next_expected_origin, validity)) if validity >= NtpInstant::now() => {
@@ -394,6 +884,20 @@ impl Peer {
             }
         };
 
+        // A server answering in interleaved mode echoes our receive timestamp of the
+        // previous exchange instead of our transmit timestamp
+        let interleaved = self.interleaved_exchange.filter(|_| {
+            !message
+                .origin_timestamp()
+                .constant_time_eq(request_identifier.expected_origin_timestamp)
+        });
+        let inconsistent_timestamps = match interleaved {
+            Some(exchange) => {
+                message.transmit_timestamp() - exchange.server_receive_timestamp < NtpDuration::ZERO
+            }
+            None => message.has_inconsistent_timestamps(),
+        };
+
         if !message.valid_server_response(request_identifier, self.nts.is_some()) {
             // Packets should be a response to a previous request from us,
             // if not just ignore. Note that this might also happen when
@@ -403,31 +907,23 @@ impl Peer {
             // to denial of service attacks.
             debug!("Received old/unexpected packet from peer");
             Err(IgnoreReason::InvalidPacketTime)
-        } else if message.is_kiss_rate() {
-            // KISS packets may not have correct timestamps at all, handle them anyway
-            self.remote_min_poll_interval = Ord::max(
-                self.remote_min_poll_interval
-                    .inc(self.system_config.poll_limits),
-                self.last_poll_interval,
-            );
-            warn!(?self.remote_min_poll_interval, "Peer requested rate limit");
-            Err(IgnoreReason::KissIgnore)
-        } else if message.is_kiss_rstr() || message.is_kiss_deny() {
-            warn!("Peer denied service");
+        } else if self.symmetric_key.is_some() && message.is_crypto_nak() {
+            // A crypto-NAK can't be authenticated, but it does echo our request. The
+            // server doesn't know our key, so retrying with it is pointless.
+            warn!("Received crypto-NAK, the server rejected our key");
+            Err(IgnoreReason::CryptoNak)
+        } else if let Some(Err(error)) = self
+            .symmetric_key
+            .as_ref()
+            .map(|(keyid, key)| message.verify_mac(data, *keyid, key))
+        {
+            // With symmetric keys, even kiss codes must be authenticated, as they could
+            // otherwise be used to take down the association
+            warn!(%error, "Received packet that failed authentication");
+            Err(IgnoreReason::InvalidMac)
+        } else if let Some(kiss_code) = message.kiss_code() {
             // KISS packets may not have correct timestamps at all, handle them anyway
-            Err(IgnoreReason::KissDemobilize)
-        } else if message.is_kiss_ntsn() {
-            warn!("Received nts not-acknowledge");
-            // as these can be easily faked, we dont immediately give up on receiving
-            // a response, however, for the purpose of backoff we do count it as a response.
-            // This ensures that if we have expired cookies, we get through them
-            // fairly quickly.
-            self.backoff_interval = self.system_config.poll_limits.min;
-            Err(IgnoreReason::KissNtsNack)
-        } else if message.is_kiss() {
-            warn!("Unrecognized KISS Message from peer");
-            // Ignore unrecognized control messages
-            Err(IgnoreReason::KissIgnore)
+            Err(self.handle_kiss(kiss_code))
         } else if message.stratum() > MAX_STRATUM {
             // A servers stratum should be between 1 and MAX_STRATUM (16) inclusive.
             warn!(
@@ -435,20 +931,89 @@ impl Peer {
                 message.stratum()
             );
             Err(IgnoreReason::InvalidStratum)
-        } else if message.mode() != NtpAssociationMode::Server {
-            // we currently only support a client <-> server association
+        } else if message.mode() != self.mode.response_mode() {
             warn!("Received packet with invalid mode");
             Err(IgnoreReason::InvalidMode)
+        } else if inconsistent_timestamps {
+            // This would give a negative processing time at the server,
+            // making the measured delay too small
+            warn!("Received packet with receive timestamp after transmit timestamp");
+            Err(IgnoreReason::InconsistentServerTimestamps)
+        } else if message.timescale().is_some_and(|timescale| {
+            !matches!(timescale, NtpTimescale::Utc | NtpTimescale::LeapSmearedUtc)
+        }) {
+            // we ask for UTC, and can't convert from other timescales. Smeared
+            // UTC only differs around leap seconds, the controller handles that.
+            warn!("Received packet with timestamps that are not in UTC");
+            Err(IgnoreReason::InvalidTimescale)
+        } else {
+            Ok(self.process_message(
+                system,
+                message,
+                interleaved,
+                local_clock_time,
+                send_time,
+                recv_time,
+            ))
+        }
+    }
+
+    fn handle_kiss(&mut self, kiss_code: KissCode) -> IgnoreReason {
+        self.last_kiss = Some(kiss_code);
+        self.kiss_count = self.kiss_count.saturating_add(1);
+
+        match kiss_code {
+            KissCode::Deny | KissCode::Rstr => {
+                warn!(%kiss_code, "Peer denied service");
+                return IgnoreReason::KissDemobilize;
+            }
+            KissCode::Ntsn => {
+                warn!("Received nts not-acknowledge");
+                // as these can be easily faked, we dont immediately give up on receiving
+                // a response, however, for the purpose of backoff we do count it as a response.
+                // This ensures that if we have expired cookies, we get through them
+                // fairly quickly.
+                self.backoff_interval = self.system_config.poll_limits.min;
+                if let Some(nts) = self.nts.as_mut() {
+                    if nts.handle_nak(self.system_config.nts_nak_recovery) {
+                        info!("Discarded all nts cookies, a new key exchange is needed");
+                    }
+                }
+                return IgnoreReason::KissNtsNack;
+            }
+            KissCode::Rate => {
+                if self.remote_min_poll_interval >= self.system_config.poll_limits.max {
+                    // we can't slow down any further
+                    self.kiss_retries += 1;
+                }
+                self.remote_min_poll_interval = Ord::max(
+                    self.remote_min_poll_interval
+                        .inc(self.system_config.poll_limits),
+                    self.last_poll_interval,
+                );
+                warn!(?self.remote_min_poll_interval, "Peer requested rate limit");
+            }
+            KissCode::Unknown(_) => {
+                // Ignore unrecognized control messages
+                warn!(%kiss_code, "Unrecognized KISS Message from peer");
+                self.kiss_retries += 1;
+            }
+        }
+
+        if self.kiss_retries >= MAX_KISS_RETRIES {
+            warn!("Peer keeps sending kiss codes, giving up on it");
+            IgnoreReason::KissDemobilize
         } else {
-            Ok(self.process_message(system, message, local_clock_time, send_time, recv_time))
+            IgnoreReason::KissIgnore
         }
     }
 
     #[allow(clippy::too_many_arguments)]
     fn process_message(
         &mut self,
-        system: SystemSnapshot,
+        system: &SystemSnapshot,
         message: NtpPacket,
+        interleaved: Option<Exchange>,
         local_clock_time: NtpInstant,
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
@@ -459,6 +1024,7 @@ impl Peer {
 
         // Got a response, so no need for unreachability backoff
         self.backoff_interval = self.system_config.poll_limits.min;
+        self.kiss_retries = 0;
 
         // we received this packet, and don't want to accept future ones with this next_expected_origin
         self.current_request_identifier = None;
@@ -466,23 +1032,84 @@ impl Peer {
         // Update stratum and reference id
         self.stratum = message.stratum();
         self.reference_id = message.reference_id();
+        if let Some(chunk) = message.reference_id_response(self.nts.is_some()) {
+            self.bloom_filter.handle_response(chunk);
+        }
 
-        // generate a measurement
-        let measurement = Measurement::from_packet(
-            &message,
-            send_time,
-            recv_time,
-            local_clock_time,
-            system.time_snapshot.precision,
-        );
+        if let ProtocolVersion::V4UpgradingToV5 { .. } = self.protocol_version {
+            if message.offers_v5_upgrade() {
+                info!("Peer supports NTPv5, upgrading");
+                self.protocol_version = ProtocolVersion::V5 { unanswered: 0 };
+            }
+        }
+
+        // A server echoing a longer poll interval than we asked for tells us the
+        // interval it wants its clients to use at least, like a RATE kiss code
+        if self.mode == NtpAssociationMode::Client {
+            match message.poll() {
+                Some(poll) if poll > self.last_poll_interval => {
+                    self.remote_min_poll_interval = Ord::max(
+                        self.remote_min_poll_interval,
+                        poll.min(self.system_config.poll_limits.max),
+                    );
+                    debug!(?self.remote_min_poll_interval, "Peer advertised a longer poll interval");
+                }
+                Some(_) => {}
+                None => debug!("Received packet with an invalid poll interval"),
+            }
+        }
 
         // Process new cookies
         if let Some(nts) = self.nts.as_mut() {
+            nts.consecutive_naks = 0;
             for cookie in message.new_cookies() {
-                nts.cookies.store(cookie);
+                if !nts.cookies.store(cookie) {
+                    warn!("Received cookie with unexpected length, ignoring it");
+                }
             }
         }
 
+        self.interleaved_exchange = None;
+        self.last_exchange = Some(Exchange {
+            send_timestamp: send_time,
+            server_receive_timestamp: message.receive_timestamp(),
+            receive_timestamp: recv_time,
+            local_clock_time,
+        });
+
+        // A server whose clock is frozen keeps sending the same transmit timestamp.
+        // Its measurements are meaningless, and it should not be used until it recovers.
+        let transmit_timestamp = message.transmit_timestamp();
+        self.frozen = self.last_transmit_timestamp == Some(transmit_timestamp);
+        self.last_transmit_timestamp = Some(transmit_timestamp);
+        if self.frozen {
+            warn!("Transmit timestamp of peer did not advance, marking it unhealthy");
+            return Update::BareUpdate(PeerSnapshot::from_peer(self));
+        }
+
+        // generate a measurement. In interleaved mode, the response completes the
+        // previous exchange with a more accurate transmit timestamp.
+        let measurement = match interleaved {
+            Some(exchange) => Measurement::from_timestamps(
+                exchange.send_timestamp,
+                exchange.server_receive_timestamp,
+                message.transmit_timestamp(),
+                exchange.receive_timestamp,
+                &message,
+                exchange.local_clock_time,
+                system.time_snapshot.precision,
+            ),
+            None => Measurement::from_packet(
+                &message,
+                send_time,
+                recv_time,
+                local_clock_time,
+                system.time_snapshot.precision,
+            ),
+        };
+
+        self.broadcast_delay = Some(measurement.delay);
+
         Update::NewMeasurement(
             PeerSnapshot::from_peer(self),
             measurement,
@@ -490,22 +1117,112 @@ impl Peer {
         )
     }
 
+    /// Whether the delay to the server is known, so broadcast packets can be used
+    pub fn broadcast_calibrated(&self) -> bool {
+        self.broadcast_delay.is_some()
+    }
+
+    /// Handle a broadcast (mode 5) packet of the server. These are only used once
+    /// a client/server exchange measured the delay to the server.
+    #[instrument(skip(self), fields(peer = debug(self.peer_id)))]
+    pub fn handle_broadcast(
+        &mut self,
+        message: &[u8],
+        local_clock_time: NtpInstant,
+        recv_time: NtpTimestamp,
+    ) -> Result<Update, IgnoreReason> {
+        let data = message;
+        let message = match NtpPacket::deserialize(data, &NoCipher) {
+            Ok((packet, _)) => packet,
+            Err(e) => {
+                warn!("received invalid packet: {}", e);
+                return Err(IgnoreReason::InvalidPacket);
+            }
+        };
+
+        if message.mode() != NtpAssociationMode::Broadcast {
+            debug!("Received packet with invalid mode on broadcast socket");
+            Err(IgnoreReason::InvalidMode)
+        } else if let Some(Err(error)) = self
+            .symmetric_key
+            .as_ref()
+            .map(|(keyid, key)| message.verify_mac(data, *keyid, key))
+        {
+            warn!(%error, "Received broadcast that failed authentication");
+            Err(IgnoreReason::InvalidMac)
+        } else if message.is_kiss() {
+            warn!("Unrecognized KISS Message from broadcast server");
+            Err(IgnoreReason::KissIgnore)
+        } else if message.stratum() > MAX_STRATUM {
+            warn!(
+                "Received broadcast from server with excessive stratum {}",
+                message.stratum()
+            );
+            Err(IgnoreReason::InvalidStratum)
+        } else if self
+            .last_transmit_timestamp
+            .is_some_and(|last| message.transmit_timestamp() - last <= NtpDuration::ZERO)
+        {
+            // Broadcasts can't be matched to a request, so replays are only
+            // recognizable by their timestamps not advancing
+            debug!("Received old broadcast packet");
+            Err(IgnoreReason::InvalidPacketTime)
+        } else {
+            let delay = self.broadcast_delay.ok_or(IgnoreReason::Uncalibrated)?;
+
+            // Every broadcast counts as an answered poll
+            self.reach.poll();
+            self.reach.received_packet();
+
+            self.stratum = message.stratum();
+            self.reference_id = message.reference_id();
+            self.last_transmit_timestamp = Some(message.transmit_timestamp());
+            self.frozen = false;
+
+            trace!("Broadcast accepted for processing");
+            let measurement =
+                Measurement::from_broadcast(&message, delay, recv_time, local_clock_time);
+
+            Ok(Update::NewMeasurement(
+                PeerSnapshot::from_peer(self),
+                measurement,
+                message.into_owned(),
+            ))
+        }
+    }
+
     #[instrument(level="trace", skip(self), fields(peer = debug(self.peer_id)))]
     pub fn reset(&mut self) {
         // make sure in-flight messages are ignored
         self.current_request_identifier = None;
+        // and timestamps from before a clock step are not used
+        self.last_exchange = None;
+        self.interleaved_exchange = None;
 
         info!(our_id = ?self.our_id, peer_id = ?self.peer_id, "Peer reset");
     }
 
+    /// Let the window for a response to the outstanding request pass
+    #[cfg(test)]
+    pub(crate) fn expire_request(&mut self) {
+        if let Some((_, validity)) = self.current_request_identifier.as_mut() {
+            *validity = NtpInstant::now();
+        }
+    }
+
     #[cfg(test)]
     pub(crate) fn test_peer() -> Self {
         Peer {
             nts: None,
+            symmetric_key: None,
+            mode: NtpAssociationMode::Client,
 
             last_poll_interval: PollInterval::default(),
             backoff_interval: PollInterval::default(),
             remote_min_poll_interval: PollInterval::default(),
+            last_kiss: None,
+            kiss_count: 0,
+            kiss_retries: 0,
 
             current_request_identifier: None,
 
@@ -517,6 +1234,17 @@ impl Peer {
             stratum: 0,
             reference_id: ReferenceId::from_int(0),
 
+            last_transmit_timestamp: None,
+            frozen: false,
+
+            protocol_version: ProtocolVersion::V4,
+            bloom_filter: RemoteBloomFilter::default(),
+
+            last_exchange: None,
+            interleaved_exchange: None,
+
+            broadcast_delay: None,
+
             system_config: SystemConfig::default(),
         }
     }
@@ -552,7 +1280,11 @@ pub fn fuzz_measurement_from_packet(
 
 #[cfg(test)]
 mod test {
-    use crate::{packet::NoCipher, time_types::PollIntervalLimits};
+    use crate::{
+        packet::{AesSivCmac256, NoCipher},
+        time_types::PollIntervalLimits,
+        ExtensionField, NtpPacketBuilder,
+    };
 
     use super::*;
     use std::time::Duration;
@@ -599,6 +1331,88 @@ mod test {
         assert_eq!(result.delay, NtpDuration::from_fixed_int(1));
     }
 
+    #[test]
+    fn test_measurement_offset_interval() {
+        let mut packet = NtpPacket::test();
+        packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(2 << 32));
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(3 << 32));
+        let result = Measurement::from_packet(
+            &packet,
+            NtpTimestamp::from_fixed_int(0),
+            NtpTimestamp::from_fixed_int(3 << 32),
+            NtpInstant::now(),
+            NtpDuration::from_exponent(-32),
+        );
+        assert_eq!(result.offset, NtpDuration::from_seconds(1.0));
+        assert_eq!(result.delay, NtpDuration::from_seconds(2.0));
+
+        // the interval is centered on the offset, and as wide as the delay
+        let (low, high) = result.offset_interval();
+        assert_eq!(low, NtpDuration::ZERO);
+        assert_eq!(high, NtpDuration::from_seconds(2.0));
+        assert_eq!(high - low, result.delay);
+
+        // the root delay of the server widens it by the same amount, and its root
+        // dispersion on both sides
+        packet.set_root_delay(NtpDuration::from_fixed_int(1 << 31));
+        packet.set_root_dispersion(NtpDuration::from_fixed_int(1 << 30));
+        let result = Measurement::from_packet(
+            &packet,
+            NtpTimestamp::from_fixed_int(0),
+            NtpTimestamp::from_fixed_int(3 << 32),
+            NtpInstant::now(),
+            NtpDuration::from_exponent(-32),
+        );
+        assert_eq!(result.root_delay, NtpDuration::from_fixed_int(1 << 31));
+        assert_eq!(result.root_dispersion, NtpDuration::from_fixed_int(1 << 30));
+        let (low, high) = result.offset_interval();
+        assert_eq!(
+            high - low,
+            result.delay + result.root_delay + result.root_dispersion + result.root_dispersion
+        );
+        assert_eq!(low, NtpDuration::from_fixed_int(-(1 << 31)));
+        assert_eq!(high, NtpDuration::from_fixed_int(5 << 31));
+    }
+
+    #[test]
+    fn test_clock_stepped() {
+        let send_instant = NtpInstant::now();
+        let recv_instant = send_instant + Duration::from_millis(20);
+        let send_timestamp = NtpTimestamp::from_fixed_int(1 << 32);
+
+        // the local clock advanced as much as the monotonic clock
+        assert!(!clock_stepped(
+            send_timestamp,
+            send_instant,
+            send_timestamp + NtpDuration::from_seconds(0.02),
+            recv_instant,
+        ));
+
+        // a little slewing is fine
+        assert!(!clock_stepped(
+            send_timestamp,
+            send_instant,
+            send_timestamp + NtpDuration::from_seconds(0.021),
+            recv_instant,
+        ));
+
+        // the local clock was stepped forward mid-exchange
+        assert!(clock_stepped(
+            send_timestamp,
+            send_instant,
+            send_timestamp + NtpDuration::from_seconds(1.02),
+            recv_instant,
+        ));
+
+        // or backward
+        assert!(clock_stepped(
+            send_timestamp,
+            send_instant,
+            send_timestamp - NtpDuration::from_seconds(0.98),
+            recv_instant,
+        ));
+    }
+
     #[test]
     fn reachability() {
         let mut reach = Reach::default();
@@ -629,16 +1443,39 @@ mod test {
         assert!(reach.is_reachable());
     }
 
+    #[test]
+    fn test_export_import_state() {
+        let mut peer = Peer::test_peer();
+        peer.reach.received_packet();
+        peer.reach.poll();
+        peer.reach.received_packet();
+        peer.stratum = 2;
+        peer.reference_id = ReferenceId::from_int(42);
+        let remote_min_poll_interval = PollInterval::default().inc(PollIntervalLimits::default());
+        peer.remote_min_poll_interval = remote_min_poll_interval;
+
+        let exported = serde_json::to_string(&peer.export_state()).unwrap();
+
+        let mut restored = Peer::test_peer();
+        restored.import_state(serde_json::from_str(&exported).unwrap());
+        assert_eq!(restored.reach, peer.reach);
+        assert_eq!(restored.stratum, 2);
+        assert_eq!(restored.reference_id, ReferenceId::from_int(42));
+        assert_eq!(restored.remote_min_poll_interval, remote_min_poll_interval);
+        assert!(restored.nts.is_none());
+    }
+
     #[test]
     fn test_accept_synchronization() {
         use AcceptSynchronizationError::*;
 
         let mut peer = Peer::test_peer();
+        let server_id = ServerId::new(&mut rand::thread_rng());
 
         macro_rules! accept {
             () => {{
                 let snapshot = PeerSnapshot::from_peer(&peer);
-                snapshot.accept_synchronization(16)
+                snapshot.accept_synchronization(16, &server_id)
             }};
         }
 
@@ -655,6 +1492,17 @@ mod test {
 
         peer.stratum = 42;
         assert_eq!(accept!(), Err(Stratum));
+        peer.stratum = 0;
+
+        // an NTPv5 server that has us in its bloom filter synchronizes to us
+        let mut snapshot = PeerSnapshot::from_peer(&peer);
+        let mut filter = BloomFilter::new();
+        filter.add_id(&ServerId::new(&mut rand::thread_rng()));
+        snapshot.bloom_filter = Some(filter);
+        assert_eq!(snapshot.accept_synchronization(16, &server_id), Ok(()));
+        filter.add_id(&server_id);
+        snapshot.bloom_filter = Some(filter);
+        assert_eq!(snapshot.accept_synchronization(16, &server_id), Err(Loop));
     }
 
     #[test]
@@ -663,51 +1511,53 @@ mod test {
         let mut peer = Peer::test_peer();
         let mut system = SystemSnapshot::default();
 
-        assert!(peer.current_poll_interval(system) >= peer.remote_min_poll_interval);
-        assert!(peer.current_poll_interval(system) >= system.time_snapshot.poll_interval);
+        assert!(peer.current_poll_interval(&system) >= peer.remote_min_poll_interval);
+        assert!(peer.current_poll_interval(&system) >= system.time_snapshot.poll_interval);
 
         system.time_snapshot.poll_interval = PollIntervalLimits::default().max;
 
-        assert!(peer.current_poll_interval(system) >= peer.remote_min_poll_interval);
-        assert!(peer.current_poll_interval(system) >= system.time_snapshot.poll_interval);
+        assert!(peer.current_poll_interval(&system) >= peer.remote_min_poll_interval);
+        assert!(peer.current_poll_interval(&system) >= system.time_snapshot.poll_interval);
 
         system.time_snapshot.poll_interval = PollIntervalLimits::default().min;
         peer.remote_min_poll_interval = PollIntervalLimits::default().max;
 
-        assert!(peer.current_poll_interval(system) >= peer.remote_min_poll_interval);
-        assert!(peer.current_poll_interval(system) >= system.time_snapshot.poll_interval);
+        assert!(peer.current_poll_interval(&system) >= peer.remote_min_poll_interval);
+        assert!(peer.current_poll_interval(&system) >= system.time_snapshot.poll_interval);
+        assert_eq!(peer.min_poll_interval(), PollIntervalLimits::default().max);
 
         peer.remote_min_poll_interval = PollIntervalLimits::default().min;
+        assert_eq!(peer.min_poll_interval(), PollIntervalLimits::default().min);
 
-        let prev = peer.current_poll_interval(system);
+        let prev = peer.current_poll_interval(&system);
         let mut buf = [0; 1024];
         let packetbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let packet = NtpPacket::deserialize(packetbuf, &NoCipher).unwrap().0;
-        assert!(peer.current_poll_interval(system) > prev);
+        assert!(peer.current_poll_interval(&system) > prev);
         let mut response = NtpPacket::test();
         response.set_mode(NtpAssociationMode::Server);
         response.set_stratum(1);
         response.set_origin_timestamp(packet.transmit_timestamp());
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &response.serialize_without_encryption_vec().unwrap(),
                 base,
                 NtpTimestamp::default(),
                 NtpTimestamp::default()
             )
             .is_ok());
-        assert_eq!(peer.current_poll_interval(system), prev);
+        assert_eq!(peer.current_poll_interval(&system), prev);
 
-        let prev = peer.current_poll_interval(system);
+        let prev = peer.current_poll_interval(&system);
         let mut buf = [0; 1024];
         let packetbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let packet = NtpPacket::deserialize(packetbuf, &NoCipher).unwrap().0;
-        assert!(peer.current_poll_interval(system) > prev);
+        assert!(peer.current_poll_interval(&system) > prev);
         let mut response = NtpPacket::test();
         response.set_mode(NtpAssociationMode::Server);
         response.set_stratum(0);
@@ -715,17 +1565,71 @@ mod test {
         response.set_reference_id(ReferenceId::KISS_RATE);
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &response.serialize_without_encryption_vec().unwrap(),
                 base,
                 NtpTimestamp::default(),
                 NtpTimestamp::default()
             )
             .is_err());
-        assert!(peer.current_poll_interval(system) > prev);
+        assert!(peer.current_poll_interval(&system) > prev);
         assert!(peer.remote_min_poll_interval > prev);
     }
 
+    #[test]
+    fn test_server_poll_echo() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer();
+        let system = SystemSnapshot::default();
+        let limits = peer.system_config.poll_limits;
+
+        let respond = |peer: &mut Peer, poll: i8| {
+            let mut buf = [0; 1024];
+            peer.expire_request();
+            let outgoingbuf = peer
+                .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+                .unwrap();
+            let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
+            let response = NtpPacketBuilder::new()
+                .mode(NtpAssociationMode::Server)
+                .stratum(1)
+                .poll(poll)
+                .origin_timestamp(outgoing.transmit_timestamp())
+                .receive_timestamp(NtpTimestamp::from_fixed_int(100))
+                .transmit_timestamp(NtpTimestamp::from_fixed_int(200))
+                .build()
+                .unwrap();
+            assert!(peer
+                .handle_incoming(
+                    &system,
+                    &response.serialize_without_encryption_vec().unwrap(),
+                    base + Duration::from_secs(1),
+                    NtpTimestamp::from_fixed_int(0),
+                    NtpTimestamp::from_fixed_int(400)
+                )
+                .is_ok());
+        };
+
+        // an echo of our own poll interval changes nothing
+        respond(&mut peer, limits.min.as_log());
+        assert_eq!(peer.min_poll_interval(), limits.min);
+
+        // a longer interval is the minimum the server wants us to use
+        let advertised = limits.min.inc(limits).inc(limits);
+        respond(&mut peer, advertised.as_log());
+        assert_eq!(peer.min_poll_interval(), advertised);
+        assert!(peer.current_poll_interval(&system) >= advertised);
+
+        // but it can't make us poll less often than our own maximum
+        respond(&mut peer, 17);
+        assert_eq!(peer.min_poll_interval(), limits.max);
+
+        // invalid values are ignored
+        let mut peer = Peer::test_peer();
+        respond(&mut peer, 100);
+        assert_eq!(peer.min_poll_interval(), limits.min);
+    }
+
     #[test]
     fn test_handle_incoming() {
         let base = NtpInstant::now();
@@ -734,7 +1638,7 @@ mod test {
         let system = SystemSnapshot::default();
         let mut buf = [0; 1024];
         let outgoingbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
         let mut packet = NtpPacket::test();
@@ -747,7 +1651,7 @@ mod test {
 
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -757,7 +1661,7 @@ mod test {
         //assert_eq!(peer.timestate.last_packet, packet);
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -767,21 +1671,625 @@ mod test {
     }
 
     #[test]
-    fn test_startup_unreachable() {
+    fn test_frozen_transmit_timestamp() {
+        let base = NtpInstant::now();
         let mut peer = Peer::test_peer();
         let system = SystemSnapshot::default();
-        let mut buf = [0; 1024];
-        assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
-            .is_ok());
+
+        let mut respond = |transmit: u64| {
+            let mut buf = [0; 1024];
+            peer.expire_request();
+            let outgoingbuf = peer
+                .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+                .unwrap();
+            let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(transmit - 100));
+            packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(transmit));
+
+            peer.handle_incoming(
+                &system,
+                &packet.serialize_without_encryption_vec().unwrap(),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+            .unwrap()
+        };
+
+        let update = respond(200);
+        assert!(matches!(update, Update::NewMeasurement(snapshot, _, _) if !snapshot.frozen));
+
+        // the second response carries the same transmit timestamp
+        let snapshot = match respond(200) {
+            Update::BareUpdate(snapshot) => snapshot,
+            Update::NewMeasurement(..) => panic!("frozen server should not give a measurement"),
+        };
+        assert!(snapshot.frozen);
+        assert!(matches!(
+            snapshot.accept_synchronization(16, &system.server_id),
+            Err(AcceptSynchronizationError::Frozen)
+        ));
+
+        // once the timestamp advances, the peer recovers
+        let update = respond(300);
+        assert!(matches!(update, Update::NewMeasurement(snapshot, _, _) if !snapshot.frozen));
+    }
+
+    fn poll_version(peer: &mut Peer, system_config: &SystemConfig) -> NtpPacket<'static> {
+        let mut buf = [0; 1024];
+        peer.expire_request();
+        let outgoing = peer
+            .generate_poll_message(&mut buf, &SystemSnapshot::default(), system_config)
+            .unwrap();
+        NtpPacket::deserialize(outgoing, &NoCipher)
+            .unwrap()
+            .0
+            .into_owned()
+    }
+
+    fn respond_version(
+        peer: &mut Peer,
+        request: &NtpPacket,
+        mut packet: NtpPacket,
+    ) -> Result<Update, IgnoreReason> {
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_origin_timestamp(request.request_identifier().expected_origin_timestamp);
+        packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+
+        peer.handle_incoming(
+            &SystemSnapshot::default(),
+            &packet.serialize_without_encryption_vec().unwrap(),
+            NtpInstant::now(),
+            NtpTimestamp::from_fixed_int(0),
+            NtpTimestamp::from_fixed_int(400),
+        )
+    }
+
+    #[test]
+    fn test_upgrade_to_v5() {
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::Auto,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer();
+        peer.update_config(system_config);
+
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 4);
+        assert!(request.offers_v5_upgrade());
+
+        // the server echoes the upgrade marker in its response
+        let response = NtpPacket::test().into_upgrade_request();
+        assert!(respond_version(&mut peer, &request, response).is_ok());
+
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 5);
+        let response = NtpPacket::test().into_v5_request();
+        assert!(respond_version(&mut peer, &request, response).is_ok());
+        assert_eq!(poll_version(&mut peer, &system_config).version(), 5);
+    }
+
+    #[test]
+    fn test_v5_bloom_filter() {
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::V5,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer();
+        peer.update_config(system_config);
+        peer.our_id = ReferenceId::from_int(42);
+
+        let server_id = ServerId::new(&mut rand::thread_rng());
+        let mut server_filter = BloomFilter::new();
+        server_filter.add_id(&server_id);
+
+        let mut snapshot = None;
+        for i in 0..4 {
+            let request = poll_version(&mut peer, &system_config);
+            assert_eq!(request.version(), 5);
+            let (offset, length) = request
+                .untrusted_fields()
+                .find_map(|field| match field {
+                    ExtensionField::ReferenceIdRequest { offset, length } => {
+                        Some((*offset as usize, *length as usize))
+                    }
+                    _ => None,
+                })
+                .unwrap();
+
+            let mut response = NtpPacket::test().into_v5_request();
+            response.set_stratum(1);
+            response.set_mode(NtpAssociationMode::Server);
+            response.set_origin_timestamp(request.request_identifier().expected_origin_timestamp);
+            response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100 + i));
+            response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200 + i));
+            let mut data = response.serialize_without_encryption_vec().unwrap();
+            data.extend_from_slice(&0xF504_u16.to_be_bytes());
+            data.extend_from_slice(&(4 + length as u16).to_be_bytes());
+            data.extend_from_slice(&server_filter.as_bytes()[offset..][..length]);
+
+            snapshot = match peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &data,
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            ) {
+                Ok(Update::NewMeasurement(snapshot, _, _)) => Some(snapshot),
+                other => panic!("unexpected update {other:?}"),
+            };
+        }
+
+        // the filter is only used once all of it arrived
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot.bloom_filter, Some(server_filter));
+        let our_id = ServerId::new(&mut rand::thread_rng());
+        assert_eq!(snapshot.accept_synchronization(16, &our_id), Ok(()));
+        assert_eq!(
+            snapshot.accept_synchronization(16, &server_id),
+            Err(AcceptSynchronizationError::Loop)
+        );
+    }
+
+    #[test]
+    fn test_interleaved_exchange() {
+        let system_config = SystemConfig {
+            interleaved_mode: true,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer();
+        peer.update_config(system_config);
+
+        let at = |seconds: u64| NtpTimestamp::from_fixed_int(seconds << 32);
+        let respond = |peer: &mut Peer, origin, send: u64, recv: u64| {
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(origin);
+            packet.set_receive_timestamp(at(send + 100));
+            packet.set_transmit_timestamp(at(send + 200));
+
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &packet.serialize_without_encryption_vec().unwrap(),
+                NtpInstant::now(),
+                at(send),
+                at(recv),
+            )
+        };
+
+        // the first request can only be a basic one
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.origin_timestamp(), NtpTimestamp::default());
+        let origin = request.request_identifier().expected_origin_timestamp;
+        assert!(respond(&mut peer, origin, 0, 400).is_ok());
+
+        // the next refers to the first exchange
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.origin_timestamp(), at(100));
+        assert_eq!(request.receive_timestamp(), at(400));
+
+        // and the interleaved response carries a better transmit timestamp of
+        // the first response, completing the first exchange
+        let mut packet = NtpPacket::test();
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_origin_timestamp(at(400));
+        packet.set_receive_timestamp(at(1100));
+        packet.set_transmit_timestamp(at(150));
+        let update = peer
+            .handle_incoming(
+                &SystemSnapshot::default(),
+                &packet.serialize_without_encryption_vec().unwrap(),
+                NtpInstant::now(),
+                at(1000),
+                at(1400),
+            )
+            .unwrap();
+        let measurement = match update {
+            Update::NewMeasurement(_, measurement, _) => measurement,
+            Update::BareUpdate(_) => panic!("expected a measurement"),
+        };
+        assert_eq!(measurement.delay, NtpDuration::from_seconds(350.0));
+        assert_eq!(measurement.offset, NtpDuration::from_seconds(-75.0));
+
+        // a server not supporting interleaved mode answers in basic mode
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.origin_timestamp(), at(1100));
+        let origin = request.request_identifier().expected_origin_timestamp;
+        assert!(respond(&mut peer, origin, 2000, 2400).is_ok());
+    }
+
+    #[test]
+    fn test_symmetric_key() {
+        use crate::MacAlgorithm;
+
+        let key = SymmetricKey::new(MacAlgorithm::Sha1, b"secret".to_vec()).unwrap();
+        let mut peer = Peer {
+            symmetric_key: Some((3, key.clone())),
+            ..Peer::test_peer()
+        };
+        let system_config = SystemConfig::default();
+
+        let respond = |peer: &mut Peer, request: &NtpPacket, keyid: Option<u32>| {
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(request.request_identifier().expected_origin_timestamp);
+            packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+            packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+            if let Some(keyid) = keyid {
+                packet.add_mac(keyid, &key).unwrap();
+            }
+
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &packet.serialize_without_encryption_vec().unwrap(),
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+        };
+
+        let mut buf = [0; 1024];
+        let outgoing = peer
+            .generate_poll_message(&mut buf, &SystemSnapshot::default(), &system_config)
+            .unwrap();
+        let request = NtpPacket::deserialize(outgoing, &NoCipher).unwrap().0;
+        assert_eq!(request.verify_mac(outgoing, 3, &key), Ok(()));
+        let request = request.into_owned();
+
+        // responses that aren't authenticated with our key are ignored
+        assert!(matches!(
+            respond(&mut peer, &request, None),
+            Err(IgnoreReason::InvalidMac)
+        ));
+        assert!(matches!(
+            respond(&mut peer, &request, Some(4)),
+            Err(IgnoreReason::InvalidMac)
+        ));
+        assert!(respond(&mut peer, &request, Some(3)).is_ok());
+
+        // even kiss codes
+        let request = poll_version(&mut peer, &system_config);
+        let mut packet = NtpPacket::deny_response(request);
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        assert!(matches!(
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &data,
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            ),
+            Err(IgnoreReason::InvalidMac)
+        ));
+        packet.add_mac(3, &key).unwrap();
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        assert!(matches!(
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &data,
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            ),
+            Err(IgnoreReason::KissDemobilize)
+        ));
+
+        // crypto-NAKs are recognized without a valid MAC, but only as response
+        let request = poll_version(&mut peer, &system_config);
+        let data = NtpPacket::crypto_nak_response(request)
+            .serialize_without_encryption_vec()
+            .unwrap();
+        assert!(matches!(
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &data,
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            ),
+            Err(IgnoreReason::CryptoNak)
+        ));
+        poll_version(&mut peer, &system_config);
+        assert!(matches!(
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &data,
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            ),
+            Err(IgnoreReason::InvalidPacketTime)
+        ));
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let system_config = SystemConfig::default();
+        let mut peer = Peer::test_peer();
+        let broadcast = |transmit: u64| {
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Broadcast);
+            packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(transmit << 32));
+            packet.serialize_without_encryption_vec().unwrap()
+        };
+        let recv_time = NtpTimestamp::from_fixed_int(1001 << 32);
+
+        // broadcasts are only used once the delay to the server is known
+        assert!(!peer.broadcast_calibrated());
+        assert!(matches!(
+            peer.handle_broadcast(&broadcast(1000), NtpInstant::now(), recv_time),
+            Err(IgnoreReason::Uncalibrated)
+        ));
+
+        let request = poll_version(&mut peer, &system_config);
+        let delay = match respond_version(&mut peer, &request, NtpPacket::test()) {
+            Ok(Update::NewMeasurement(_, measurement, _)) => measurement.delay,
+            other => panic!("expected a measurement, got {other:?}"),
+        };
+        assert!(peer.broadcast_calibrated());
+
+        match peer.handle_broadcast(&broadcast(1000), NtpInstant::now(), recv_time) {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.delay, delay);
+                assert_eq!(
+                    measurement.offset,
+                    NtpDuration::from_seconds(-1.0) + delay / 2
+                );
+                assert_eq!(measurement.localtime, recv_time);
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+
+        // replayed broadcasts are ignored
+        assert!(matches!(
+            peer.handle_broadcast(&broadcast(1000), NtpInstant::now(), recv_time),
+            Err(IgnoreReason::InvalidPacketTime)
+        ));
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .handle_broadcast(&broadcast(1064), NtpInstant::now(), recv_time)
             .is_ok());
+
+        // as are packets that are not broadcasts
+        let mut packet = NtpPacket::test();
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(2000 << 32));
+        assert!(matches!(
+            peer.handle_broadcast(
+                &packet.serialize_without_encryption_vec().unwrap(),
+                NtpInstant::now(),
+                recv_time
+            ),
+            Err(IgnoreReason::InvalidMode)
+        ));
+    }
+
+    #[test]
+    fn test_upgrade_unsupported() {
+        let mut peer = Peer::test_peer();
+        let request = poll_version(&mut peer, &SystemConfig::default());
+        assert_eq!(request.version(), 4);
+        assert!(!request.offers_v5_upgrade());
+
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::Auto,
+            ..SystemConfig::default()
+        };
+        peer.update_config(system_config);
+
+        for _ in 0..V5_UPGRADE_TRIES {
+            let request = poll_version(&mut peer, &system_config);
+            assert!(request.offers_v5_upgrade());
+            assert!(respond_version(&mut peer, &request, NtpPacket::test()).is_ok());
+        }
+
+        // the server never offered to upgrade, so we stop asking
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 4);
+        assert!(!request.offers_v5_upgrade());
+    }
+
+    #[test]
+    fn test_v5_fallback() {
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::Auto,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer();
+        peer.update_config(system_config);
+        peer.protocol_version = ProtocolVersion::V5 { unanswered: 0 };
+
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 5);
+        let response = NtpPacket::test().into_v5_request();
+        assert!(respond_version(&mut peer, &request, response).is_ok());
+
+        // the server stops answering NTPv5 requests
+        for _ in 0..V5_FALLBACK_TRIES {
+            assert_eq!(poll_version(&mut peer, &system_config).version(), 5);
+        }
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 4);
+        assert!(!request.offers_v5_upgrade());
+    }
+
+    #[test]
+    fn test_v5_only() {
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::V5,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer();
+        peer.update_config(system_config);
+
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 5);
+        let response = NtpPacket::test().into_v5_request();
+        assert!(respond_version(&mut peer, &request, response).is_ok());
+
+        // without falling back when the server stops answering
+        for _ in 0..=V5_FALLBACK_TRIES {
+            assert_eq!(poll_version(&mut peer, &system_config).version(), 5);
+        }
+    }
+
+    #[test]
+    fn test_v5_kiss_codes() {
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::V5,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer();
+        peer.update_config(system_config);
+        let handle = |peer: &mut Peer, response: NtpPacket| {
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &response.serialize_without_encryption_vec().unwrap(),
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+        };
+
+        // a rate limited NTPv5 request makes us back off
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 5);
+        let min_poll = peer.remote_min_poll_interval;
+        assert!(matches!(
+            handle(&mut peer, NtpPacket::rate_limit_response(request)),
+            Err(IgnoreReason::KissIgnore)
+        ));
+        assert!(peer.remote_min_poll_interval > min_poll);
+
+        // and a denied one demobilizes the peer
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.version(), 5);
+        assert!(matches!(
+            handle(&mut peer, NtpPacket::deny_response(request)),
+            Err(IgnoreReason::KissDemobilize)
+        ));
+    }
+
+    #[test]
+    fn test_symmetric_association() {
+        let system_config = SystemConfig {
+            ntp_version: NtpVersion::Auto,
+            ..SystemConfig::default()
+        };
+        let mut peer = Peer::test_peer().with_mode(NtpAssociationMode::SymmetricActive);
+        peer.update_config(system_config);
+        let handle = |peer: &mut Peer, request: &NtpPacket, mode| {
+            let mut response = NtpPacket::test();
+            response.set_stratum(1);
+            response.set_mode(mode);
+            response.set_origin_timestamp(request.request_identifier().expected_origin_timestamp);
+            response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+            response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+            peer.handle_incoming(
+                &SystemSnapshot::default(),
+                &response.serialize_without_encryption_vec().unwrap(),
+                NtpInstant::now(),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+        };
+
+        // symmetric associations don't try to upgrade to NTPv5
+        let request = poll_version(&mut peer, &system_config);
+        assert_eq!(request.mode(), NtpAssociationMode::SymmetricActive);
+        assert_eq!(request.version(), 4);
+
+        assert!(matches!(
+            handle(&mut peer, &request, NtpAssociationMode::Server),
+            Err(IgnoreReason::InvalidMode)
+        ));
+        assert!(matches!(
+            handle(&mut peer, &request, NtpAssociationMode::SymmetricPassive),
+            Ok(Update::NewMeasurement(..))
+        ));
+
+        // and the passive side expects answers from the active one
+        let mut peer = Peer::test_peer().with_mode(NtpAssociationMode::SymmetricPassive);
+        let request = poll_version(&mut peer, &SystemConfig::default());
+        assert_eq!(request.mode(), NtpAssociationMode::SymmetricPassive);
+        assert!(matches!(
+            handle(&mut peer, &request, NtpAssociationMode::SymmetricActive),
+            Ok(Update::NewMeasurement(..))
+        ));
+    }
+
+    #[test]
+    fn test_startup_unreachable() {
+        let mut peer = Peer::test_peer();
+        let system = SystemSnapshot::default();
+        let mut buf = [0; 1024];
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
+        assert!(peer
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+            .is_ok());
+        peer.expire_request();
+        assert!(peer
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+            .is_ok());
+        peer.expire_request();
         assert!(matches!(
-            peer.generate_poll_message(&mut buf, system, &SystemConfig::default()),
+            peer.generate_poll_message(&mut buf, &system, &SystemConfig::default()),
+            Err(PollError::Unreachable)
+        ));
+    }
+
+    #[test]
+    fn test_single_request_in_flight() {
+        let mut peer = Peer::test_peer();
+        let system = SystemSnapshot::default();
+        let mut buf = [0; 1024];
+
+        // get the peer reachable first
+        peer.reach.received_packet();
+        peer.tries = STARTUP_TRIES_THRESHOLD;
+
+        for _ in 0..7 {
+            assert!(peer
+                .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+                .is_ok());
+            let outstanding = peer.current_request_identifier;
+            let reach = peer.reach;
+
+            // a non-responding source gets no further requests within the window
+            for _ in 0..3 {
+                assert!(matches!(
+                    peer.generate_poll_message(&mut buf, &system, &SystemConfig::default()),
+                    Err(PollError::RequestInFlight)
+                ));
+            }
+            assert_eq!(peer.current_request_identifier, outstanding);
+            assert_eq!(peer.reach, reach);
+            assert!(peer.reach.is_reachable());
+
+            peer.expire_request();
+        }
+
+        // after the window, each request counted toward unreachability
+        assert!(peer
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+            .is_ok());
+        assert!(!peer.reach.is_reachable());
+        peer.expire_request();
+        assert!(matches!(
+            peer.generate_poll_message(&mut buf, &system, &SystemConfig::default()),
             Err(PollError::Unreachable)
         ));
     }
@@ -794,7 +2302,7 @@ mod test {
         let system = SystemSnapshot::default();
         let mut buf = [0; 1024];
         let outgoingbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
         let mut packet = NtpPacket::test();
@@ -806,7 +2314,7 @@ mod test {
         packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -815,31 +2323,39 @@ mod test {
             .is_ok());
 
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .is_ok());
+        peer.expire_request();
         assert!(matches!(
-            peer.generate_poll_message(&mut buf, system, &SystemConfig::default()),
+            peer.generate_poll_message(&mut buf, &system, &SystemConfig::default()),
             Err(PollError::Unreachable)
         ));
     }
@@ -852,7 +2368,7 @@ mod test {
         let system = SystemSnapshot::default();
         let mut buf = [0; 1024];
         let outgoingbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
         let mut packet = NtpPacket::test();
@@ -864,7 +2380,7 @@ mod test {
         packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -875,7 +2391,7 @@ mod test {
         packet.set_stratum(0);
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -884,6 +2400,47 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_inconsistent_server_timestamps() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer();
+
+        let system = SystemSnapshot::default();
+        let mut buf = [0; 1024];
+        let outgoingbuf = peer
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+            .unwrap();
+        let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
+        let mut packet = NtpPacket::test();
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_origin_timestamp(outgoing.transmit_timestamp());
+        packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(200));
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(100));
+        assert!(matches!(
+            peer.handle_incoming(
+                &system,
+                &packet.serialize_without_encryption_vec().unwrap(),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            ),
+            Err(IgnoreReason::InconsistentServerTimestamps)
+        ));
+
+        // the request is still awaiting a proper response
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+        assert!(peer
+            .handle_incoming(
+                &system,
+                &packet.serialize_without_encryption_vec().unwrap(),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            )
+            .is_ok());
+    }
+
     #[test]
     fn test_handle_kod() {
         let base = NtpInstant::now();
@@ -895,7 +2452,7 @@ mod test {
         packet.set_mode(NtpAssociationMode::Server);
         assert!(!matches!(
             peer.handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -908,7 +2465,7 @@ mod test {
         let system = SystemSnapshot::default();
         let mut buf = [0; 1024];
         let outgoingbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
         packet.set_reference_id(ReferenceId::KISS_RSTR);
@@ -916,7 +2473,7 @@ mod test {
         packet.set_mode(NtpAssociationMode::Server);
         assert!(matches!(
             peer.handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -931,7 +2488,7 @@ mod test {
         packet.set_mode(NtpAssociationMode::Server);
         assert!(!matches!(
             peer.handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -942,8 +2499,9 @@ mod test {
 
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
+        peer.expire_request();
         let outgoingbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
         packet.set_reference_id(ReferenceId::KISS_DENY);
@@ -951,7 +2509,7 @@ mod test {
         packet.set_mode(NtpAssociationMode::Server);
         assert!(matches!(
             peer.handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -968,7 +2526,7 @@ mod test {
         packet.set_mode(NtpAssociationMode::Server);
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -983,8 +2541,9 @@ mod test {
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
         let mut buf = [0; 1024];
+        peer.expire_request();
         let outgoingbuf = peer
-            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
             .unwrap();
         let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
         packet.set_reference_id(ReferenceId::KISS_RATE);
@@ -992,7 +2551,7 @@ mod test {
         packet.set_mode(NtpAssociationMode::Server);
         assert!(peer
             .handle_incoming(
-                system,
+                &system,
                 &packet.serialize_without_encryption_vec().unwrap(),
                 base + Duration::from_secs(1),
                 NtpTimestamp::from_fixed_int(0),
@@ -1002,4 +2561,177 @@ mod test {
         assert!(peer.remote_min_poll_interval > old_poll_interval);
         assert!(peer.remote_min_poll_interval >= old_remote_interval);
     }
+
+    #[test]
+    fn test_kod_retries_capped() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer();
+        let system = SystemSnapshot::default();
+
+        // the server responds with kiss code `kiss`, or the time when there is none
+        let respond = |peer: &mut Peer, kiss: Option<ReferenceId>| {
+            let mut buf = [0; 1024];
+            peer.expire_request();
+            let outgoingbuf = peer
+                .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+                .unwrap();
+            let outgoing = NtpPacket::deserialize(outgoingbuf, &NoCipher).unwrap().0;
+            let mut packet = NtpPacket::test();
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            match kiss {
+                Some(reference_id) => packet.set_reference_id(reference_id),
+                None => {
+                    packet.set_stratum(1);
+                    packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+                    packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+                }
+            }
+
+            peer.handle_incoming(
+                &system,
+                &packet.serialize_without_encryption_vec().unwrap(),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+        };
+
+        let unknown = ReferenceId::from_bytes(*b"AUTH");
+        assert!(matches!(
+            respond(&mut peer, Some(unknown)),
+            Err(IgnoreReason::KissIgnore)
+        ));
+        let snapshot = PeerSnapshot::from_peer(&peer);
+        assert_eq!(snapshot.last_kiss, Some(KissCode::Unknown(unknown)));
+        assert_eq!(snapshot.kiss_count, 1);
+
+        // a valid response resets the retries
+        assert!(respond(&mut peer, None).is_ok());
+        assert_eq!(peer.kiss_retries, 0);
+
+        // rate limiting while we can't slow down any further
+        peer.remote_min_poll_interval = peer.system_config.poll_limits.max;
+        for _ in 1..MAX_KISS_RETRIES {
+            assert!(matches!(
+                respond(&mut peer, Some(ReferenceId::KISS_RATE)),
+                Err(IgnoreReason::KissIgnore)
+            ));
+        }
+        assert!(matches!(
+            respond(&mut peer, Some(ReferenceId::KISS_RATE)),
+            Err(IgnoreReason::KissDemobilize)
+        ));
+        let snapshot = PeerSnapshot::from_peer(&peer);
+        assert_eq!(snapshot.last_kiss, Some(KissCode::Rate));
+        assert_eq!(snapshot.kiss_count, 1 + MAX_KISS_RETRIES as u32);
+    }
+
+    fn nts_data(cookies: usize) -> PeerNtsData {
+        let mut stash = CookieStash::default();
+        for i in 0..cookies {
+            stash.store(vec![i as u8; 64]);
+        }
+
+        PeerNtsData {
+            cookies: stash,
+            c2s: Box::new(AesSivCmac256::new([0; 32].into())),
+            s2c: Box::new(AesSivCmac256::new([0; 32].into())),
+            algorithm: AeadAlgorithm::default(),
+            uid_prefix: None,
+            consecutive_naks: 0,
+        }
+    }
+
+    #[test]
+    fn test_nts_cookies_replenished() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer();
+        peer.nts = Some(Box::new(nts_data(2)));
+        let system = SystemSnapshot::default();
+        let cipher = AesSivCmac256::new([0; 32].into());
+
+        // one cookie is sent, and placeholders ask for enough new ones to fill the stash
+        let mut buf = [0; 1024];
+        let outgoingbuf = peer
+            .generate_poll_message(&mut buf, &system, &SystemConfig::default())
+            .unwrap();
+        let outgoing = NtpPacket::deserialize(outgoingbuf, &cipher).unwrap().0;
+        let placeholders = outgoing
+            .authenticated_fields()
+            .filter(|field| matches!(field, ExtensionField::NtsCookiePlaceholder { .. }))
+            .count();
+        assert_eq!(placeholders, 6);
+        let uid = outgoing
+            .authenticated_fields()
+            .find_map(|field| match field {
+                ExtensionField::UniqueIdentifier(uid) => Some(uid.clone()),
+                _ => None,
+            })
+            .unwrap();
+
+        // the server returns a cookie for the one we used and each placeholder
+        let mut response = NtpPacketBuilder::new()
+            .mode(NtpAssociationMode::Server)
+            .stratum(1)
+            .origin_timestamp(outgoing.transmit_timestamp())
+            .receive_timestamp(NtpTimestamp::from_fixed_int(100))
+            .transmit_timestamp(NtpTimestamp::from_fixed_int(200))
+            .authenticated_field(ExtensionField::UniqueIdentifier(uid));
+        for i in 0..=placeholders {
+            response =
+                response.encrypted_field(ExtensionField::NtsCookie(vec![i as u8; 64].into()));
+        }
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        response
+            .build()
+            .unwrap()
+            .serialize(&mut cursor, &cipher)
+            .unwrap();
+        let size = cursor.position() as usize;
+
+        assert!(peer
+            .handle_incoming(
+                &system,
+                &buf[..size],
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            )
+            .is_ok());
+        assert_eq!(peer.nts.as_ref().unwrap().cookies.len(), 8);
+    }
+
+    #[test]
+    fn test_nts_nak_discards_single_cookie() {
+        let mut nts = nts_data(4);
+
+        // the cookie used for the request is gone, the others remain
+        assert!(nts.poll_message(PollInterval::default()).is_some());
+        assert!(!nts.handle_nak(NtsNakRecovery::DiscardCookie));
+        assert_eq!(nts.cookies.len(), 3);
+
+        // a valid response in between means the earlier cookie alone was bad
+        nts.consecutive_naks = 0;
+        assert!(nts.poll_message(PollInterval::default()).is_some());
+        assert!(!nts.handle_nak(NtsNakRecovery::DiscardCookie));
+        assert_eq!(nts.cookies.len(), 2);
+
+        // a second nak in a row invalidates all cookies
+        assert!(nts.poll_message(PollInterval::default()).is_some());
+        assert!(nts.handle_nak(NtsNakRecovery::DiscardCookie));
+        assert!(nts.cookies.is_empty());
+        assert!(nts.poll_message(PollInterval::default()).is_none());
+    }
+
+    #[test]
+    fn test_nts_nak_rekey() {
+        let mut nts = nts_data(4);
+
+        assert!(nts.poll_message(PollInterval::default()).is_some());
+        assert!(nts.handle_nak(NtsNakRecovery::Rekey));
+        assert!(nts.cookies.is_empty());
+        assert!(nts.poll_message(PollInterval::default()).is_none());
+    }
 }
@@ -0,0 +1,84 @@
+use crate::{Measurement, NtpDuration, RefClockReading};
+
+/// Combine a coarse offset measurement from an NTP source with the edge of a
+/// pulse-per-second (PPS) signal, giving a much more precise offset.
+///
+/// A PPS signal marks the start of each second very accurately, but doesn't tell
+/// which second it is. For a PPS reference clock, the timestamp of the reading is the
+/// local time at which the edge was observed. The NTP measurement is used to find
+/// which second the edge marks, after which the offset follows from the edge alone,
+/// with the dispersion of the PPS signal as its error. This requires a measurement
+/// taken recently enough that the local clock hasn't drifted significantly since.
+///
+/// Returns `None` when the NTP measurement is too uncertain to tell which second the
+/// edge marks, that is, when its offset interval straddles the halfway point
+/// between two seconds.
+pub fn pps_offset(measurement: &Measurement, pps: &RefClockReading) -> Option<NtpDuration> {
+    let (low, high) = measurement.offset_interval();
+
+    let second = (pps.timestamp + low).round_to_second();
+    if second != (pps.timestamp + high).round_to_second() {
+        return None;
+    }
+
+    Some(second - pps.timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NtpInstant, NtpTimestamp, ReferenceId};
+
+    use super::*;
+
+    fn measurement(offset: f64, delay: f64) -> Measurement {
+        Measurement {
+            delay: NtpDuration::from_seconds(delay),
+            offset: NtpDuration::from_seconds(offset),
+            localtime: NtpTimestamp::from_fixed_int(1000 << 32),
+            monotime: NtpInstant::now(),
+            remote_precision: NtpDuration::ZERO,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::ZERO,
+        }
+    }
+
+    fn pps_edge(offset: f64) -> RefClockReading {
+        // the edge marks the start of second 1000, which is seen locally
+        // shifted by the offset of the local clock
+        RefClockReading {
+            timestamp: NtpTimestamp::from_fixed_int(1000 << 32) - NtpDuration::from_seconds(offset),
+            dispersion: NtpDuration::from_seconds(1e-6),
+            reference_id: ReferenceId::from_bytes(*b"PPS\0"),
+        }
+    }
+
+    #[test]
+    fn test_pps_tightens_offset() {
+        let true_offset = 0.312_345;
+        let coarse = measurement(0.3, 0.1);
+        let edge = pps_edge(true_offset);
+
+        let offset = pps_offset(&coarse, &edge).unwrap();
+        assert!((offset.to_seconds() - true_offset).abs() < 1e-9);
+
+        // much closer than the ntp measurement on its own
+        assert!(
+            (offset.to_seconds() - true_offset).abs()
+                < (coarse.offset.to_seconds() - true_offset).abs() / 1000.
+        );
+
+        // also when the local clock is ahead, or off by more than a second
+        let edge = pps_edge(-2.25);
+        let offset = pps_offset(&measurement(-2.2, 0.1), &edge).unwrap();
+        assert!((offset.to_seconds() + 2.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pps_ambiguous_second() {
+        let edge = pps_edge(0.5);
+
+        // the ntp measurement can't tell whether the edge is at second 999 or 1000
+        assert_eq!(pps_offset(&measurement(0.0, 0.2), &edge), None);
+        assert_eq!(pps_offset(&measurement(0.0, 1.5), &edge), None);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    io::Cursor,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    peer::PeerNtsData, validate_response, Measurement, NtpClock, NtpInstant, NtpPacket,
+    PollInterval, RejectReason, ValidationPolicy,
+};
+
+// Maximum size of udp packet we handle
+const MAX_PACKET_SIZE: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SntpError<E: std::error::Error> {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not read the clock: {0}")]
+    Clock(#[source] E),
+    #[error("Ran out of nts cookies")]
+    OutOfCookies,
+    #[error("no valid response received before the timeout")]
+    Timeout,
+    #[error("response rejected: {0:?}")]
+    Rejected(RejectReason),
+}
+
+pub type SntpResult<E> = Result<Measurement, SntpError<E>>;
+
+/// A blocking client performing a single SNTP request/response exchange.
+///
+/// This does not need an async runtime: the exchange uses a
+/// [`std::net::UdpSocket`], and the response goes through the same validation
+/// as the responses received by the daemon.
+#[derive(Debug, Clone)]
+pub struct SntpClient<C: NtpClock> {
+    clock: C,
+    timeout: Duration,
+    policy: ValidationPolicy,
+}
+
+impl<C: NtpClock> SntpClient<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            timeout: Duration::from_secs(5),
+            policy: ValidationPolicy::default(),
+        }
+    }
+
+    /// Maximum time to wait for a valid response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: ValidationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Query `server` once, and measure our offset to it.
+    ///
+    /// With `nts`, the request uses one of its cookies and the response must be
+    /// authenticated. Any new cookies sent by the server are stored in `nts`.
+    ///
+    /// Datagrams that are not a response to our request are skipped; the first
+    /// response to our request determines the result.
+    pub fn query(
+        &self,
+        server: impl ToSocketAddrs,
+        mut nts: Option<&mut PeerNtsData>,
+    ) -> Result<Measurement, SntpError<C::Error>> {
+        let server = server.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no server address")
+        })?;
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+
+        let (packet, identifier) = match &mut nts {
+            Some(nts) => nts
+                .poll_message(PollInterval::default())
+                .ok_or(SntpError::OutOfCookies)?,
+            None => NtpPacket::poll_message(PollInterval::default()),
+        };
+
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, &nts.as_ref().map(|nts| nts.c2s.as_ref()))?;
+        let used = cursor.position() as usize;
+
+        let send_time = self.clock.now().map_err(SntpError::Clock)?;
+        socket.send(&buf[..used])?;
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(SntpError::Timeout);
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            let size = match socket.recv(&mut buf) {
+                Ok(size) => size,
+                Err(e) => match e.kind() {
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                        return Err(SntpError::Timeout)
+                    }
+                    _ => return Err(e.into()),
+                },
+            };
+            let recv_time = self.clock.now().map_err(SntpError::Clock)?;
+            let local_clock_time = NtpInstant::now();
+
+            let cipher = nts.as_ref().map(|nts| nts.s2c.as_ref());
+            let response = match NtpPacket::deserialize(&buf[..size], &cipher) {
+                Ok((response, _)) => response,
+                Err(e) => {
+                    tracing::debug!("received invalid packet: {}", e);
+                    continue;
+                }
+            };
+
+            match validate_response(
+                &identifier,
+                &response,
+                &self.policy,
+                send_time,
+                recv_time,
+                local_clock_time,
+            ) {
+                // not a response to our request
+                Err(RejectReason::InvalidOrigin) => continue,
+                Err(reason) => return Err(SntpError::Rejected(reason)),
+                Ok(measurement) => {
+                    if let Some(nts) = nts.as_mut() {
+                        for cookie in response.new_cookies() {
+                            if !nts.cookies.store(cookie) {
+                                tracing::warn!(
+                                    "Received cookie with unexpected length, ignoring it"
+                                );
+                            }
+                        }
+                    }
+
+                    return Ok(measurement);
+                }
+            }
+        }
+    }
+
+    /// Compare the local clock to each of the reference `servers`, without
+    /// adjusting it. The servers are queried one after the other, so this takes
+    /// at most the timeout for each server.
+    pub fn compare_to_references<S: ToSocketAddrs>(
+        &self,
+        servers: impl IntoIterator<Item = S>,
+    ) -> Vec<(S, SntpResult<C::Error>)> {
+        servers
+            .into_iter()
+            .map(|server| {
+                let result = self.query(&server, None);
+                (server, result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NtpDuration, NtpLeapIndicator, NtpTimestamp, SystemSnapshot, TimeSnapshot};
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct TestClock {
+        now: NtpTimestamp,
+    }
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(self.now)
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<NtpTimestamp, Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by sntp client");
+        }
+    }
+
+    /// A server answering a single request with `system`, `offset` ahead of the client
+    fn mock_server(system: SystemSnapshot, offset: u64) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET_SIZE];
+            let (size, client) = socket.recv_from(&mut buf).unwrap();
+            let clock = TestClock {
+                now: NtpTimestamp::from_fixed_int((10 + offset) << 32),
+            };
+            let request = NtpPacket::deserialize(&buf[..size], &crate::NoCipher)
+                .unwrap()
+                .0;
+
+            // a stray packet that doesn't answer the request is skipped
+            socket
+                .send_to(
+                    &NtpPacket::test()
+                        .serialize_without_encryption_vec()
+                        .unwrap(),
+                    client,
+                )
+                .unwrap();
+
+            let response = NtpPacket::timestamp_response(&system, request, clock.now, &clock);
+            socket
+                .send_to(
+                    &response.serialize_without_encryption_vec().unwrap(),
+                    client,
+                )
+                .unwrap();
+        });
+
+        addr
+    }
+
+    fn client() -> SntpClient<TestClock> {
+        SntpClient::new(TestClock {
+            now: NtpTimestamp::from_fixed_int(10 << 32),
+        })
+        .with_timeout(Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_sntp_query() {
+        let system = SystemSnapshot {
+            stratum: 1,
+            time_snapshot: TimeSnapshot {
+                leap_indicator: NtpLeapIndicator::NoWarning,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = mock_server(system, 2);
+
+        let measurement = client().query(server, None).unwrap();
+        assert_eq!(measurement.offset, NtpDuration::from_seconds(2.0));
+        // the delay is only limited by our precision
+        assert!(measurement.delay <= ValidationPolicy::default().precision);
+    }
+
+    #[test]
+    fn test_sntp_query_unsynchronized() {
+        // the default system is not synchronized, and advertises stratum 16
+        let server = mock_server(SystemSnapshot::default(), 0);
+
+        assert!(matches!(
+            client().query(server, None),
+            Err(SntpError::Rejected(RejectReason::InvalidStratum))
+        ));
+    }
+
+    #[test]
+    fn test_compare_to_references() {
+        let system = SystemSnapshot {
+            stratum: 1,
+            time_snapshot: TimeSnapshot {
+                leap_indicator: NtpLeapIndicator::NoWarning,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let first = mock_server(system.clone(), 2);
+        let second = mock_server(system, 3);
+        // a socket that never answers
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let silent = silent.local_addr().unwrap();
+
+        let client = client().with_timeout(Duration::from_millis(200));
+        let results = client.compare_to_references([first, silent, second]);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, first);
+        assert_eq!(
+            results[0].1.as_ref().unwrap().offset,
+            NtpDuration::from_seconds(2.0)
+        );
+        assert_eq!(results[1].0, silent);
+        assert!(matches!(results[1].1, Err(SntpError::Timeout)));
+        assert_eq!(results[2].0, second);
+        assert_eq!(
+            results[2].1.as_ref().unwrap().offset,
+            NtpDuration::from_seconds(3.0)
+        );
+    }
+
+    #[test]
+    fn test_sntp_query_timeout() {
+        // a socket that never answers
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        let client = client().with_timeout(Duration::from_millis(10));
+        assert!(matches!(
+            client.query(socket.local_addr().unwrap(), None),
+            Err(SntpError::Timeout)
+        ));
+    }
+}
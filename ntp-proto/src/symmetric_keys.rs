@@ -0,0 +1,490 @@
+use std::{collections::HashMap, str::FromStr};
+
+use aes::Aes128;
+use cmac::{digest::KeyInit, Cmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use sha2::Sha256;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::packet::constant_time_eq;
+
+/// Keys given as printable ASCII can be at most this long, longer keys are
+/// given in hexadecimal
+const MAX_ASCII_KEY_LENGTH: usize = 20;
+
+/// Algorithm of a symmetric key, used for the MACs of RFC 5905
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    Md5,
+    Sha1,
+    /// SHA-256 of the key and data, like the other legacy digests but with a
+    /// 32 byte digest
+    Sha256,
+    /// AES-128-CMAC, which RFC 8573 recommends over the legacy digests
+    Aes128Cmac,
+}
+
+impl MacAlgorithm {
+    pub(crate) const ALL: [MacAlgorithm; 4] = [
+        MacAlgorithm::Md5,
+        MacAlgorithm::Sha1,
+        MacAlgorithm::Sha256,
+        MacAlgorithm::Aes128Cmac,
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Some(MacAlgorithm::Md5),
+            "SHA1" | "SHA-1" => Some(MacAlgorithm::Sha1),
+            "SHA256" | "SHA-256" => Some(MacAlgorithm::Sha256),
+            "AES128CMAC" | "AES-128-CMAC" => Some(MacAlgorithm::Aes128Cmac),
+            _ => None,
+        }
+    }
+
+    /// Length of the digest in a MAC, in bytes
+    pub fn digest_len(self) -> usize {
+        match self {
+            MacAlgorithm::Md5 => 16,
+            MacAlgorithm::Sha1 => 20,
+            MacAlgorithm::Sha256 => 32,
+            MacAlgorithm::Aes128Cmac => 16,
+        }
+    }
+}
+
+/// A symmetric key shared between a client and a server. The key material is
+/// erased from memory once the key is dropped.
+#[derive(Clone)]
+pub struct SymmetricKey {
+    key: KeyMaterial,
+}
+
+#[derive(Clone)]
+enum KeyMaterial {
+    Md5(Vec<u8>),
+    Sha1(Vec<u8>),
+    Sha256(Vec<u8>),
+    Aes128Cmac([u8; 16]),
+}
+
+impl KeyMaterial {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            KeyMaterial::Md5(key) | KeyMaterial::Sha1(key) | KeyMaterial::Sha256(key) => key,
+            KeyMaterial::Aes128Cmac(key) => key,
+        }
+    }
+}
+
+impl PartialEq for SymmetricKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.algorithm() == other.algorithm()
+            && constant_time_eq(self.key.as_bytes(), other.key.as_bytes())
+    }
+}
+
+impl Eq for SymmetricKey {}
+
+impl std::fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("algorithm", &self.algorithm())
+            .finish()
+    }
+}
+
+impl Drop for SymmetricKey {
+    fn drop(&mut self) {
+        match &mut self.key {
+            KeyMaterial::Md5(key) | KeyMaterial::Sha1(key) | KeyMaterial::Sha256(key) => {
+                key.zeroize()
+            }
+            KeyMaterial::Aes128Cmac(key) => key.zeroize(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("invalid key length for {algorithm:?}")]
+pub struct InvalidKeyLength {
+    pub algorithm: MacAlgorithm,
+}
+
+impl SymmetricKey {
+    /// A key for `algorithm`. AES-128-CMAC keys must be 16 bytes long, and keys
+    /// for the legacy digests must not be empty.
+    pub fn new(algorithm: MacAlgorithm, mut key: Vec<u8>) -> Result<Self, InvalidKeyLength> {
+        let key = match algorithm {
+            MacAlgorithm::Md5 if !key.is_empty() => KeyMaterial::Md5(key),
+            MacAlgorithm::Sha1 if !key.is_empty() => KeyMaterial::Sha1(key),
+            MacAlgorithm::Sha256 if !key.is_empty() => KeyMaterial::Sha256(key),
+            MacAlgorithm::Aes128Cmac if key.len() == 16 => {
+                let mut material = [0; 16];
+                material.copy_from_slice(&key);
+                key.zeroize();
+                KeyMaterial::Aes128Cmac(material)
+            }
+            _ => {
+                key.zeroize();
+                return Err(InvalidKeyLength { algorithm });
+            }
+        };
+
+        Ok(SymmetricKey { key })
+    }
+
+    pub fn algorithm(&self) -> MacAlgorithm {
+        match self.key {
+            KeyMaterial::Md5(_) => MacAlgorithm::Md5,
+            KeyMaterial::Sha1(_) => MacAlgorithm::Sha1,
+            KeyMaterial::Sha256(_) => MacAlgorithm::Sha256,
+            KeyMaterial::Aes128Cmac(_) => MacAlgorithm::Aes128Cmac,
+        }
+    }
+
+    /// The digest of a MAC over `data`. For the legacy digest algorithms this is
+    /// the digest of the key followed by the data.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match &self.key {
+            KeyMaterial::Md5(key) => Md5::new()
+                .chain_update(key)
+                .chain_update(data)
+                .finalize()
+                .to_vec(),
+            KeyMaterial::Sha1(key) => Sha1::new()
+                .chain_update(key)
+                .chain_update(data)
+                .finalize()
+                .to_vec(),
+            KeyMaterial::Sha256(key) => Sha256::new()
+                .chain_update(key)
+                .chain_update(data)
+                .finalize()
+                .to_vec(),
+            KeyMaterial::Aes128Cmac(key) => {
+                let mut mac = <Cmac<Aes128> as KeyInit>::new(key.into());
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Whether `digest` is the digest of a MAC over `data` with this key
+    pub fn verify(&self, data: &[u8], digest: &[u8]) -> bool {
+        constant_time_eq(&self.digest(data), digest)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum MacError {
+    #[error("packet has no MAC")]
+    Missing,
+    #[error("MAC with unexpected key id {0}")]
+    UnknownKey(u32),
+    #[error("MAC does not match the packet")]
+    Mismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum KeysFileError {
+    #[error("line {line}: expected a key id, digest algorithm and key")]
+    MissingField { line: usize },
+    #[error("line {line}: invalid key id {id:?}, must be between 1 and 65535")]
+    InvalidKeyId { line: usize, id: String },
+    #[error("line {line}: duplicate key id {id}")]
+    DuplicateKeyId { line: usize, id: u32 },
+    #[error("line {line}: unsupported digest algorithm {algorithm:?}")]
+    UnsupportedAlgorithm { line: usize, algorithm: String },
+    #[error(
+        "line {line}: the key must be printable ASCII or hexadecimal, of 16 bytes for AES-128-CMAC"
+    )]
+    InvalidKey { line: usize },
+}
+
+/// The symmetric keys of a keys file, by key id.
+///
+/// The format is that of the `ntp.keys` file of the reference implementation:
+/// each line has a key id between 1 and 65535, the algorithm (`AES128CMAC`,
+/// `MD5`, `SHA1` or `SHA256`) and the key, which is either printable ASCII of up to 20
+/// characters or hexadecimal. Everything after a `#` is a comment.
+#[derive(Debug, Clone, Default)]
+pub struct SymmetricKeys {
+    keys: HashMap<u32, SymmetricKey>,
+}
+
+impl SymmetricKeys {
+    pub fn get(&self, id: u32) -> Option<&SymmetricKey> {
+        self.keys.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl FromStr for SymmetricKeys {
+    type Err = KeysFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let content = line.split('#').next().unwrap_or_default();
+            let mut fields = content.split_whitespace();
+
+            let id = match fields.next() {
+                Some(id) => id,
+                None => continue,
+            };
+            let (algorithm, key) = match (fields.next(), fields.next()) {
+                (Some(algorithm), Some(key)) => (algorithm, key),
+                _ => return Err(KeysFileError::MissingField { line: line_number }),
+            };
+
+            let id = match id.parse::<u32>() {
+                Ok(parsed) if (1..=65535).contains(&parsed) => parsed,
+                _ => {
+                    return Err(KeysFileError::InvalidKeyId {
+                        line: line_number,
+                        id: id.to_owned(),
+                    })
+                }
+            };
+
+            let algorithm = MacAlgorithm::from_name(algorithm).ok_or_else(|| {
+                KeysFileError::UnsupportedAlgorithm {
+                    line: line_number,
+                    algorithm: algorithm.to_owned(),
+                }
+            })?;
+
+            let key = parse_key(key)
+                .and_then(|key| SymmetricKey::new(algorithm, key).ok())
+                .ok_or(KeysFileError::InvalidKey { line: line_number })?;
+
+            if keys.insert(id, key).is_some() {
+                return Err(KeysFileError::DuplicateKeyId {
+                    line: line_number,
+                    id,
+                });
+            }
+        }
+
+        Ok(SymmetricKeys { keys })
+    }
+}
+
+fn parse_key(key: &str) -> Option<Vec<u8>> {
+    if key.len() <= MAX_ASCII_KEY_LENGTH {
+        return key
+            .bytes()
+            .all(|b| b.is_ascii_graphic())
+            .then(|| key.as_bytes().to_vec());
+    }
+
+    // an odd number of digits fails at the last byte
+    (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(key.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys_file() {
+        let keys: SymmetricKeys = "
+# a comment
+1 MD5 secret
+2 SHA1 0123456789abcdef0123456789abcdef01234567 # hex key
+65535 M ab#cd
+"
+        .parse()
+        .unwrap();
+
+        assert_eq!(keys.len(), 3);
+        assert_eq!(
+            keys.get(1),
+            Some(&SymmetricKey::new(MacAlgorithm::Md5, b"secret".to_vec()).unwrap())
+        );
+        let key = keys.get(2).unwrap();
+        assert_eq!(key.algorithm(), MacAlgorithm::Sha1);
+        assert_eq!(key.key.as_bytes()[..4], [0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(key.key.as_bytes().len(), 20);
+        // the comment starts in the middle of the key
+        assert_eq!(keys.get(65535).unwrap().key.as_bytes(), b"ab");
+        assert!(keys.get(3).is_none());
+    }
+
+    #[test]
+    fn test_invalid_keys_file() {
+        let parse = |s: &str| s.parse::<SymmetricKeys>().unwrap_err();
+
+        assert_eq!(parse("1 MD5"), KeysFileError::MissingField { line: 1 });
+        assert_eq!(
+            parse("\n0 MD5 secret"),
+            KeysFileError::InvalidKeyId {
+                line: 2,
+                id: "0".into()
+            }
+        );
+        assert_eq!(
+            parse("65536 MD5 secret"),
+            KeysFileError::InvalidKeyId {
+                line: 1,
+                id: "65536".into()
+            }
+        );
+        assert_eq!(
+            parse("1 SHA512 secret"),
+            KeysFileError::UnsupportedAlgorithm {
+                line: 1,
+                algorithm: "SHA512".into()
+            }
+        );
+        assert_eq!(
+            parse("1 MD5 0123456789abcdef0123456789abcdefg1234567"),
+            KeysFileError::InvalidKey { line: 1 }
+        );
+        assert_eq!(
+            parse("1 MD5 secret\n1 SHA1 other"),
+            KeysFileError::DuplicateKeyId { line: 2, id: 1 }
+        );
+    }
+
+    #[test]
+    fn test_digest() {
+        // RFC 1321, RFC 3174 and FIPS 180-2 test vectors, with the key as prefix of the data
+        let key = SymmetricKey::new(MacAlgorithm::Md5, b"a".to_vec()).unwrap();
+        let digest = key.digest(b"bc");
+        assert_eq!(digest.len(), MacAlgorithm::Md5.digest_len());
+        assert_eq!(
+            digest,
+            [
+                0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1,
+                0x7f, 0x72
+            ]
+        );
+        assert!(key.verify(b"bc", &digest));
+        assert!(!key.verify(b"bd", &digest));
+
+        let key = SymmetricKey::new(MacAlgorithm::Sha1, b"a".to_vec()).unwrap();
+        let digest = key.digest(b"bc");
+        assert_eq!(digest.len(), MacAlgorithm::Sha1.digest_len());
+        assert_eq!(
+            digest,
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+        assert!(key.verify(b"bc", &digest));
+
+        let key = SymmetricKey::new(MacAlgorithm::Sha256, b"a".to_vec()).unwrap();
+        let digest = key.digest(b"bc");
+        assert_eq!(digest.len(), MacAlgorithm::Sha256.digest_len());
+        assert_eq!(
+            digest,
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad
+            ]
+        );
+        assert!(key.verify(b"bc", &digest));
+    }
+
+    #[test]
+    fn test_parse_cmac_keys() {
+        let keys: SymmetricKeys = "
+1 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c
+2 aes-128-cmac 0123456789abcdef
+3 MD5 secret
+"
+        .parse()
+        .unwrap();
+
+        assert_eq!(keys.len(), 3);
+        let key = keys.get(1).unwrap();
+        assert_eq!(key.algorithm(), MacAlgorithm::Aes128Cmac);
+        assert_eq!(key.key.as_bytes()[..4], [0x2b, 0x7e, 0x15, 0x16]);
+        assert_eq!(key.key.as_bytes().len(), 16);
+        assert_eq!(keys.get(2).unwrap().key.as_bytes(), b"0123456789abcdef");
+        // legacy keys can be used side by side
+        assert_eq!(keys.get(3).unwrap().algorithm(), MacAlgorithm::Md5);
+
+        // AES-128-CMAC needs a key of exactly 16 bytes
+        let parse = |s: &str| s.parse::<SymmetricKeys>().unwrap_err();
+        assert_eq!(
+            parse("1 AES128CMAC secret"),
+            KeysFileError::InvalidKey { line: 1 }
+        );
+        assert_eq!(
+            parse("1 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c2b"),
+            KeysFileError::InvalidKey { line: 1 }
+        );
+    }
+
+    #[test]
+    fn test_invalid_key_length() {
+        for (algorithm, key) in [
+            (MacAlgorithm::Md5, &b""[..]),
+            (MacAlgorithm::Sha1, b""),
+            (MacAlgorithm::Aes128Cmac, b""),
+            (MacAlgorithm::Aes128Cmac, b"fifteen byte ke"),
+            (MacAlgorithm::Aes128Cmac, b"seventeen byte ke"),
+        ] {
+            assert_eq!(
+                SymmetricKey::new(algorithm, key.to_vec()),
+                Err(InvalidKeyLength { algorithm })
+            );
+        }
+
+        assert!(SymmetricKey::new(MacAlgorithm::Aes128Cmac, b"sixteen byte key".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_cmac_digest() {
+        // RFC 4493 test vectors
+        let key = SymmetricKey::new(
+            MacAlgorithm::Aes128Cmac,
+            vec![
+                0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+                0x4f, 0x3c,
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            key.digest(&[]),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+        let data = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        let digest = key.digest(&data);
+        assert_eq!(digest.len(), MacAlgorithm::Aes128Cmac.digest_len());
+        assert_eq!(
+            digest,
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+        assert!(key.verify(&data, &digest));
+        assert!(!key.verify(&data[1..], &digest));
+    }
+}
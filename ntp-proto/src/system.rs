@@ -1,6 +1,11 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
-use crate::{NtpDuration, NtpLeapIndicator, PeerSnapshot, PollInterval, ReferenceId, SystemConfig};
+use crate::{
+    BloomFilter, NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PeerSnapshot, PollInterval,
+    RefClock, ReferenceId, ServerId, SystemConfig,
+};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TimeSnapshot {
@@ -31,7 +36,7 @@ impl Default for TimeSnapshot {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SystemSnapshot {
     /// Log of the precision of the local clock
     pub stratum: u8,
@@ -42,18 +47,110 @@ pub struct SystemSnapshot {
     /// Timekeeping data
     #[serde(flatten)]
     pub time_snapshot: TimeSnapshot,
+    /// Error bound the clock reported right after the last timekeeping update
+    #[serde(default)]
+    pub clock_error_bound: NtpDuration,
+    /// Time at which the clock was last set or corrected, zero when it never was
+    #[serde(default)]
+    pub reference_timestamp: NtpTimestamp,
+    /// Our id in the NTPv5 bloom filters, see [`SystemSnapshot::with_server_id`]
+    #[serde(skip)]
+    pub server_id: ServerId,
+    /// NTPv5 bloom filter with our id and those in the filters of the peers we
+    /// synchronize to, which our NTPv5 clients use for loop detection. It is
+    /// shared, as the snapshot is cloned for every response.
+    #[serde(skip)]
+    pub bloom_filter: Arc<BloomFilter>,
 }
 
 impl SystemSnapshot {
+    /// Snapshot of a system that is not synchronized yet, known as `server_id`
+    /// in NTPv5 bloom filters. The id should be chosen at random once, when the
+    /// system starts.
+    pub fn with_server_id(server_id: ServerId) -> Self {
+        let mut bloom_filter = BloomFilter::new();
+        bloom_filter.add_id(&server_id);
+
+        SystemSnapshot {
+            server_id,
+            bloom_filter: Arc::new(bloom_filter),
+            ..Default::default()
+        }
+    }
+
+    /// Snapshot of a system synchronized directly to a reference clock, which
+    /// makes it a stratum 1 server. The dispersion of the reference clock becomes
+    /// the root dispersion, and there is no root delay.
+    pub fn from_refclock<R: RefClock>(
+        refclock: &R,
+        config: &SystemConfig,
+    ) -> Result<Self, R::Error> {
+        let reading = refclock.read()?;
+
+        Ok(SystemSnapshot {
+            stratum: 1,
+            reference_id: reading.reference_id,
+            accumulated_steps_threshold: config.accumulated_threshold,
+            time_snapshot: TimeSnapshot {
+                root_delay: NtpDuration::ZERO,
+                root_dispersion: reading.dispersion,
+                leap_indicator: NtpLeapIndicator::NoWarning,
+                ..TimeSnapshot::default()
+            },
+            clock_error_bound: NtpDuration::ZERO,
+            reference_timestamp: reading.timestamp,
+            ..Default::default()
+        })
+    }
+
     pub fn update_timedata(&mut self, timedata: TimeSnapshot, config: &SystemConfig) {
         self.time_snapshot = timedata;
         self.accumulated_steps_threshold = config.accumulated_threshold;
     }
 
+    /// Record the error bound of the clock right after the timekeeping data was
+    /// updated. At that point the bound is the one the clock controller just
+    /// gave the clock, which is already accounted for in the root delay and
+    /// dispersion.
+    pub fn update_error_bound<C: NtpClock>(&mut self, clock: &C) -> Result<(), C::Error> {
+        self.clock_error_bound = clock.error_bound()?;
+        Ok(())
+    }
+
+    /// Record that the clock was just set or corrected, which is reported to our
+    /// clients as the reference timestamp
+    pub fn update_reference_timestamp<C: NtpClock>(&mut self, clock: &C) -> Result<(), C::Error> {
+        self.reference_timestamp = clock.now()?;
+        Ok(())
+    }
+
+    /// This snapshot, with the growth of the clock's error bound since the last
+    /// timekeeping update added to the root dispersion. The clock (e.g. the
+    /// kernel's maxerror) increases its bound over time while the controller
+    /// only updates the dispersion when it processes a measurement, so this
+    /// should be sampled regularly while the snapshot is in use.
+    pub fn with_error_bound_growth<C: NtpClock>(&self, clock: &C) -> Result<Self, C::Error> {
+        let growth = (clock.error_bound()? - self.clock_error_bound).max(NtpDuration::ZERO);
+        let mut snapshot = self.clone();
+        snapshot.time_snapshot.root_dispersion += growth;
+        Ok(snapshot)
+    }
+
     pub fn update_used_peers(&mut self, mut used_peers: impl Iterator<Item = PeerSnapshot>) {
         if let Some(system_peer_snapshot) = used_peers.next() {
             self.stratum = system_peer_snapshot.stratum.saturating_add(1);
-            self.reference_id = system_peer_snapshot.reference_id;
+            // Our reference is the system peer itself, not its reference, as
+            // clients use this for loop detection
+            self.reference_id = system_peer_snapshot.peer_id;
+
+            let mut bloom_filter = BloomFilter::new();
+            bloom_filter.add_id(&self.server_id);
+            for peer in std::iter::once(system_peer_snapshot).chain(used_peers) {
+                if let Some(peer_filter) = &peer.bloom_filter {
+                    bloom_filter.add(peer_filter);
+                }
+            }
+            self.bloom_filter = Arc::new(bloom_filter);
         }
     }
 }
@@ -65,13 +162,17 @@ impl Default for SystemSnapshot {
             reference_id: ReferenceId::NONE,
             accumulated_steps_threshold: None,
             time_snapshot: TimeSnapshot::default(),
+            clock_error_bound: NtpDuration::ZERO,
+            reference_timestamp: NtpTimestamp::default(),
+            server_id: ServerId::default(),
+            bloom_filter: Default::default(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::PollIntervalLimits;
+    use crate::{NtpTimestamp, PollIntervalLimits, RefClockReading};
 
     use super::*;
 
@@ -88,7 +189,11 @@ mod tests {
 
     #[test]
     fn test_peer_update() {
-        let mut system = SystemSnapshot::default();
+        let mut system = SystemSnapshot::with_server_id(ServerId::new(&mut rand::thread_rng()));
+        assert!(system.bloom_filter.contains_id(&system.server_id));
+        let upstream_id = ServerId::new(&mut rand::thread_rng());
+        let mut upstream_filter = BloomFilter::new();
+        upstream_filter.add_id(&upstream_id);
 
         system.update_used_peers(
             vec![
@@ -99,6 +204,10 @@ mod tests {
                     reach: Default::default(),
                     stratum: 2,
                     reference_id: ReferenceId::KISS_DENY,
+                    frozen: false,
+                    bloom_filter: Some(upstream_filter),
+                    last_kiss: None,
+                    kiss_count: 0,
                 },
                 PeerSnapshot {
                     peer_id: ReferenceId::KISS_RATE,
@@ -107,6 +216,10 @@ mod tests {
                     reach: Default::default(),
                     stratum: 3,
                     reference_id: ReferenceId::NONE,
+                    frozen: false,
+                    bloom_filter: None,
+                    last_kiss: None,
+                    kiss_count: 0,
                 },
             ]
             .into_iter(),
@@ -114,6 +227,151 @@ mod tests {
 
         assert_eq!(system.stratum, 3);
         assert_eq!(system.reference_id, ReferenceId::KISS_DENY);
+        // we advertise ourselves and the servers our peers synchronize to
+        assert!(system.bloom_filter.contains_id(&system.server_id));
+        assert!(system.bloom_filter.contains_id(&upstream_id));
+    }
+
+    #[derive(Debug, Clone)]
+    struct ErrorBoundClock {
+        error_bound: NtpDuration,
+    }
+
+    impl NtpClock for ErrorBoundClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<crate::NtpTimestamp, Self::Error> {
+            panic!("Unexpected clock read");
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<crate::NtpTimestamp, Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<crate::NtpTimestamp, Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn error_bound(&self) -> Result<NtpDuration, Self::Error> {
+            Ok(self.error_bound)
+        }
+    }
+
+    #[test]
+    fn test_error_bound_update() {
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.root_delay = NtpDuration::from_seconds(0.02);
+        system.time_snapshot.root_dispersion = NtpDuration::from_seconds(0.01);
+
+        // right after an update, the bound is the one the controller gave the
+        // clock, derived from root delay and dispersion, so it isn't counted again
+        let clock = ErrorBoundClock {
+            error_bound: NtpDuration::from_seconds(0.02),
+        };
+        system.update_error_bound(&clock).unwrap();
+        assert_eq!(system.clock_error_bound, NtpDuration::from_seconds(0.02));
+        let current = system.with_error_bound_growth(&clock).unwrap();
+        assert_eq!(
+            current.time_snapshot.root_dispersion,
+            NtpDuration::from_seconds(0.01)
+        );
+        assert_eq!(
+            current.time_snapshot.root_delay,
+            NtpDuration::from_seconds(0.02)
+        );
+
+        // but whatever the clock added since is advertised
+        let clock = ErrorBoundClock {
+            error_bound: NtpDuration::from_seconds(0.02) + NtpDuration::from_seconds(0.5),
+        };
+        let current = system.with_error_bound_growth(&clock).unwrap();
+        assert_eq!(
+            current.time_snapshot.root_dispersion,
+            NtpDuration::from_seconds(0.01) + NtpDuration::from_seconds(0.5)
+        );
+
+        // a bound that was lowered by someone else doesn't lower the dispersion
+        let clock = ErrorBoundClock {
+            error_bound: NtpDuration::ZERO,
+        };
+        let current = system.with_error_bound_growth(&clock).unwrap();
+        assert_eq!(
+            current.time_snapshot.root_dispersion,
+            NtpDuration::from_seconds(0.01)
+        );
+    }
+
+    struct MockRefClock {
+        reading: RefClockReading,
+    }
+
+    impl RefClock for MockRefClock {
+        type Error = std::io::Error;
+
+        fn read(&self) -> Result<RefClockReading, Self::Error> {
+            Ok(self.reading)
+        }
+    }
+
+    #[test]
+    fn test_from_refclock() {
+        let refclock = MockRefClock {
+            reading: RefClockReading {
+                timestamp: NtpTimestamp::from_fixed_int(1 << 32),
+                dispersion: NtpDuration::from_seconds(1e-6),
+                reference_id: ReferenceId::from_bytes(*b"GPS\0"),
+            },
+        };
+        let config = SystemConfig {
+            accumulated_threshold: Some(NtpDuration::from_seconds(1.0)),
+            ..Default::default()
+        };
+
+        let system = SystemSnapshot::from_refclock(&refclock, &config).unwrap();
+        assert_eq!(system.stratum, 1);
+        assert_eq!(system.reference_id, ReferenceId::from_bytes(*b"GPS\0"));
+        assert_eq!(
+            system.accumulated_steps_threshold,
+            Some(NtpDuration::from_seconds(1.0))
+        );
+        assert_eq!(
+            system.time_snapshot.root_dispersion,
+            NtpDuration::from_seconds(1e-6)
+        );
+        assert_eq!(system.time_snapshot.root_delay, NtpDuration::ZERO);
+        assert!(system.time_snapshot.leap_indicator.is_synchronized());
+        assert_eq!(
+            system.reference_timestamp,
+            NtpTimestamp::from_fixed_int(1 << 32)
+        );
     }
 
     #[test]
@@ -4,7 +4,7 @@ use rand::{
 };
 use serde::{de::Unexpected, Deserialize, Serialize};
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 /// NtpInstant is a monotonically increasing value modelling the uptime of the NTP service
 ///
@@ -45,6 +45,32 @@ impl NtpInstant {
     }
 }
 
+// Instants only have meaning within a single process, so they are (de)serialized
+// as their age, relative to the moment of (de)serialization.
+impl Serialize for NtpInstant {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.elapsed().as_secs_f64().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NtpInstant {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let age = f64::deserialize(deserializer)?;
+        let age = Duration::try_from_secs_f64(age).map_err(serde::de::Error::custom)?;
+        let now = Instant::now();
+
+        Ok(NtpInstant {
+            instant: now.checked_sub(age).unwrap_or(now),
+        })
+    }
+}
+
 impl Add<Duration> for NtpInstant {
     type Output = NtpInstant;
 
@@ -55,6 +81,14 @@ impl Add<Duration> for NtpInstant {
     }
 }
 
+/// Seconds from the start of NTP era 0 in 1900 to the unix epoch
+const UNIX_EPOCH_NTP_SECONDS: i64 = (70 * 365 + 17) * 86400;
+/// Seconds in an NTP era, after which the seconds of a timestamp wrap around
+const ERA_SECONDS: i64 = 1 << 32;
+/// Timestamps without context for their era are taken to lie in the 136 years
+/// from this unix time, 2000-01-01
+const ERA_WINDOW_START: i64 = 946_684_800;
+
 /// NtpTimestamp represents an ntp timestamp without the era number.
 #[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Default, Serialize, Deserialize)]
 pub struct NtpTimestamp {
@@ -80,6 +114,13 @@ impl NtpTimestamp {
         self.timestamp.to_be_bytes()
     }
 
+    /// Whether the timestamps are equal, in time independent of how many of
+    /// their bytes match. Origin timestamps echo our random transmit timestamp,
+    /// so a spoofer must not be able to guess them byte by byte.
+    pub(crate) fn constant_time_eq(self, other: NtpTimestamp) -> bool {
+        crate::packet::constant_time_eq(&self.to_bits(), &other.to_bits())
+    }
+
     /// Create an NTP timestamp from the number of seconds and nanoseconds that have
     /// passed since the last ntp era boundary.
     pub const fn from_seconds_nanos_since_ntp_era(seconds: u32, nanos: u32) -> Self {
@@ -98,6 +139,49 @@ impl NtpTimestamp {
         NtpTimestamp::from_bits(timestamp.to_be_bytes())
     }
 
+    /// The timestamp of the time `seconds` and `nanos` after the unix epoch.
+    /// The era of the time is lost, see [`NtpTimestamp::era_of_unix_time`].
+    pub const fn from_unix_time(seconds: i64, nanos: u32) -> Self {
+        let seconds = (seconds + UNIX_EPOCH_NTP_SECONDS).rem_euclid(ERA_SECONDS);
+        Self::from_seconds_nanos_since_ntp_era(seconds as u32, nanos)
+    }
+
+    /// The NTP era of the time `seconds` after the unix epoch. Era 0 started
+    /// in 1900, era 1 starts on 2036-02-07.
+    pub const fn era_of_unix_time(seconds: i64) -> i32 {
+        (seconds + UNIX_EPOCH_NTP_SECONDS).div_euclid(ERA_SECONDS) as i32
+    }
+
+    /// The time of this timestamp in `era`, in seconds and nanoseconds after
+    /// the unix epoch
+    pub const fn to_unix_time(self, era: i32) -> (i64, u32) {
+        let mut seconds =
+            (self.timestamp >> 32) as i64 + era as i64 * ERA_SECONDS - UNIX_EPOCH_NTP_SECONDS;
+        let mut nanos = ((self.timestamp & 0xFFFF_FFFF) * 1_000_000_000 + (1 << 31)) >> 32;
+        if nanos == 1_000_000_000 {
+            seconds += 1;
+            nanos = 0;
+        }
+        (seconds, nanos as u32)
+    }
+
+    /// The era of this timestamp, taking it to lie within 68 years of `pivot`,
+    /// in seconds after the unix epoch. The current time is a good pivot for
+    /// timestamps in packets.
+    pub const fn era_near(self, pivot: i64) -> i32 {
+        let pivot = pivot + UNIX_EPOCH_NTP_SECONDS;
+        let seconds = (self.timestamp >> 32) as u32;
+        // the wrapping difference is the distance to the pivot within an era
+        let difference = seconds.wrapping_sub(pivot.rem_euclid(ERA_SECONDS) as u32) as i32;
+        (pivot + difference as i64).div_euclid(ERA_SECONDS) as i32
+    }
+
+    /// The era of this timestamp, taking it to lie in the 136 years from 2000
+    /// on, which spans the rollover to era 1 in 2036.
+    pub const fn era(self) -> i32 {
+        self.era_near(ERA_WINDOW_START + ERA_SECONDS / 2)
+    }
+
     pub fn is_before(self, other: NtpTimestamp) -> bool {
         // Around an era change, self can be near the maximum value
         // for NtpTimestamp and other near the minimum, and that must
@@ -106,12 +190,56 @@ impl NtpTimestamp {
         self - other < NtpDuration::ZERO
     }
 
+    /// The whole second closest to this timestamp
+    pub(crate) const fn round_to_second(self) -> NtpTimestamp {
+        NtpTimestamp {
+            timestamp: self.timestamp.wrapping_add(1 << 31) & !0xFFFF_FFFF,
+        }
+    }
+
+    /// The time of this timestamp in `era`, if a [`SystemTime`] can represent it
+    pub fn to_system_time(self, era: i32) -> Option<SystemTime> {
+        let (seconds, nanos) = self.to_unix_time(era);
+        let since_epoch = Duration::from_secs(seconds.unsigned_abs());
+        let time = match seconds >= 0 {
+            true => SystemTime::UNIX_EPOCH.checked_add(since_epoch)?,
+            false => SystemTime::UNIX_EPOCH.checked_sub(since_epoch)?,
+        };
+        time.checked_add(Duration::from_nanos(nanos as u64))
+    }
+
     #[cfg(any(test, feature = "fuzz"))]
     pub(crate) const fn from_fixed_int(timestamp: u64) -> NtpTimestamp {
         NtpTimestamp { timestamp }
     }
 }
 
+impl From<SystemTime> for NtpTimestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => Self::from_unix_time(since.as_secs() as i64, since.subsec_nanos()),
+            Err(error) => {
+                let before = error.duration();
+                match before.subsec_nanos() {
+                    0 => Self::from_unix_time(-(before.as_secs() as i64), 0),
+                    nanos => {
+                        Self::from_unix_time(-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The time of a timestamp, taking it to lie in the era given by [`NtpTimestamp::era`]
+impl From<NtpTimestamp> for SystemTime {
+    fn from(timestamp: NtpTimestamp) -> Self {
+        timestamp
+            .to_system_time(timestamp.era())
+            .expect("times between 2000 and 2136 fit in a SystemTime")
+    }
+}
+
 // In order to provide increased entropy on origin timestamps,
 // we should generate these randomly. This helps avoid
 // attacks from attackers guessing our current time.
@@ -247,6 +375,25 @@ impl NtpDuration {
         .to_be_bytes()
     }
 
+    /// Parse the time32 format of NTPv5, with 4 integer and 28 fractional bits
+    pub(crate) const fn from_bits_time32(bits: [u8; 4]) -> Self {
+        NtpDuration {
+            duration: (u32::from_be_bytes(bits) as i64) << 4,
+        }
+    }
+
+    pub(crate) const fn to_bits_time32(self) -> [u8; 4] {
+        // as with the short format, negative durations indicate a programming
+        // error, and durations of 16 seconds or more saturate
+        assert!(self.duration >= 0);
+
+        match self.duration > 0x0000000FFFFFFFFF {
+            true => 0xFFFFFFFF_u32,
+            false => (self.duration >> 4) as u32,
+        }
+        .to_be_bytes()
+    }
+
     /// Convert to an f64; required for statistical calculations
     /// (e.g. in clock filtering)
     pub fn to_seconds(self) -> f64 {
@@ -330,12 +477,63 @@ impl NtpDuration {
         NtpDuration::from_bits(timestamp.to_be_bytes())
     }
 
+    /// The length of this duration as a [`Duration`], and whether it is negative
+    pub fn to_system_duration_signed(self) -> (Duration, bool) {
+        let magnitude = self.duration.unsigned_abs() as u128;
+        let nanos = (magnitude * 1_000_000_000 + (1 << 31)) >> 32;
+        (Duration::from_nanos(nanos as u64), self.duration < 0)
+    }
+
+    /// A duration of length `duration`, negative when `negative` is set. Lengths
+    /// beyond the range of an NtpDuration saturate.
+    pub fn from_system_duration_signed(duration: Duration, negative: bool) -> Self {
+        let magnitude =
+            NtpDuration::try_from(duration).unwrap_or(NtpDuration { duration: i64::MAX });
+        match negative {
+            true => -magnitude,
+            false => magnitude,
+        }
+    }
+
     #[cfg(any(test, feature = "fuzz"))]
     pub(crate) const fn from_fixed_int(duration: i64) -> NtpDuration {
         NtpDuration { duration }
     }
 }
 
+/// A conversion between an [`NtpDuration`] and a [`Duration`] that does not fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DurationConversionError {
+    #[error("negative durations can't be converted to a std::time::Duration")]
+    Negative,
+    #[error("duration of 2^31 seconds or more does not fit in an NtpDuration")]
+    OutOfRange,
+}
+
+impl TryFrom<Duration> for NtpDuration {
+    type Error = DurationConversionError;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        // NTP uses 1/2^32 sec as its unit of fractional time
+        let fixed = (duration.as_nanos() << 32) / 1_000_000_000;
+        match i64::try_from(fixed) {
+            Ok(duration) => Ok(NtpDuration { duration }),
+            Err(_) => Err(DurationConversionError::OutOfRange),
+        }
+    }
+}
+
+impl TryFrom<NtpDuration> for Duration {
+    type Error = DurationConversionError;
+
+    fn try_from(duration: NtpDuration) -> Result<Self, Self::Error> {
+        match duration.to_system_duration_signed() {
+            (duration, false) => Ok(duration),
+            (_, true) => Err(DurationConversionError::Negative),
+        }
+    }
+}
+
 impl Serialize for NtpDuration {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -551,6 +749,12 @@ impl PollInterval {
         Self(self.0 - 1).max(limits.min)
     }
 
+    /// The poll interval of 2^`log` seconds, if that is a poll interval NTP
+    /// could use (at most 2^17 seconds)
+    pub fn from_log(log: i8) -> Option<Self> {
+        (0..=17).contains(&log).then_some(Self(log))
+    }
+
     pub const fn as_log(self) -> i8 {
         self.0
     }
@@ -654,6 +858,122 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_timestamp_constant_time_eq() {
+        let a = NtpTimestamp::from_fixed_int(0x0123_4567_89AB_CDEF);
+        assert!(a.constant_time_eq(a));
+        assert!(!a.constant_time_eq(NtpTimestamp::from_fixed_int(0x0123_4567_89AB_CDEE)));
+        assert!(!a.constant_time_eq(NtpTimestamp::from_fixed_int(0x1123_4567_89AB_CDEF)));
+    }
+
+    #[test]
+    fn test_timestamp_unix_time_eras() {
+        // 2036-02-07T06:28:16Z, the start of era 1
+        const ROLLOVER: i64 = 2_085_978_496;
+
+        // 2020-01-01
+        assert_eq!(NtpTimestamp::from_unix_time(1_577_836_800, 0).era(), 0);
+        assert_eq!(
+            NtpTimestamp::from_unix_time(0, 0),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(2_208_988_800, 0)
+        );
+
+        for (seconds, era) in [
+            (ROLLOVER - 1, 0),
+            (ROLLOVER, 1),
+            (ROLLOVER + 1, 1),
+            (0, 0),
+            (-2_208_988_801, -1),
+            (ROLLOVER + (1 << 32), 2),
+        ] {
+            let timestamp = NtpTimestamp::from_unix_time(seconds, 123_456_789);
+            assert_eq!(NtpTimestamp::era_of_unix_time(seconds), era);
+            assert_eq!(timestamp.to_unix_time(era), (seconds, 123_456_789));
+            // the era is found again from a pivot within 68 years
+            assert_eq!(timestamp.era_near(seconds + (1 << 30)), era);
+            assert_eq!(timestamp.era_near(seconds - (1 << 30)), era);
+        }
+
+        // timestamps just after the rollover are close to zero, but later
+        assert_eq!(
+            NtpTimestamp::from_unix_time(ROLLOVER, 0),
+            NtpTimestamp::from_fixed_int(0)
+        );
+        assert_eq!(NtpTimestamp::from_fixed_int(0).era(), 1);
+        assert_eq!(NtpTimestamp::from_unix_time(ROLLOVER - 1, 0).era(), 0);
+        assert!(NtpTimestamp::from_unix_time(ROLLOVER - 1, 0)
+            .is_before(NtpTimestamp::from_unix_time(ROLLOVER, 0)));
+
+        // fractions that round up to a whole second
+        assert_eq!(
+            NtpTimestamp::from_fixed_int(u32::MAX as u64).to_unix_time(0),
+            (-2_208_988_799, 0)
+        );
+    }
+
+    #[test]
+    fn test_system_time_conversions() {
+        let time = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let timestamp = NtpTimestamp::from(time);
+        assert_eq!(
+            timestamp,
+            NtpTimestamp::from_unix_time(1_700_000_000, 123_456_789)
+        );
+        assert_eq!(SystemTime::from(timestamp), time);
+
+        // after the rollover in 2036
+        let time = SystemTime::UNIX_EPOCH + Duration::new(2_100_000_000, 5);
+        let timestamp = NtpTimestamp::from(time);
+        assert_eq!(timestamp.era(), 1);
+        assert_eq!(SystemTime::from(timestamp), time);
+        assert_eq!(
+            timestamp.to_system_time(0),
+            Some(time - Duration::from_secs(1 << 32))
+        );
+
+        // before the unix epoch
+        let time = SystemTime::UNIX_EPOCH - Duration::new(10, 250_000_000);
+        assert_eq!(
+            NtpTimestamp::from(time),
+            NtpTimestamp::from_unix_time(-11, 750_000_000)
+        );
+        assert_eq!(NtpTimestamp::from(time).to_system_time(0), Some(time));
+    }
+
+    #[test]
+    fn test_system_duration_conversions() {
+        let duration = Duration::new(3, 500_000_000);
+        let ntp = NtpDuration::try_from(duration).unwrap();
+        assert_eq!(ntp, NtpDuration::from_fixed_int(7 << 31));
+        assert_eq!(Duration::try_from(ntp), Ok(duration));
+        assert_eq!(
+            Duration::try_from(-ntp),
+            Err(DurationConversionError::Negative)
+        );
+
+        // nanoseconds survive the round trip through the fixed point format
+        for nanos in [0, 1, 999_999_999] {
+            let duration = Duration::new(7, nanos);
+            let ntp = NtpDuration::try_from(duration).unwrap();
+            assert_eq!(Duration::try_from(ntp), Ok(duration));
+        }
+
+        assert_eq!(
+            NtpDuration::try_from(Duration::from_secs(1 << 31)),
+            Err(DurationConversionError::OutOfRange)
+        );
+
+        assert_eq!(
+            NtpDuration::from_system_duration_signed(duration, true),
+            -ntp
+        );
+        assert_eq!((-ntp).to_system_duration_signed(), (duration, true));
+        assert_eq!(
+            NtpDuration::from_system_duration_signed(Duration::from_secs(1 << 40), true),
+            -NtpDuration::from_fixed_int(i64::MAX)
+        );
+    }
+
     #[test]
     fn test_timestamp_duration_math() {
         let mut a = NtpTimestamp::from_fixed_int(5);
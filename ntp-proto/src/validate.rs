@@ -0,0 +1,522 @@
+use crate::{
+    packet::{NtpAssociationMode, RequestIdentifier},
+    Measurement, NtpDuration, NtpInstant, NtpPacket, NtpTimestamp,
+};
+
+/// Largest stratum of a server that is synchronized (RFC 5905 uses 16 for unsynchronized)
+const MAX_SYNCHRONIZED_STRATUM: u8 = 15;
+
+/// The reason [`validate_response`] rejected a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The response does not echo the origin timestamp of our request
+    InvalidOrigin,
+    /// The response is not authenticated, or not authenticated for our request
+    Unauthenticated,
+    /// Received a RATE Kiss-o'-Death, the poll interval should be increased
+    KissRate,
+    /// Received a DENY or RSTR Kiss-o'-Death, and must demobilize the association
+    KissDemobilize,
+    /// Received an NTS-Nack, the cookie used in the request was not accepted
+    KissNtsNack,
+    /// Received an unrecognized Kiss-o'-Death
+    KissUnknown,
+    /// Received a crypto-NAK, the server could not authenticate the MAC of our request
+    CryptoNak,
+    /// The association mode is not server
+    InvalidMode,
+    /// The stratum of the server is too high
+    InvalidStratum,
+    /// The server indicates that its clock is not synchronized
+    Unsynchronized,
+    /// The timestamps in the response are missing, or we received it before sending
+    InvalidTimestamps,
+    /// The server claims to have sent the response before receiving the request,
+    /// which only a buggy server does
+    InconsistentServerTimestamps,
+    /// The root distance of the server is too large
+    RootDistance,
+}
+
+/// Limits applied by [`validate_response`]
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationPolicy {
+    /// Strict NTS mode: require every response to be authenticated with NTS, and never
+    /// fall back to plain NTP. This requires the request to have been created through
+    /// `NtpPacket::nts_poll_message`, and the response to have been deserialized using
+    /// the server-to-client key.
+    ///
+    /// This fails closed: when NTS can't be established no time is used at all, and
+    /// unauthenticated NTS-Nacks are rejected rather than acted upon. That trades
+    /// availability for security, as a network that blocks or breaks NTS leaves the
+    /// client without time instead of with unauthenticated time.
+    pub strict_nts: bool,
+    /// Highest stratum of the server that is accepted
+    pub max_stratum: u8,
+    /// Largest root distance (half the round trip delay to the root plus the root
+    /// dispersion) that is accepted
+    pub max_root_distance: NtpDuration,
+    /// Precision of the local clock, used as lower bound for the measured delay
+    pub precision: NtpDuration,
+    /// Accept responses with a receive timestamp after their transmit timestamp.
+    /// The negative processing time of the server then decreases the measured delay.
+    /// Only useful to get any time at all from a buggy server.
+    pub accept_inconsistent_server_timestamps: bool,
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> Self {
+        Self {
+            strict_nts: false,
+            max_stratum: MAX_SYNCHRONIZED_STRATUM,
+            // MAXDIST from RFC 5905
+            max_root_distance: NtpDuration::from_seconds(1.5),
+            precision: NtpDuration::from_exponent(-18),
+            accept_inconsistent_server_timestamps: false,
+        }
+    }
+}
+
+/// Check a response from a server to a request we sent, and turn it into a measurement.
+///
+/// This combines all the checks a client needs to do on a response before using it:
+/// the response must echo our request (and for NTS be authenticated for it), must not
+/// be a Kiss-o'-Death, must come from a synchronized server in server mode, must have
+/// consistent timestamps and must be within the root distance allowed by the policy.
+/// The checks are done in that order, so that unauthenticated packets never cause a
+/// Kiss-o'-Death to be acted upon.
+///
+/// `send_time` and `now` are the local times at which the request was sent and the
+/// response was received, `local_clock_time` is the monotonic time at which the response
+/// was received.
+pub fn validate_response(
+    request: &RequestIdentifier,
+    response: &NtpPacket,
+    policy: &ValidationPolicy,
+    send_time: NtpTimestamp,
+    now: NtpTimestamp,
+    local_clock_time: NtpInstant,
+) -> Result<Measurement, RejectReason> {
+    if !response
+        .origin_timestamp()
+        .constant_time_eq(request.expected_origin_timestamp)
+    {
+        return Err(RejectReason::InvalidOrigin);
+    }
+
+    let nts = request.uid.is_some();
+    if (policy.strict_nts && !(nts && response.is_authenticated()))
+        || !response.valid_server_response(*request, nts)
+    {
+        return Err(RejectReason::Unauthenticated);
+    }
+
+    if response.is_crypto_nak() {
+        return Err(RejectReason::CryptoNak);
+    }
+
+    if response.is_kiss_rate() {
+        return Err(RejectReason::KissRate);
+    } else if response.is_kiss_deny() || response.is_kiss_rstr() {
+        return Err(RejectReason::KissDemobilize);
+    } else if response.is_kiss_ntsn() {
+        return Err(RejectReason::KissNtsNack);
+    } else if response.is_kiss() {
+        return Err(RejectReason::KissUnknown);
+    }
+
+    if response.mode() != NtpAssociationMode::Server {
+        return Err(RejectReason::InvalidMode);
+    }
+
+    if response.stratum() > policy.max_stratum {
+        return Err(RejectReason::InvalidStratum);
+    }
+
+    if !response.leap().is_synchronized() {
+        return Err(RejectReason::Unsynchronized);
+    }
+
+    let zero = NtpTimestamp::default();
+    if response.receive_timestamp() == zero
+        || response.transmit_timestamp() == zero
+        || now - send_time < NtpDuration::ZERO
+    {
+        return Err(RejectReason::InvalidTimestamps);
+    }
+
+    if response.has_inconsistent_timestamps() && !policy.accept_inconsistent_server_timestamps {
+        return Err(RejectReason::InconsistentServerTimestamps);
+    }
+
+    let measurement =
+        Measurement::from_packet(response, send_time, now, local_clock_time, policy.precision);
+
+    let root_distance =
+        (response.root_delay() + measurement.delay) / 2 + response.root_dispersion();
+    if root_distance > policy.max_root_distance {
+        return Err(RejectReason::RootDistance);
+    }
+
+    Ok(measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        nts_record::AeadAlgorithm, packet::AesSivCmac256, DecodedServerCookie, KeySetProvider,
+        NtpClock, NtpLeapIndicator, PollInterval, ReferenceId, SystemSnapshot,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct TestClock {
+        now: NtpTimestamp,
+    }
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(self.now)
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<NtpTimestamp, Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            panic!("Unexpected clock steer");
+        }
+    }
+
+    const SEND_TIME: u64 = 10 << 32;
+    const RECV_TIME: u64 = 12 << 32;
+
+    fn system() -> SystemSnapshot {
+        let mut system = SystemSnapshot {
+            stratum: 1,
+            ..Default::default()
+        };
+        system.time_snapshot.leap_indicator = NtpLeapIndicator::NoWarning;
+        system
+    }
+
+    fn clock() -> TestClock {
+        TestClock {
+            now: NtpTimestamp::from_fixed_int(11 << 32),
+        }
+    }
+
+    fn check(
+        request: &RequestIdentifier,
+        response: &NtpPacket,
+        policy: &ValidationPolicy,
+    ) -> Result<Measurement, RejectReason> {
+        validate_response(
+            request,
+            response,
+            policy,
+            NtpTimestamp::from_fixed_int(SEND_TIME),
+            NtpTimestamp::from_fixed_int(RECV_TIME),
+            NtpInstant::now(),
+        )
+    }
+
+    fn exchange() -> (RequestIdentifier, NtpPacket<'static>) {
+        let (request, id) = NtpPacket::poll_message(PollInterval::default());
+        let response = NtpPacket::timestamp_response(
+            &system(),
+            request,
+            NtpTimestamp::from_fixed_int(11 << 32),
+            &clock(),
+        );
+        (id, response)
+    }
+
+    #[test]
+    fn test_accept() {
+        let (id, response) = exchange();
+        let measurement = check(&id, &response, &ValidationPolicy::default()).unwrap();
+        assert_eq!(measurement.offset, NtpDuration::ZERO);
+        assert_eq!(measurement.delay, NtpDuration::from_seconds(2.0));
+        assert_eq!(
+            measurement.localtime,
+            NtpTimestamp::from_fixed_int(11 << 32)
+        );
+    }
+
+    #[test]
+    fn test_reject_origin() {
+        let (id, mut response) = exchange();
+        response.set_origin_timestamp(response.origin_timestamp() + NtpDuration::from_seconds(1.0));
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InvalidOrigin
+        );
+
+        // a response to another request is rejected as well
+        let (_, response) = exchange();
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InvalidOrigin
+        );
+    }
+
+    #[test]
+    fn test_reject_kiss() {
+        let cases = [
+            (ReferenceId::KISS_RATE, RejectReason::KissRate),
+            (ReferenceId::KISS_DENY, RejectReason::KissDemobilize),
+            (ReferenceId::KISS_RSTR, RejectReason::KissDemobilize),
+            (ReferenceId::KISS_NTSN, RejectReason::KissNtsNack),
+            (ReferenceId::NONE, RejectReason::KissUnknown),
+        ];
+
+        for (code, reason) in cases {
+            let (id, mut response) = exchange();
+            response.set_stratum(0);
+            response.set_reference_id(code);
+            assert_eq!(
+                check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+                reason
+            );
+        }
+
+        let (request, id) = NtpPacket::poll_message(PollInterval::default());
+        let response = NtpPacket::deny_response(request);
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::KissDemobilize
+        );
+    }
+
+    #[test]
+    fn test_reject_crypto_nak() {
+        let (request, id) = NtpPacket::poll_message(PollInterval::default());
+        let response = NtpPacket::crypto_nak_response(request);
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::CryptoNak
+        );
+    }
+
+    #[test]
+    fn test_reject_mode() {
+        let (id, mut response) = exchange();
+        response.set_mode(NtpAssociationMode::Client);
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InvalidMode
+        );
+    }
+
+    #[test]
+    fn test_reject_stratum() {
+        let (id, mut response) = exchange();
+        response.set_stratum(16);
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InvalidStratum
+        );
+
+        response.set_stratum(3);
+        let policy = ValidationPolicy {
+            max_stratum: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&id, &response, &policy).unwrap_err(),
+            RejectReason::InvalidStratum
+        );
+    }
+
+    #[test]
+    fn test_reject_unsynchronized() {
+        let (id, mut response) = exchange();
+        response.set_leap(NtpLeapIndicator::Unknown);
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::Unsynchronized
+        );
+    }
+
+    #[test]
+    fn test_reject_timestamps() {
+        let (id, mut response) = exchange();
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(0));
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InvalidTimestamps
+        );
+
+        let (id, mut response) = exchange();
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(0));
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InvalidTimestamps
+        );
+
+        // and we cannot receive the response before sending the request
+        let (id, response) = exchange();
+        assert_eq!(
+            validate_response(
+                &id,
+                &response,
+                &ValidationPolicy::default(),
+                NtpTimestamp::from_fixed_int(RECV_TIME),
+                NtpTimestamp::from_fixed_int(SEND_TIME),
+                NtpInstant::now(),
+            )
+            .unwrap_err(),
+            RejectReason::InvalidTimestamps
+        );
+    }
+
+    #[test]
+    fn test_reject_inconsistent_server_timestamps() {
+        // the server cannot send the response before receiving the request
+        let (id, mut response) = exchange();
+        response
+            .set_transmit_timestamp(response.receive_timestamp() - NtpDuration::from_seconds(0.5));
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::InconsistentServerTimestamps
+        );
+
+        // unless explicitly allowed
+        let policy = ValidationPolicy {
+            accept_inconsistent_server_timestamps: true,
+            ..Default::default()
+        };
+        let measurement = check(&id, &response, &policy).unwrap();
+        assert!((measurement.delay.to_seconds() - 2.5).abs() < 1e-6);
+        assert!((measurement.offset.to_seconds() + 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_reject_root_distance() {
+        let (id, mut response) = exchange();
+        response.set_root_dispersion(NtpDuration::from_seconds(1.0));
+        // 1s of dispersion plus half of the 2s delay exceeds the default 1.5s
+        assert_eq!(
+            check(&id, &response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::RootDistance
+        );
+
+        let policy = ValidationPolicy {
+            max_root_distance: NtpDuration::from_seconds(2.5),
+            ..Default::default()
+        };
+        assert!(check(&id, &response, &policy).is_ok());
+    }
+
+    #[test]
+    fn test_nts() {
+        let decoded = DecodedServerCookie {
+            algorithm: AeadAlgorithm::AeadAesSivCmac256,
+            s2c: Box::new(AesSivCmac256::new((0..32_u8).collect())),
+            c2s: Box::new(AesSivCmac256::new((32..64_u8).collect())),
+        };
+        let keyset = KeySetProvider::new(1).get();
+        let cookie = keyset.encode_cookie(&decoded);
+
+        let policy = ValidationPolicy {
+            strict_nts: true,
+            ..Default::default()
+        };
+
+        // an unauthenticated exchange is accepted in lenient mode, but never in strict mode
+        let (id, response) = exchange();
+        assert!(!response.is_authenticated());
+        assert!(check(&id, &response, &ValidationPolicy::default()).is_ok());
+        assert_eq!(
+            check(&id, &response, &policy).unwrap_err(),
+            RejectReason::Unauthenticated
+        );
+
+        let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+
+        // a response without authentication is rejected, even when nts is not required
+        let plain_response = NtpPacket::timestamp_response(
+            &system(),
+            request.clone(),
+            NtpTimestamp::from_fixed_int(11 << 32),
+            &clock(),
+        );
+        assert_eq!(
+            check(&id, &plain_response, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::Unauthenticated
+        );
+
+        let response = NtpPacket::nts_timestamp_response(
+            &system(),
+            request,
+            NtpTimestamp::from_fixed_int(11 << 32),
+            &clock(),
+            &decoded,
+            &keyset,
+        );
+        let mut buf = [0u8; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        response
+            .serialize(&mut cursor, decoded.s2c.as_ref())
+            .unwrap();
+        let length = cursor.position() as usize;
+
+        let (response, _) =
+            NtpPacket::deserialize(&buf[..length], &Some(decoded.s2c.as_ref())).unwrap();
+        assert!(response.is_authenticated());
+        assert!(check(&id, &response, &policy).is_ok());
+
+        // an unauthenticated nts nack is not acted upon in strict mode
+        let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+        let mut nack = NtpPacket::timestamp_response(
+            &system(),
+            request,
+            NtpTimestamp::from_fixed_int(11 << 32),
+            &clock(),
+        );
+        nack.set_stratum(0);
+        nack.set_reference_id(ReferenceId::KISS_NTSN);
+        assert_eq!(
+            check(&id, &nack, &ValidationPolicy::default()).unwrap_err(),
+            RejectReason::KissNtsNack
+        );
+        assert_eq!(
+            check(&id, &nack, &policy).unwrap_err(),
+            RejectReason::Unauthenticated
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use tracing::{error, warn};
+
+use crate::{config::StaleSyncAction, NtpDuration, NtpInstant};
+
+/// Watches how long ago the clock was last synchronized, and takes an action
+/// once that exceeds a timeout, for instance because all peers became
+/// unreachable or no majority of them agrees on the time anymore.
+///
+/// The action is taken once each time synchronization goes stale, and can
+/// happen again only after the next successful synchronization.
+pub struct SyncWatchdog {
+    timeout: NtpDuration,
+    last_sync: NtpInstant,
+    stale: bool,
+    on_stale: Box<dyn FnMut(NtpDuration) + Send>,
+}
+
+impl std::fmt::Debug for SyncWatchdog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncWatchdog")
+            .field("timeout", &self.timeout)
+            .field("last_sync", &self.last_sync)
+            .field("stale", &self.stale)
+            .finish()
+    }
+}
+
+impl SyncWatchdog {
+    /// Create a watchdog that calls `on_stale` with the time since the last
+    /// synchronization when that exceeds `timeout`. The watchdog starts
+    /// counting at `now`. The watchdog uses monotonic time, so steps of the
+    /// system clock do not make it fire early or late.
+    pub fn new(
+        timeout: NtpDuration,
+        now: NtpInstant,
+        on_stale: impl FnMut(NtpDuration) + Send + 'static,
+    ) -> Self {
+        SyncWatchdog {
+            timeout,
+            last_sync: now,
+            stale: false,
+            on_stale: Box::new(on_stale),
+        }
+    }
+
+    /// Create a watchdog taking one of the configurable actions
+    pub fn with_action(timeout: NtpDuration, now: NtpInstant, action: StaleSyncAction) -> Self {
+        Self::new(timeout, now, move |since| match action {
+            StaleSyncAction::Log => {
+                warn!(
+                    "The clock was not synchronized in the last {}s",
+                    since.to_seconds()
+                );
+            }
+            StaleSyncAction::Exit => {
+                error!(
+                    "The clock was not synchronized in the last {}s, stopping",
+                    since.to_seconds()
+                );
+                std::process::exit(exitcode::SOFTWARE);
+            }
+        })
+    }
+
+    /// Record a successful synchronization of the clock at `now`
+    pub fn synchronized(&mut self, now: NtpInstant) {
+        self.last_sync = now;
+        self.stale = false;
+    }
+
+    /// The time of the last synchronization, or of the creation of the watchdog
+    /// if there was none
+    pub fn last_sync(&self) -> NtpInstant {
+        self.last_sync
+    }
+
+    /// Check the freshness of the synchronization at `now`, taking the stale
+    /// action if it newly went stale. Returns whether synchronization is stale.
+    pub fn check(&mut self, now: NtpInstant) -> bool {
+        let since = now.max(self.last_sync).abs_diff(self.last_sync);
+        if since > self.timeout && !self.stale {
+            self.stale = true;
+            (self.on_stale)(since);
+        }
+
+        self.stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_fires_after_timeout() {
+        let fired = Arc::new(Mutex::new(vec![]));
+        let fired_clone = fired.clone();
+        let start = NtpInstant::now();
+        let mut watchdog =
+            SyncWatchdog::new(NtpDuration::from_seconds(60.0), start, move |since| {
+                fired_clone.lock().unwrap().push(since)
+            });
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // synchronizing regularly keeps it quiet
+        for t in (16..=160).step_by(16) {
+            watchdog.synchronized(at(t));
+            assert!(!watchdog.check(at(t + 8)));
+        }
+        assert!(fired.lock().unwrap().is_empty());
+
+        // then all sources are lost, and no synchronization happens anymore
+        assert!(!watchdog.check(at(200)));
+        assert!(fired.lock().unwrap().is_empty());
+        assert!(watchdog.check(at(221)));
+        assert_eq!(
+            *fired.lock().unwrap(),
+            vec![NtpDuration::from_seconds(61.0)]
+        );
+
+        // it fires only once while stale
+        assert!(watchdog.check(at(300)));
+        assert_eq!(fired.lock().unwrap().len(), 1);
+
+        // and again after recovering and going stale once more
+        watchdog.synchronized(at(310));
+        assert!(!watchdog.check(at(320)));
+        assert!(watchdog.check(at(400)));
+        assert_eq!(fired.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_counts_from_creation() {
+        let fired = Arc::new(Mutex::new(0));
+        let fired_clone = fired.clone();
+        let start = NtpInstant::now();
+        let mut watchdog = SyncWatchdog::new(NtpDuration::from_seconds(60.0), start, move |_| {
+            *fired_clone.lock().unwrap() += 1
+        });
+
+        // never synchronized at all
+        assert!(!watchdog.check(start + Duration::from_secs(50)));
+        assert!(watchdog.check(start + Duration::from_secs(70)));
+        assert_eq!(*fired.lock().unwrap(), 1);
+    }
+}
@@ -8,14 +8,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let peer_configs = [PeerConfig::try_from("0.0.0.0:8080").unwrap()];
 
     // we always generate the keyset (even if NTS is not used)
-    let keyset = ntp_daemon::nts_key_provider::spawn(KeysetConfig::default()).await;
+    let keyset = ntp_daemon::nts_key_provider::spawn(KeysetConfig::default()).await?;
 
     let (handle, _) = ntp_daemon::spawn(
         CombinedSystemConfig::default(),
         ClockConfig::default(),
         &peer_configs,
         &[],
+        &[],
         keyset,
+        Default::default(),
     )
     .await?;
 